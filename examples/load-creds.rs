@@ -12,7 +12,8 @@ fn main() {
 
     let start = Instant::now();
 
-    let creds = badtouch::utils::load_creds(&path)
+    let options = badtouch::utils::ListOptions::default();
+    let (creds, _, _) = badtouch::utils::load_creds(&path, &options)
                                     .expect("failed to load creds");
 
     let elapsed = start.elapsed();
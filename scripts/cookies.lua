@@ -14,7 +14,7 @@ function verify(user, password)
     if last_err() then return end
 
     -- print(resp)
-    -- print(resp["headers"]["set-cookie"])
+    -- print(resp["headers_all"]["set-cookie"])
 
     -- check cookies have been setup
     -- TODO: removing the {} causes a segfault
@@ -1,12 +1,6 @@
 descr = "local mysql"
 
 function verify(user, password)
-    mysql_connect("127.0.0.1", 3306, user, password)
-
-    if last_err() then
-        clear_err()
-        return false
-    else
-        return true
-    end
+    -- rejected credentials return nil, other problems are recorded as errors
+    return mysql_connect("127.0.0.1", 3306, user, password) ~= nil
 end
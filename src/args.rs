@@ -1,20 +1,215 @@
 use structopt::StructOpt;
 use structopt::clap::AppSettings;
+use humantime;
+use config::ScriptOption;
+use dns::Override;
+use fsck::Delimiter;
+use hashes::InputFormat;
+use proxy::Rotation;
+use report::{Dedup, Format};
+use scheduler::Order;
+use syslog::{Facility, Target};
+use std::time::Duration;
 
 #[derive(StructOpt, Debug)]
 #[structopt(author = "",
-            raw(global_settings = "&[AppSettings::ColoredHelp]"))]
+            raw(global_settings = "&[AppSettings::ColoredHelp]"),
+            after_help = "EXIT CODES:
+    0    The attack completed and found valid credentials, or none with --fail-on-found
+    1    An error occurred
+    2    The attack completed and found no valid credentials, or some with --fail-on-found
+    130  The attack was interrupted with ctrl+c
+    143  The attack was stopped with SIGTERM
+
+oneshot uses the same exit codes for the credentials it tested.")]
 pub struct Args {
     #[structopt(short = "v", long = "verbose",
                 raw(global = "true"), parse(from_occurrences),
                 help="Verbose output")]
     pub verbose: u8,
+    #[structopt(short = "q", long = "quiet",
+                parse(from_occurrences),
+                help="Don't show errors of individual attempts, twice to hide the progress bar")]
+    pub quiet: u8,
     #[structopt(short = "n", long = "workers", default_value = "16",
                 help="Concurrent workers")]
     pub workers: usize,
     #[structopt(short = "o", long = "output",
                 help="Write results to file")]
     pub output: Option<String>,
+    #[structopt(long = "format",
+                help="Report format, plain, json, csv, hydra or potfile")]
+    pub format: Option<Format>,
+    #[structopt(long = "append",
+                help="Append to the report instead of overwriting it")]
+    pub append: bool,
+    #[structopt(long = "force",
+                help="Overwrite an existing report")]
+    pub force: bool,
+    #[structopt(long = "log",
+                help="Append every attempt and its outcome to this file")]
+    pub log: Option<String>,
+    #[structopt(long = "plain-metadata",
+                help="Add the timestamp, target, status and note to plain reports")]
+    pub plain_metadata: bool,
+    #[structopt(long = "dedup-report",
+                help="Credentials that were already reported by another script, drop or annotate")]
+    pub dedup_report: Option<Dedup>,
+    #[structopt(long = "no-dedup",
+                help="Keep duplicates in lists")]
+    pub no_dedup: bool,
+    #[structopt(long = "trim",
+                help="Remove trailing whitespace from lists")]
+    pub trim: bool,
+    #[structopt(long = "skip-found-users",
+                help="Stop trying passwords for a user once a valid password was found")]
+    pub skip_found_users: bool,
+    #[structopt(long = "skip-report",
+                help="Skip the credentials of a previous plain or json report")]
+    pub skip_report: Option<String>,
+    #[structopt(long = "lockout-threshold",
+                help="Skip a user after this many failed attempts in a row with a script")]
+    pub lockout_threshold: Option<usize>,
+    #[structopt(long = "stop-after",
+                help="Stop after this number of valid credentials was found")]
+    pub stop_after: Option<usize>,
+    #[structopt(long = "verify-found",
+                help="Run the valid attempts again at the end and mark them as confirmed or flaky in the report")]
+    pub verify_found: bool,
+    #[structopt(long = "verify-runs", default_value = "3",
+                help="How often --verify-found runs each valid attempt")]
+    pub verify_runs: usize,
+    #[structopt(long = "verify-delay", default_value = "5s",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="Delay between the runs of --verify-found")]
+    pub verify_delay: Duration,
+    #[structopt(long = "dry-run",
+                help="Load everything and show the attempts that would be started")]
+    pub dry_run: bool,
+    #[structopt(long = "fail-on-found",
+                help="Exit with 2 if valid credentials were found and 0 otherwise")]
+    pub fail_on_found: bool,
+    #[structopt(long = "shuffle",
+                help="Randomize the order of attempts")]
+    pub shuffle: bool,
+    #[structopt(long = "seed",
+                help="Seed for --shuffle, to repeat the order of a previous run")]
+    pub seed: Option<u64>,
+    #[structopt(long = "rate",
+                help="Maximum attempts per second")]
+    pub rate: Option<f64>,
+    #[structopt(long = "delay",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="Minimum delay between attempts, eg. 500ms")]
+    pub delay: Option<Duration>,
+    #[structopt(long = "timeout",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="Abort an attempt if it takes longer than this, eg. 30s")]
+    pub timeout: Option<Duration>,
+    #[structopt(long = "retries",
+                help="Retry an attempt this often after an error, 0 gives up immediately")]
+    pub retries: Option<u8>,
+    #[structopt(long = "backoff",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="Delay before the first retry, doubled for every retry, 0s disables it")]
+    pub backoff: Option<Duration>,
+    #[structopt(long = "backoff-cap",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="Maximum delay before a retry")]
+    pub backoff_cap: Option<Duration>,
+    #[structopt(long = "http-timeout",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="Timeout of a single http request, defaults to 30s")]
+    pub http_timeout: Option<Duration>,
+    #[structopt(long = "http-max-response",
+                help="Read at most this many bytes of an http response body, defaults to 5MiB")]
+    pub http_max_response: Option<u64>,
+    #[structopt(long = "proxy",
+                help="Route all connections through this proxy, eg. socks5://127.0.0.1:9050")]
+    pub proxy: Option<String>,
+    #[structopt(long = "proxy-strict",
+                help="Fail instead of connecting directly if something can't use --proxy")]
+    pub proxy_strict: bool,
+    #[structopt(long = "proxy-list",
+                help="Rotate through the proxies in this file, one per line")]
+    pub proxy_list: Option<String>,
+    #[structopt(long = "proxy-rotation",
+                help="How the proxy of an attempt is picked: round-robin, random or sticky, defaults to round-robin")]
+    pub proxy_rotation: Option<Rotation>,
+    #[structopt(long = "resolve",
+                raw(number_of_values = "1"),
+                help="Resolve a host to this ip, eg. sso.corp.local:443:10.0.0.1, can be used multiple times")]
+    pub resolve: Vec<Override>,
+    #[structopt(long = "no-verify-hostname",
+                help="Accept tls certificates that don't match the hostname")]
+    pub no_verify_hostname: bool,
+    #[structopt(long = "tls-ca-file",
+                help="Trust the certificates in this pem file for https")]
+    pub tls_ca_file: Option<String>,
+    #[structopt(long = "auto-scale",
+                help="Adjust the number of workers to the errors and latency of the target")]
+    pub auto_scale: bool,
+    #[structopt(long = "auto-scale-min", default_value = "1",
+                help="Minimum workers with --auto-scale")]
+    pub auto_scale_min: usize,
+    #[structopt(long = "auto-scale-max",
+                help="Maximum workers with --auto-scale, defaults to 4 times -n")]
+    pub auto_scale_max: Option<usize>,
+    #[structopt(long = "auto-scale-errors", default_value = "0.05",
+                help="Share of transient errors --auto-scale backs off at")]
+    pub auto_scale_errors: f64,
+    #[structopt(long = "no-keyboard",
+                help="Don't read keys, implied if stdin isn't a terminal")]
+    pub no_keyboard: bool,
+    #[structopt(long = "status-json",
+                help="Write the progress and findings as json lines to stderr")]
+    pub status_json: bool,
+    #[structopt(long = "status-file",
+                help="Write --status-json to this file or fifo instead of stderr")]
+    pub status_file: Option<String>,
+    #[structopt(long = "status-interval", default_value = "5s",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="How often the progress is written with --status-json")]
+    pub status_interval: Duration,
+    #[structopt(long = "notify-webhook",
+                help="Post every valid credential as json to this url")]
+    pub notify_webhook: Option<String>,
+    #[structopt(long = "notify-template",
+                help="Json file with the body for --notify-webhook, eg. for slack")]
+    pub notify_template: Option<String>,
+    #[structopt(long = "notify-password",
+                help="Send the full password to --notify-webhook instead of a redacted one")]
+    pub notify_password: bool,
+    #[structopt(long = "syslog",
+                help="Send findings, lockouts and the progress to syslog, local, unix:path, udp://host:port, tcp://host:port or file:path")]
+    pub syslog: Option<Target>,
+    #[structopt(long = "syslog-facility", default_value = "user",
+                help="Syslog facility, eg. auth or local0")]
+    pub syslog_facility: Facility,
+    #[structopt(long = "syslog-interval", default_value = "1m",
+                parse(try_from_str = "humantime::parse_duration"),
+                help="How often the progress is sent with --syslog")]
+    pub syslog_interval: Duration,
+    #[structopt(long = "control-socket",
+                help="Accept pause, resume, set_threads, stats and stop on this unix socket, see badtouch ctl")]
+    pub control_socket: Option<String>,
+    #[structopt(long = "checkpoint",
+                help="Save progress to this file and resume from it")]
+    pub checkpoint: Option<String>,
+    #[structopt(long = "targets",
+                help="Run every script against each target in this file")]
+    pub targets: Option<String>,
+    #[structopt(long = "per-host",
+                help="Concurrent attempts per target host")]
+    pub per_host: Option<usize>,
+    #[structopt(long = "config",
+                raw(global = "true"),
+                help="Load the config from this file instead of ~/.config/badtouch.toml")]
+    pub config: Option<String>,
+    #[structopt(long = "sandbox",
+                raw(global = "true"),
+                help="Disable functions that execute programs")]
+    pub sandbox: bool,
     #[structopt(subcommand)]
     pub subcommand: SubCommand,
 }
@@ -29,6 +224,10 @@ pub enum SubCommand {
                 name="creds",
                 about="Credential confirmation attack")]
     Creds(Creds),
+    #[structopt(author = "",
+                name="combo",
+                about="Dictionary attack with a list of user:password pairs")]
+    Combo(Combo),
     #[structopt(author = "",
                 name="enum",
                 about="Enumerate users")]
@@ -41,14 +240,175 @@ pub enum SubCommand {
                 name="fsck",
                 about="Verify and fix encoding of a list")]
     Fsck(Fsck),
+    #[structopt(author = "",
+                name="scripts",
+                about="List scripts and their metadata")]
+    Scripts(Scripts),
+    #[structopt(author = "",
+                name="benchmark",
+                about="Measure the cost of a script's attempts")]
+    Benchmark(Benchmark),
+    #[structopt(author = "",
+                name="serve",
+                about="Hand out the attempts of an attack to workers on other hosts")]
+    Serve(Serve),
+    #[structopt(author = "",
+                name="worker",
+                about="Run attempts for a coordinator started with serve")]
+    Worker(Worker),
+    #[structopt(author = "",
+                name="ctl",
+                about="Control an attack that was started with --control-socket")]
+    Ctl(Ctl),
+}
+
+impl SubCommand {
+    /// Returns true if one of the lists is read from stdin
+    pub fn reads_stdin(&self) -> bool {
+        match *self {
+            SubCommand::Dict(ref dict) => dict.users == "-" || dict.passwords == "-",
+            SubCommand::Creds(ref creds) => creds.creds == "-",
+            SubCommand::Combo(ref combo) => combo.combos == "-",
+            SubCommand::Enum(ref enumerate) => enumerate.users == "-",
+            SubCommand::Oneshot(_) | SubCommand::Fsck(_) | SubCommand::Scripts(_) | SubCommand::Benchmark(_) |
+            SubCommand::Serve(_) | SubCommand::Worker(_) | SubCommand::Ctl(_) => false,
+        }
+    }
+
+    /// The options for the scripts, see -x
+    pub fn script_options(&self) -> &[ScriptOption] {
+        match *self {
+            SubCommand::Dict(ref dict) => &dict.options,
+            SubCommand::Creds(ref creds) => &creds.options,
+            SubCommand::Combo(ref combo) => &combo.options,
+            SubCommand::Enum(ref enumerate) => &enumerate.options,
+            SubCommand::Oneshot(ref oneshot) => &oneshot.options,
+            SubCommand::Benchmark(ref benchmark) => &benchmark.options,
+            SubCommand::Fsck(_) | SubCommand::Scripts(_) | SubCommand::Serve(_) | SubCommand::Worker(_) |
+            SubCommand::Ctl(_) => &[],
+        }
+    }
+
+    /// Returns true if the subcommand runs an attack with the scheduler
+    pub fn is_attack(&self) -> bool {
+        match *self {
+            SubCommand::Dict(_) | SubCommand::Creds(_) | SubCommand::Combo(_) | SubCommand::Enum(_) => true,
+            SubCommand::Oneshot(_) | SubCommand::Fsck(_) | SubCommand::Scripts(_) | SubCommand::Benchmark(_) |
+            SubCommand::Serve(_) | SubCommand::Worker(_) | SubCommand::Ctl(_) => false,
+        }
+    }
+
+    /// Replace serve with the attack it serves, the options of serve are
+    /// returned separately
+    pub fn into_serve(self) -> (SubCommand, Option<ServeOptions>) {
+        match self {
+            SubCommand::Serve(serve) => {
+                let attack = match serve.attack {
+                    Attack::Dict(dict) => SubCommand::Dict(dict),
+                    Attack::Creds(creds) => SubCommand::Creds(creds),
+                    Attack::Combo(combo) => SubCommand::Combo(combo),
+                    Attack::Enum(enumerate) => SubCommand::Enum(enumerate),
+                };
+                (attack, Some(serve.options))
+            },
+            subcommand => (subcommand, None),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Serve {
+    #[structopt(flatten)]
+    pub options: ServeOptions,
+    #[structopt(subcommand)]
+    pub attack: Attack,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct ServeOptions {
+    #[structopt(long = "listen", default_value = "0.0.0.0:7700",
+                help="Address the workers connect to")]
+    pub listen: String,
+    #[structopt(long = "token",
+                help="Secret the workers authenticate with, defaults to $BADTOUCH_TOKEN")]
+    pub token: Option<String>,
+    #[structopt(long = "batch", default_value = "50",
+                help="Maximum attempts that are handed out at once")]
+    pub batch: usize,
+    #[structopt(long = "tls-identity",
+                help="PKCS#12 file with the certificate and key of the coordinator, \
+                      required unless --listen is a loopback address")]
+    pub tls_identity: Option<String>,
+    #[structopt(long = "tls-identity-password",
+                help="Password of the --tls-identity, defaults to $BADTOUCH_TLS_PASSWORD")]
+    pub tls_identity_password: Option<String>,
+}
+
+/// The attacks that can be spread over workers
+#[derive(StructOpt, Debug)]
+pub enum Attack {
+    #[structopt(author = "",
+                name="dict",
+                about="Dictionary attack")]
+    Dict(Dict),
+    #[structopt(author = "",
+                name="creds",
+                about="Credential confirmation attack")]
+    Creds(Creds),
+    #[structopt(author = "",
+                name="combo",
+                about="Dictionary attack with a list of user:password pairs")]
+    Combo(Combo),
+    #[structopt(author = "",
+                name="enum",
+                about="Enumerate users")]
+    Enum(Enum),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Worker {
+    #[structopt(help="Address of the coordinator, host:port")]
+    pub connect: String,
+    #[structopt(long = "token",
+                help="Secret of the coordinator, defaults to $BADTOUCH_TOKEN")]
+    pub token: Option<String>,
+    #[structopt(long = "unsandboxed",
+                help="Allow the scripts of the coordinator to execute programs")]
+    pub unsandboxed: bool,
+    #[structopt(long = "no-tls",
+                help="Connect without tls, only allowed for a coordinator on a loopback address")]
+    pub no_tls: bool,
+    #[structopt(long = "lists",
+                help="Directory with the lists of the coordinator, defaults to the paths the coordinator loaded them from")]
+    pub lists: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Ctl {
+    #[structopt(help="Path of the --control-socket")]
+    pub socket: String,
+    #[structopt(help="pause, resume, set_threads, stats or stop")]
+    pub command: String,
+    #[structopt(help="The number of threads for set_threads")]
+    pub value: Option<String>,
 }
 
 #[derive(StructOpt, Debug)]
 pub struct Dict {
-    #[structopt(help="Username list path")]
+    #[structopt(help="Username list path, - for stdin")]
     pub users: String,
-    #[structopt(help="Password list path")]
+    #[structopt(help="Password list path, - for stdin")]
     pub passwords: String,
+    #[structopt(long = "order",
+                help="Order of attempts, password-first (default) or user-first")]
+    pub order: Option<Order>,
+    #[structopt(long = "stream",
+                help="Read the passwords from disk as they are needed instead of loading them into memory")]
+    pub stream: bool,
+    #[structopt(short = "x", long = "option",
+                raw(number_of_values = "1"),
+                help="Set an option for the scripts, key=value")]
+    pub options: Vec<ScriptOption>,
     #[structopt(raw(required="true"),
                 help="Scripts to run")]
     pub scripts: Vec<String>,
@@ -56,8 +416,29 @@ pub struct Dict {
 
 #[derive(StructOpt, Debug)]
 pub struct Creds {
-    #[structopt(help="Credential list path")]
+    #[structopt(help="Credential list path, - for stdin")]
     pub creds: String,
+    #[structopt(long = "input-format", default_value = "plain",
+                help="Format of the credential list, plain, secretsdump or pwdump. \
+                      The NT hashes of a dump are attempted as passwords")]
+    pub input_format: InputFormat,
+    #[structopt(short = "x", long = "option",
+                raw(number_of_values = "1"),
+                help="Set an option for the scripts, key=value")]
+    pub options: Vec<ScriptOption>,
+    #[structopt(raw(required="true"),
+                help="Scripts to run")]
+    pub scripts: Vec<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Combo {
+    #[structopt(help="Combo list path, - for stdin")]
+    pub combos: String,
+    #[structopt(short = "x", long = "option",
+                raw(number_of_values = "1"),
+                help="Set an option for the scripts, key=value")]
+    pub options: Vec<ScriptOption>,
     #[structopt(raw(required="true"),
                 help="Scripts to run")]
     pub scripts: Vec<String>,
@@ -65,8 +446,12 @@ pub struct Creds {
 
 #[derive(StructOpt, Debug)]
 pub struct Enum {
-    #[structopt(help="Username list path")]
+    #[structopt(help="Username list path, - for stdin")]
     pub users: String,
+    #[structopt(short = "x", long = "option",
+                raw(number_of_values = "1"),
+                help="Set an option for the scripts, key=value")]
+    pub options: Vec<ScriptOption>,
     #[structopt(raw(required="true"),
                 help="Scripts to run")]
     pub scripts: Vec<String>,
@@ -80,7 +465,13 @@ pub struct Oneshot {
     pub user: String,
     #[structopt(help="Password to test")]
     pub password: Option<String>,
+    #[structopt(long = "option",
+                raw(number_of_values = "1"),
+                help="Set an option for the script, key=value")]
+    pub options: Vec<ScriptOption>,
+    // the exit code is always set now, this is kept so existing scripts work
     #[structopt(short = "x", long = "exitcode",
+                raw(hidden = "true"),
                 help="Set the exitcode to 2 if the credentials are invalid")]
     pub exitcode: bool,
 }
@@ -94,12 +485,72 @@ pub struct Fsck {
                 help="Do not show valid lines")]
     pub silent: bool,
     #[structopt(short = "c", long = "colon",
-                help="Require one colon per line")]
+                help="Require a user and a password separated by the delimiter on every line")]
     pub require_colon: bool,
+    #[structopt(long = "delimiter", default_value = ":",
+                help="Delimiter between user and password, a character, tab or auto")]
+    pub delimiter: Delimiter,
+    #[structopt(long = "to-delimiter",
+                help="Write the lines with this delimiter instead")]
+    pub to_delimiter: Option<Delimiter>,
+    #[structopt(long = "json",
+                help="Show invalid lines and the summary as json on stderr")]
+    pub json: bool,
+    #[structopt(long = "dedup",
+                help="Drop lines that were already seen")]
+    pub dedup: bool,
+    #[structopt(long = "sort",
+                help="Sort the lines by user, then password")]
+    pub sort: bool,
+    #[structopt(long = "unique-users",
+                help="Only keep the first password of every user")]
+    pub unique_users: bool,
+    #[structopt(long = "filter-user",
+                help="Only keep lines whose user matches this regex")]
+    pub filter_user: Option<String>,
+    #[structopt(long = "filter-password",
+                help="Only keep lines whose password matches this regex")]
+    pub filter_password: Option<String>,
+    #[structopt(long = "invert",
+                help="Only keep the lines that don't match the filters")]
+    pub invert: bool,
+    #[structopt(short = "o", long = "output",
+                help="Write the lines to this file instead of stdout")]
+    pub output: Option<String>,
     #[structopt(help="Files to read")]
     pub paths: Vec<String>,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct Scripts {
+    #[structopt(long = "json",
+                help="Print the scripts as json")]
+    pub json: bool,
+    #[structopt(raw(required="true"),
+                help="Scripts or folders of scripts")]
+    pub paths: Vec<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Benchmark {
+    #[structopt(help="Script to run")]
+    pub script: String,
+    #[structopt(short = "n", long = "attempts", default_value = "100",
+                help="Attempts for each number of workers")]
+    pub attempts: usize,
+    #[structopt(long = "workers", default_value = "1,4,16",
+                raw(use_delimiter = "true"),
+                help="Numbers of workers to compare")]
+    pub workers: Vec<usize>,
+    #[structopt(long = "offline",
+                help="Disable the functions that connect to the target")]
+    pub offline: bool,
+    #[structopt(short = "x", long = "option",
+                raw(number_of_values = "1"),
+                help="Set an option for the script, key=value")]
+    pub options: Vec<ScriptOption>,
+}
+
 pub fn parse() -> Args {
     Args::from_args()
 }
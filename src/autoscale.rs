@@ -0,0 +1,202 @@
+//! Adjust the number of threads to what the target can handle, see --auto-scale
+
+use humantime;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+// how often the number of threads is reconsidered
+const INTERVAL: Duration = Duration::from_secs(10);
+// fewer results than this don't tell us anything
+const MIN_RESULTS: usize = 5;
+// manual changes pause the automatic ones for this long
+pub const OVERRIDE: Duration = Duration::from_secs(60);
+// back off if the latency grows beyond the best latency times this
+const LATENCY_FACTOR: u32 = 2;
+
+
+/// A decision to change the number of threads
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub threads: usize,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub struct AutoScale {
+    min: usize,
+    max: usize,
+    // the share of transient errors we back off at
+    threshold: f64,
+    window_start: Instant,
+    results: usize,
+    errors: usize,
+    latency: Duration,
+    // the average latency of the best window without too many errors
+    best_latency: Option<Duration>,
+    suspended_until: Option<Instant>,
+}
+
+impl AutoScale {
+    pub fn new(min: usize, max: usize, threshold: f64, now: Instant) -> AutoScale {
+        AutoScale {
+            min,
+            max,
+            threshold,
+            window_start: now,
+            results: 0,
+            errors: 0,
+            latency: Duration::from_secs(0),
+            best_latency: None,
+            suspended_until: None,
+        }
+    }
+
+    /// Count the result of an attempt, only transient errors should be
+    /// counted as errors since the others don't depend on the load
+    pub fn record(&mut self, duration: Duration, error: bool) {
+        self.results += 1;
+        self.latency += duration;
+        if error {
+            self.errors += 1;
+        }
+    }
+
+    /// The threads were changed by hand, keep them for a while
+    pub fn suspend(&mut self, now: Instant) {
+        self.suspended_until = Some(now + OVERRIDE);
+        self.reset(now);
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.window_start = now;
+        self.results = 0;
+        self.errors = 0;
+        self.latency = Duration::from_secs(0);
+    }
+
+    fn decrease(&self, threads: usize, reason: String) -> Option<Change> {
+        let target = cmp::max(self.min, threads.saturating_sub(cmp::max(1, threads / 4)));
+        if target >= threads {
+            return None;
+        }
+        Some(Change {
+            threads: target,
+            reason,
+        })
+    }
+
+    fn increase(&self, threads: usize, reason: String) -> Option<Change> {
+        let target = cmp::min(self.max, threads + cmp::max(1, threads / 4));
+        if target <= threads {
+            return None;
+        }
+        Some(Change {
+            threads: target,
+            reason,
+        })
+    }
+
+    /// Decide if the number of threads should change, this is called
+    /// regularly but only decides once per interval
+    pub fn tick(&mut self, now: Instant, threads: usize) -> Option<Change> {
+        if now.saturating_duration_since(self.window_start) < INTERVAL {
+            return None;
+        }
+        if let Some(until) = self.suspended_until {
+            if now < until {
+                self.reset(now);
+                return None;
+            }
+            self.suspended_until = None;
+        }
+        if self.results < MIN_RESULTS {
+            return None;
+        }
+
+        let errors = self.errors;
+        let ratio = errors as f64 / self.results as f64;
+        let latency = self.latency / self.results as u32;
+        self.reset(now);
+
+        if ratio > self.threshold {
+            return self.decrease(threads, format!("{:.0}% of the attempts failed", ratio * 100.0));
+        }
+        if let Some(best) = self.best_latency {
+            if latency > best * LATENCY_FACTOR {
+                return self.decrease(threads, format!("the latency rose from {} to {}",
+                    format_latency(best), format_latency(latency)));
+            }
+        }
+
+        self.best_latency = Some(match self.best_latency {
+            Some(best) => cmp::min(best, latency),
+            None => latency,
+        });
+        if errors == 0 {
+            self.increase(threads, "there were no errors".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+fn format_latency(latency: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_millis(latency.as_millis() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn record(scale: &mut AutoScale, results: usize, errors: usize, latency: Duration) {
+        for i in 0..results {
+            scale.record(latency, i < errors);
+        }
+    }
+
+    #[test]
+    fn verify_ramp_up() {
+        let start = Instant::now();
+        let mut scale = AutoScale::new(1, 10, 0.1, start);
+        record(&mut scale, 10, 0, secs(1));
+        assert_eq!(scale.tick(start + secs(5), 8), None);
+        assert_eq!(scale.tick(start + secs(10), 8).map(|x| x.threads), Some(10));
+
+        record(&mut scale, 10, 0, secs(1));
+        assert_eq!(scale.tick(start + secs(20), 10), None);
+    }
+
+    #[test]
+    fn verify_back_off() {
+        let start = Instant::now();
+        let mut scale = AutoScale::new(2, 10, 0.1, start);
+        record(&mut scale, 10, 2, secs(1));
+        assert_eq!(scale.tick(start + secs(10), 8), Some(Change {
+            threads: 6,
+            reason: "20% of the attempts failed".to_string(),
+        }));
+
+        record(&mut scale, 10, 0, secs(1));
+        assert_eq!(scale.tick(start + secs(20), 10), None);
+        record(&mut scale, 10, 0, secs(3));
+        assert_eq!(scale.tick(start + secs(30), 4), Some(Change {
+            threads: 3,
+            reason: "the latency rose from 1s to 3s".to_string(),
+        }));
+    }
+
+    #[test]
+    fn verify_suspend() {
+        let start = Instant::now();
+        let mut scale = AutoScale::new(1, 10, 0.1, start);
+        scale.suspend(start);
+        record(&mut scale, 10, 5, secs(1));
+        assert_eq!(scale.tick(start + secs(10), 8), None);
+        record(&mut scale, 10, 5, secs(1));
+        assert_eq!(scale.tick(start + OVERRIDE + secs(10), 8).map(|x| x.threads), Some(6));
+    }
+}
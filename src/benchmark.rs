@@ -0,0 +1,359 @@
+//! Measure the cost of a script's attempts, see badtouch benchmark
+
+use errors::{Result, ResultExt};
+use args::Benchmark;
+use config::Config;
+use ctx::Script;
+use hlua::AnyLuaValue;
+
+use std::cmp;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the credentials every attempt is started with
+const USER: &str = "badtouch";
+const PASSWORD: &str = "benchmark";
+// fewer attempts than this don't show a trend
+const MIN_TREND_ATTEMPTS: usize = 20;
+// warn if the last attempts are this much slower than the first ones
+const SLOWDOWN_FACTOR: f64 = 1.5;
+
+
+#[derive(Debug, PartialEq)]
+pub struct Latency {
+    pub min: Duration,
+    pub avg: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+/// The attempts with one number of workers
+#[derive(Debug)]
+pub struct Round {
+    pub workers: usize,
+    // in the order the attempts finished
+    pub durations: Vec<Duration>,
+    pub errors: usize,
+    pub first_error: Option<String>,
+    pub elapsed: Duration,
+    // open file descriptors before and after the round
+    pub files: Option<(usize, usize)>,
+}
+
+fn average(durations: &[Duration]) -> Duration {
+    if durations.is_empty() {
+        return Duration::from_secs(0);
+    }
+    durations.iter().sum::<Duration>() / durations.len() as u32
+}
+
+impl Round {
+    pub fn latency(&self) -> Option<Latency> {
+        let mut sorted = self.durations.clone();
+        sorted.sort();
+        let p95 = (sorted.len() * 95).div_ceil(100);
+        Some(Latency {
+            min: *sorted.first()?,
+            avg: average(&sorted),
+            p95: sorted[cmp::max(p95, 1) - 1],
+            max: *sorted.last()?,
+        })
+    }
+
+    /// Attempts per second
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.durations.len() as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// The average latency of the first and the last quarter of the attempts,
+    /// if they got slower the script likely leaks something
+    pub fn slowdown(&self) -> Option<(Duration, Duration)> {
+        let len = self.durations.len();
+        if len < MIN_TREND_ATTEMPTS {
+            return None;
+        }
+        let first = average(&self.durations[..len / 4]);
+        let last = average(&self.durations[len - len / 4..]);
+        if last.as_secs_f64() > first.as_secs_f64() * SLOWDOWN_FACTOR {
+            Some((first, last))
+        } else {
+            None
+        }
+    }
+}
+
+/// The open file descriptors of this process, sockets that aren't closed
+/// show up here
+fn open_files() -> Option<usize> {
+    fs::read_dir("/proc/self/fd").ok()
+        .map(|dir| dir.count())
+}
+
+/// The highest resident set size in KiB, parsed from /proc/self/status
+fn parse_peak_rss(status: &str) -> Option<u64> {
+    status.lines()
+        .find(|line| line.starts_with("VmHWM:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+}
+
+fn peak_rss() -> Option<u64> {
+    fs::read_to_string("/proc/self/status").ok()
+        .and_then(|status| parse_peak_rss(&status))
+}
+
+/// Run `attempts` attempts spread over `workers` threads
+pub fn run_round(script: &Arc<Script>, workers: usize, attempts: usize) -> Result<Round> {
+    let next = Arc::new(AtomicUsize::new(0));
+    let results = Arc::new(Mutex::new(Vec::with_capacity(attempts)));
+    let files_before = open_files();
+    let start = Instant::now();
+
+    let threads = (0..workers)
+        .map(|_| {
+            let script = script.clone();
+            let next = next.clone();
+            let results = results.clone();
+            thread::spawn(move || -> Result<()> {
+                let worker = if script.has_worker_hooks() {
+                    Some(script.worker_setup(None)?)
+                } else {
+                    None
+                };
+                while next.fetch_add(1, Ordering::SeqCst) < attempts {
+                    let user = AnyLuaValue::LuaString(USER.to_string());
+                    let password = AnyLuaValue::LuaString(PASSWORD.to_string());
+                    let start = Instant::now();
+                    let result = script.run_on(user, password, None, worker.as_ref());
+                    let error = result.err().map(|err| err.to_string());
+                    results.lock().unwrap().push((start.elapsed(), error));
+                }
+                if let Some(worker) = worker {
+                    script.worker_teardown(worker)?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join()
+            .map_err(|_| "benchmark thread panicked")?
+            .chain_err(|| "worker hook failed")?;
+    }
+    let elapsed = start.elapsed();
+
+    let results = results.lock().unwrap();
+    let errors = results.iter()
+        .filter(|x| x.1.is_some())
+        .count();
+    let first_error = results.iter()
+        .filter_map(|x| x.1.clone())
+        .next();
+    Ok(Round {
+        workers,
+        durations: results.iter().map(|x| x.0).collect(),
+        errors,
+        first_error,
+        elapsed,
+        files: files_before.and_then(|before| open_files().map(|after| (before, after))),
+    })
+}
+
+fn format_latency(latency: Duration) -> String {
+    format!("{:.1}ms", latency.as_secs_f64() * 1000.0)
+}
+
+/// Format the rounds as a table
+fn table(rounds: &[Round]) -> String {
+    let header = ["WORKERS", "ATTEMPTS", "ERRORS", "MIN", "AVG", "P95", "MAX", "RATE"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    let rows = Some(header).into_iter()
+        .chain(rounds.iter().map(|round| {
+            let mut row = vec![
+                round.workers.to_string(),
+                round.durations.len().to_string(),
+                round.errors.to_string(),
+            ];
+            match round.latency() {
+                Some(latency) => row.extend([latency.min, latency.avg, latency.p95, latency.max].iter()
+                    .map(|x| format_latency(*x))),
+                None => row.extend((0..4).map(|_| "-".to_string())),
+            }
+            row.push(format!("{:.1}/s", round.rate()));
+            row
+        }))
+        .collect::<Vec<_>>();
+
+    let mut widths = [0; 8];
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = cmp::max(*width, column.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in &rows {
+        let line = row.iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{:width$}", column, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out += line.trim_end();
+        out += "\n";
+    }
+    out
+}
+
+pub fn run_benchmark(args: &Benchmark, config: &Arc<Config>) -> Result<()> {
+    if args.attempts == 0 {
+        bail!("attempts must be greater than zero");
+    }
+    if args.workers.is_empty() || args.workers.contains(&0) {
+        bail!("workers must be greater than zero");
+    }
+
+    let mut script = Script::load(&args.script, config.clone())?;
+    script.check_options()?;
+    script.init()?;
+    let script = Arc::new(script);
+
+    println!("[*] benchmarking {} with {} attempts per round{}", script.descr(), args.attempts,
+        if config.runtime.offline { ", offline" } else { "" });
+
+    let mut rounds = Vec::new();
+    for &workers in &args.workers {
+        rounds.push(run_round(&script, workers, args.attempts)?);
+    }
+    print!("{}", table(&rounds));
+
+    for round in &rounds {
+        if let Some(ref err) = round.first_error {
+            println!("[!] {} of {} attempts with {} workers failed, eg. {}", round.errors,
+                round.durations.len(), round.workers, err.lines().next().unwrap_or(""));
+        }
+        if let Some((before, after)) = round.files {
+            if after > before {
+                println!("[!] {} files were left open after the attempts with {} workers, the script might leak sockets",
+                    after - before, round.workers);
+            }
+        }
+    }
+
+    // more workers slow down the attempts while they start, only the round
+    // with the fewest workers shows a trend
+    let fewest = rounds.iter().min_by_key(|x| x.workers);
+    if let Some((first, last)) = fewest.and_then(|x| x.slowdown()) {
+        println!("[!] the attempts got slower from {} to {}, the script might leak sockets or sessions",
+            format_latency(first), format_latency(last));
+    }
+
+    let best = rounds.iter()
+        .filter(|x| x.errors < x.durations.len())
+        .max_by(|a, b| a.rate().partial_cmp(&b.rate()).unwrap_or(cmp::Ordering::Equal));
+    if let Some(best) = best {
+        println!("[+] the best rate was {:.1} attempts per second with -n {}", best.rate(), best.workers);
+    }
+    if let Some(rss) = peak_rss() {
+        println!("[+] the peak memory usage was {:.1} MiB", rss as f64 / 1024.0);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn round(durations: Vec<Duration>) -> Round {
+        Round {
+            workers: 1,
+            durations,
+            errors: 0,
+            first_error: None,
+            elapsed: Duration::from_secs(2),
+            files: None,
+        }
+    }
+
+    #[test]
+    fn verify_latency() {
+        let many = round((1..=100).rev().map(ms).collect());
+        assert_eq!(many.latency(), Some(Latency {
+            min: ms(1),
+            avg: Duration::from_micros(50_500),
+            p95: ms(95),
+            max: ms(100),
+        }));
+        assert_eq!(many.rate(), 50.0);
+
+        let single = round(vec![ms(7)]);
+        assert_eq!(single.latency().map(|x| x.p95), Some(ms(7)));
+        assert_eq!(round(Vec::new()).latency(), None);
+    }
+
+    #[test]
+    fn verify_slowdown() {
+        let steady = round((0..40).map(|i| ms(10 + i % 3)).collect());
+        assert_eq!(steady.slowdown(), None);
+
+        let leaking = round((0..40).map(|i| ms(10 + i)).collect());
+        assert_eq!(leaking.slowdown(), Some((Duration::from_micros(14_500), Duration::from_micros(44_500))));
+
+        let short = round((0..10).map(|i| ms(10 + i * 10)).collect());
+        assert_eq!(short.slowdown(), None);
+    }
+
+    #[test]
+    fn verify_peak_rss() {
+        let status = "Name:\tbadtouch\nVmPeak:\t  120000 kB\nVmHWM:\t   31744 kB\nVmRSS:\t   30000 kB\n";
+        assert_eq!(parse_peak_rss(status), Some(31744));
+        assert_eq!(parse_peak_rss("Name:\tbadtouch\n"), None);
+    }
+
+    #[test]
+    fn verify_table() {
+        let mut errors = round(vec![ms(1), ms(2), ms(3)]);
+        errors.workers = 4;
+        errors.errors = 3;
+        assert_eq!(table(&[round(vec![ms(10), ms(20)]), errors]), "\
+WORKERS  ATTEMPTS  ERRORS  MIN     AVG     P95     MAX     RATE
+1        2         0       10.0ms  15.0ms  20.0ms  20.0ms  1.0/s
+4        3         3       1.0ms   2.0ms   3.0ms   3.0ms   1.5/s
+");
+    }
+
+    #[test]
+    fn verify_offline() {
+        let mut config = Config::default();
+        config.runtime.offline = true;
+        let script = Script::load_from(r#"
+        descr = "offline"
+
+        function verify(user, password)
+            hmac_sha2_256(user, password)
+            sock_connect("127.0.0.1", 22)
+            return true
+        end
+        "#.as_bytes(), Arc::new(config)).unwrap();
+
+        let round = run_round(&Arc::new(script), 3, 10).unwrap();
+        assert_eq!(round.durations.len(), 10);
+        assert_eq!(round.errors, 10);
+        assert!(round.first_error.unwrap().contains("sock_connect is disabled by offline"));
+    }
+}
@@ -0,0 +1,154 @@
+use errors::Result;
+use args::Scripts;
+use config::Config;
+use ctx::Script;
+use utils;
+
+use serde_json;
+use std::cmp;
+use std::sync::Arc;
+
+
+/// What we know about a script, broken scripts only have an error
+#[derive(Debug, Serialize)]
+pub struct ScriptInfo {
+    pub path: String,
+    pub descr: Option<String>,
+    pub verify: bool,
+    pub author: Option<String>,
+    pub target: Option<String>,
+    // the functions that are disabled by sandbox, see Script::unsafe_functions
+    pub unsafe_functions: Vec<&'static str>,
+    pub error: Option<String>,
+}
+
+impl ScriptInfo {
+    pub fn load(path: &str, config: &Arc<Config>) -> ScriptInfo {
+        match Script::load(path, config.clone()) {
+            Ok(script) => ScriptInfo {
+                path: path.to_string(),
+                descr: Some(script.descr().to_string()),
+                verify: true,
+                author: script.author().map(|x| x.to_string()),
+                target: script.target().map(|x| x.to_string()),
+                unsafe_functions: script.unsafe_functions(),
+                error: None,
+            },
+            // verify is only checked if the rest of the script loaded
+            Err(err) => ScriptInfo {
+                path: path.to_string(),
+                descr: None,
+                verify: false,
+                author: None,
+                target: None,
+                unsafe_functions: Vec::new(),
+                error: Some(err.to_string()),
+            },
+        }
+    }
+
+    fn columns(&self) -> [String; 7] {
+        let unsafe_functions = if self.unsafe_functions.is_empty() {
+            "-".to_string()
+        } else {
+            self.unsafe_functions.join(",")
+        };
+        [
+            self.path.clone(),
+            self.descr.as_deref().unwrap_or("-").to_string(),
+            if self.verify { "yes" } else { "no" }.to_string(),
+            self.author.as_deref().unwrap_or("-").to_string(),
+            self.target.as_deref().unwrap_or("-").to_string(),
+            unsafe_functions,
+            // tracebacks don't fit into the table
+            self.error.as_deref().and_then(|x| x.lines().next()).unwrap_or("-").to_string(),
+        ]
+    }
+}
+
+/// Format the scripts as a table, the last column isn't padded
+fn table(infos: &[ScriptInfo]) -> String {
+    let header = ["FILE", "DESCR", "VERIFY", "AUTHOR", "TARGET", "UNSAFE", "ERROR"].map(String::from);
+    let rows = Some(header).into_iter()
+        .chain(infos.iter().map(|x| x.columns()))
+        .collect::<Vec<_>>();
+
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = cmp::max(*width, column.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (i, column) in row.iter().enumerate() {
+            if i == row.len() - 1 {
+                line += column;
+            } else {
+                line += &format!("{:width$}  ", column, width = widths[i]);
+            }
+        }
+        out += line.trim_end();
+        out += "\n";
+    }
+    out
+}
+
+pub fn run_scripts(args: &Scripts, config: &Arc<Config>) -> Result<()> {
+    let infos = utils::script_paths(args.paths.clone())?
+        .iter()
+        .map(|path| ScriptInfo::load(path, config))
+        .collect::<Vec<_>>();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+    } else {
+        print!("{}", table(&infos));
+    }
+
+    let broken = infos.iter()
+        .filter(|x| x.error.is_some())
+        .count();
+    if broken > 0 {
+        bail!("{} of {} scripts failed to load", broken, infos.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_table() {
+        let infos = vec![
+            ScriptInfo {
+                path: "scripts/ssh.lua".into(),
+                descr: Some("ssh".into()),
+                verify: true,
+                author: None,
+                target: Some("10.0.0.1".into()),
+                unsafe_functions: vec!["execve", "proc_spawn"],
+                error: None,
+            },
+            ScriptInfo {
+                path: "broken.lua".into(),
+                descr: None,
+                verify: false,
+                author: None,
+                target: None,
+                unsafe_functions: Vec::new(),
+                error: Some("broken.lua:1: syntax error\nstack traceback:".into()),
+            },
+        ];
+
+        assert_eq!(table(&infos), "\
+FILE             DESCR  VERIFY  AUTHOR  TARGET    UNSAFE             ERROR
+scripts/ssh.lua  ssh    yes     -       10.0.0.1  execve,proc_spawn  -
+broken.lua       -      no      -       -         -                  broken.lua:1: syntax error
+");
+    }
+}
@@ -0,0 +1,302 @@
+use errors::{Result, ResultExt};
+
+use base64;
+use serde_json;
+use sha2::{Sha256, Digest};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// how often the checkpoint is written while the attack is running
+const SAVE_INTERVAL: u64 = 10;
+
+
+/// Something that affects which attempts are generated and in which order,
+/// resuming with a different input would skip the wrong attempts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Input {
+    pub name: String,
+    pub sha256: String,
+}
+
+impl Input {
+    pub fn new(name: &str, data: &[u8]) -> Input {
+        Input::from_digest(name, &Sha256::digest(data))
+    }
+
+    fn from_digest(name: &str, digest: &[u8]) -> Input {
+        let sha256 = digest.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Input {
+            name: name.to_string(),
+            sha256,
+        }
+    }
+
+    pub fn file(path: &str) -> Result<Input> {
+        if path == "-" {
+            bail!("checkpoints can't be used with a list from stdin");
+        }
+
+        // lists can be larger than the available memory, hash them in chunks
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::default();
+        let mut buf = [0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.input(&buf[..n]);
+        }
+        Ok(Input::from_digest(path, &hasher.result()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Found {
+    pub script: String,
+    pub user: String,
+    pub password: Option<String>,
+    // missing in checkpoints of older versions
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointFile {
+    inputs: Vec<Input>,
+    total: usize,
+    // bitset of completed attempts, base64 encoded
+    completed: String,
+    found: Vec<Found>,
+}
+
+/// One bit per attempt
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitset(Vec<u8>);
+
+impl Bitset {
+    pub fn new(len: usize) -> Bitset {
+        Bitset(vec![0; len.div_ceil(8)])
+    }
+
+    #[inline]
+    pub fn contains(&self, idx: usize) -> bool {
+        self.0[idx / 8] & (1 << (idx % 8)) != 0
+    }
+
+    #[inline]
+    pub fn insert(&mut self, idx: usize) {
+        self.0[idx / 8] |= 1 << (idx % 8);
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.iter()
+            .map(|x| x.count_ones() as usize)
+            .sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: String,
+    inputs: Vec<Input>,
+    total: usize,
+    completed: Bitset,
+    found: Vec<Found>,
+    dirty: bool,
+    last_save: Instant,
+}
+
+impl Checkpoint {
+    /// Resume from the checkpoint if the file exists, refuse if the inputs changed
+    pub fn open(path: &str, inputs: Vec<Input>, total: usize) -> Result<Checkpoint> {
+        let mut checkpoint = Checkpoint {
+            path: path.to_string(),
+            inputs,
+            total,
+            completed: Bitset::new(total),
+            found: Vec::new(),
+            dirty: false,
+            last_save: Instant::now(),
+        };
+
+        if Path::new(path).exists() {
+            let file = File::open(path)?;
+            let file: CheckpointFile = serde_json::from_reader(file)
+                .chain_err(|| "failed to parse checkpoint")?;
+            checkpoint.resume(file)?;
+        }
+
+        Ok(checkpoint)
+    }
+
+    /// The inputs of an existing checkpoint, None if there is no checkpoint yet
+    pub fn inputs(path: &str) -> Result<Option<Vec<Input>>> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+        let file = File::open(path)?;
+        let file: CheckpointFile = serde_json::from_reader(file)
+            .chain_err(|| "failed to parse checkpoint")?;
+        Ok(Some(file.inputs))
+    }
+
+    fn resume(&mut self, file: CheckpointFile) -> Result<()> {
+        if file.inputs.len() != self.inputs.len() {
+            bail!("checkpoint was created with different inputs");
+        }
+
+        for (old, new) in file.inputs.iter().zip(self.inputs.iter()) {
+            if old.name != new.name {
+                bail!("checkpoint was created with {:?} instead of {:?}", old.name, new.name);
+            }
+            if old.sha256 != new.sha256 {
+                bail!("checkpoint doesn't match the inputs, {:?} has changed", new.name);
+            }
+        }
+
+        let completed = base64::decode(&file.completed)?;
+        if file.total != self.total || completed.len() != self.completed.0.len() {
+            bail!("checkpoint was created with a different number of attempts");
+        }
+
+        self.completed = Bitset(completed);
+        self.found = file.found;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_completed(&self, idx: usize) -> bool {
+        self.completed.contains(idx)
+    }
+
+    /// The attempts that are completed
+    #[inline]
+    pub fn completed(&self) -> &Bitset {
+        &self.completed
+    }
+
+    #[inline]
+    pub fn complete(&mut self, idx: usize) {
+        self.completed.insert(idx);
+        self.dirty = true;
+    }
+
+    pub fn num_completed(&self) -> usize {
+        self.completed.count()
+    }
+
+    pub fn add_found(&mut self, found: Found) {
+        self.found.push(found);
+        self.dirty = true;
+    }
+
+    #[inline]
+    pub fn found(&self) -> &[Found] {
+        &self.found
+    }
+
+    /// Write the checkpoint if it changed and wasn't written recently
+    pub fn tick(&mut self) -> Result<()> {
+        if self.dirty && self.last_save.elapsed() >= Duration::from_secs(SAVE_INTERVAL) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        let file = CheckpointFile {
+            inputs: self.inputs.clone(),
+            total: self.total,
+            completed: base64::encode(&self.completed.0),
+            found: self.found.clone(),
+        };
+
+        // write to a temporary file first so a crash doesn't corrupt the checkpoint
+        let tmp = format!("{}.tmp", self.path);
+        {
+            let mut f = File::create(&tmp)
+                .chain_err(|| "failed to write checkpoint")?;
+            serde_json::to_writer(&mut f, &file)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)
+            .chain_err(|| "failed to write checkpoint")?;
+
+        self.dirty = false;
+        self.last_save = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("badtouch-{}-{}.json", name, ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn verify_resume() {
+        let path = path("resume");
+        let inputs = vec![Input::new("users.txt", b"foo\nbar\n")];
+
+        let mut checkpoint = Checkpoint::open(&path, inputs.clone(), 10).unwrap();
+        checkpoint.complete(0);
+        checkpoint.complete(9);
+        checkpoint.add_found(Found {
+            script: "test".into(),
+            user: "foo".into(),
+            password: Some("a:b".into()),
+            timestamp: None,
+            target: None,
+            status: None,
+            note: None,
+        });
+        checkpoint.save().unwrap();
+
+        let checkpoint = Checkpoint::open(&path, inputs, 10).unwrap();
+        assert!(checkpoint.is_completed(0));
+        assert!(!checkpoint.is_completed(1));
+        assert!(checkpoint.is_completed(9));
+        assert_eq!(checkpoint.num_completed(), 2);
+        assert_eq!(checkpoint.found().len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn verify_file_input() {
+        let path = path("input");
+        let data = (0..100_000).map(|i| format!("password{}\n", i)).collect::<String>();
+        fs::write(&path, &data).unwrap();
+        assert_eq!(Input::file(&path).unwrap(), Input::new(&path, data.as_bytes()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn verify_changed_input() {
+        let path = path("changed");
+
+        let mut checkpoint = Checkpoint::open(&path, vec![Input::new("users.txt", b"foo\n")], 1).unwrap();
+        checkpoint.save().unwrap();
+
+        let err = Checkpoint::open(&path, vec![Input::new("users.txt", b"bar\n")], 1).unwrap_err();
+        assert_eq!(err.to_string(), "checkpoint doesn't match the inputs, \"users.txt\" has changed");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1213 @@
+//! Spread an attack over several hosts, see badtouch serve and badtouch worker
+//!
+//! The coordinator runs the attack as usual, but instead of starting the
+//! attempts itself they are handed out to the workers that connected to it.
+//! Every message is a big endian u32 with the length, followed by json. After
+//! the handshake every message is followed by a mac with the session key, so
+//! messages can't be injected, replayed or reordered. The connection uses tls
+//! unless the coordinator only listens on a loopback address. The workers
+//! load the same lists as the coordinator, an attempt is sent as its index
+//! instead of the credentials.
+
+use errors::{Result, ResultExt, ErrorKind};
+use args::{ServeOptions, Worker};
+use base64;
+use checkpoint::Input;
+use config::Config;
+use ctx::Script;
+use errkind;
+use hashes::{self, InputFormat};
+use hmac::{Hmac, Mac};
+use native_tls::{Certificate, HandshakeError, Pkcs12, TlsAcceptor, TlsConnector, TlsStream};
+use proxy;
+use rand::{thread_rng, Rng};
+use scheduler::{Attempt, Msg, Level, Order, Scheduler};
+use serde_json;
+use sha2::Sha256;
+use utils::{self, Cred, ListOptions, Passwords};
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::mem;
+use std::io::{self, BufReader};
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bumped whenever a message changes, the coordinator and the workers have
+/// to speak the same version
+pub const PROTOCOL_VERSION: u32 = 3;
+// larger messages are rejected before they are read
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+// a worker that didn't send anything for this long is considered dead
+const WORKER_TIMEOUT: Duration = Duration::from_secs(60);
+// workers ping the coordinator if they had nothing else to send for this long
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+// a worker asks again after this long if there was nothing to do
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+// how long a worker waits for results before it asks for more attempts
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    // coordinator: the challenge for the worker
+    Hello { version: u32, nonce: String },
+    // worker: the answer and a challenge for the coordinator
+    Auth { version: u32, mac: String, nonce: String },
+    // coordinator: the answer and the options that were set with -x
+    Welcome { mac: String, options: HashMap<String, String> },
+    Rejected { reason: String },
+    // worker: ask for up to max attempts
+    Pull { max: usize },
+    // coordinator: the lists and the scripts that weren't sent to this worker
+    // yet are included. The attempts are ranges of their index, the end is
+    // excluded
+    Batch { lists: Option<Lists>, scripts: Vec<WireScript>, attempts: Vec<(usize, usize)> },
+    // coordinator: there's nothing to do right now
+    Wait,
+    Results { results: Vec<WireResult> },
+    Ping,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct WireScript {
+    label: String,
+    name: String,
+    code: String,
+    // the line of --targets
+    assigned: Option<String>,
+}
+
+impl WireScript {
+    fn new(script: &Script) -> WireScript {
+        WireScript {
+            label: script.label().to_string(),
+            name: script.name().to_string(),
+            code: script.code().to_string(),
+            assigned: script.assigned().map(|x| x.to_string()),
+        }
+    }
+}
+
+/// The lists of an attack, the workers load the same files and build the
+/// attempts from their index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lists {
+    pub mode: Mode,
+    // the path and the hash of every list the coordinator loaded
+    pub files: Vec<Input>,
+    pub dedup: bool,
+    pub trim: bool,
+    // the labels of the scripts, the index of an attempt is split into the
+    // script and the entry of the lists
+    pub scripts: Vec<String>,
+    // users, credentials or combos, a dictionary attack has users * passwords
+    pub entries: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Mode {
+    Dict { order: String, stream: bool },
+    Creds { input_format: String },
+    Combo,
+    Enum,
+}
+
+impl Mode {
+    fn files(&self) -> usize {
+        match *self {
+            Mode::Dict { .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// The lists of the coordinator, loaded by a worker
+enum Entries {
+    Dict { users: Vec<Arc<String>>, passwords: Passwords, order: Order },
+    Creds(Vec<Cred>),
+    Combo(Vec<(Arc<String>, Arc<String>)>),
+    Enum(Vec<Arc<String>>),
+}
+
+impl Entries {
+    /// Load the lists like the coordinator did, they have to be the same
+    /// files. With a directory the lists are looked up by their file name
+    fn load(lists: &Lists, dir: Option<&str>) -> Result<Entries> {
+        if lists.files.len() != lists.mode.files() {
+            bail!("expected {} lists, got {}", lists.mode.files(), lists.files.len());
+        }
+        if lists.scripts.is_empty() {
+            bail!("the coordinator didn't load any scripts");
+        }
+
+        let mut paths = Vec::new();
+        for file in &lists.files {
+            let path = match dir {
+                Some(dir) => {
+                    let name = Path::new(&file.name).file_name()
+                        .chain_err(|| format!("invalid list path {:?}", file.name))?;
+                    Path::new(dir).join(name).to_string_lossy().into_owned()
+                },
+                None => file.name.clone(),
+            };
+            let local = Input::file(&path)
+                .chain_err(|| format!("failed to read {:?}", path))?;
+            if local.sha256 != file.sha256 {
+                bail!("{:?} isn't the list the coordinator loaded", path);
+            }
+            paths.push(path);
+        }
+
+        let options = ListOptions {
+            dedup: lists.dedup,
+            trim: lists.trim,
+        };
+        let entries = match lists.mode {
+            Mode::Dict { ref order, stream } => {
+                let (users, _) = utils::load_list(&paths[0], &options)?;
+                let passwords = if stream {
+                    Passwords::Streamed(utils::StreamedList::open(&paths[1], &options)?)
+                } else {
+                    Passwords::Loaded(utils::load_list(&paths[1], &options)?.0)
+                };
+                Entries::Dict {
+                    users,
+                    passwords,
+                    order: order.parse()?,
+                }
+            },
+            Mode::Creds { ref input_format } => {
+                let (creds, _, _) = match input_format.parse()? {
+                    InputFormat::Plain => utils::load_creds(&paths[0], &options)?,
+                    format => hashes::load_hashes(&paths[0], format, &options)?,
+                };
+                Entries::Creds(creds)
+            },
+            Mode::Combo => {
+                let combos = utils::load_combos(&paths[0], &options)?.into_iter()
+                    .filter_map(|combo| combo.ok())
+                    .collect();
+                Entries::Combo(options.dedup(combos).0)
+            },
+            Mode::Enum => Entries::Enum(utils::load_list(&paths[0], &options)?.0),
+        };
+
+        if entries.len() != lists.entries {
+            bail!("loaded {} entries, the coordinator has {}", entries.len(), lists.entries);
+        }
+        Ok(entries)
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Entries::Dict { ref users, ref passwords, .. } => users.len() * passwords.len(),
+            Entries::Creds(ref creds) => creds.len(),
+            Entries::Combo(ref combos) => combos.len(),
+            Entries::Enum(ref users) => users.len(),
+        }
+    }
+
+    /// The attempt of an index, the same one the coordinator created
+    fn attempt(&mut self, idx: usize, script: &Arc<Script>, scripts: usize) -> Result<Attempt> {
+        let entry = idx / scripts;
+        if entry >= self.len() {
+            bail!("attempt {} is out of range", idx);
+        }
+        let attempt = match *self {
+            Entries::Dict { ref users, ref mut passwords, order } => {
+                let (user, password) = order.split(entry, users.len(), passwords.len());
+                Attempt::new(&users[user], &passwords.get(password)?, script, idx)
+            },
+            Entries::Creds(ref creds) => match creds[entry] {
+                Cred::Line(ref line) => Attempt::bytes(line, script, idx),
+                Cred::Pair(ref user, ref password) => Attempt::new(user, password, script, idx),
+                Cred::Hash(ref hash) => Attempt::hash(hash, script, idx),
+            },
+            Entries::Combo(ref combos) => {
+                let (ref user, ref password) = combos[entry];
+                Attempt::new(user, password, script, idx)
+            },
+            Entries::Enum(ref users) => Attempt::enumerate(&users[entry], script, idx),
+        };
+        Ok(attempt)
+    }
+}
+
+/// Merge the indexes into ranges, the end is excluded
+fn ranges(mut ids: Vec<usize>) -> Vec<(usize, usize)> {
+    ids.sort_unstable();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for id in ids {
+        match ranges.last_mut() {
+            Some(range) if range.1 == id => range.1 += 1,
+            _ => ranges.push((id, id + 1)),
+        }
+    }
+    ranges
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct WireError {
+    message: String,
+    retryable: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct WireResult {
+    id: usize,
+    valid: bool,
+    error: Option<WireError>,
+    // seconds
+    duration: f64,
+    target: Option<String>,
+    status: Option<String>,
+    note: Option<String>,
+    locked: Vec<String>,
+}
+
+impl WireResult {
+    fn new(attempt: &Attempt, result: Result<bool>) -> WireResult {
+        let (valid, error) = match result {
+            Ok(valid) => (valid, None),
+            Err(err) => (false, Some(WireError {
+                message: err.iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(": "),
+                retryable: errkind::is_retryable(&err),
+            })),
+        };
+        WireResult {
+            id: attempt.idx,
+            valid,
+            error,
+            duration: attempt.duration.as_secs_f64(),
+            target: attempt.target.clone(),
+            status: attempt.status.map(|x| x.as_str().to_string()),
+            note: attempt.note.clone(),
+            locked: attempt.locked.clone(),
+        }
+    }
+
+    /// The attempt couldn't be started, eg. because the script didn't load
+    fn failed(id: usize, message: String) -> WireResult {
+        WireResult {
+            id,
+            valid: false,
+            error: Some(WireError {
+                message,
+                retryable: false,
+            }),
+            duration: 0.0,
+            target: None,
+            status: None,
+            note: None,
+            locked: Vec::new(),
+        }
+    }
+
+    /// Copy the result into the attempt it belongs to, the coordinator
+    /// decides if an error is retried
+    fn apply(self, attempt: &mut Attempt) -> Result<bool> {
+        attempt.duration = Duration::try_from_secs_f64(self.duration).unwrap_or_default();
+        attempt.target = self.target;
+        attempt.status = self.status.and_then(|x| x.parse().ok());
+        attempt.note = self.note;
+        attempt.locked = self.locked;
+        match self.error {
+            Some(err) if err.retryable => Err(ErrorKind::Transient(err.message).into()),
+            Some(err) => Err(ErrorKind::Fatal(err.message).into()),
+            None => Ok(self.valid),
+        }
+    }
+}
+
+fn send<W: Write>(w: &mut W, msg: &Message) -> Result<()> {
+    let data = serde_json::to_vec(msg)?;
+    w.write_all(&frame(&data, &[]))?;
+    w.flush()?;
+    Ok(())
+}
+
+/// The length, the data and the mac, they are written at once so a message
+/// isn't split into several tls records
+fn frame(data: &[u8], mac: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + data.len() + mac.len());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame.extend_from_slice(mac);
+    frame
+}
+
+/// Read the next message, None if the connection was closed
+fn recv<R: Read>(r: &mut R) -> Result<Option<Message>> {
+    match read_frame(r)? {
+        Some(data) => parse(&data).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn read_frame<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(_) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE {
+        bail!("message of {} bytes is too large", len);
+    }
+
+    let mut data = vec![0; len];
+    r.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+fn parse(data: &[u8]) -> Result<Message> {
+    let msg = serde_json::from_slice(data)
+        .chain_err(|| "invalid message")?;
+    Ok(msg)
+}
+
+/// Signs the messages that are sent and verifies the messages that are
+/// received after the handshake. The mac covers the direction and a counter,
+/// a message that was recorded can't be sent again
+struct Session {
+    key: Vec<u8>,
+    // the role of this side, the peer signs with the other one
+    role: &'static str,
+    peer: &'static str,
+    sent: u64,
+    received: u64,
+}
+
+impl Session {
+    /// Both nonces are part of the key, every connection gets a new one
+    fn new(token: &str, hello: &str, auth: &str, role: &'static str) -> Session {
+        let key = mac(token, "session", &format!("{}:{}", hello, auth))
+            .result()
+            .code()
+            .to_vec();
+        let peer = if role == "coordinator" { "worker" } else { "coordinator" };
+        Session {
+            key,
+            role,
+            peer,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    fn mac(&self, role: &str, counter: u64, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key)
+            .expect("hmac accepts keys of any length");
+        mac.input(role.as_bytes());
+        mac.input(&counter.to_be_bytes());
+        mac.input(data);
+        mac
+    }
+
+    fn send<W: Write>(&mut self, w: &mut W, msg: &Message) -> Result<()> {
+        let data = serde_json::to_vec(msg)?;
+        let mac = self.mac(self.role, self.sent, &data).result().code();
+        self.sent += 1;
+        w.write_all(&frame(&data, &mac))?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read the next message, None if the connection was closed. A message
+    /// with an invalid mac fails the connection
+    fn recv<R: Read>(&mut self, r: &mut R) -> Result<Option<Message>> {
+        let data = match read_frame(r)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let mut mac = [0; 32];
+        r.read_exact(&mut mac)
+            .chain_err(|| "message without a mac")?;
+        if self.mac(self.peer, self.received, &data).verify(&mac).is_err() {
+            bail!("message with an invalid mac");
+        }
+        self.received += 1;
+        parse(&data).map(Some)
+    }
+}
+
+/// The secret of the coordinator and its workers, --token or $BADTOUCH_TOKEN
+pub fn token(token: Option<&str>) -> Result<String> {
+    let token = match token {
+        Some(token) => token.to_string(),
+        None => env::var("BADTOUCH_TOKEN")
+            .map_err(|_| "a token is required, use --token or set BADTOUCH_TOKEN")?,
+    };
+    if token.is_empty() {
+        bail!("the token can't be empty");
+    }
+    Ok(token)
+}
+
+fn nonce() -> String {
+    let nonce: [u8; 16] = thread_rng().gen();
+    base64::encode(&nonce)
+}
+
+/// The role is part of the mac, so an answer can't be sent back as a
+/// challenge
+fn mac(token: &str, role: &str, nonce: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(token.as_bytes())
+        .expect("hmac accepts keys of any length");
+    mac.input(role.as_bytes());
+    mac.input(b":");
+    mac.input(nonce.as_bytes());
+    mac
+}
+
+fn sign(token: &str, role: &str, nonce: &str) -> String {
+    base64::encode(&mac(token, role, nonce).result().code())
+}
+
+fn verify(token: &str, role: &str, nonce: &str, signature: &str) -> bool {
+    match base64::decode(signature) {
+        Ok(signature) => mac(token, role, nonce).verify(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// The connection of a worker and its coordinator
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// Load the certificate and key of the coordinator, see --tls-identity
+fn tls_acceptor(path: &str, password: Option<&str>) -> Result<TlsAcceptor> {
+    let password = match password {
+        Some(password) => password.to_string(),
+        None => env::var("BADTOUCH_TLS_PASSWORD").unwrap_or_default(),
+    };
+    let der = fs::read(path)
+        .chain_err(|| format!("failed to read {:?}", path))?;
+    let identity = Pkcs12::from_der(&der, &password)
+        .chain_err(|| format!("failed to load the identity {:?}", path))?;
+    let acceptor = TlsAcceptor::builder(identity)
+        .and_then(|builder| builder.build())
+        .chain_err(|| "failed to setup tls")?;
+    Ok(acceptor)
+}
+
+/// The certificate of the coordinator is verified like the certificate of a
+/// target, see --tls-ca-file and --no-verify-hostname
+fn tls_connect(addr: &str, stream: TcpStream, config: &Config) -> Result<TlsStream<TcpStream>> {
+    let mut builder = TlsConnector::builder()
+        .chain_err(|| "failed to setup tls")?;
+    if let Some(ref ca) = config.runtime.tls_ca {
+        let ca = Certificate::from_pem(ca)
+            .chain_err(|| "invalid tls_ca_file")?;
+        builder.add_root_certificate(ca)
+            .chain_err(|| "failed to setup tls")?;
+    }
+    let connector = builder.build()
+        .chain_err(|| "failed to setup tls")?;
+
+    let stream = if config.runtime.verify_hostname() {
+        let (host, _) = proxy::split_host_port(addr, None)?;
+        connector.connect(host.trim_start_matches('[').trim_end_matches(']'), stream)
+    } else {
+        connector.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+    };
+    match stream {
+        Ok(stream) => Ok(stream),
+        Err(HandshakeError::Failure(err)) => Err(err).chain_err(|| "tls handshake failed"),
+        Err(HandshakeError::Interrupted(_)) => bail!("tls handshake timed out"),
+    }
+}
+
+/// Attempts that wait for a worker and attempts that were handed out, see
+/// Scheduler::set_remote
+struct Queue {
+    queued: VecDeque<Attempt>,
+    // by index, with the worker that runs them
+    leased: HashMap<usize, (usize, Attempt)>,
+    // set once the attack is setup, see Dispatcher::describe
+    lists: Option<Lists>,
+    paused: Option<Arc<(Mutex<bool>, Condvar)>>,
+    draining: Option<Arc<AtomicBool>>,
+}
+
+impl Queue {
+    fn is_paused(&self) -> bool {
+        self.paused.as_ref()
+            .is_some_and(|paused| *paused.0.lock().unwrap())
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.as_ref()
+            .is_some_and(|draining| draining.load(Ordering::SeqCst))
+    }
+}
+
+/// Hands the attempts of the scheduler to the workers, their results are
+/// sent to the scheduler like the results of local attempts
+#[derive(Clone)]
+pub struct Dispatcher {
+    queue: Arc<Mutex<Queue>>,
+    tx: mpsc::Sender<Msg>,
+}
+
+impl Dispatcher {
+    pub fn new(tx: mpsc::Sender<Msg>) -> Dispatcher {
+        Dispatcher {
+            queue: Arc::new(Mutex::new(Queue {
+                queued: VecDeque::new(),
+                leased: HashMap::new(),
+                lists: None,
+                paused: None,
+                draining: None,
+            })),
+            tx,
+        }
+    }
+
+    /// Follow the pause and drain of the scheduler
+    pub fn attach(&self, paused: Arc<(Mutex<bool>, Condvar)>, draining: Arc<AtomicBool>) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.paused = Some(paused);
+        queue.draining = Some(draining);
+    }
+
+    /// The lists the workers have to load, this has to be set before the
+    /// first attempt is submitted
+    pub fn describe(&self, lists: Lists) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.lists = Some(lists);
+    }
+
+    fn lists(&self) -> Option<Lists> {
+        let queue = self.queue.lock().unwrap();
+        queue.lists.clone()
+    }
+
+    pub fn submit(&self, attempt: Attempt) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.queued.push_back(attempt);
+    }
+
+    /// Hand out up to max attempts to a worker, nothing while we are paused.
+    /// If we are stopping the attempts that weren't handed out are canceled
+    fn lease(&self, worker: usize, max: usize) -> (Vec<(usize, usize)>, Vec<Arc<Script>>) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_draining() {
+            for attempt in queue.queued.drain(..) {
+                self.tx.send(Msg::Cancel(Box::new(attempt))).expect("failed to send result");
+            }
+        }
+        if queue.is_paused() {
+            return (Vec::new(), Vec::new());
+        }
+
+        let mut ids = Vec::new();
+        let mut scripts = Vec::new();
+        while ids.len() < max {
+            let attempt = match queue.queued.pop_front() {
+                Some(attempt) => attempt,
+                None => break,
+            };
+            ids.push(attempt.idx);
+            scripts.push(attempt.script.clone());
+            queue.leased.insert(attempt.idx, (worker, attempt));
+        }
+        (ranges(ids), scripts)
+    }
+
+    /// A worker finished an attempt, results for attempts that were handed
+    /// out to another worker are ignored
+    fn finish(&self, worker: usize, result: WireResult) {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.leased.get(&result.id) {
+            Some(&(owner, _)) if owner == worker => (),
+            _ => return,
+        }
+        let (_, mut attempt) = queue.leased.remove(&result.id).unwrap();
+        let result = result.apply(&mut attempt);
+        self.tx.send(Msg::Attempt(Box::new(attempt), result)).expect("failed to send result");
+    }
+
+    /// Hand the attempts of a worker that disconnected to the next worker,
+    /// returns how many there were
+    fn requeue(&self, worker: usize) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        let ids = queue.leased.iter()
+            .filter(|(_, (owner, _))| *owner == worker)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        let draining = queue.is_draining();
+        for id in &ids {
+            let (_, attempt) = queue.leased.remove(id).unwrap();
+            if draining {
+                self.tx.send(Msg::Cancel(Box::new(attempt))).expect("failed to send result");
+            } else {
+                queue.queued.push_front(attempt);
+            }
+        }
+        ids.len()
+    }
+}
+
+/// The coordinator side of the handshake, the worker has to prove it knows
+/// the token before the coordinator does
+fn accept<S: Read + Write>(stream: &mut BufReader<S>, token: &str, options: &HashMap<String, String>) -> Result<Session> {
+    let nonce = nonce();
+    send(stream.get_mut(), &Message::Hello {
+        version: PROTOCOL_VERSION,
+        nonce: nonce.clone(),
+    })?;
+
+    let (version, mac, challenge) = match recv(stream)? {
+        Some(Message::Auth { version, mac, nonce }) => (version, mac, nonce),
+        Some(_) => bail!("expected authentication"),
+        None => bail!("connection closed during the handshake"),
+    };
+    let rejected = if version != PROTOCOL_VERSION {
+        Some(format!("the coordinator speaks protocol version {}, the worker version {}", PROTOCOL_VERSION, version))
+    } else if !verify(token, "worker", &nonce, &mac) {
+        Some("invalid token".to_string())
+    } else {
+        None
+    };
+    if let Some(reason) = rejected {
+        send(stream.get_mut(), &Message::Rejected {
+            reason: reason.clone(),
+        })?;
+        bail!("rejected: {}", reason);
+    }
+
+    send(stream.get_mut(), &Message::Welcome {
+        mac: sign(token, "coordinator", &challenge),
+        options: options.clone(),
+    })?;
+    Ok(Session::new(token, &nonce, &challenge, "coordinator"))
+}
+
+/// Hand out attempts to a worker until it disconnects
+fn handle(stream: TcpStream, tls: Option<&TlsAcceptor>, worker: usize, token: &str, options: &HashMap<String, String>, batch: usize, dispatcher: &Dispatcher) -> Result<()> {
+    stream.set_read_timeout(Some(WORKER_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let addr = stream.peer_addr()?;
+    let stream = match tls {
+        Some(tls) => match tls.accept(stream) {
+            Ok(stream) => Stream::Tls(Box::new(stream)),
+            Err(HandshakeError::Failure(err)) => return Err(err).chain_err(|| "tls handshake failed"),
+            Err(HandshakeError::Interrupted(_)) => bail!("tls handshake timed out"),
+        },
+        None => Stream::Plain(stream),
+    };
+    let mut stream = BufReader::new(stream);
+    let mut session = accept(&mut stream, token, options)?;
+
+    let _ = dispatcher.tx.send(Msg::Print(Level::Info, format!("worker {} connected", addr)));
+
+    // the scripts this worker already has and if it got the lists
+    let mut sent = HashSet::new();
+    let mut sent_lists = false;
+    loop {
+        let msg = match session.recv(&mut stream) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        match msg {
+            Message::Pull { max } => {
+                let (attempts, scripts) = dispatcher.lease(worker, cmp::min(max, batch));
+                if attempts.is_empty() {
+                    session.send(stream.get_mut(), &Message::Wait)?;
+                    continue;
+                }
+                let lists = if sent_lists {
+                    None
+                } else {
+                    sent_lists = true;
+                    Some(dispatcher.lists().chain_err(|| "the lists of the attack are unknown")?)
+                };
+                let scripts = scripts.iter()
+                    .filter(|script| sent.insert(script.label().to_string()))
+                    .map(|script| WireScript::new(script))
+                    .collect();
+                session.send(stream.get_mut(), &Message::Batch {
+                    lists,
+                    scripts,
+                    attempts,
+                })?;
+            },
+            Message::Results { results } => {
+                for result in results {
+                    dispatcher.finish(worker, result);
+                }
+            },
+            Message::Ping => (),
+            _ => bail!("unexpected message"),
+        }
+    }
+}
+
+/// Listen for workers and hand the attempts of the scheduler to them. The
+/// attempts are only started once a worker connected, the lists have to be
+/// set with Dispatcher::describe before
+pub fn serve(options: &ServeOptions, config: &Arc<Config>, pool: &mut Scheduler) -> Result<Dispatcher> {
+    if options.batch == 0 {
+        bail!("batch must be greater than zero");
+    }
+    let token = token(options.token.as_deref())?;
+    let tls = match options.tls_identity {
+        Some(ref path) => Some(Arc::new(tls_acceptor(path, options.tls_identity_password.as_deref())
+            .chain_err(|| "invalid --tls-identity")?)),
+        None => None,
+    };
+    let listener = TcpListener::bind(&options.listen)
+        .chain_err(|| format!("failed to listen on {}", options.listen))?;
+    let addr = listener.local_addr()?;
+    // the workers receive the scripts and the options, anyone on the way
+    // could read them
+    if tls.is_none() && !addr.ip().is_loopback() {
+        bail!("refusing to listen on {} without --tls-identity, only a loopback address can be used without tls", addr);
+    }
+    info!("listening for workers on {}", addr);
+
+    let dispatcher = Dispatcher::new(pool.tx());
+    pool.set_remote(dispatcher.clone());
+
+    let script_options = config.options.clone();
+    let batch = options.batch;
+    let remote = dispatcher.clone();
+    thread::spawn(move || {
+        for (worker, stream) in listener.incoming().enumerate() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let addr = stream.peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "?".to_string());
+            let tls = tls.clone();
+            let token = token.clone();
+            let script_options = script_options.clone();
+            let dispatcher = dispatcher.clone();
+            thread::spawn(move || {
+                let result = handle(stream, tls.as_deref(), worker, &token, &script_options, batch, &dispatcher);
+                let retried = dispatcher.requeue(worker);
+                let msg = match result {
+                    Ok(_) => format!("worker {} disconnected", addr),
+                    Err(err) => format!("worker {} failed: {}", addr, err),
+                };
+                let msg = if retried > 0 {
+                    format!("{}, {} attempts are handed to the other workers", msg, retried)
+                } else {
+                    msg
+                };
+                let _ = dispatcher.tx.send(Msg::Print(Level::Warn, msg));
+            });
+        }
+    });
+
+    Ok(remote)
+}
+
+/// The connection of a worker to its coordinator
+pub struct Connection {
+    stream: BufReader<Stream>,
+    session: Session,
+    // set with -x on the coordinator
+    pub options: HashMap<String, String>,
+    // see worker --lists
+    lists: Option<String>,
+}
+
+impl Connection {
+    /// Connect to the coordinator, both sides prove they know the token
+    pub fn open(args: &Worker, config: &Config) -> Result<Connection> {
+        let token = token(args.token.as_deref())?;
+        let stream = TcpStream::connect(&args.connect)
+            .chain_err(|| format!("failed to connect to {}", args.connect))?;
+        stream.set_nodelay(true)?;
+        let stream = if args.no_tls {
+            if !stream.peer_addr()?.ip().is_loopback() {
+                bail!("--no-tls can only be used for a coordinator on a loopback address");
+            }
+            Stream::Plain(stream)
+        } else {
+            let stream = tls_connect(&args.connect, stream, config)
+                .chain_err(|| format!("failed to connect to {}", args.connect))?;
+            Stream::Tls(Box::new(stream))
+        };
+        let mut stream = BufReader::new(stream);
+
+        let (options, session) = Connection::handshake(&mut stream, &token)
+            .chain_err(|| format!("failed to authenticate with {}", args.connect))?;
+        Ok(Connection {
+            stream,
+            session,
+            options,
+            lists: args.lists.clone(),
+        })
+    }
+
+    fn handshake<S: Read + Write>(stream: &mut BufReader<S>, token: &str) -> Result<(HashMap<String, String>, Session)> {
+        let challenge = match recv(stream)? {
+            Some(Message::Hello { version, nonce }) if version == PROTOCOL_VERSION => nonce,
+            Some(Message::Hello { version, .. }) => bail!("the coordinator speaks protocol version {}, this worker version {}", version, PROTOCOL_VERSION),
+            Some(_) => bail!("expected a challenge"),
+            None => bail!("connection closed during the handshake"),
+        };
+
+        let nonce = nonce();
+        send(stream.get_mut(), &Message::Auth {
+            version: PROTOCOL_VERSION,
+            mac: sign(token, "worker", &challenge),
+            nonce: nonce.clone(),
+        })?;
+
+        match recv(stream)? {
+            // the coordinator sends us code, make sure it knows the token too
+            Some(Message::Welcome { ref mac, .. }) if !verify(token, "coordinator", &nonce, mac) => {
+                bail!("the coordinator doesn't know the token")
+            },
+            Some(Message::Welcome { options, .. }) => Ok((options, Session::new(token, &challenge, &nonce, "worker"))),
+            Some(Message::Rejected { reason }) => bail!("rejected: {}", reason),
+            Some(_) => bail!("expected a welcome"),
+            None => bail!("connection closed during the handshake"),
+        }
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        self.session.send(self.stream.get_mut(), msg)
+    }
+
+    fn request(&mut self, msg: &Message) -> Result<Option<Message>> {
+        self.send(msg)?;
+        self.session.recv(&mut self.stream)
+    }
+}
+
+/// Load the scripts of a batch, attempts of a script that failed to load
+/// fail with the error
+fn load_scripts(scripts: Vec<WireScript>, loaded: &mut HashMap<String, std::result::Result<Arc<Script>, String>>, config: &Arc<Config>) {
+    for wire in scripts {
+        let script = Script::load_code(&wire.code, &wire.name, wire.assigned.as_deref(), config.clone())
+            .and_then(|mut script| {
+                if script.label() != wire.label {
+                    bail!("expected {:?}, got {:?}", wire.label, script.label());
+                }
+                script.check_options()?;
+                script.init()?;
+                Ok(script)
+            })
+            .map(Arc::new)
+            .map_err(|err| {
+                let err = format!("failed to load {}: {}", wire.name, err);
+                eprintln!("{}", Level::Warn.format(&err));
+                err
+            });
+        loaded.insert(wire.label, script);
+    }
+}
+
+/// Pull attempts from the coordinator and run them until the coordinator
+/// closes the connection. Errors are reported to the coordinator, it decides
+/// if they are retried
+pub fn run_worker(mut conn: Connection, config: &Arc<Config>, workers: usize, interval: Option<Duration>) -> Result<()> {
+    let mut pool = Scheduler::new(workers, interval, None, false);
+    pool.resume();
+
+    let mut scripts = HashMap::new();
+    let mut lists = None;
+    let mut done = 0;
+    let mut results = Vec::new();
+    let mut next_pull = Instant::now();
+    let mut last_sent = Instant::now();
+    loop {
+        if pool.inflight() < workers && Instant::now() >= next_pull {
+            let reply = conn.request(&Message::Pull {
+                max: workers - pool.inflight(),
+            })?;
+            last_sent = Instant::now();
+            match reply {
+                Some(Message::Batch { lists: new_lists, scripts: new, attempts }) => {
+                    if let Some(new_lists) = new_lists {
+                        let entries = Entries::load(&new_lists, conn.lists.as_deref())
+                            .chain_err(|| "failed to load the lists of the coordinator")?;
+                        eprintln!("{}", Level::Info.format(&format!("loaded the lists of the coordinator, {} entries", entries.len())));
+                        lists = Some((new_lists, entries));
+                    }
+                    let (lists, entries) = match lists {
+                        Some((ref lists, ref mut entries)) => (lists, entries),
+                        None => bail!("the coordinator didn't send the lists"),
+                    };
+                    load_scripts(new, &mut scripts, config);
+                    for idx in attempts.into_iter().flat_map(|(start, end)| start..end) {
+                        let label = &lists.scripts[idx % lists.scripts.len()];
+                        let script = match scripts.get(label) {
+                            Some(Ok(script)) => script.clone(),
+                            Some(Err(err)) => {
+                                results.push(WireResult::failed(idx, err.clone()));
+                                continue;
+                            },
+                            None => bail!("the coordinator didn't send the script {:?}", label),
+                        };
+                        match entries.attempt(idx, &script, lists.scripts.len()) {
+                            Ok(attempt) => pool.run(attempt),
+                            Err(err) => results.push(WireResult::failed(idx, err.to_string())),
+                        }
+                    }
+                },
+                Some(Message::Wait) => next_pull = Instant::now() + WAIT_INTERVAL,
+                Some(_) => bail!("unexpected message"),
+                None => break,
+            }
+        }
+
+        // collect what finished, without waiting long so the workers don't
+        // run dry
+        let mut deadline = Some(Instant::now() + POLL_INTERVAL);
+        while let Some(msg) = pool.recv_until(deadline) {
+            match msg {
+                Msg::Attempt(attempt, result) => {
+                    results.push(WireResult::new(&attempt, result));
+                    done += 1;
+                },
+                Msg::Print(level, msg) => eprintln!("{}", level.format(&msg)),
+                _ => (),
+            }
+            deadline = Some(Instant::now());
+        }
+
+        if !results.is_empty() {
+            conn.send(&Message::Results {
+                results: mem::take(&mut results),
+            })?;
+            last_sent = Instant::now();
+        } else if last_sent.elapsed() >= PING_INTERVAL {
+            conn.send(&Message::Ping)?;
+            last_sent = Instant::now();
+        }
+    }
+
+    for err in pool.teardown_workers() {
+        eprintln!("{}", Level::Warn.format(&err));
+    }
+    println!("[+] the coordinator closed the connection after {} attempts", done);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_frames() {
+        let mut buf = Vec::new();
+        send(&mut buf, &Message::Pull { max: 3 }).unwrap();
+        send(&mut buf, &Message::Ping).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 23]);
+
+        let mut r = &buf[..];
+        assert_eq!(recv(&mut r).unwrap(), Some(Message::Pull { max: 3 }));
+        assert_eq!(recv(&mut r).unwrap(), Some(Message::Ping));
+        assert_eq!(recv(&mut r).unwrap(), None);
+
+        let mut r = &[0xff, 0xff, 0xff, 0xff][..];
+        assert!(recv(&mut r).is_err());
+    }
+
+    fn handshake(coordinator: &str, worker: &str) -> (Result<Session>, Result<(HashMap<String, String>, Session)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let coordinator = coordinator.to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut options = HashMap::new();
+            options.insert("port".to_string(), "2222".to_string());
+            accept(&mut BufReader::new(stream), &coordinator, &options)
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let options = Connection::handshake(&mut BufReader::new(stream), worker);
+        (server.join().unwrap(), options)
+    }
+
+    #[test]
+    fn verify_handshake() {
+        let (server, options) = handshake("hunter2", "hunter2");
+        assert!(server.is_ok());
+        assert_eq!(options.unwrap().0.get("port").map(|x| x.as_str()), Some("2222"));
+
+        let (server, options) = handshake("hunter2", "hunter3");
+        assert_eq!(server.err().unwrap().to_string(), "rejected: invalid token");
+        assert_eq!(options.err().unwrap().to_string(), "rejected: invalid token");
+    }
+
+    #[test]
+    fn verify_session() {
+        let (server, options) = handshake("hunter2", "hunter2");
+        let mut coordinator = server.unwrap();
+        let mut worker = options.unwrap().1;
+
+        let mut buf = Vec::new();
+        worker.send(&mut buf, &Message::Pull { max: 3 }).unwrap();
+        worker.send(&mut buf, &Message::Ping).unwrap();
+        let mut r = &buf[..];
+        assert_eq!(coordinator.recv(&mut r).unwrap(), Some(Message::Pull { max: 3 }));
+        assert_eq!(coordinator.recv(&mut r).unwrap(), Some(Message::Ping));
+        assert_eq!(coordinator.recv(&mut r).unwrap(), None);
+
+        // a replayed message
+        let mut r = &buf[..];
+        assert!(coordinator.recv(&mut r).is_err());
+
+        // a message that was changed on the way
+        let mut buf = Vec::new();
+        coordinator.send(&mut buf, &Message::Pull { max: 3 }).unwrap();
+        buf[25] = b'4';
+        assert!(worker.recv(&mut &buf[..]).is_err());
+
+        // a message without a mac
+        let mut buf = Vec::new();
+        send(&mut buf, &Message::Wait).unwrap();
+        assert!(worker.recv(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn verify_dispatcher() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "cluster"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+        let user = Arc::new("root".to_string());
+        let password = Arc::new("toor".to_string());
+
+        let (tx, rx) = mpsc::channel();
+        let dispatcher = Dispatcher::new(tx);
+        for idx in 0..3 {
+            dispatcher.submit(Attempt::new(&user, &password, &script, idx));
+        }
+
+        let (attempts, scripts) = dispatcher.lease(0, 2);
+        assert_eq!(attempts, vec![(0, 2)]);
+        assert_eq!(scripts.len(), 2);
+
+        // a worker can only finish its own attempts
+        let mut result = WireResult::failed(0, "connection refused".to_string());
+        result.error.as_mut().unwrap().retryable = true;
+        dispatcher.finish(1, result);
+        assert!(rx.try_recv().is_err());
+        dispatcher.finish(0, WireResult::failed(0, "connection refused".to_string()));
+        match rx.try_recv().unwrap() {
+            Msg::Attempt(attempt, Err(err)) => {
+                assert_eq!(attempt.idx, 0);
+                assert_eq!(err.to_string(), "connection refused");
+                assert!(!errkind::is_retryable(&err));
+            },
+            _ => panic!("expected a failed attempt"),
+        }
+
+        // the attempts of a worker that disconnected are handed out first
+        assert_eq!(dispatcher.requeue(0), 1);
+        let (attempts, _) = dispatcher.lease(1, 5);
+        assert_eq!(attempts, vec![(1, 3)]);
+        let ids = dispatcher.queue.lock().unwrap().leased.iter()
+            .map(|(id, x)| (*id, x.0))
+            .collect::<HashSet<_>>();
+        assert_eq!(ids, [(1, 1), (2, 1)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn verify_ranges() {
+        assert_eq!(ranges(vec![]), vec![]);
+        assert_eq!(ranges(vec![7, 3, 4, 5, 9, 8]), vec![(3, 6), (7, 10)]);
+        assert_eq!(ranges(vec![10, 0]), vec![(0, 1), (10, 11)]);
+    }
+
+    #[test]
+    fn verify_entries() {
+        let users = env::temp_dir().join(format!("badtouch-cluster-users-{}.txt", ::std::process::id()));
+        let passwords = env::temp_dir().join(format!("badtouch-cluster-passwords-{}.txt", ::std::process::id()));
+        fs::write(&users, "root\nadmin\nroot\n").unwrap();
+        fs::write(&passwords, "toor\nhunter2\n").unwrap();
+        let (users, passwords) = (users.to_str().unwrap(), passwords.to_str().unwrap());
+
+        let script = Arc::new(Script::load_from(r#"
+        descr = "cluster"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+        let mut lists = Lists {
+            mode: Mode::Dict { order: "password-first".to_string(), stream: false },
+            files: vec![Input::file(users).unwrap(), Input::file(passwords).unwrap()],
+            dedup: true,
+            trim: false,
+            scripts: vec![script.label().to_string()],
+            entries: 4,
+        };
+
+        let mut entries = Entries::load(&lists, None).unwrap();
+        let attempt = entries.attempt(3, &script, 1).unwrap();
+        assert_eq!((attempt.user(), attempt.password(), attempt.idx), ("admin", "hunter2", 3));
+        assert!(entries.attempt(4, &script, 1).is_err());
+
+        // the lists are looked up by their name
+        let dir = env::temp_dir();
+        assert!(Entries::load(&lists, dir.to_str()).is_ok());
+
+        // the worker removes the duplicates like the coordinator did
+        lists.dedup = false;
+        assert_eq!(Entries::load(&lists, None).err().unwrap().to_string(), "loaded 6 entries, the coordinator has 4");
+        lists.dedup = true;
+
+        // a list that changed
+        fs::write(users, "root\n").unwrap();
+        assert!(Entries::load(&lists, None).is_err());
+
+        fs::remove_file(users).unwrap();
+        fs::remove_file(passwords).unwrap();
+    }
+}
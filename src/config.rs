@@ -1,39 +1,386 @@
-use errors::{Result, ResultExt};
+use errors::{Result, ResultExt, Error};
 
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 use libc::rlim_t;
 use toml;
+use humantime;
+use reqwest;
+use dns::{Override, Resolver};
+use proxy::{self, Proxy, ProxyPool, Route, Scheme};
+use std::time::Duration;
+use utils;
+
+const DEFAULT_MYSQL_TIMEOUT: u64 = 5;
+const DEFAULT_RETRIES: u8 = 5;
+const DEFAULT_BACKOFF: &str = "1s";
+const DEFAULT_BACKOFF_CAP: &str = "1m";
+const DEFAULT_HTTP_TIMEOUT: u64 = 30;
+// 5 MiB
+const DEFAULT_HTTP_MAX_RESPONSE: u64 = 5 * 1024 * 1024;
+const DEFAULT_PROXY_COOLDOWN: u64 = 60;
 
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default)]
     pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub dns: BTreeMap<String, IpAddr>,
+    #[serde(default)]
+    pub proxies: ProxiesConfig,
+    // set with -x, read by scripts with getopt
+    #[serde(skip)]
+    pub options: HashMap<String, String>,
+    // see start_route
+    #[serde(skip)]
+    pub route: Route,
+    #[serde(skip)]
+    pub proxy_pool: Option<Arc<ProxyPool>>,
+}
+
+/// The [proxies] section, see --proxy-list
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxiesConfig {
+    #[serde(default)]
+    pub list: Vec<String>,
+    #[serde(default)]
+    pub rotation: proxy::Rotation,
+    // how long a proxy that couldn't be reached is skipped
+    #[serde(default, with = "duration")]
+    pub cooldown: Option<Duration>,
+}
+
+impl ProxiesConfig {
+    #[inline]
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown.unwrap_or_else(|| Duration::from_secs(DEFAULT_PROXY_COOLDOWN))
+    }
+
+    /// Add the proxies of --proxy-list, one per line. Empty lines and lines
+    /// that start with # are skipped
+    pub fn load_list(&mut self, path: &str) -> Result<()> {
+        let mut buf = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut buf))
+            .chain_err(|| format!("failed to read proxy list {:?}", path))?;
+        let before = self.list.len();
+        self.list.extend(buf.lines()
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(String::from));
+        if self.list.len() == before {
+            bail!("proxy list {:?} has no proxies", path);
+        }
+        Ok(())
+    }
+
+    pub fn proxies(&self) -> Result<Vec<Proxy>> {
+        self.list.iter()
+            .map(|proxy| proxy.parse()
+                .map_err(|err| format!("invalid proxy {:?} in the proxy list: {}", proxy, err).into()))
+            .collect()
+    }
+}
+
+/// An option for the scripts, see -x
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptOption {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for ScriptOption {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ScriptOption> {
+        match s.find('=') {
+            Some(idx) if idx > 0 => Ok(ScriptOption {
+                key: s[..idx].to_string(),
+                value: s[idx + 1..].to_string(),
+            }),
+            _ => bail!("expected key=value, got {:?}", s),
+        }
+    }
+}
+
+/// A single user agent, or a list that is rotated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UserAgent {
+    One(String),
+    List(Vec<String>),
+}
+
+/// How often a rotated user agent is picked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Request,
+    Session,
 }
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RuntimeConfig {
     #[serde(default)]
-    pub user_agent: Option<String>,
+    pub user_agent: Option<UserAgent>,
+    #[serde(default)]
+    pub user_agent_file: Option<String>,
+    #[serde(default)]
+    pub user_agent_rotation: Rotation,
     #[serde(default)]
     pub rlimit_nofile: Option<rlim_t>,
+    #[serde(default)]
+    pub mysql_timeout: Option<u64>,
+    #[serde(default)]
+    pub sandbox: bool,
+    #[serde(default)]
+    pub retries: Option<u8>,
+    #[serde(default)]
+    pub backoff: Option<String>,
+    #[serde(default)]
+    pub backoff_cap: Option<String>,
+    #[serde(default, with = "duration")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub rate: Option<f64>,
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub proxy_strict: bool,
+    #[serde(default, with = "duration")]
+    pub http_timeout: Option<Duration>,
+    #[serde(default)]
+    pub http_max_response: Option<u64>,
+    #[serde(default)]
+    pub tls_verify_hostname: Option<bool>,
+    #[serde(default)]
+    pub tls_ca_file: Option<String>,
+    #[serde(default)]
+    pub notify_webhook: Option<String>,
+    #[serde(default)]
+    pub notify_template: Option<String>,
+    #[serde(default)]
+    pub notify_password: bool,
+    // the content of tls_ca_file, see load_ca
+    #[serde(skip)]
+    pub tls_ca: Option<Vec<u8>>,
+    // the lines of user_agent_file, see load_user_agents
+    #[serde(skip)]
+    pub user_agent_list: Vec<String>,
+    // the proxy reqwest connects to, see Config::start_route
+    #[serde(skip)]
+    pub http_proxy: Option<String>,
+    // only local functions are available, see badtouch benchmark --offline
+    #[serde(skip)]
+    pub offline: bool,
 }
 
-impl Config {
-    pub fn load() -> Result<Config> {
-        let home = env::home_dir()
-                        .chain_err(|| "home folder not found")?;
+/// Durations are written like the arguments, eg. "30s"
+mod duration {
+    use humantime;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match *duration {
+            Some(duration) => serializer.serialize_str(&humantime::format_duration(duration).to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        let duration = String::deserialize(deserializer)?;
+        humantime::parse_duration(&duration)
+            .map(Some)
+            .map_err(|err| D::Error::custom(format!("invalid duration {:?}: {}", duration, err)))
+    }
+}
+
+impl RuntimeConfig {
+    /// The default connect, read and write timeout for mysql
+    #[inline]
+    pub fn mysql_timeout(&self) -> Duration {
+        Duration::from_secs(self.mysql_timeout.unwrap_or(DEFAULT_MYSQL_TIMEOUT))
+    }
+
+    /// How often an attempt is retried after an error, unless the script overrides it
+    #[inline]
+    pub fn retries(&self) -> u8 {
+        self.retries.unwrap_or(DEFAULT_RETRIES)
+    }
+
+    /// The delay before the first retry, it's doubled for every retry after that
+    pub fn backoff(&self) -> Result<Duration> {
+        let backoff = self.backoff.as_deref().unwrap_or(DEFAULT_BACKOFF);
+        humantime::parse_duration(backoff)
+            .map_err(|err| format!("invalid backoff {:?}: {}", backoff, err).into())
+    }
+
+    /// The maximum delay before a retry
+    pub fn backoff_cap(&self) -> Result<Duration> {
+        let cap = self.backoff_cap.as_deref().unwrap_or(DEFAULT_BACKOFF_CAP);
+        humantime::parse_duration(cap)
+            .map_err(|err| format!("invalid backoff_cap {:?}: {}", cap, err).into())
+    }
+
+    /// The timeout of a single http request, shortened to the deadline of the attempt
+    #[inline]
+    pub fn http_timeout(&self) -> Duration {
+        self.http_timeout.unwrap_or_else(|| Duration::from_secs(DEFAULT_HTTP_TIMEOUT))
+    }
+
+    /// The number of bytes of a response body that are read, the rest is cut off
+    #[inline]
+    pub fn http_max_response(&self) -> u64 {
+        self.http_max_response.unwrap_or(DEFAULT_HTTP_MAX_RESPONSE)
+    }
+
+    /// Hostname verification can be disabled for targets with self-signed certificates
+    #[inline]
+    pub fn verify_hostname(&self) -> bool {
+        self.tls_verify_hostname.unwrap_or(true)
+    }
+
+    /// The proxy for all connections, see --proxy
+    pub fn proxy(&self) -> Result<Option<Proxy>> {
+        match self.proxy {
+            Some(ref proxy) => proxy.parse()
+                .map(Some)
+                .map_err(|err| format!("invalid proxy {:?}: {}", proxy, err).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Where valid credentials are posted to, see --notify-webhook
+    pub fn notify_webhook(&self) -> Result<Option<reqwest::Url>> {
+        let url = match self.notify_webhook {
+            Some(ref url) => url,
+            None => return Ok(None),
+        };
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|err| format!("invalid webhook {:?}: {}", url, err))?;
+        match parsed.scheme() {
+            "http" | "https" => Ok(Some(parsed)),
+            scheme => bail!("invalid webhook {:?}: unsupported scheme {}", url, scheme),
+        }
+    }
 
-        let path = home.join(".config/badtouch.toml");
+    /// Read tls_ca_file once instead of for every request
+    pub fn load_ca(&mut self) -> Result<()> {
+        self.tls_ca = match self.tls_ca_file {
+            Some(ref path) => {
+                let mut file = File::open(path)
+                    .chain_err(|| format!("failed to open tls_ca_file {:?}", path))?;
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                reqwest::Certificate::from_pem(&buf)
+                    .chain_err(|| format!("invalid tls_ca_file {:?}", path))?;
+                Some(buf)
+            },
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Read user_agent_file once, empty lines and lines that start with # are
+    /// skipped
+    pub fn load_user_agents(&mut self) -> Result<()> {
+        self.user_agent_list = match self.user_agent_file {
+            Some(ref path) => {
+                let mut buf = String::new();
+                File::open(path)
+                    .and_then(|mut file| file.read_to_string(&mut buf))
+                    .chain_err(|| format!("failed to read user_agent_file {:?}", path))?;
+                let agents = buf.lines()
+                    .map(|x| x.trim())
+                    .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                if agents.is_empty() {
+                    bail!("user_agent_file {:?} has no user agents", path);
+                }
+                agents
+            },
+            None => Vec::new(),
+        };
+        Ok(())
+    }
 
-        if path.exists() {
-            Config::from_file(path)
-        } else {
-            Ok(Config::default())
+    /// The user agents of user_agent and user_agent_file
+    pub fn user_agents(&self) -> Vec<&str> {
+        let mut agents = match self.user_agent {
+            Some(UserAgent::One(ref agent)) => vec![agent.as_str()],
+            Some(UserAgent::List(ref agents)) => agents.iter().map(|x| x.as_str()).collect(),
+            None => Vec::new(),
+        };
+        agents.extend(self.user_agent_list.iter().map(|x| x.as_str()));
+        agents
+    }
+
+    /// A random user agent, none if there are no user agents
+    pub fn pick_user_agent(&self) -> Option<String> {
+        let agents = self.user_agents();
+        if agents.is_empty() {
+            return None;
         }
+        Some(agents[utils::rand_below(agents.len())].to_string())
+    }
+
+    /// Check the values that are only parsed on use, returns the key of the
+    /// first invalid one
+    fn validate(&self) -> ::std::result::Result<(), (&'static str, Error)> {
+        self.backoff().map_err(|err| ("backoff", err))?;
+        self.backoff_cap().map_err(|err| ("backoff_cap", err))?;
+        self.proxy().map_err(|err| ("proxy", err))?;
+        self.notify_webhook().map_err(|err| ("notify_webhook", err))?;
+        if let Some(rate) = self.rate {
+            if rate.is_nan() || rate <= 0.0 {
+                return Err(("rate", "rate must be greater than zero".into()));
+            }
+        }
+        if self.user_agent == Some(UserAgent::List(Vec::new())) {
+            return Err(("user_agent", "user_agent must not be an empty list".into()));
+        }
+        if self.http_max_response == Some(0) {
+            return Err(("http_max_response", "http_max_response must be greater than zero".into()));
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Load the config from --config, $BADTOUCH_CONFIG or ~/.config/badtouch.toml
+    /// and apply the BADTOUCH_* variables on top of it
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let path = match path.map(String::from).or_else(|| env::var("BADTOUCH_CONFIG").ok()) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => {
+                let home = env::home_dir()
+                                .chain_err(|| "home folder not found")?;
+                Some(home.join(".config/badtouch.toml"))
+                    .filter(|path| path.exists())
+            },
+        };
+
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)
+                .chain_err(|| format!("failed to load config {}", path.display()))?,
+            None => Config::default(),
+        };
+        config.apply_env(|key| env::var(key).ok())?;
+        Ok(config)
     }
 
     #[inline]
@@ -46,11 +393,179 @@ impl Config {
         Config::try_from_str(&buf)
     }
 
-    #[inline]
+    /// Combine --proxy, --resolve and the [dns] section, the overrides from
+    /// --resolve win. reqwest only supports http proxies and no overrides,
+    /// so it gets a local http proxy in front of everything else. With
+    /// [proxies] every attempt picks its own route from the pool, see
+    /// State::route
+    pub fn start_route(&mut self, overrides: Vec<Override>) -> Result<()> {
+        let mut resolver = Resolver::default();
+        for entry in overrides {
+            resolver.add(entry);
+        }
+        for (name, ip) in &self.dns {
+            let entry = Override::new(name, None, *ip)
+                .chain_err(|| "invalid [dns] section")?;
+            resolver.add(entry);
+        }
+
+        if !self.proxies.list.is_empty() {
+            if self.runtime.proxy.is_some() {
+                bail!("--proxy can't be combined with a proxy list");
+            }
+            let pool = ProxyPool::start(self.proxies.proxies()?, &resolver, self.proxies.rotation, self.proxies.cooldown())
+                .chain_err(|| "failed to start the local http proxy")?;
+            self.proxy_pool = Some(Arc::new(pool));
+        }
+
+        self.route = Route {
+            proxy: self.runtime.proxy()?,
+            resolver,
+            health: None,
+        };
+        if self.runtime.proxy_strict && self.route.proxy.is_none() && self.proxy_pool.is_none() {
+            bail!("proxy_strict requires a proxy");
+        }
+
+        self.runtime.http_proxy = match self.route.proxy {
+            Some(ref proxy) if proxy.scheme == Scheme::Http && self.route.resolver.is_empty() => {
+                Some(proxy.to_string())
+            },
+            _ if self.route.is_direct() => None,
+            _ => {
+                let addr = self.route.serve()
+                    .chain_err(|| "failed to start the local http proxy")?;
+                Some(format!("http://{}", addr))
+            },
+        };
+        Ok(())
+    }
+
     pub fn try_from_str(buf: &str) -> Result<Config> {
-        let config = toml::from_str(&buf)?;
+        let config: Config = match toml::from_str(buf) {
+            Ok(config) => config,
+            Err(err) => {
+                let msg = err.to_string();
+                // toml only knows the line of syntax errors
+                match error_key(&msg).and_then(|key| key_line(buf, key)) {
+                    Some(line) if err.line_col().is_none() => bail!("{} at line {}", msg, line),
+                    _ => bail!(msg),
+                }
+            },
+        };
+
+        if let Err((key, err)) = config.runtime.validate() {
+            match key_line(buf, key) {
+                Some(line) => bail!("{} for key `runtime.{}` at line {}", err, key, line),
+                None => bail!(err),
+            }
+        }
+
         Ok(config)
     }
+
+    /// Override the config file with the BADTOUCH_* variables, the flags
+    /// override both
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<()> {
+        let var = |key: &str| var(key).filter(|value| !value.is_empty());
+        let duration = |key: &str| -> Result<Option<Duration>> {
+            match var(key) {
+                Some(value) => humantime::parse_duration(&value)
+                    .map(Some)
+                    .map_err(|err| format!("invalid {} {:?}: {}", key, value, err).into()),
+                None => Ok(None),
+            }
+        };
+        fn parse<T: FromStr>(key: &str, value: Option<String>) -> Result<Option<T>>
+                where T::Err: ::std::fmt::Display {
+            match value {
+                Some(value) => value.parse()
+                    .map(Some)
+                    .map_err(|err| format!("invalid {} {:?}: {}", key, value, err).into()),
+                None => Ok(None),
+            }
+        }
+
+        let runtime = &mut self.runtime;
+        if let Some(proxy) = var("BADTOUCH_PROXY") {
+            runtime.proxy = Some(proxy);
+        }
+        if let Some(strict) = parse("BADTOUCH_PROXY_STRICT", var("BADTOUCH_PROXY_STRICT"))? {
+            runtime.proxy_strict = strict;
+        }
+        if let Some(rate) = parse("BADTOUCH_RATE", var("BADTOUCH_RATE"))? {
+            runtime.rate = Some(rate);
+        }
+        if let Some(timeout) = duration("BADTOUCH_TIMEOUT")? {
+            runtime.timeout = Some(timeout);
+        }
+        if let Some(retries) = parse("BADTOUCH_RETRIES", var("BADTOUCH_RETRIES"))? {
+            runtime.retries = Some(retries);
+        }
+        if duration("BADTOUCH_BACKOFF")?.is_some() {
+            runtime.backoff = var("BADTOUCH_BACKOFF");
+        }
+        if duration("BADTOUCH_BACKOFF_CAP")?.is_some() {
+            runtime.backoff_cap = var("BADTOUCH_BACKOFF_CAP");
+        }
+        if let Some(timeout) = duration("BADTOUCH_HTTP_TIMEOUT")? {
+            runtime.http_timeout = Some(timeout);
+        }
+        if let Some(max) = parse("BADTOUCH_HTTP_MAX_RESPONSE", var("BADTOUCH_HTTP_MAX_RESPONSE"))? {
+            runtime.http_max_response = Some(max);
+        }
+        if let Some(verify) = parse("BADTOUCH_TLS_VERIFY_HOSTNAME", var("BADTOUCH_TLS_VERIFY_HOSTNAME"))? {
+            runtime.tls_verify_hostname = Some(verify);
+        }
+        if let Some(path) = var("BADTOUCH_TLS_CA_FILE") {
+            runtime.tls_ca_file = Some(path);
+        }
+        if let Some(url) = var("BADTOUCH_NOTIFY_WEBHOOK") {
+            runtime.notify_webhook = Some(url);
+        }
+        if let Some(path) = var("BADTOUCH_NOTIFY_TEMPLATE") {
+            runtime.notify_template = Some(path);
+        }
+        if let Some(full) = parse("BADTOUCH_NOTIFY_PASSWORD", var("BADTOUCH_NOTIFY_PASSWORD"))? {
+            runtime.notify_password = full;
+        }
+
+        if let Err((_, err)) = runtime.validate() {
+            return Err(err).chain_err(|| "invalid BADTOUCH_* variable");
+        }
+        Ok(())
+    }
+}
+
+/// The key toml complained about, unknown keys are named in the message
+fn error_key(msg: &str) -> Option<&str> {
+    let key = if let Some(idx) = msg.find("unknown field `") {
+        &msg[idx + 15..]
+    } else {
+        let idx = msg.rfind("for key `")?;
+        &msg[idx + 9..]
+    };
+    Some(&key[..key.find('`')?])
+}
+
+/// The first line that assigns the key, counted from 1. The table isn't
+/// known, so the path is shortened until something matches
+fn key_line(buf: &str, mut path: &str) -> Option<usize> {
+    loop {
+        let line = buf.lines()
+            .position(|line| {
+                let line = line.trim_start();
+                let line = line.strip_prefix('"').unwrap_or(line);
+                match line.strip_prefix(path) {
+                    Some(rest) => rest.trim_start_matches('"').trim_start().starts_with('='),
+                    None => false,
+                }
+            });
+        if let Some(idx) = line {
+            return Some(idx + 1);
+        }
+        path = &path[path.find('.')? + 1..];
+    }
 }
 
 #[cfg(test)]
@@ -61,5 +576,186 @@ mod tests {
     fn verify_empty() {
         let config = Config::try_from_str("").unwrap();
         assert_eq!(config, Config::default());
+        assert_eq!(config.runtime.mysql_timeout(), Duration::from_secs(5));
+        assert_eq!(config.runtime.retries(), 5);
+        assert_eq!(config.runtime.backoff().unwrap(), Duration::from_secs(1));
+        assert_eq!(config.runtime.backoff_cap().unwrap(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn verify_script_option() {
+        let option = "url=https://vpn.acme.corp/?a=b".parse::<ScriptOption>().unwrap();
+        assert_eq!(option.key, "url");
+        assert_eq!(option.value, "https://vpn.acme.corp/?a=b");
+        assert_eq!("realm=".parse::<ScriptOption>().unwrap().value, "");
+        assert!("realm".parse::<ScriptOption>().is_err());
+        assert!("=corp".parse::<ScriptOption>().is_err());
+    }
+
+    #[test]
+    fn verify_mysql_timeout() {
+        let config = Config::try_from_str("[runtime]\nmysql_timeout = 10\n").unwrap();
+        assert_eq!(config.runtime.mysql_timeout(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn verify_retries() {
+        let config = Config::try_from_str("[runtime]\nretries = 0\n").unwrap();
+        assert_eq!(config.runtime.retries(), 0);
+    }
+
+    #[test]
+    fn verify_backoff() {
+        let config = Config::try_from_str("[runtime]\nbackoff = \"250ms\"\nbackoff_cap = \"10s\"\n").unwrap();
+        assert_eq!(config.runtime.backoff().unwrap(), Duration::from_millis(250));
+        assert_eq!(config.runtime.backoff_cap().unwrap(), Duration::from_secs(10));
+
+        let err = Config::try_from_str("[runtime]\nretries = 3\nbackoff = \"soon\"\n").unwrap_err();
+        assert!(err.to_string().ends_with("for key `runtime.backoff` at line 3"), "{}", err);
+    }
+
+    #[test]
+    fn verify_runtime() {
+        let config = Config::try_from_str(r#"
+[runtime]
+timeout = "10s"
+rate = 2.5
+proxy = "socks5://127.0.0.1:9050"
+proxy_strict = true
+http_timeout = "5s"
+http_max_response = 65536
+tls_verify_hostname = false
+notify_webhook = "https://hooks.slack.com/services/T0/B0/X"
+notify_password = true
+"#).unwrap();
+        assert_eq!(config.runtime.timeout, Some(Duration::from_secs(10)));
+        assert_eq!(config.runtime.rate, Some(2.5));
+        assert_eq!(config.runtime.proxy().unwrap().unwrap().port, 9050);
+        assert!(config.runtime.proxy_strict);
+        assert_eq!(config.runtime.http_timeout(), Duration::from_secs(5));
+        assert_eq!(config.runtime.http_max_response(), 65536);
+        assert!(!config.runtime.verify_hostname());
+        assert_eq!(config.runtime.notify_webhook().unwrap().unwrap().host_str(), Some("hooks.slack.com"));
+        assert!(config.runtime.notify_password);
+    }
+
+    #[test]
+    fn verify_user_agents() {
+        let config = Config::try_from_str("[runtime]
+user_agent = \"w3m/0.5.3+git20180125\"\n").unwrap();
+        assert_eq!(config.runtime.user_agents(), vec!["w3m/0.5.3+git20180125"]);
+        assert_eq!(config.runtime.pick_user_agent(), Some("w3m/0.5.3+git20180125".to_string()));
+        assert_eq!(Config::default().runtime.pick_user_agent(), None);
+
+        let path = env::temp_dir().join(format!("badtouch-user-agents-{}.txt", ::std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"# from the proxy logs\nMozilla/5.0 (X11; Linux x86_64; rv:60.0) Gecko/20100101 Firefox/60.0\n\n").unwrap();
+        let mut config = Config::try_from_str(&format!(r#"
+[runtime]
+user_agent = ["curl/7.60.0", "Wget/1.19.5"]
+user_agent_file = {:?}
+user_agent_rotation = "session"
+"#, path.to_str().unwrap())).unwrap();
+        config.runtime.load_user_agents().unwrap();
+        assert_eq!(config.runtime.user_agents(), vec![
+            "curl/7.60.0",
+            "Wget/1.19.5",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:60.0) Gecko/20100101 Firefox/60.0",
+        ]);
+        assert_eq!(config.runtime.user_agent_rotation, Rotation::Session);
+        let agent = config.runtime.pick_user_agent().unwrap();
+        assert!(config.runtime.user_agents().contains(&agent.as_str()));
+
+        File::create(&path).unwrap().write_all(b"# nothing yet\n").unwrap();
+        assert!(config.runtime.load_user_agents().is_err());
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert!(Config::try_from_str("[runtime]
+user_agent = []
+").is_err());
+        assert!(Config::try_from_str("[runtime]
+user_agent_rotation = \"attempt\"\n").is_err());
+    }
+
+    #[test]
+    fn verify_errors() {
+        let err = Config::try_from_str("[runtime]\nretries = \"3\"\n").unwrap_err();
+        assert!(err.to_string().ends_with("for key `runtime.retries` at line 2"), "{}", err);
+
+        let err = Config::try_from_str("[runtime]\n\nproxyy = \"http://127.0.0.1:8080\"\n").unwrap_err();
+        assert!(err.to_string().starts_with("unknown field `proxyy`"), "{}", err);
+        assert!(err.to_string().ends_with("at line 3"), "{}", err);
+
+        let err = Config::try_from_str("[runtime]\ntimeout = \"soon\"\n").unwrap_err();
+        assert!(err.to_string().ends_with("for key `runtime.timeout` at line 2"), "{}", err);
+
+        assert!(Config::try_from_str("[runtime]\nrate = 0.0\n").is_err());
+        assert!(Config::try_from_str("[runtime]\nhttp_max_response = 0\n").is_err());
+        assert!(Config::try_from_str("[runtime]\nnotify_webhook = \"ftp://hooks.corp\"\n").is_err());
+
+        let err = Config::try_from_str("[dns]\n\"*.corp.local\" = \"10.0.0.1\"\n\"sso.corp.local\" = \"sso\"\n").unwrap_err();
+        assert!(err.to_string().ends_with("for key `dns.sso.corp.local` at line 3"), "{}", err);
+    }
+
+    #[test]
+    fn verify_dns() {
+        let mut config = Config::try_from_str("[dns]\n\"*.corp.local\" = \"10.0.0.1\"\n").unwrap();
+        config.start_route(vec!["sso.corp.local:443:10.0.0.2".parse().unwrap()]).unwrap();
+        assert_eq!(config.route.resolver.lookup("sso.corp.local", 443), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(config.route.resolver.lookup("sso.corp.local", 80), Some("10.0.0.1".parse().unwrap()));
+        assert!(config.runtime.http_proxy.is_some());
+
+        let mut config = Config::try_from_str("[dns]\n\"corp.*\" = \"10.0.0.1\"\n").unwrap();
+        assert!(config.start_route(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn verify_proxies() {
+        let mut config = Config::try_from_str(r#"
+[runtime]
+proxy_strict = true
+
+[proxies]
+list = ["socks5://10.0.0.1:1080", "http://10.0.0.2:3128"]
+rotation = "sticky"
+cooldown = "5m"
+"#).unwrap();
+        assert_eq!(config.proxies.rotation, proxy::Rotation::Sticky);
+        assert_eq!(config.proxies.cooldown(), Duration::from_secs(300));
+        config.start_route(Vec::new()).unwrap();
+        let pool = config.proxy_pool.as_ref().unwrap();
+        assert_eq!(pool.stats(), vec![
+            ("socks5://10.0.0.1:1080".to_string(), 0, 0),
+            ("http://10.0.0.2:3128".to_string(), 0, 0),
+        ]);
+
+        let err = Config::try_from_str("[proxies]\nrotation = \"fastest\"\n").unwrap_err();
+        assert!(err.to_string().contains("unknown variant `fastest`"), "{}", err);
+
+        let mut config = Config::try_from_str("[runtime]\nproxy = \"socks5://127.0.0.1:9050\"\n[proxies]\nlist = [\"socks5://10.0.0.1:1080\"]\n").unwrap();
+        assert!(config.start_route(Vec::new()).is_err());
+        let mut config = Config::try_from_str("[proxies]\nlist = [\"10.0.0.1:1080\"]\n").unwrap();
+        assert!(config.start_route(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn verify_env() {
+        let mut config = Config::try_from_str("[runtime]\nretries = 1\ntimeout = \"10s\"\n").unwrap();
+        config.apply_env(|key| match key {
+            "BADTOUCH_RETRIES" => Some("3".to_string()),
+            "BADTOUCH_HTTP_TIMEOUT" => Some("1s".to_string()),
+            "BADTOUCH_PROXY" => Some("".to_string()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.runtime.retries(), 3);
+        assert_eq!(config.runtime.timeout, Some(Duration::from_secs(10)));
+        assert_eq!(config.runtime.http_timeout(), Duration::from_secs(1));
+        assert_eq!(config.runtime.proxy, None);
+
+        let err = config.apply_env(|key| match key {
+            "BADTOUCH_RATE" => Some("fast".to_string()),
+            _ => None,
+        }).unwrap_err();
+        assert!(err.to_string().starts_with("invalid BADTOUCH_RATE \"fast\""), "{}", err);
     }
 }
@@ -0,0 +1,282 @@
+//! Control a running attack through a unix socket, see --control-socket and
+//! badtouch ctl
+//!
+//! Every line a client sends is a json command, every command is answered
+//! with a json line that contains the status after the command was handled.
+
+use errors::{Result, ResultExt};
+use args::Ctl;
+use keyboard::Key;
+use scheduler::Msg;
+use serde_json;
+use status::Status;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// the main loop answers right away unless the attack is over
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    Pause,
+    Resume,
+    SetThreads { threads: usize },
+    Stats,
+    Stop,
+}
+
+impl Command {
+    /// Parse the command of badtouch ctl, only set_threads takes a value
+    pub fn parse(command: &str, value: Option<&str>) -> Result<Command> {
+        let command = match (command, value) {
+            ("pause", None) => Command::Pause,
+            ("resume", None) => Command::Resume,
+            ("stats", None) => Command::Stats,
+            ("stop", None) => Command::Stop,
+            ("set_threads", Some(threads)) => Command::SetThreads {
+                threads: threads.parse()
+                    .chain_err(|| format!("invalid number of threads: {:?}", threads))?,
+            },
+            ("set_threads", None) => bail!("set_threads requires the number of threads"),
+            ("pause", _) | ("resume", _) | ("stats", _) | ("stop", _) => bail!("{} doesn't take a value", command),
+            _ => bail!("unknown command {:?}, expected pause, resume, set_threads, stats or stop", command),
+        };
+        Ok(command)
+    }
+
+    /// The message that does the same as the keyboard or a signal would
+    fn msg(&self) -> Option<Msg> {
+        match *self {
+            Command::Pause => Some(Msg::Key(Key::P)),
+            Command::Resume => Some(Msg::Key(Key::R)),
+            Command::SetThreads { threads } => Some(Msg::Key(Key::Threads(threads))),
+            Command::Stats => None,
+            Command::Stop => Some(Msg::Terminate),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Reply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+}
+
+impl Reply {
+    fn error<I: Into<String>>(err: I) -> Reply {
+        Reply {
+            ok: false,
+            error: Some(err.into()),
+            status: None,
+        }
+    }
+}
+
+/// Send the command to the main loop and wait for the status after it was
+/// handled, the messages are handled in order
+fn execute(command: &Command, tx: &mpsc::Sender<Msg>) -> Reply {
+    if *command == (Command::SetThreads { threads: 0 }) {
+        return Reply::error("threads must be greater than zero");
+    }
+
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let sent = match command.msg() {
+        Some(msg) => tx.send(msg).is_ok(),
+        None => true,
+    };
+    if !sent || tx.send(Msg::Query(reply_tx)).is_err() {
+        return Reply::error("the attack is over");
+    }
+
+    match reply_rx.recv_timeout(REPLY_TIMEOUT) {
+        Ok(status) => Reply {
+            ok: true,
+            error: None,
+            status: Some(status),
+        },
+        Err(_) => Reply::error("the attack is over"),
+    }
+}
+
+fn handle(stream: UnixStream, tx: &mpsc::Sender<Msg>) -> Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => execute(&command, tx),
+            Err(err) => Reply::error(format!("invalid command: {}", err)),
+        };
+        let mut reply = serde_json::to_vec(&reply)?;
+        reply.push(b'\n');
+        writer.write_all(&reply)?;
+    }
+    Ok(())
+}
+
+/// The socket of --control-socket, it's removed when this is dropped
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Only the user that started the attack can connect to the socket
+    pub fn bind(path: &str, tx: mpsc::Sender<Msg>) -> Result<ControlSocket> {
+        if UnixStream::connect(path).is_ok() {
+            bail!("{} is used by another attack", path);
+        }
+        // left over from an attack that was killed
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                bail!("{} exists and isn't a socket", path);
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .chain_err(|| format!("failed to create control socket {}", path))?;
+        let socket = ControlSocket {
+            path: PathBuf::from(path),
+        };
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .chain_err(|| format!("failed to restrict the permissions of {}", path))?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handle(stream, &tx) {
+                        debug!("control client failed: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(socket)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Send a command to the socket of a running attack and print the status
+pub fn run_ctl(args: &Ctl) -> Result<()> {
+    let command = Command::parse(&args.command, args.value.as_deref())?;
+    let mut stream = UnixStream::connect(&args.socket)
+        .chain_err(|| format!("failed to connect to {}", args.socket))?;
+
+    let mut request = serde_json::to_vec(&command)?;
+    request.push(b'\n');
+    stream.write_all(&request)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    if line.is_empty() {
+        bail!("the attack closed the connection");
+    }
+    let reply = serde_json::from_str::<serde_json::Value>(&line)
+        .chain_err(|| "invalid reply")?;
+    if reply["ok"] != true {
+        bail!("{}", reply["error"].as_str().unwrap_or("the command failed"));
+    }
+    println!("{}", serde_json::to_string_pretty(&reply["status"])?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_parse() {
+        assert_eq!(Command::parse("pause", None).unwrap(), Command::Pause);
+        assert_eq!(Command::parse("set_threads", Some("8")).unwrap(), Command::SetThreads { threads: 8 });
+        assert!(Command::parse("set_threads", None).is_err());
+        assert!(Command::parse("set_threads", Some("eight")).is_err());
+        assert!(Command::parse("stats", Some("8")).is_err());
+        assert!(Command::parse("quit", None).is_err());
+
+        assert_eq!(serde_json::to_string(&Command::SetThreads { threads: 8 }).unwrap(),
+            r#"{"command":"set_threads","threads":8}"#);
+    }
+
+    #[test]
+    fn verify_socket() {
+        let path = ::std::env::temp_dir().join(format!("badtouch-control-{}.sock", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        let socket = ControlSocket::bind(&path, tx).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(ControlSocket::bind(&path, mpsc::channel().0).is_err());
+
+        // the main loop
+        let main = thread::spawn(move || {
+            let mut msgs = Vec::new();
+            for msg in rx {
+                match msg {
+                    Msg::Query(reply) => {
+                        reply.send(Status {
+                            attempts_done: 3,
+                            attempts_total: Some(10),
+                            valid: 1,
+                            errors: 0,
+                            retries: 0,
+                            expired: 0,
+                            rate: None,
+                            eta_seconds: None,
+                            threads: 4,
+                            paused: true,
+                        }).unwrap();
+                        msgs.push("query".to_string());
+                    },
+                    Msg::Key(key) => msgs.push(format!("{:?}", key)),
+                    _ => msgs.push("other".to_string()),
+                }
+                if msgs.len() == 4 {
+                    break;
+                }
+            }
+            msgs
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"{\"command\":\"set_threads\",\"threads\":0}\n{\"command\":\"pause\"}\nfoo\n{\"command\":\"stop\"}\n").unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":false,"error":"threads must be greater than zero"}"#);
+        let reply = lines.next().unwrap().unwrap();
+        assert!(reply.starts_with(r#"{"ok":true,"status":{"attempts_done":3,"#), "{}", reply);
+        assert!(lines.next().unwrap().unwrap().starts_with(r#"{"ok":false,"error":"invalid command: "#));
+        assert!(lines.next().unwrap().unwrap().starts_with(r#"{"ok":true,"#));
+        assert_eq!(main.join().unwrap(), vec!["P", "query", "other", "query"]);
+
+        drop(socket);
+        assert!(fs::symlink_metadata(&path).is_err());
+    }
+}
@@ -1,44 +1,252 @@
 use hlua::{self, AnyLuaValue};
-use errors::{Result, Error};
+use errors::{Result, ResultExt, Error, ErrorKind};
+use errkind::Kind;
 use runtime;
+use traceback;
+use logger;
+use log;
 
 use std::fs::File;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
 use std::io::prelude::*;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use rand::{Rng, thread_rng};
+use regex::Regex;
 use rand::distributions::Alphanumeric;
 use http::{HttpSession,
            HttpRequest,
-           RequestOptions};
+           RequestOptions,
+           SetCookie};
 use config::Config;
+use hashes::NtHash;
 use mysql;
+use reqwest;
 use sockets::Socket;
+use utils;
+use process::Process;
+use proxy::Route;
+use scheduler::{Level, Msg};
+
+
+/// Details a script can return with a table instead of a boolean
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    // valid, but a second factor is required
+    Mfa,
+    // valid, but the password has to be changed
+    Expired,
+    NoUser,
+    // the attempt is retried like a transient error
+    RateLimited,
+}
+
+impl FromStr for Status {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Status> {
+        match s {
+            "mfa" => Ok(Status::Mfa),
+            "expired" => Ok(Status::Expired),
+            "nouser" => Ok(Status::NoUser),
+            "ratelimited" => Ok(Status::RateLimited),
+            _ => bail!("unknown status {:?}, expected mfa, expired, nouser or ratelimited", s),
+        }
+    }
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Mfa => "mfa",
+            Status::Expired => "expired",
+            Status::NoUser => "nouser",
+            Status::RateLimited => "ratelimited",
+        }
+    }
+}
+
+/// The result of a single attempt
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub valid: bool,
+    pub status: Option<Status>,
+    pub note: Option<String>,
+    // set with set_target, falls back to the target of the script
+    pub target: Option<String>,
+    // users the script reported as locked out, see mark_locked
+    pub locked: Vec<String>,
+    // the target asked to slow down, see throttle
+    pub throttle: Option<Duration>,
+}
+
+impl Outcome {
+    /// A rate limit is turned into a transient error so the attempt is
+    /// retried after the backoff. An attempt that was throttled is started
+    /// again after the throttle, unless it was valid
+    pub fn check(&self) -> Result<bool> {
+        if let Some(delay) = self.throttle {
+            if !self.valid {
+                return Err(ErrorKind::Throttled(delay).into());
+            }
+        }
+        if self.status != Some(Status::RateLimited) {
+            return Ok(self.valid);
+        }
+        let msg = match self.note {
+            Some(ref note) => format!("rate limited: {}", note),
+            None => "rate limited".to_string(),
+        };
+        Err(ErrorKind::Transient(msg).into())
+    }
+}
+
+/// What verify returned, either a boolean or a table like
+/// `{valid=true, status="mfa", note="..."}`
+fn verdict(result: AnyLuaValue) -> Result<(bool, Option<Status>, Option<String>)> {
+    use hlua::AnyLuaValue::*;
+    match result {
+        LuaBoolean(x) => Ok((x, None, None)),
+        LuaString(x) => Err(format!("error: {:?}", x).into()),
+        LuaArray(fields) => {
+            let mut valid = false;
+            let mut status = None;
+            let mut note = None;
+            for (key, value) in fields {
+                match (lua_str(&key), value) {
+                    ("valid", LuaBoolean(x)) => valid = x,
+                    ("status", LuaString(x)) => status = Some(x.parse()?),
+                    ("note", LuaString(x)) => note = Some(x),
+                    (_, value) => bail!("lua returned unexpected field: {:?} = {:?}", key, value),
+                }
+            }
+            Ok((valid, status, note))
+        },
+        x => Err(format!("lua returned wrong type: {:?}", x).into()),
+    }
+}
+
+/// Values that can be passed from init() to verify, functions and userdata
+/// can't be copied to another lua state
+fn is_data(x: &AnyLuaValue) -> bool {
+    match *x {
+        AnyLuaValue::LuaArray(ref fields) => fields.iter().all(|(k, v)| is_data(k) && is_data(v)),
+        AnyLuaValue::LuaOther => false,
+        _ => true,
+    }
+}
+
+/// The user or password of an attempt, nil if we are enumerating users
+fn lua_str(x: &AnyLuaValue) -> &str {
+    match *x {
+        AnyLuaValue::LuaString(ref x) => x,
+        _ => "",
+    }
+}
+
+/// An error that was recorded with set_error
+#[derive(Debug)]
+struct LastError {
+    kind: Kind,
+    location: Option<String>,
+    err: Error,
+}
 
+impl LastError {
+    /// Prefix the error with the location in the script
+    fn into_error(self) -> Error {
+        match self.location {
+            Some(location) => {
+                let msg = format!("{}: {}", location, self.err);
+                Error::with_chain(self.err, msg)
+            },
+            None => self.err,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct State {
     config: Arc<Config>,
-    error: Arc<Mutex<Option<Error>>>,
+    error: Arc<Mutex<Option<LastError>>>,
     http_sessions: Arc<Mutex<HashMap<String, HttpSession>>>,
     mysql_sessions: Arc<Mutex<HashMap<String, Arc<Mutex<mysql::Conn>>>>>,
     socket_sessions: Arc<Mutex<HashMap<String, Arc<Mutex<Socket>>>>>,
+    proc_sessions: Arc<Mutex<HashMap<String, Arc<Mutex<Process>>>>>,
+    target: Arc<Mutex<Option<String>>>,
+    // the account of a dump that is attempted, see cred_info
+    cred_info: Arc<Mutex<Option<Arc<NtHash>>>>,
+    // overrides if an error is retried, see set_retry
+    retry: Arc<Mutex<Option<bool>>>,
+    // the longest delay a target asked for, see throttle
+    throttle: Arc<Mutex<Option<Duration>>>,
+    locked: Arc<Mutex<Vec<String>>>,
+    // the attempt is aborted after this, see --timeout
+    deadline: Option<Instant>,
+    // with [proxies] every state picks a proxy from the pool
+    route: Route,
+    http_proxy: Option<String>,
+    tx: Option<mpsc::Sender<Msg>>,
 }
 
 impl State {
-    pub fn new(config: Arc<Config>) -> State {
+    #[inline]
+    pub fn new(config: Arc<Config>, tx: Option<mpsc::Sender<Msg>>) -> State {
+        State::for_user(config, tx, None)
+    }
+
+    /// The user keeps the same proxy with sticky rotation, see [proxies]
+    pub fn for_user(config: Arc<Config>, tx: Option<mpsc::Sender<Msg>>, user: Option<&str>) -> State {
+        let deadline = config.runtime.timeout
+            .map(|timeout| Instant::now() + timeout);
+        let (route, http_proxy) = match config.proxy_pool {
+            Some(ref pool) => {
+                let member = pool.pick(user, Instant::now());
+                (member.route.clone(), Some(member.http_proxy.clone()))
+            },
+            None => (config.route.clone(), config.runtime.http_proxy.clone()),
+        };
         State {
             config,
             error: Arc::new(Mutex::new(None)),
             http_sessions: Arc::new(Mutex::new(HashMap::new())),
             mysql_sessions: Arc::new(Mutex::new(HashMap::new())),
             socket_sessions: Arc::new(Mutex::new(HashMap::new())),
+            proc_sessions: Arc::new(Mutex::new(HashMap::new())),
+            target: Arc::new(Mutex::new(None)),
+            cred_info: Arc::new(Mutex::new(None)),
+            retry: Arc::new(Mutex::new(None)),
+            throttle: Arc::new(Mutex::new(None)),
+            locked: Arc::new(Mutex::new(Vec::new())),
+            deadline,
+            route,
+            http_proxy,
+            tx,
         }
     }
 
-    pub fn last_error(&self) -> Option<String> {
+    #[inline]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Where the connections of the attempt go, see --proxy and [proxies]
+    #[inline]
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
+    /// The proxy reqwest connects to, see Config::start_route
+    #[inline]
+    pub fn http_proxy(&self) -> Option<&str> {
+        self.http_proxy.as_deref()
+    }
+
+    pub fn last_error(&self) -> Option<(Kind, String)> {
         let lock = self.error.lock().unwrap();
-        lock.as_ref().map(|err| err.to_string())
+        lock.as_ref().map(|last| (last.kind, last.err.to_string()))
     }
 
     pub fn clear_error(&self) {
@@ -49,21 +257,119 @@ impl State {
     pub fn set_error(&self, err: Error) -> Error {
         let mut mtx = self.error.lock().unwrap();
         let cp = err.to_string();
-        *mtx = Some(err);
+        *mtx = Some(LastError {
+            kind: Kind::of(&err),
+            location: traceback::location(),
+            err,
+        });
         cp.into()
     }
 
+    #[inline]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The time that is left for this attempt, fails if the timeout passed
+    #[inline]
+    pub fn remaining(&self) -> Result<Option<Duration>> {
+        let remaining = utils::remaining(self.deadline)?;
+        Ok(remaining)
+    }
+
+    /// The target that was set with set_target during this attempt
+    pub fn target(&self) -> Option<String> {
+        self.target.lock().unwrap().clone()
+    }
+
+    pub fn set_target(&self, target: String) {
+        let mut lock = self.target.lock().unwrap();
+        *lock = Some(target);
+    }
+
+    /// The account of a dump this attempt uses, none for plain credentials
+    pub fn cred_info(&self) -> Option<Arc<NtHash>> {
+        self.cred_info.lock().unwrap().clone()
+    }
+
+    pub fn set_cred_info(&self, hash: Arc<NtHash>) {
+        let mut lock = self.cred_info.lock().unwrap();
+        *lock = Some(hash);
+    }
+
+    /// If the error of this attempt should be retried, set by the script
+    pub fn retry(&self) -> Option<bool> {
+        *self.retry.lock().unwrap()
+    }
+
+    pub fn set_retry(&self, retry: bool) {
+        let mut lock = self.retry.lock().unwrap();
+        *lock = Some(retry);
+    }
+
+    #[inline]
+    pub fn throttled(&self) -> Option<Duration> {
+        *self.throttle.lock().unwrap()
+    }
+
+    /// Pause the attempts of the script and its target, the longest delay
+    /// of the attempt wins
+    pub fn throttle(&self, delay: Duration) {
+        let mut lock = self.throttle.lock().unwrap();
+        *lock = Some(lock.map_or(delay, |x| x.max(delay)));
+    }
+
+    /// The users that were marked as locked out during this attempt
+    pub fn locked(&self) -> Vec<String> {
+        self.locked.lock().unwrap().clone()
+    }
+
+    pub fn mark_locked(&self, user: String) {
+        let mut lock = self.locked.lock().unwrap();
+        if !lock.contains(&user) {
+            lock.push(user);
+        }
+    }
+
+    /// Print a message above the progress bar, or to stderr if we don't have one
+    pub fn print(&self, level: Level, msg: String) {
+        if let Some(ref tx) = self.tx {
+            // this only fails if the receiver is already gone
+            let _ = tx.send(Msg::Print(level, msg));
+        } else {
+            eprintln!("{}", level.format(&msg));
+        }
+    }
+
     fn random_id(&self) -> String {
         thread_rng().sample_iter(&Alphanumeric).take(16).collect()
     }
 
-    pub fn register_in_jar(&self, session: &str, cookies: Vec<(String, String)>) {
+    pub fn register_in_jar(&self, session: &str, url: &reqwest::Url, cookies: Vec<SetCookie>, now: i64) {
         let mut mtx = self.http_sessions.lock().unwrap();
         if let Some(session) = mtx.get_mut(session) {
-            session.cookies.register_in_jar(cookies);
+            session.cookies.register_in_jar(url, cookies, now);
+        }
+    }
+
+    /// The client of a session for the proxy of this attempt, connections
+    /// are reused by the copies of the session, see inherit
+    pub fn http_client<F: FnOnce() -> Result<reqwest::Client>>(&self, session: &str, verify_hostname: bool, build: F) -> Result<reqwest::Client> {
+        let mtx = self.http_sessions.lock().unwrap();
+        match mtx.get(session) {
+            Some(session) => session.client(self.http_proxy(), verify_hostname, build),
+            None => build(),
         }
     }
 
+    /// Forget the cookies of a session, eg. to start over within an attempt
+    pub fn http_clear_cookies(&self, session: &str) -> Result<()> {
+        let mut mtx = self.http_sessions.lock().unwrap();
+        let session = mtx.get_mut(session).chain_err(|| "invalid session reference")?;
+        session.cookies.clear();
+        Ok(())
+    }
+
     pub fn http_mksession(&self) -> String {
         let mut mtx = self.http_sessions.lock().unwrap();
         let (id, session) = HttpSession::new();
@@ -88,79 +394,378 @@ impl State {
         id
     }
 
-    pub fn mysql_session(&self, id: &str) -> Arc<Mutex<mysql::Conn>> {
+    pub fn mysql_session(&self, id: &str) -> Result<Arc<Mutex<mysql::Conn>>> {
         let mtx = self.mysql_sessions.lock().unwrap();
-        let sock = mtx.get(id).expect("invalid session reference"); // TODO
-        sock.clone()
+        let sock = mtx.get(id).chain_err(|| "invalid session reference")?;
+        Ok(sock.clone())
+    }
+
+    pub fn mysql_close(&self, id: &str) {
+        let mut mtx = self.mysql_sessions.lock().unwrap();
+        mtx.remove(id);
     }
 
     pub fn sock_connect(&self, host: &str, port: u16) -> Result<String> {
         let mut mtx = self.socket_sessions.lock().unwrap();
         let id = self.random_id();
 
-        let sock = Socket::connect(host, port)?;
+        let sock = Socket::connect(host, port, self.deadline, &self.route)?;
         mtx.insert(id.clone(), Arc::new(Mutex::new(sock)));
 
         Ok(id)
     }
 
-    pub fn get_sock(&self, id: &str)-> Arc<Mutex<Socket>> {
+    pub fn get_sock(&self, id: &str)-> Result<Arc<Mutex<Socket>>> {
         let mtx = self.socket_sessions.lock().unwrap();
-        let sock = mtx.get(id).expect("invalid session reference"); // TODO
-        sock.clone()
+        let sock = mtx.get(id).chain_err(|| "invalid session reference")?;
+        Ok(sock.clone())
+    }
+
+    pub fn sock_close(&self, id: &str) {
+        let mut mtx = self.socket_sessions.lock().unwrap();
+        mtx.remove(id);
+    }
+
+    pub fn proc_spawn(&self, prog: &str, args: &[String]) -> Result<String> {
+        let mut mtx = self.proc_sessions.lock().unwrap();
+        let id = self.random_id();
+
+        let process = Process::spawn(prog, args)?;
+        mtx.insert(id.clone(), Arc::new(Mutex::new(process)));
+
+        Ok(id)
+    }
+
+    pub fn get_proc(&self, id: &str) -> Result<Arc<Mutex<Process>>> {
+        let mtx = self.proc_sessions.lock().unwrap();
+        let process = mtx.get(id).chain_err(|| "invalid process reference")?;
+        Ok(process.clone())
+    }
+
+    pub fn proc_kill(&self, id: &str) -> Result<()> {
+        let process = {
+            let mut mtx = self.proc_sessions.lock().unwrap();
+            mtx.remove(id).chain_err(|| "invalid process reference")?
+        };
+        let mut process = process.lock().unwrap();
+        process.kill()
+    }
+
+    /// Make the connections of a worker available to this attempt, the
+    /// worker keeps them open when the attempt closes its sessions. Cookies
+    /// the attempt receives aren't added to the sessions of the worker
+    pub fn inherit(&self, worker: &State) {
+        fn copy<T: Clone>(from: &Mutex<HashMap<String, T>>, to: &Mutex<HashMap<String, T>>) {
+            let from = from.lock().unwrap();
+            to.lock().unwrap().extend(from.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        copy(&worker.http_sessions, &self.http_sessions);
+        copy(&worker.mysql_sessions, &self.mysql_sessions);
+        copy(&worker.socket_sessions, &self.socket_sessions);
+        copy(&worker.proc_sessions, &self.proc_sessions);
+
+        for sock in self.socket_sessions.lock().unwrap().values() {
+            sock.lock().unwrap().set_deadline(self.deadline);
+        }
+    }
+
+    /// Drop all connections, processes and http sessions that are still open
+    /// after an attempt. Nothing an attempt creates is kept for the next one,
+    /// only what worker_setup created is shared
+    pub fn close_sessions(&self) {
+        self.http_sessions.lock().unwrap().clear();
+        self.mysql_sessions.lock().unwrap().clear();
+        self.socket_sessions.lock().unwrap().clear();
+        self.proc_sessions.lock().unwrap().clear();
     }
 }
 
 
+/// What worker_setup() returned on a worker thread, the connections it opened
+/// are reused by every attempt of the script on that thread
+#[derive(Debug, Clone)]
+pub struct Worker {
+    state: State,
+    value: AnyLuaValue,
+}
+
 #[derive(Debug, Clone)]
 pub struct Script {
     descr: String,
+    // used for the location in error messages
+    name: String,
+    target: Option<String>,
+    // the line of --targets this copy of the script runs against
+    assigned: Option<String>,
+    // tells the copies of a script apart, see label()
+    label: String,
+    author: Option<String>,
+    retries: Option<u8>,
+    max_concurrency: Option<usize>,
+    // options that have to be set with -x
+    required_options: Vec<String>,
     code: String,
+    // returned by init(), passed to verify
+    setup: AnyLuaValue,
+    // worker_setup() or worker_teardown() is defined
+    worker_hooks: bool,
     config: Arc<Config>,
 }
 
 impl Script {
     pub fn load(path: &str, config: Arc<Config>) -> Result<Script> {
         let mut file = File::open(path)?;
-        Script::load_from(&mut file, config)
+        Script::load_named(&mut file, Some(path), None, config)
     }
 
-    pub fn load_from<R: Read>(mut src: R, config: Arc<Config>) -> Result<Script> {
+    /// Load a copy of the script that runs against `target`, it's set as the
+    /// `target` variable before the script is executed
+    pub fn load_target(path: &str, target: &str, config: Arc<Config>) -> Result<Script> {
+        let mut file = File::open(path)?;
+        Script::load_named(&mut file, Some(path), Some(target), config)
+    }
+
+    #[inline]
+    pub fn load_from<R: Read>(src: R, config: Arc<Config>) -> Result<Script> {
+        Script::load_named(src, None, None, config)
+    }
+
+    /// Load a script that was sent by the coordinator, the name and the
+    /// target are the same so the label matches, see badtouch worker
+    pub fn load_code(code: &str, name: &str, assigned: Option<&str>, config: Arc<Config>) -> Result<Script> {
+        Script::load_named(code.as_bytes(), Some(name), assigned, config)
+    }
+
+    fn load_named<R: Read>(mut src: R, path: Option<&str>, assigned: Option<&str>, config: Arc<Config>) -> Result<Script> {
         let mut code = String::new();
         src.read_to_string(&mut code)?;
 
-        let (mut lua, _) = Script::ctx(&config);
-        lua.execute::<()>(&code)?;
+        let (mut lua, _) = Script::ctx(&config, None);
+        if let Some(assigned) = assigned {
+            lua.set("target", assigned);
+        }
+        traceback::execute(&mut lua, &code, path.unwrap_or("script"))?;
+
+        let descr = match Script::read_descr(&mut lua)? {
+            Some(descr) => descr,
+            // fallback if the script doesn't set a descr
+            None => path.and_then(|path| Path::new(path).file_stem())
+                        .map(|x| x.to_string_lossy().into_owned())
+                        .ok_or("descr undefined")?,
+        };
+        let name = path.map(|x| x.to_owned())
+                    .unwrap_or_else(|| descr.clone());
 
-        let descr = {
-            let descr: Result<_> = lua.get("descr").ok_or_else(|| "descr undefined".into());
-            let descr: hlua::StringInLua<_> = descr?;
-            (*descr).to_owned()
+        let target = match lua.get::<AnyLuaValue, _>("target") {
+            Some(AnyLuaValue::LuaString(target)) => Some(target.to_lowercase()),
+            Some(AnyLuaValue::LuaNil) | None => None,
+            Some(x) => bail!("target must be a string, got {:?}", x),
+        };
+
+        let author = match lua.get::<AnyLuaValue, _>("author") {
+            Some(AnyLuaValue::LuaString(author)) => Some(author),
+            Some(AnyLuaValue::LuaNil) | None => None,
+            Some(x) => bail!("author must be a string, got {:?}", x),
+        };
+
+        let retries = match lua.get::<AnyLuaValue, _>("retries") {
+            Some(AnyLuaValue::LuaNumber(n)) if (0.0..=255.0).contains(&n) && n.fract() == 0.0 => Some(n as u8),
+            Some(AnyLuaValue::LuaNil) | None => None,
+            Some(x) => bail!("retries must be an integer between 0 and 255, got {:?}", x),
+        };
+
+        let max_concurrency = match lua.get::<AnyLuaValue, _>("max_concurrency") {
+            Some(AnyLuaValue::LuaNumber(n)) if n >= 1.0 && n.fract() == 0.0 => Some(n as usize),
+            Some(AnyLuaValue::LuaNil) | None => None,
+            Some(x) => bail!("max_concurrency must be a positive integer, got {:?}", x),
+        };
+
+        let required_options = match lua.get::<AnyLuaValue, _>("required_options") {
+            Some(AnyLuaValue::LuaArray(items)) => items.into_iter()
+                .map(|(_, x)| match x {
+                    AnyLuaValue::LuaString(x) => Ok(x),
+                    x => bail!("required_options must be a list of strings, got {:?}", x),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            Some(AnyLuaValue::LuaNil) | None => Vec::new(),
+            Some(x) => bail!("required_options must be a list of strings, got {:?}", x),
         };
 
         {
             let verify: Result<_> = lua.get("verify").ok_or_else(|| "verify undefined".into());
             let _: hlua::LuaFunction<_> = verify?;
         };
+        let worker_hooks = lua.get::<hlua::LuaFunction<_>, _>("worker_setup").is_some() ||
+            lua.get::<hlua::LuaFunction<_>, _>("worker_teardown").is_some();
+
+        let label = match assigned {
+            Some(assigned) => format!("{}@{}", descr, assigned),
+            None => descr.clone(),
+        };
 
         Ok(Script {
             descr,
+            name,
+            target,
+            assigned: assigned.map(|x| x.to_owned()),
+            label,
+            author,
+            retries,
+            max_concurrency,
+            required_options,
             code,
+            setup: AnyLuaValue::LuaNil,
+            worker_hooks,
             config,
         })
     }
 
-    fn ctx<'a>(config: &Arc<Config>) -> (hlua::Lua<'a>, State) {
+    /// Make sure the options in required_options were set with -x
+    pub fn check_options(&self) -> Result<()> {
+        let mut missing = self.required_options.iter()
+            .filter(|key| !self.config.options.contains_key(*key))
+            .map(|key| key.as_str())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        missing.sort();
+        bail!("{} requires the options {}, set them with -x {}=...",
+            self.descr, missing.join(", "), missing[0])
+    }
+
+    /// Run init() if the script has one, this is done once before the
+    /// attempts are started. The return value is passed to every verify
+    pub fn init(&mut self) -> Result<()> {
+        let (mut lua, state) = Script::ctx(&self.config, None);
+        traceback::set_deadline(&mut lua, state.deadline());
+        self.execute(&mut lua)?;
+
+        let result = Script::call_hook(&mut lua, &state, "init", Vec::new());
+        state.close_sessions();
+
+        self.setup = result?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn has_worker_hooks(&self) -> bool {
+        self.worker_hooks
+    }
+
+    /// Run worker_setup() if the script has one, this is done when a worker
+    /// thread runs its first attempt of the script
+    pub fn worker_setup(&self, tx: Option<mpsc::Sender<Msg>>) -> Result<Worker> {
+        let (mut lua, state) = Script::ctx(&self.config, tx);
+        traceback::set_deadline(&mut lua, state.deadline());
+        self.execute(&mut lua)?;
+
+        match Script::call_hook(&mut lua, &state, "worker_setup", vec![self.setup.clone()]) {
+            Ok(value) => Ok(Worker {
+                state,
+                value,
+            }),
+            Err(err) => {
+                state.close_sessions();
+                Err(err)
+            },
+        }
+    }
+
+    /// Run worker_teardown() if the script has one and close the connections
+    /// of the worker
+    pub fn worker_teardown(&self, worker: Worker) -> Result<()> {
+        let Worker { state: worker, value } = worker;
+        let state = State::new(self.config.clone(), None);
+        state.inherit(&worker);
+        let mut lua = Script::lua(state.clone());
+        traceback::set_deadline(&mut lua, state.deadline());
+
+        let result = self.execute(&mut lua)
+            .and_then(|_| Script::call_hook(&mut lua, &state, "worker_teardown", vec![value]));
+        state.close_sessions();
+        worker.close_sessions();
+
+        result.map(|_| ())
+    }
+
+    /// Run the code of the script so its functions are defined
+    fn execute(&self, lua: &mut hlua::Lua) -> Result<()> {
+        if let Some(ref assigned) = self.assigned {
+            lua.set("target", assigned.as_str());
+        }
+        traceback::execute(lua, &self.code, &self.name)?;
+        Ok(())
+    }
+
+    /// Call a function of the script that doesn't have to be defined, like
+    /// init(). Returns nil if it isn't
+    fn call_hook(lua: &mut hlua::Lua, state: &State, function: &str, args: Vec<AnyLuaValue>) -> Result<AnyLuaValue> {
+        if lua.get::<hlua::LuaFunction<_>, _>(function).is_none() {
+            return Ok(AnyLuaValue::LuaNil);
+        }
+        let result = traceback::call(lua, function, args);
+
+        let recorded = state.error.lock().unwrap().take();
+        let value = match (result, recorded) {
+            (_, Some(recorded)) => Err(recorded.into_error()),
+            (Err(err), None) => Err(Error::from(err)),
+            (Ok(value), None) => Ok(value),
+        };
+        let value = value.chain_err(|| format!("{}() failed", function))?;
+        if !is_data(&value) {
+            bail!("{}() failed: it can only return data, not functions", function);
+        }
+        Ok(value)
+    }
+
+    /// Read descr from the script, it can be either a string or a function
+    /// that returns a string
+    fn read_descr(lua: &mut hlua::Lua) -> Result<Option<String>> {
+        let descr = {
+            let descr: Option<hlua::LuaFunction<_>> = lua.get("descr");
+            match descr {
+                Some(mut descr) => Some(descr.call::<AnyLuaValue>()
+                                        .map_err(|err| format!("descr() failed: {:?}", err))?),
+                None => None,
+            }
+        };
+        let descr = match descr {
+            Some(descr) => Some(descr),
+            None => lua.get::<AnyLuaValue, _>("descr"),
+        };
+
+        match descr {
+            Some(AnyLuaValue::LuaString(descr)) => Ok(Some(descr)),
+            Some(AnyLuaValue::LuaNil) | None => Ok(None),
+            Some(x) => bail!("descr must be a string, got {:?}", x),
+        }
+    }
+
+    #[inline]
+    fn ctx<'a>(config: &Arc<Config>, tx: Option<mpsc::Sender<Msg>>) -> (hlua::Lua<'a>, State) {
+        Script::ctx_for(config, tx, None)
+    }
+
+    fn ctx_for<'a>(config: &Arc<Config>, tx: Option<mpsc::Sender<Msg>>, user: Option<&str>) -> (hlua::Lua<'a>, State) {
+        let state = State::for_user(config.clone(), tx, user);
+        (Script::lua(state.clone()), state)
+    }
+
+    /// A lua state with the runtime bound to `state`
+    fn lua<'a>(state: State) -> hlua::Lua<'a> {
         let mut lua = hlua::Lua::new();
         lua.open_string();
-        let state = State::new(config.clone());
 
         runtime::base64_decode(&mut lua, state.clone());
         runtime::base64_encode(&mut lua, state.clone());
         runtime::bcrypt(&mut lua, state.clone());
         runtime::bcrypt_verify(&mut lua, state.clone());
         runtime::clear_err(&mut lua, state.clone());
+        runtime::cred_info(&mut lua, state.clone());
         runtime::execve(&mut lua, state.clone());
+        runtime::execve_output(&mut lua, state.clone());
+        runtime::getopt(&mut lua, state.clone());
         runtime::hex(&mut lua, state.clone());
         runtime::hmac_md5(&mut lua, state.clone());
         runtime::hmac_sha1(&mut lua, state.clone());
@@ -171,27 +776,42 @@ impl Script {
         runtime::html_select(&mut lua, state.clone());
         runtime::html_select_list(&mut lua, state.clone());
         runtime::http_basic_auth(&mut lua, state.clone()); // TODO: deprecate?
+        runtime::http_clear_cookies(&mut lua, state.clone());
         runtime::http_mksession(&mut lua, state.clone());
         runtime::http_request(&mut lua, state.clone());
         runtime::http_send(&mut lua, state.clone());
         runtime::json_decode(&mut lua, state.clone());
         runtime::json_encode(&mut lua, state.clone());
         runtime::last_err(&mut lua, state.clone());
+        runtime::last_err_str(&mut lua, state.clone());
         runtime::ldap_bind(&mut lua, state.clone());
         runtime::ldap_escape(&mut lua, state.clone());
+        runtime::ldap_sasl_bind(&mut lua, state.clone());
+        runtime::ldap_search(&mut lua, state.clone());
         runtime::ldap_search_bind(&mut lua, state.clone());
+        runtime::mark_locked(&mut lua, state.clone());
         runtime::md5(&mut lua, state.clone());
+        runtime::mysql_close(&mut lua, state.clone());
         runtime::mysql_connect(&mut lua, state.clone());
         runtime::mysql_query(&mut lua, state.clone());
         runtime::print(&mut lua, state.clone());
+        runtime::print_info(&mut lua, state.clone());
+        runtime::print_warn(&mut lua, state.clone());
+        runtime::proc_kill(&mut lua, state.clone());
+        runtime::proc_recv_until(&mut lua, state.clone());
+        runtime::proc_send(&mut lua, state.clone());
+        runtime::proc_spawn(&mut lua, state.clone());
         runtime::rand(&mut lua, state.clone());
         runtime::randombytes(&mut lua, state.clone());
+        runtime::set_retry(&mut lua, state.clone());
+        runtime::set_target(&mut lua, state.clone());
         runtime::sha1(&mut lua, state.clone());
         runtime::sha2_256(&mut lua, state.clone());
         runtime::sha2_512(&mut lua, state.clone());
         runtime::sha3_256(&mut lua, state.clone());
         runtime::sha3_512(&mut lua, state.clone());
         runtime::sleep(&mut lua, state.clone());
+        runtime::sock_close(&mut lua, state.clone());
         runtime::sock_connect(&mut lua, state.clone());
         runtime::sock_send(&mut lua, state.clone());
         runtime::sock_recv(&mut lua, state.clone());
@@ -204,8 +824,25 @@ impl Script {
         runtime::sock_recvuntil(&mut lua, state.clone());
         runtime::sock_sendafter(&mut lua, state.clone());
         runtime::sock_newline(&mut lua, state.clone());
+        runtime::throttle(&mut lua, state.clone());
+
+        if state.config().runtime.sandbox {
+            for name in runtime::UNSAFE_FUNCTIONS {
+                runtime::sandboxed(&mut lua, state.clone(), name, "sandbox");
+            }
+        } else if state.config().runtime.proxy_strict {
+            // programs open their own connections, they can't be routed through the proxy
+            for name in runtime::UNSAFE_FUNCTIONS {
+                runtime::sandboxed(&mut lua, state.clone(), name, "proxy-strict");
+            }
+        }
+        if state.config().runtime.offline {
+            for name in runtime::NETWORK_FUNCTIONS.iter().chain(runtime::UNSAFE_FUNCTIONS) {
+                runtime::sandboxed(&mut lua, state.clone(), name, "offline");
+            }
+        }
 
-        (lua, state)
+        lua
     }
 
     #[inline]
@@ -213,60 +850,205 @@ impl Script {
         self.descr.as_str()
     }
 
-    /*
+    /// The descr and the target from --targets, if any. Stats, lockouts and
+    /// findings are tracked by this
+    #[inline]
+    pub fn label(&self) -> &str {
+        self.label.as_str()
+    }
+
+    /// The host this script connects to, used for --per-host
+    #[inline]
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    #[inline]
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    /// How often an attempt is retried after an error, the script can
+    /// override --retries
+    #[inline]
+    pub fn retries(&self) -> u8 {
+        self.retries.unwrap_or_else(|| self.config.runtime.retries())
+    }
+
+    /// How many attempts of this script may run at the same time
+    #[inline]
+    pub fn max_concurrency(&self) -> Option<usize> {
+        self.max_concurrency
+    }
+
+    /// How long an attempt may run, see --timeout
+    #[inline]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.config.runtime.timeout
+    }
+
+    /// The functions of this script that would be disabled by --sandbox
+    pub fn unsafe_functions(&self) -> Vec<&'static str> {
+        runtime::UNSAFE_FUNCTIONS.iter()
+            .filter(|name| {
+                let re = Regex::new(&format!(r"\b{}\b", name)).unwrap();
+                re.is_match(&self.code)
+            })
+            .cloned()
+            .collect()
+    }
+
     #[inline]
     pub fn code(&self) -> &str {
         self.code.as_str()
     }
-    */
 
-    pub fn run_once(&self, user: AnyLuaValue, password: AnyLuaValue) -> Result<bool> {
-        debug!("executing {:?} with {:?}:{:?}", self.descr(), user, password);
+    /// The path the script was loaded from, or its descr
+    #[inline]
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
 
-        let (mut lua, state) = Script::ctx(&self.config);
-        lua.execute::<()>(&self.code)?;
+    /// The line of --targets this copy of the script runs against
+    #[inline]
+    pub fn assigned(&self) -> Option<&str> {
+        self.assigned.as_deref()
+    }
 
-        let verify: Result<_> = lua.get("verify").ok_or_else(|| "verify undefined".into());
-        let mut verify: hlua::LuaFunction<_> = verify?;
+    #[inline]
+    pub fn run(&self, user: AnyLuaValue, password: AnyLuaValue, tx: Option<mpsc::Sender<Msg>>) -> Result<Outcome> {
+        self.run_on(user, password, tx, None)
+    }
 
-        let result: hlua::AnyLuaValue = match verify.call_with_args((user, password)) {
-            Ok(res) => res,
-            Err(err) => {
-                let err = format!("execution failed: {:?}", err);
-                return Err(err.into())
-            },
+    /// Run an attempt with the connections of a worker, see worker_setup
+    #[inline]
+    pub fn run_on(&self, user: AnyLuaValue, password: AnyLuaValue, tx: Option<mpsc::Sender<Msg>>, worker: Option<&Worker>) -> Result<Outcome> {
+        self.run_with(user, password, None, tx, worker)
+    }
+
+    /// Run an attempt with the account of a dump, the password is the NT hash
+    pub fn run_with(&self, user: AnyLuaValue, password: AnyLuaValue, hash: Option<Arc<NtHash>>, tx: Option<mpsc::Sender<Msg>>, worker: Option<&Worker>) -> Result<Outcome> {
+        // tag log records with the attempt, see -v
+        let _scope = if log_enabled!(log::Level::Info) {
+            let label = format!("{}, {:?}:{:?}", self.descr(), lua_str(&user), lua_str(&password));
+            Some(logger::Scope::enter(label))
+        } else {
+            None
         };
+        debug!("executing {:?} with {:?}:{:?}", self.descr(), user, password);
 
-        if let Some(err) = state.error.lock().unwrap().take() {
-            return Err(err);
+        let (mut lua, state) = Script::ctx_for(&self.config, tx, Some(lua_str(&user)).filter(|x| !x.is_empty()));
+        traceback::set_deadline(&mut lua, state.deadline());
+        // failures of the proxy while the attempt runs, see [proxies]
+        let health = state.route().health.clone();
+        let failures = health.as_ref().map(|health| {
+            health.attempted();
+            health.failures()
+        });
+        if let Some(hash) = hash {
+            state.set_cred_info(hash);
         }
+        let value = match worker {
+            Some(worker) => {
+                state.inherit(&worker.state);
+                worker.value.clone()
+            },
+            None => AnyLuaValue::LuaNil,
+        };
+        let result = self.verify(&mut lua, &state, user, password, value);
+
+        // connections are bound to the attempt, close everything that is left
+        state.close_sessions();
+
+        let (valid, status, note) = result.map_err(|err| {
+            // most likely the attempt failed because of the proxy and not
+            // the target, it's moved to another proxy instead of retried
+            if let (Some(health), Some(failures)) = (health, failures) {
+                if health.failures() > failures {
+                    let proxy = state.route().proxy.as_ref().map(|x| x.to_string()).unwrap_or_default();
+                    return Error::with_chain(err, ErrorKind::ProxyDown(proxy));
+                }
+            }
+            // errors of throttled attempts are most likely caused by the throttle
+            if let Some(delay) = state.throttled() {
+                return Error::with_chain(err, ErrorKind::Throttled(delay));
+            }
+            let msg = err.to_string();
+            match state.retry() {
+                Some(true) => Error::with_chain(err, ErrorKind::Transient(msg)),
+                Some(false) => Error::with_chain(err, ErrorKind::Fatal(msg)),
+                None => err,
+            }
+        })?;
+        Ok(Outcome {
+            valid,
+            status,
+            note,
+            target: state.target().or_else(|| self.target.clone()),
+            locked: state.locked(),
+            throttle: state.throttled(),
+        })
+    }
 
-        use hlua::AnyLuaValue::*;
-        match result {
-            LuaBoolean(x) => Ok(x),
-            LuaString(x) => Err(format!("error: {:?}", x).into()),
-            x => Err(format!("lua returned wrong type: {:?}", x).into()),
-        }
+    #[inline]
+    pub fn run_once(&self, user: AnyLuaValue, password: AnyLuaValue, tx: Option<mpsc::Sender<Msg>>) -> Result<bool> {
+        self.run(user, password, tx)?
+            .check()
+    }
+
+    fn verify(&self, lua: &mut hlua::Lua, state: &State, user: AnyLuaValue, password: AnyLuaValue, worker: AnyLuaValue) -> Result<(bool, Option<Status>, Option<String>)> {
+        self.execute(lua)?;
+
+        let result = traceback::call(lua, "verify", vec![user, password, self.setup.clone(), worker]);
+
+        let recorded = state.error.lock().unwrap().take();
+        let result = match (result, recorded) {
+            // the script most likely failed because it didn't check for the
+            // recorded error, keep it as the cause so it's classified correctly
+            (Err(err), Some(recorded)) => return Err(Error::with_chain(recorded.into_error(), ErrorKind::Lua(err))),
+            (Err(err), None) => return Err(err.into()),
+            // rejected credentials are recorded for scripts that check
+            // last_err, the verdict is up to the script
+            (Ok(result), Some(ref recorded)) if recorded.kind == Kind::Denied => result,
+            (Ok(_), Some(recorded)) => return Err(recorded.into_error()),
+            (Ok(result), None) => result,
+        };
+
+        verdict(result)
     }
 
     #[inline]
-    pub fn run_creds(&self, user: &str, password: &str) -> Result<bool> {
+    pub fn run_creds(&self, user: &str, password: &str, tx: Option<mpsc::Sender<Msg>>) -> Result<bool> {
         let user = AnyLuaValue::LuaString(user.to_string());
         let password = AnyLuaValue::LuaString(password.to_string());
-        self.run_once(user, password)
+        self.run_once(user, password, tx)
+    }
+
+    /// Run a single attempt with its own worker, see badtouch oneshot
+    pub fn run_single(&self, user: &str, password: Option<&str>) -> Result<bool> {
+        let user = AnyLuaValue::LuaString(user.to_string());
+        let password = match password {
+            Some(password) => AnyLuaValue::LuaString(password.to_string()),
+            None => AnyLuaValue::LuaNil,
+        };
+        let worker = self.worker_setup(None)?;
+        let result = self.run_on(user, password, None, Some(&worker));
+        self.worker_teardown(worker)?;
+        result?.check()
     }
 
     #[inline]
-    pub fn run_enum(&self, user: &str) -> Result<bool> {
+    pub fn run_enum(&self, user: &str, tx: Option<mpsc::Sender<Msg>>) -> Result<bool> {
         let user = AnyLuaValue::LuaString(user.to_string());
         let password = AnyLuaValue::LuaNil;
-        self.run_once(user, password)
+        self.run_once(user, password, tx)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use errkind;
 
     fn empty_config() -> Arc<Config> {
         Arc::new(Config::default())
@@ -282,7 +1064,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("foo", "bar").expect("test script failed");
+        let result = script.run_creds("foo", "bar", None).expect("test script failed");
         assert!(!result);
     }
 
@@ -296,10 +1078,37 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("foo", "bar").expect("test script failed");
+        let result = script.run_creds("foo", "bar", None).expect("test script failed");
         assert!(result);
     }
 
+    #[test]
+    fn verify_cred_info() {
+        let script = Script::load_from(r#"
+        descr = "cred_info"
+
+        function verify(user, password)
+            info = cred_info()
+            if user == "plain" then
+                return info == nil
+            end
+            return info['domain'] == 'CORP' and info['rid'] == 1104 and info['lm'] == nil and info['nt'] == password
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+
+        assert!(script.run_creds("plain", "bar", None).unwrap());
+        let hash = Arc::new(NtHash {
+            user: "alice".to_string(),
+            domain: Some("CORP".to_string()),
+            rid: 1104,
+            lm: None,
+            nt: "32ed87bdb5fdc5e9cba88547376818d4".to_string(),
+        });
+        let user = AnyLuaValue::LuaString(hash.user.clone());
+        let password = AnyLuaValue::LuaString(hash.nt.clone());
+        assert!(script.run_with(user, password, Some(hash), None, None).unwrap().valid);
+    }
+
     #[test]
     fn verify_record_error() {
         let script = Script::load_from(r#"
@@ -311,7 +1120,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x");
+        let result = script.run_creds("x", "x", None);
         assert!(result.is_err());
     }
 
@@ -327,7 +1136,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -342,7 +1151,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("foo", "bar").expect("test script failed");
+        let result = script.run_creds("foo", "bar", None).expect("test script failed");
         assert!(result);
     }
 
@@ -356,7 +1165,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("foo", "buzz").expect("test script failed");
+        let result = script.run_creds("foo", "buzz", None).expect("test script failed");
         assert!(result);
     }
 
@@ -370,7 +1179,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("invalid", "wrong").expect("test script failed");
+        let result = script.run_creds("invalid", "wrong", None).expect("test script failed");
         assert!(!result);
     }
 
@@ -385,7 +1194,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -400,7 +1209,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -424,7 +1233,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -450,7 +1259,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -465,7 +1274,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -480,7 +1289,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x");
+        let result = script.run_creds("x", "x", None);
         assert!(result.is_err());
     }
 
@@ -496,7 +1305,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -512,7 +1321,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -528,7 +1337,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -544,7 +1353,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -560,7 +1369,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -576,7 +1385,7 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "x").expect("test script failed");
+        let result = script.run_creds("x", "x", None).expect("test script failed");
         assert!(result);
     }
 
@@ -590,7 +1399,565 @@ mod tests {
         end
         "#.as_bytes(), empty_config()).unwrap();
 
-        let result = script.run_creds("x", "hunter2").expect("test script failed");
+        let result = script.run_creds("x", "hunter2", None).expect("test script failed");
+        assert!(result);
+    }
+
+    #[test]
+    fn verify_sock_close() {
+        use std::net::TcpListener;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let state = State::new(empty_config(), None);
+        let sock = state.sock_connect("127.0.0.1", port).unwrap();
+        assert!(state.get_sock(&sock).is_ok());
+        state.sock_close(&sock);
+        assert!(state.get_sock(&sock).is_err());
+
+        let sock = state.sock_connect("127.0.0.1", port).unwrap();
+        state.close_sessions();
+        assert!(state.get_sock(&sock).is_err());
+    }
+
+    #[test]
+    fn verify_proc() {
+        let script = Script::load_from(r#"
+        descr = "proc"
+
+        function verify(user, password)
+            p = proc_spawn("sh", {"-c", "printf 'password: '; read x; echo \"got $x\""})
+            proc_recv_until(p, "password: ", 5000)
+            proc_send(p, password .. "\n")
+            x = proc_recv_until(p, "got \\w+", 5000)
+            proc_kill(p)
+            return x == "got hunter2"
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+
+        let result = script.run_creds("x", "hunter2", None).expect("test script failed");
+        assert!(result);
+    }
+
+    #[test]
+    fn verify_print_info() {
+        let script = Script::load_from(r#"
+        descr = "print_info"
+
+        function verify(user, password)
+            print_info("trying " .. user)
+            print_warn("password is " .. password)
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let result = script.run_creds("foo", "bar", Some(tx)).expect("test script failed");
         assert!(result);
+
+        let msgs = rx.iter()
+            .map(|msg| match msg {
+                Msg::Print(level, msg) => (level, msg),
+                msg => panic!("unexpected msg: {:?}", msg),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(msgs, vec![
+            (Level::Info, "trying foo".to_string()),
+            (Level::Warn, "password is bar".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn verify_sandbox() {
+        let config = Arc::new(Config::try_from_str("[runtime]\nsandbox = true\n").unwrap());
+        let script = Script::load_from(r#"
+        descr = "sandbox"
+
+        function verify(user, password)
+            execve("id", {})
+            return true
+        end
+        "#.as_bytes(), config).unwrap();
+
+        assert_eq!(script.unsafe_functions(), vec!["execve"]);
+        let err = script.run_creds("foo", "bar", None).unwrap_err();
+        assert!(err.to_string().contains("execve is disabled by sandbox"));
+    }
+
+    #[test]
+    fn verify_descr_function() {
+        let script = Script::load_from(r#"
+        function descr()
+            return "descr_function"
+        end
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        assert_eq!(script.descr(), "descr_function");
+    }
+
+    #[test]
+    fn verify_descr_fallback() {
+        let code = r#"
+        function verify(user, password)
+            return true
+        end
+        "#;
+        let script = Script::load_named(code.as_bytes(), Some("scripts/c-01-final2.lua"), None, empty_config()).unwrap();
+        assert_eq!(script.descr(), "c-01-final2");
+        assert!(Script::load_from(code.as_bytes(), empty_config()).is_err());
+    }
+
+    #[test]
+    fn verify_target() {
+        let script = Script::load_from(r#"
+        descr = "target"
+        target = "VPN.acme.corp"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        assert_eq!(script.target(), Some("vpn.acme.corp"));
+
+        let script = Script::load_from(r#"
+        descr = "target"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        assert_eq!(script.target(), None);
+    }
+
+    #[test]
+    fn verify_assigned_target() {
+        let code = r#"
+        descr = "tomcat"
+        url = "http://" .. target .. "/manager/html"
+
+        function init()
+            return target
+        end
+
+        function verify(user, password, setup)
+            return url == "http://" .. setup .. "/manager/html" and user == setup
+        end
+        "#;
+        let mut script = Script::load_named(code.as_bytes(), None, Some("10.0.0.5:8080"), empty_config()).unwrap();
+        assert_eq!(script.descr(), "tomcat");
+        assert_eq!(script.label(), "tomcat@10.0.0.5:8080");
+        assert_eq!(script.target(), Some("10.0.0.5:8080"));
+
+        script.init().unwrap();
+        assert!(script.run_creds("10.0.0.5:8080", "x", None).unwrap());
+        assert!(!script.run_creds("10.0.0.6:8080", "x", None).unwrap());
+    }
+
+    #[test]
+    fn verify_retries() {
+        let mut config = Config::default();
+        config.runtime.retries = Some(2);
+        let config = Arc::new(config);
+
+        let script = Script::load_from(r#"
+        descr = "retries"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), config.clone()).unwrap();
+        assert_eq!(script.retries(), 2);
+
+        let script = Script::load_from(r#"
+        descr = "retries"
+        retries = 0
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), config.clone()).unwrap();
+        assert_eq!(script.retries(), 0);
+
+        let result = Script::load_from(r#"
+        descr = "retries"
+        retries = 1.5
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), config);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn verify_max_concurrency() {
+        let script = Script::load_from(r#"
+        descr = "fragile"
+        max_concurrency = 2
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        assert_eq!(script.max_concurrency(), Some(2));
+
+        let result = Script::load_from(r#"
+        descr = "fragile"
+        max_concurrency = 0
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), empty_config());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn verify_mark_locked() {
+        let script = Script::load_from(r#"
+        descr = "mark_locked"
+
+        function verify(user, password)
+            mark_locked(user)
+            return false
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+
+        let user = AnyLuaValue::LuaString("foo".into());
+        let outcome = script.run(user, AnyLuaValue::LuaNil, None).unwrap();
+        assert!(!outcome.valid);
+        assert_eq!(outcome.locked, vec!["foo".to_string()]);
+    }
+
+    #[test]
+    fn verify_status() {
+        let script = Script::load_from(r#"
+        descr = "status"
+
+        function verify(user, password)
+            if user == "mfa" then
+                return {valid=true, status="mfa", note="push notification"}
+            elseif user == "limited" then
+                return {status="ratelimited"}
+            elseif user == "typo" then
+                return {status="locked"}
+            end
+            return false
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+
+        let run = |user: &str| script.run(AnyLuaValue::LuaString(user.into()), AnyLuaValue::LuaNil, None);
+
+        let outcome = run("mfa").unwrap();
+        assert!(outcome.valid);
+        assert_eq!(outcome.status, Some(Status::Mfa));
+        assert_eq!(outcome.note.as_deref(), Some("push notification"));
+        assert_eq!(outcome.check().unwrap(), true);
+
+        let outcome = run("limited").unwrap();
+        assert!(!outcome.valid);
+        let err = outcome.check().unwrap_err();
+        assert!(errkind::is_retryable(&err));
+
+        assert!(run("typo").is_err());
+        assert_eq!(run("foo").unwrap().status, None);
+    }
+
+    #[test]
+    fn verify_init() {
+        let mut script = Script::load_from(r#"
+        descr = "init"
+        calls = 0
+
+        function init()
+            calls = calls + 1
+            return {token="secret", calls=calls}
+        end
+
+        function verify(user, password, setup)
+            return setup["token"] == password and setup["calls"] == 1
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        script.init().unwrap();
+        assert!(script.run_creds("foo", "secret", None).unwrap());
+        assert!(!script.run_creds("foo", "bar", None).unwrap());
+
+        let mut script = Script::load_from(r#"
+        descr = "init"
+
+        function init()
+            local endpoint = nil
+            return endpoint["path"]
+        end
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        let err = script.init().unwrap_err();
+        assert_eq!(err.to_string(), "init() failed");
+        assert!(err.iter().any(|x| x.to_string().contains("attempt to index local 'endpoint'")));
+    }
+
+    #[test]
+    fn verify_worker_hooks() {
+        let script = Script::load_from(r#"
+        descr = "worker"
+
+        function init()
+            return {realm="corp"}
+        end
+
+        function worker_setup(setup)
+            return {probe=setup["realm"] .. "-probe"}
+        end
+
+        function verify(user, password, setup, worker)
+            return worker["probe"] == "corp-probe"
+        end
+
+        function worker_teardown(worker)
+            if worker["probe"] ~= "corp-probe" then
+                return nil + 1
+            end
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        let mut script = script;
+        script.init().unwrap();
+        assert!(script.has_worker_hooks());
+
+        let worker = script.worker_setup(None).unwrap();
+        let user = AnyLuaValue::LuaString("foo".into());
+        let outcome = script.run_on(user.clone(), AnyLuaValue::LuaNil, None, Some(&worker)).unwrap();
+        assert!(outcome.valid);
+        script.worker_teardown(worker).unwrap();
+
+        // without a worker the fourth argument is nil
+        assert!(script.run(user, AnyLuaValue::LuaNil, None).is_err());
+    }
+
+    #[test]
+    fn verify_cookie_isolation() {
+        let script = Script::load_from(r#"
+        descr = "cookies"
+
+        function worker_setup(setup)
+            return http_mksession()
+        end
+
+        function verify(user, password, setup, session)
+            cookies = http_request(session, 'GET', 'https://example.com/', {})['cookies']
+            -- only what worker_setup received, nothing from earlier attempts
+            if cookies[0]['name'] ~= 'lb' or cookies[0]['value'] ~= 'node1' or cookies[1] ~= nil then
+                return false
+            end
+            http_clear_cookies(session)
+            cookies = http_request(session, 'GET', 'https://example.com/', {})['cookies']
+            return cookies[0] == nil
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+
+        let worker = script.worker_setup(None).unwrap();
+        let session = match worker.value {
+            AnyLuaValue::LuaString(ref session) => session.clone(),
+            _ => panic!("expected a session"),
+        };
+        // a cookie worker_setup received from the target
+        let url = "https://example.com/".parse().unwrap();
+        worker.state.register_in_jar(&session, &url, vec![SetCookie {
+            name: "lb".to_string(),
+            value: "node1".to_string(),
+            ..SetCookie::default()
+        }], 0);
+
+        let user = AnyLuaValue::LuaString("alice".into());
+        assert!(script.run_on(user, AnyLuaValue::LuaNil, None, Some(&worker)).unwrap().valid);
+
+        // an attempt that logged in, the same way run_on sets it up
+        let state = State::new(empty_config(), None);
+        state.inherit(&worker.state);
+        state.register_in_jar(&session, &url, vec![SetCookie {
+            name: "sid".to_string(),
+            value: "alice".to_string(),
+            ..SetCookie::default()
+        }], 0);
+        let own = state.http_mksession();
+        state.close_sessions();
+        assert!(state.http_clear_cookies(&own).is_err());
+
+        let user = AnyLuaValue::LuaString("bob".into());
+        assert!(script.run_on(user, AnyLuaValue::LuaNil, None, Some(&worker)).unwrap().valid);
+        script.worker_teardown(worker).unwrap();
+    }
+
+    #[test]
+    fn verify_options() {
+        let code = r#"
+        descr = "options"
+        required_options = {"realm"}
+        target = getopt("url")
+
+        function verify(user, password)
+            return getopt("realm") == "corp" and (getopt("port") or "443") == "443" and getopt("missing") == nil
+        end
+        "#;
+
+        let script = Script::load_from(code.as_bytes(), empty_config()).unwrap();
+        let err = script.check_options().unwrap_err();
+        assert_eq!(err.to_string(), "options requires the options realm, set them with -x realm=...");
+
+        let mut config = Config::default();
+        config.options.insert("realm".into(), "corp".into());
+        config.options.insert("url".into(), "vpn.acme.corp".into());
+        let script = Script::load_from(code.as_bytes(), Arc::new(config)).unwrap();
+        script.check_options().unwrap();
+        assert_eq!(script.target(), Some("vpn.acme.corp"));
+        assert!(script.run_creds("foo", "bar", None).unwrap());
+    }
+
+    #[test]
+    fn verify_set_target() {
+        let script = Script::load_from(r#"
+        descr = "set_target"
+        target = "vpn.acme.corp"
+
+        function verify(user, password)
+            if user == "foo" then
+                set_target("https://vpn.acme.corp/login")
+            end
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+
+        let user = AnyLuaValue::LuaString("foo".into());
+        let outcome = script.run(user, AnyLuaValue::LuaNil, None).unwrap();
+        assert_eq!(outcome.target, Some("https://vpn.acme.corp/login".into()));
+
+        // the target isn't shared between attempts
+        let user = AnyLuaValue::LuaString("bar".into());
+        let outcome = script.run(user, AnyLuaValue::LuaNil, None).unwrap();
+        assert_eq!(outcome.target, Some("vpn.acme.corp".into()));
+    }
+
+    #[test]
+    fn verify_descr_invalid() {
+        let result = Script::load_from(r#"
+        descr = {}
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), empty_config());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn verify_error_location() {
+        let script = Script::load_from(r#"
+        descr = "location"
+
+        function verify(user, password)
+            json_decode("{")
+            return true
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        let err = script.run_creds("foo", "bar", None).unwrap_err();
+        assert!(err.to_string().starts_with("location:5: "));
+    }
+
+    #[test]
+    fn verify_retryable() {
+        let script = Script::load_from(r#"
+        descr = "retryable"
+
+        function verify(user, password)
+            sock = sock_connect("127.0.0.1", 1)
+            if user == "fatal" then
+                set_retry(false)
+            end
+            -- the recorded error is kept as the cause
+            return sock_send(sock, "x")
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        let err = script.run_creds("foo", "bar", None).unwrap_err();
+        assert!(errkind::is_retryable(&err));
+        let err = script.run_creds("fatal", "bar", None).unwrap_err();
+        assert!(!errkind::is_retryable(&err));
+
+        let script = Script::load_from(r#"
+        descr = "retryable"
+
+        function verify(user, password)
+            if user == "transient" then
+                set_retry(true)
+            end
+            return "service unavailable"
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        let err = script.run_creds("foo", "bar", None).unwrap_err();
+        assert!(!errkind::is_retryable(&err));
+        let err = script.run_creds("transient", "bar", None).unwrap_err();
+        assert!(errkind::is_retryable(&err));
+        assert_eq!(err.to_string(), "error: \"service unavailable\"");
+    }
+
+    #[test]
+    fn verify_throttle() {
+        let script = Script::load_from(r#"
+        descr = "throttle"
+
+        function verify(user, password)
+            if user == "broken" then
+                throttle(30)
+                json_decode("{")
+            end
+            throttle(10)
+            throttle(5)
+            return user == "root"
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        let throttled = |user: &str| match *script.run_creds(user, "bar", None).unwrap_err().kind() {
+            ErrorKind::Throttled(delay) => delay,
+            ref kind => panic!("expected a throttle, got {:?}", kind),
+        };
+        // a valid result is kept
+        assert!(script.run_creds("root", "bar", None).unwrap());
+        assert_eq!(throttled("foo"), Duration::from_secs(10));
+        assert_eq!(throttled("broken"), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn verify_traceback() {
+        let script = Script::load_from(r#"
+        descr = "traceback"
+
+        function verify(user, password)
+            return user.foo.bar
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        let err = script.run_creds("foo", "bar", None).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("traceback:5: attempt to index field 'foo'"));
+        assert!(err.contains("stack traceback:\n\ttraceback:5: in function"));
+    }
+
+    #[test]
+    fn verify_last_err() {
+        let script = Script::load_from(r#"
+        descr = "last_err"
+
+        function verify(user, password)
+            json_decode("{")
+            err = last_err()
+            if err['kind'] ~= 'script' then return false end
+            if err['message'] ~= last_err_str() then return false end
+            clear_err()
+            return last_err() == nil
+        end
+        "#.as_bytes(), empty_config()).unwrap();
+        assert!(script.run_creds("foo", "bar", None).unwrap());
     }
 }
@@ -1,11 +1,153 @@
-use hlua::{AnyHashableLuaValue, AnyLuaValue};
+use errors::{Error, Result, ResultExt};
+use hlua::{AnyHashableLuaValue, AnyLuaValue, AnyLuaString};
 use mysql;
+use json;
+#[cfg(all(unix, not(target_os = "macos")))]
+use openssl_probe;
 
+use config::Config;
+use proxy::Route;
 use std::collections::HashMap;
 use std::hash::BuildHasherDefault;
+use std::io;
+#[cfg(all(unix, not(target_os = "macos")))]
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use twox_hash::XxHash;
 use structs::LuaMap;
 
+// ER_DBACCESS_DENIED_ERROR and ER_ACCESS_DENIED_ERROR
+const MYSQL_DBACCESS_DENIED: u16 = 1044;
+const MYSQL_ACCESS_DENIED: u16 = 1045;
+// ER_SECURE_TRANSPORT_REQUIRED, sent if require_secure_transport is enabled
+const MYSQL_SECURE_TRANSPORT_REQUIRED: u16 = 3159;
+
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MysqlOptions {
+    timeout: Option<u64>,
+    tls: Option<bool>,
+    tls_verify: Option<bool>,
+    ca: Option<String>,
+}
+
+impl MysqlOptions {
+    #[inline]
+    pub fn try_from(x: AnyLuaValue) -> Result<MysqlOptions> {
+        json::options(x)
+    }
+
+    /// The connect, read and write timeout, defaults to config.runtime
+    #[inline]
+    pub fn timeout(&self, config: &Config) -> Duration {
+        self.timeout.map(Duration::from_secs)
+            .unwrap_or_else(|| config.runtime.mysql_timeout())
+    }
+
+    pub fn apply(&self, config: &Config, builder: &mut mysql::OptsBuilder) -> Result<()> {
+        let timeout = self.timeout(config);
+        builder.tcp_connect_timeout(Some(timeout))
+               .read_timeout(Some(timeout))
+               .write_timeout(Some(timeout));
+
+        if self.tls.unwrap_or(false) {
+            self.apply_tls(builder)?;
+            builder.verify_peer(self.tls_verify.unwrap_or(true));
+        }
+        Ok(())
+    }
+
+    /// openssl needs a file with the ca certificates, its location depends
+    /// on the distribution
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn apply_tls(&self, builder: &mut mysql::OptsBuilder) -> Result<()> {
+        let ca = match self.ca {
+            Some(ref ca) => PathBuf::from(ca),
+            None => match openssl_probe::probe().cert_file {
+                Some(ca) => ca,
+                None => bail!("no ca certificates found, set ca to a pem file"),
+            },
+        };
+        builder.ssl_opts(Some((ca, None::<(String, String)>)));
+        Ok(())
+    }
+
+    /// Security.framework always uses the certificates of the system
+    #[cfg(target_os = "macos")]
+    fn apply_tls(&self, builder: &mut mysql::OptsBuilder) -> Result<()> {
+        if self.ca.is_some() {
+            bail!("ca isn't supported on macos, the system certificates are used");
+        }
+        builder.ssl_opts(Some(None::<(String, String, Vec<String>)>));
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn apply_tls(&self, _builder: &mut mysql::OptsBuilder) -> Result<()> {
+        bail!("mysql doesn't support tls on windows")
+    }
+
+    /// Apply --resolve and connect through a relay to --proxy. mysql verifies
+    /// the certificate against the address it connects to, so that doesn't
+    /// work through the proxy
+    pub fn apply_route(&self, config: &Config, route: &Route, builder: &mut mysql::OptsBuilder, host: &str, port: u16) -> Result<()> {
+        let verify = self.tls.unwrap_or(false) && self.tls_verify.unwrap_or(true);
+        if route.proxy.is_none() || verify {
+            if route.proxy.is_some() {
+                if config.runtime.proxy_strict {
+                    bail!("mysql can't verify tls through the proxy, set tls_verify=false or disable --proxy-strict");
+                }
+                debug!("connecting to mysql directly, tls can't be verified through the proxy");
+            }
+            if let Some(ip) = route.resolver.lookup(host, port) {
+                builder.ip_or_hostname(Some(ip.to_string()));
+            }
+            return Ok(());
+        }
+
+        let addr = route.relay(host, port, Some(Instant::now() + self.timeout(config)))?;
+        builder.ip_or_hostname(Some(addr.ip().to_string()))
+               .tcp_port(addr.port());
+        Ok(())
+    }
+
+    /// Turn connection errors into something readable
+    pub fn connect_error(&self, err: mysql::Error) -> Error {
+        match err {
+            mysql::Error::MySqlError(ref err) if err.code == MYSQL_SECURE_TRANSPORT_REQUIRED => {
+                "server requires tls, set tls=true".into()
+            },
+            mysql::Error::DriverError(mysql::DriverError::SslNotSupported) => {
+                "server doesn't support tls".into()
+            },
+            ref err if is_timeout(err) => "mysql connection timed out".into(),
+            err => err.into(),
+        }
+    }
+}
+
+/// The server rejected our credentials, this isn't worth a retry
+pub fn is_access_denied(err: &mysql::Error) -> bool {
+    match *err {
+        mysql::Error::MySqlError(ref err) => {
+            err.code == MYSQL_ACCESS_DENIED || err.code == MYSQL_DBACCESS_DENIED
+        },
+        _ => false,
+    }
+}
+
+pub fn is_timeout(err: &mysql::Error) -> bool {
+    match *err {
+        mysql::Error::DriverError(mysql::DriverError::ConnectTimeout) => true,
+        mysql::Error::DriverError(mysql::DriverError::Timeout) => true,
+        // read and write timeouts on the socket
+        mysql::Error::IoError(ref err) => {
+            let kind = err.kind();
+            kind == io::ErrorKind::TimedOut || kind == io::ErrorKind::WouldBlock
+        },
+        _ => false,
+    }
+}
 
 impl From<mysql::Params> for LuaMap {
     fn from(params: mysql::Params) -> LuaMap {
@@ -17,44 +159,87 @@ impl From<mysql::Params> for LuaMap {
                     .collect::<HashMap<AnyHashableLuaValue, AnyLuaValue>>()
                     .into()
             },
-            mysql::Params::Positional(_) => unimplemented!(),
+            mysql::Params::Positional(list) => {
+                list.into_iter()
+                    .enumerate()
+                    .map(|(i, v)| (AnyHashableLuaValue::LuaNumber((i + 1) as i32), mysql_value_to_lua(v)))
+                    .collect::<HashMap<AnyHashableLuaValue, AnyLuaValue>>()
+                    .into()
+            },
         }
     }
 }
 
-impl Into<mysql::Params> for LuaMap {
-    fn into(self) -> mysql::Params {
-        if self.is_empty() {
-            mysql::Params::Empty
-        } else {
-            let mut params: HashMap<String, mysql::Value, BuildHasherDefault<XxHash>> = HashMap::default();
+/// Convert the parameters of mysql_query, a table with either named or
+/// positional parameters
+pub fn lua_to_params(params: AnyLuaValue) -> Result<mysql::Params> {
+    match params {
+        AnyLuaValue::LuaNil => Ok(mysql::Params::Empty),
+        AnyLuaValue::LuaArray(params) => table_to_params(params),
+        _ => bail!("parameters need to be a table"),
+    }
+}
 
-            for (k, v) in self {
-                if let AnyHashableLuaValue::LuaString(k) = k {
-                    params.insert(k, lua_to_mysql_value(v));
-                } else {
-                    panic!("unsupported keys in map");
-                }
-            }
+fn table_to_params(table: Vec<(AnyLuaValue, AnyLuaValue)>) -> Result<mysql::Params> {
+    if table.is_empty() {
+        return Ok(mysql::Params::Empty);
+    }
+
+    let mut named: HashMap<String, mysql::Value, BuildHasherDefault<XxHash>> = HashMap::default();
+    let mut positional = Vec::new();
+
+    for (k, v) in table {
+        match k {
+            AnyLuaValue::LuaString(k) => {
+                let v = lua_to_mysql_value(v)
+                    .chain_err(|| format!("invalid value for parameter {:?}", k))?;
+                named.insert(k, v);
+            },
+            AnyLuaValue::LuaNumber(idx) if idx >= 1.0 && idx % 1f64 == 0f64 => {
+                let v = lua_to_mysql_value(v)
+                    .chain_err(|| format!("invalid value for parameter {}", idx))?;
+                positional.push((idx as usize, v));
+            },
+            k => bail!("unsupported parameter key: {:?}", k),
+        }
+    }
 
-            mysql::Params::Named(params)
+    if !named.is_empty() && !positional.is_empty() {
+        bail!("named and positional parameters can't be mixed");
+    }
+
+    if positional.is_empty() {
+        return Ok(mysql::Params::Named(named));
+    }
+
+    positional.sort_by_key(|&(idx, _)| idx);
+    let mut params = Vec::new();
+    for (i, (idx, v)) in positional.into_iter().enumerate() {
+        if idx != i + 1 {
+            bail!("positional parameters need to be a sequence, missing parameter {}", i + 1);
         }
+        params.push(v);
     }
+
+    Ok(params.into())
 }
 
-fn lua_to_mysql_value(value: AnyLuaValue) -> mysql::Value {
+fn lua_to_mysql_value(value: AnyLuaValue) -> Result<mysql::Value> {
     match value {
-        AnyLuaValue::LuaString(x) => mysql::Value::Bytes(x.into_bytes()),
-        AnyLuaValue::LuaAnyString(x) => mysql::Value::Bytes(x.0),
+        AnyLuaValue::LuaString(x) => Ok(mysql::Value::Bytes(x.into_bytes())),
+        AnyLuaValue::LuaAnyString(x) => Ok(mysql::Value::Bytes(x.0)),
         AnyLuaValue::LuaNumber(v) => if v % 1f64 == 0f64 {
-            mysql::Value::Int(v as i64)
+            Ok(mysql::Value::Int(v as i64))
         } else {
-            mysql::Value::Float(v)
+            Ok(mysql::Value::Float(v))
+        },
+        AnyLuaValue::LuaBoolean(x) => Ok(mysql::Value::Int(if x { 1 } else { 0 })),
+        AnyLuaValue::LuaArray(x) => match lua_table_to_date(&x)? {
+            Some(date) => Ok(date),
+            None => bail!("tables are only supported as dates"),
         },
-        AnyLuaValue::LuaBoolean(x) => mysql::Value::Int(if x { 1 } else { 0 }),
-        AnyLuaValue::LuaArray(_x) => unimplemented!(),
-        AnyLuaValue::LuaNil => mysql::Value::NULL,
-        AnyLuaValue::LuaOther => unimplemented!(),
+        AnyLuaValue::LuaNil => Ok(mysql::Value::NULL),
+        AnyLuaValue::LuaOther => bail!("unsupported lua type"),
     }
 }
 
@@ -62,11 +247,232 @@ pub fn mysql_value_to_lua(value: mysql::Value) -> AnyLuaValue {
     use mysql::Value::*;
     match value {
         NULL => AnyLuaValue::LuaNil,
-        Bytes(bytes) => AnyLuaValue::LuaString(String::from_utf8(bytes).unwrap()),
+        Bytes(bytes) => match String::from_utf8(bytes) {
+            Ok(x) => AnyLuaValue::LuaString(x),
+            // latin1 text or binary data
+            Err(err) => AnyLuaValue::LuaAnyString(AnyLuaString(err.into_bytes())),
+        },
         Int(i) => AnyLuaValue::LuaNumber(i as f64),
         UInt(i) => AnyLuaValue::LuaNumber(i as f64),
         Float(i) => AnyLuaValue::LuaNumber(i),
-        Date(_, _, _, _, _, _, _) => unimplemented!(),
-        Time(_, _, _, _, _, _) => unimplemented!(),
+        Date(year, month, day, hour, min, sec, micros) => {
+            let mut date = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, hour, min, sec);
+            if micros > 0 {
+                date += &format!(".{:06}", micros);
+            }
+            AnyLuaValue::LuaString(date)
+        },
+        Time(neg, days, hours, min, sec, micros) => {
+            let mut time = format!("{}{:02}:{:02}:{:02}",
+                                   if neg { "-" } else { "" },
+                                   days * 24 + u32::from(hours), min, sec);
+            if micros > 0 {
+                time += &format!(".{:06}", micros);
+            }
+            AnyLuaValue::LuaString(time)
+        },
+    }
+}
+
+/// Convert a table like the one returned by `os.date("*t")` into a date,
+/// returns None if the table isn't a date
+fn lua_table_to_date(table: &[(AnyLuaValue, AnyLuaValue)]) -> Result<Option<mysql::Value>> {
+    let get = |key: &str| {
+        table.iter()
+            .find(|(k, _)| match *k {
+                AnyLuaValue::LuaString(ref k) => k == key,
+                _ => false,
+            })
+            .and_then(|(_, v)| match *v {
+                AnyLuaValue::LuaNumber(v) => Some(v),
+                _ => None,
+            })
+    };
+    let field = |key: &str, min: u16, max: u16| -> Result<Option<u16>> {
+        match get(key) {
+            Some(v) if v % 1f64 == 0f64 && v >= f64::from(min) && v <= f64::from(max) => Ok(Some(v as u16)),
+            Some(v) => bail!("invalid {} in date: {}, expected {} to {}", key, v, min, max),
+            None => Ok(None),
+        }
+    };
+
+    let (year, month, day) = match (field("year", 0, 9999)?, field("month", 1, 12)?, field("day", 1, 31)?) {
+        (Some(year), Some(month), Some(day)) => (year, month as u8, day as u8),
+        _ => return Ok(None),
+    };
+    let hour = field("hour", 0, 23)?.unwrap_or(0) as u8;
+    let min = field("min", 0, 59)?.unwrap_or(0) as u8;
+    let sec = field("sec", 0, 59)?.unwrap_or(0) as u8;
+
+    Ok(Some(mysql::Value::Date(year, month, day, hour, min, sec, 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_options_nil() {
+        let options = MysqlOptions::try_from(AnyLuaValue::LuaNil).unwrap();
+        let mut builder = mysql::OptsBuilder::new();
+        options.apply(&Config::default(), &mut builder).unwrap();
+
+        let opts = mysql::Opts::from(builder);
+        assert_eq!(opts.get_ssl_opts(), &None);
+        assert_eq!(opts.get_tcp_connect_timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(opts.get_read_timeout(), Some(&Duration::from_secs(5)));
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn verify_options_tls() {
+        let options = MysqlOptions::try_from(AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString("timeout".into()), AnyLuaValue::LuaNumber(2.0)),
+            (AnyLuaValue::LuaString("tls".into()), AnyLuaValue::LuaBoolean(true)),
+            (AnyLuaValue::LuaString("tls_verify".into()), AnyLuaValue::LuaBoolean(false)),
+            (AnyLuaValue::LuaString("ca".into()), AnyLuaValue::LuaString("/tmp/ca.pem".into())),
+        ])).unwrap();
+        let mut builder = mysql::OptsBuilder::new();
+        options.apply(&Config::default(), &mut builder).unwrap();
+
+        let opts = mysql::Opts::from(builder);
+        assert_eq!(opts.get_ssl_opts(), &Some((PathBuf::from("/tmp/ca.pem"), None)));
+        assert!(!opts.get_verify_peer());
+        assert_eq!(opts.get_write_timeout(), Some(&Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn verify_secure_transport_error() {
+        let err = mysql::Error::MySqlError(mysql::MySqlError {
+            state: "HY000".into(),
+            message: "Connections using insecure transport are prohibited while --require_secure_transport=ON.".into(),
+            code: MYSQL_SECURE_TRANSPORT_REQUIRED,
+        });
+        let err = MysqlOptions::default().connect_error(err);
+        assert_eq!(err.to_string(), "server requires tls, set tls=true");
+    }
+
+    #[test]
+    fn verify_date_to_lua() {
+        let date = mysql_value_to_lua(mysql::Value::Date(2018, 4, 3, 13, 37, 0, 0));
+        assert_eq!(date, AnyLuaValue::LuaString("2018-04-03T13:37:00".into()));
+
+        let date = mysql_value_to_lua(mysql::Value::Date(2018, 4, 3, 0, 0, 0, 1500));
+        assert_eq!(date, AnyLuaValue::LuaString("2018-04-03T00:00:00.001500".into()));
+    }
+
+    #[test]
+    fn verify_time_to_lua() {
+        let time = mysql_value_to_lua(mysql::Value::Time(false, 0, 13, 37, 5, 0));
+        assert_eq!(time, AnyLuaValue::LuaString("13:37:05".into()));
+
+        let time = mysql_value_to_lua(mysql::Value::Time(true, 2, 1, 0, 0, 0));
+        assert_eq!(time, AnyLuaValue::LuaString("-49:00:00".into()));
+    }
+
+    #[test]
+    fn verify_lua_to_date() {
+        let date = lua_to_mysql_value(AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString("year".into()), AnyLuaValue::LuaNumber(2018.0)),
+            (AnyLuaValue::LuaString("month".into()), AnyLuaValue::LuaNumber(4.0)),
+            (AnyLuaValue::LuaString("day".into()), AnyLuaValue::LuaNumber(3.0)),
+            (AnyLuaValue::LuaString("hour".into()), AnyLuaValue::LuaNumber(13.0)),
+        ])).unwrap();
+        assert_eq!(date, mysql::Value::Date(2018, 4, 3, 13, 0, 0, 0));
+    }
+
+    #[test]
+    fn verify_invalid_date() {
+        let date = |month: f64, hour: f64| lua_to_mysql_value(AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString("year".into()), AnyLuaValue::LuaNumber(2018.0)),
+            (AnyLuaValue::LuaString("month".into()), AnyLuaValue::LuaNumber(month)),
+            (AnyLuaValue::LuaString("day".into()), AnyLuaValue::LuaNumber(3.0)),
+            (AnyLuaValue::LuaString("hour".into()), AnyLuaValue::LuaNumber(hour)),
+        ]));
+        assert!(date(4.0, 23.0).is_ok());
+        assert!(date(13.0, 0.0).is_err());
+        assert!(date(0.0, 0.0).is_err());
+        assert!(date(4.5, 0.0).is_err());
+        assert!(date(4.0, 24.0).is_err());
+        assert!(date(4.0, 256.0).is_err());
+    }
+
+    #[test]
+    fn verify_non_utf8_to_lua() {
+        let x = mysql_value_to_lua(mysql::Value::Bytes(b"caf\xe9".to_vec()));
+        assert_eq!(x, AnyLuaValue::LuaAnyString(AnyLuaString(b"caf\xe9".to_vec())));
+
+        let x = mysql_value_to_lua(mysql::Value::Bytes(b"caf\xc3\xa9".to_vec()));
+        assert_eq!(x, AnyLuaValue::LuaString("caf\u{e9}".into()));
+    }
+
+    fn lua_table(table: Vec<(AnyLuaValue, AnyLuaValue)>) -> AnyLuaValue {
+        AnyLuaValue::LuaArray(table)
+    }
+
+    #[test]
+    fn verify_invalid_params() {
+        let params = lua_table(vec![
+            (AnyLuaValue::LuaString("foo".into()), lua_table(vec![
+                (AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNumber(2.0)),
+            ])),
+        ]);
+        assert!(lua_to_params(params).is_err());
+
+        let params = lua_table(vec![
+            (AnyLuaValue::LuaString("foo".into()), AnyLuaValue::LuaOther),
+        ]);
+        assert!(lua_to_params(params).is_err());
+
+        assert!(lua_to_params(AnyLuaValue::LuaString("foo".into())).is_err());
+    }
+
+    #[test]
+    fn verify_empty_params() {
+        assert_eq!(lua_to_params(AnyLuaValue::LuaNil).unwrap(), mysql::Params::Empty);
+        assert_eq!(lua_to_params(lua_table(vec![])).unwrap(), mysql::Params::Empty);
+    }
+
+    #[test]
+    fn verify_positional_params() {
+        let params = lua_table(vec![
+            (AnyLuaValue::LuaNumber(2.0), AnyLuaValue::LuaString("bar".into())),
+            (AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNumber(1.0)),
+        ]);
+        assert_eq!(lua_to_params(params).unwrap(), mysql::Params::from(vec![
+            mysql::Value::Int(1),
+            mysql::Value::Bytes(b"bar".to_vec()),
+        ]));
+    }
+
+    #[test]
+    fn verify_invalid_positional_params() {
+        // missing parameter 2
+        let params = lua_table(vec![
+            (AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNumber(1.0)),
+            (AnyLuaValue::LuaNumber(3.0), AnyLuaValue::LuaNumber(3.0)),
+        ]);
+        assert!(lua_to_params(params).is_err());
+
+        let params = lua_table(vec![
+            (AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNumber(1.0)),
+            (AnyLuaValue::LuaString("foo".into()), AnyLuaValue::LuaNumber(2.0)),
+        ]);
+        assert!(lua_to_params(params).is_err());
+    }
+
+    #[test]
+    fn verify_access_denied() {
+        let err = mysql::Error::MySqlError(mysql::MySqlError {
+            state: "28000".into(),
+            message: "Access denied for user 'root'@'localhost' (using password: YES)".into(),
+            code: MYSQL_ACCESS_DENIED,
+        });
+        assert!(is_access_denied(&err));
+        assert!(!is_timeout(&err));
+
+        let err = mysql::Error::DriverError(mysql::DriverError::ConnectTimeout);
+        assert!(!is_access_denied(&err));
+        assert!(is_timeout(&err));
     }
 }
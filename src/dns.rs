@@ -0,0 +1,136 @@
+//! Static dns overrides, see --resolve and the [dns] section of the config
+
+use errors::{Result, ResultExt, Error};
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+
+/// Resolve a name to a fixed address, `*.corp.local` matches all subdomains
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    name: String,
+    // only used for this port, any port if none
+    port: Option<u16>,
+    ip: IpAddr,
+}
+
+impl Override {
+    pub fn new(name: &str, port: Option<u16>, ip: IpAddr) -> Result<Override> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let domain = name.strip_prefix("*.").unwrap_or(&name);
+        if domain.is_empty() || domain.contains('*') || domain.contains(':') {
+            bail!("invalid name {:?}, expected a hostname or *.domain", name);
+        }
+        Ok(Override {
+            name,
+            port,
+            ip,
+        })
+    }
+
+    #[inline]
+    fn is_wildcard(&self) -> bool {
+        self.name.starts_with("*.")
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some() && self.port != Some(port) {
+            return false;
+        }
+        if self.is_wildcard() {
+            // keep the dot so corp.local doesn't match *.corp.local
+            host.ends_with(&self.name[1..])
+        } else {
+            host == self.name
+        }
+    }
+}
+
+/// The curl style host:port:ip of --resolve
+impl FromStr for Override {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Override> {
+        let mut parts = s.splitn(3, ':');
+        let (name, port, ip) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(port), Some(ip)) => (name, port, ip),
+            _ => bail!("expected host:port:ip, got {:?}", s),
+        };
+        let port = port.parse()
+            .chain_err(|| format!("invalid port {:?}", port))?;
+        let ip = ip.trim_start_matches('[').trim_end_matches(']');
+        let ip = ip.parse()
+            .chain_err(|| format!("invalid ip {:?}", ip))?;
+        Override::new(name, Some(port), ip)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Resolver {
+    overrides: Vec<Override>,
+}
+
+impl Resolver {
+    /// Overrides that are added first win
+    #[inline]
+    pub fn add(&mut self, entry: Override) {
+        self.overrides.push(entry);
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.overrides.is_empty()
+    }
+
+    /// The address of host, exact names are preferred over wildcards.
+    /// None if the system resolver should be used
+    pub fn lookup(&self, host: &str, port: u16) -> Option<IpAddr> {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let exact = self.overrides.iter()
+            .filter(|x| !x.is_wildcard())
+            .find(|x| x.matches(&host, port));
+        let entry = exact.or_else(|| self.overrides.iter()
+            .filter(|x| x.is_wildcard())
+            .find(|x| x.matches(&host, port)));
+
+        if let Some(entry) = entry {
+            debug!("resolving {} to {}", host, entry.ip);
+        }
+        entry.map(|x| x.ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn verify_parse() {
+        let entry = "Intranet.corp.local:443:10.0.0.1".parse::<Override>().unwrap();
+        assert_eq!(entry, Override::new("intranet.corp.local", Some(443), "10.0.0.1".parse().unwrap()).unwrap());
+        assert!("*.corp.local:80:[fd00::1]".parse::<Override>().is_ok());
+        assert!("intranet.corp.local:10.0.0.1".parse::<Override>().is_err());
+        assert!("intranet.corp.local:https:10.0.0.1".parse::<Override>().is_err());
+        assert!("intranet.corp.local:443:intranet".parse::<Override>().is_err());
+        assert!("sso.*.local:443:10.0.0.1".parse::<Override>().is_err());
+    }
+
+    #[test]
+    fn verify_lookup() {
+        let mut resolver = Resolver::default();
+        resolver.add("sso.corp.local:443:10.0.0.2".parse().unwrap());
+        resolver.add(Override::new("*.corp.local", None, "10.0.0.1".parse().unwrap()).unwrap());
+        resolver.add(Override::new("sso.corp.local", None, "10.0.0.3".parse().unwrap()).unwrap());
+
+        assert_eq!(resolver.lookup("sso.corp.local", 443), ip("10.0.0.2"));
+        assert_eq!(resolver.lookup("SSO.corp.local.", 80), ip("10.0.0.3"));
+        assert_eq!(resolver.lookup("a.b.corp.local", 443), ip("10.0.0.1"));
+        assert_eq!(resolver.lookup("corp.local", 443), None);
+        assert_eq!(resolver.lookup("evilcorp.local", 443), None);
+    }
+}
@@ -0,0 +1,230 @@
+use errors::{Error, ErrorKind};
+
+use db;
+use mysql;
+use reqwest;
+use std::error::Error as StdError;
+use std::io;
+
+
+/// A rough classification of errors so scripts can decide how to react
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Timeout,
+    Connection,
+    Tls,
+    Protocol,
+    Script,
+    // the credentials were rejected, this doesn't fail the attempt
+    Denied,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Timeout => "timeout",
+            Kind::Connection => "connection",
+            Kind::Tls => "tls",
+            Kind::Protocol => "protocol",
+            Kind::Script => "script",
+            Kind::Denied => "denied",
+        }
+    }
+
+    /// Errors that might go away if the attempt is tried again
+    #[inline]
+    pub fn is_retryable(self) -> bool {
+        match self {
+            Kind::Timeout | Kind::Connection | Kind::Tls => true,
+            Kind::Protocol | Kind::Script | Kind::Denied => false,
+        }
+    }
+
+    pub fn of(err: &Error) -> Kind {
+        from_error(err)
+            .or_else(|| from_root_msg(err))
+            .unwrap_or(Kind::Script)
+    }
+}
+
+/// Retrying a deterministic error, like a bug in the script, only repeats
+/// the same error. The script can override this with set_retry
+pub fn is_retryable(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::Transient(_) => true,
+        ErrorKind::Fatal(_) => false,
+        _ => Kind::of(err).is_retryable(),
+    }
+}
+
+fn from_error(err: &Error) -> Option<Kind> {
+    let kind = match *err.kind() {
+        ErrorKind::Io(ref err) => Some(from_io(err)),
+        ErrorKind::Mysql(ref err) => Some(from_mysql(err)),
+        ErrorKind::Reqwest(ref err) => from_reqwest(err),
+        _ => None,
+    };
+
+    // the source of errors that were chained with chain_err
+    kind.or_else(|| {
+        err.1.next_error.as_ref()
+            .and_then(|err| from_std(err.as_ref()))
+    })
+}
+
+fn from_std(err: &(dyn StdError + 'static)) -> Option<Kind> {
+    if let Some(err) = err.downcast_ref::<Error>() {
+        from_error(err)
+    } else if let Some(err) = err.downcast_ref::<io::Error>() {
+        Some(from_io(err))
+    } else if let Some(err) = err.downcast_ref::<mysql::Error>() {
+        Some(from_mysql(err))
+    } else if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        from_reqwest(err)
+    } else {
+        None
+    }
+}
+
+fn from_io(err: &io::Error) -> Kind {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Kind::Timeout,
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Kind::Protocol,
+        _ => Kind::Connection,
+    }
+}
+
+fn from_mysql(err: &mysql::Error) -> Kind {
+    match *err {
+        ref err if db::mysql::is_timeout(err) => Kind::Timeout,
+        ref err if db::mysql::is_access_denied(err) => Kind::Denied,
+        mysql::Error::IoError(ref err) => from_io(err),
+        mysql::Error::DriverError(mysql::DriverError::CouldNotConnect(_)) => Kind::Connection,
+        #[cfg(unix)]
+        mysql::Error::SslError(_) => Kind::Tls,
+        _ => Kind::Protocol,
+    }
+}
+
+fn from_reqwest(err: &reqwest::Error) -> Option<Kind> {
+    if let Some(err) = err.get_ref() {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return Some(from_io(err));
+        }
+    }
+
+    from_msg(&err.to_string())
+}
+
+/// Guess the kind from the message of the innermost error, the outer ones
+/// can contain the path of the script. Lua errors are bugs in the script,
+/// their messages include the script path and the traceback
+fn from_root_msg(err: &Error) -> Option<Kind> {
+    match err.1.next_error {
+        Some(ref next) => match next.downcast_ref::<Error>() {
+            Some(next) => from_root_msg(next),
+            None => from_msg(&next.to_string()),
+        },
+        None => match *err.kind() {
+            ErrorKind::Lua(_) => None,
+            _ => from_msg(&err.to_string()),
+        },
+    }
+}
+
+/// Guess the kind from the error message if the source isn't available
+fn from_msg(msg: &str) -> Option<Kind> {
+    let msg = msg.to_lowercase();
+
+    if msg.contains("timed out") || msg.contains("timeout") {
+        Some(Kind::Timeout)
+    } else if msg.contains("tls") || msg.contains("ssl") || msg.contains("certificate") {
+        Some(Kind::Tls)
+    } else if msg.contains("connection refused") || msg.contains("connection reset")
+            || msg.contains("couldn't connect") || msg.contains("could not connect")
+            || msg.contains("failed to lookup address") {
+        Some(Kind::Connection)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::ResultExt;
+    use hlua;
+
+    #[test]
+    fn verify_io_kind() {
+        let err: Error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        assert_eq!(Kind::of(&err), Kind::Connection);
+
+        let err: Error = io::Error::new(io::ErrorKind::TimedOut, "timeout").into();
+        assert_eq!(Kind::of(&err), Kind::Timeout);
+    }
+
+    #[test]
+    fn verify_chained_kind() {
+        let err: Result<(), io::Error> = Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+        let err = err.chain_err(|| "request failed").unwrap_err();
+        assert_eq!(Kind::of(&err), Kind::Connection);
+    }
+
+    #[test]
+    fn verify_msg_kind() {
+        let err: Error = "program timed out".into();
+        assert_eq!(Kind::of(&err), Kind::Timeout);
+
+        let err: Error = "server requires tls, set tls=true".into();
+        assert_eq!(Kind::of(&err), Kind::Tls);
+
+        let err: Error = "invalid selector".into();
+        assert_eq!(Kind::of(&err), Kind::Script);
+    }
+
+    #[test]
+    fn verify_lua_kind() {
+        let err: Error = hlua::LuaError::ExecutionError("scripts/ssl-vpn.lua:12: attempt to index field 'timeout' (a nil value)".into()).into();
+        assert_eq!(Kind::of(&err), Kind::Script);
+
+        let err: Result<(), Error> = Err(err);
+        let err = err.chain_err(|| "init() failed").unwrap_err();
+        assert_eq!(Kind::of(&err), Kind::Script);
+
+        // an error that was recorded with set_error is the cause
+        let cause: Error = "server requires tls, set tls=true".into();
+        let cause = Error::with_chain(cause, "scripts/vpn.lua:3: server requires tls, set tls=true");
+        let lua = hlua::LuaError::ExecutionError("scripts/vpn.lua:3: error".into());
+        let err = Error::with_chain(cause, ErrorKind::Lua(lua));
+        assert_eq!(Kind::of(&err), Kind::Tls);
+    }
+
+    #[test]
+    fn verify_retryable() {
+        let err: Error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        assert!(is_retryable(&err));
+
+        let err: Error = "failed to lookup address information: Name or service not known".into();
+        assert!(is_retryable(&err));
+
+        let err: Error = "invalid selector".into();
+        assert!(!is_retryable(&err));
+
+        let err: Error = ErrorKind::Transient("invalid selector".into()).into();
+        assert!(is_retryable(&err));
+
+        let cause: Error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        let err = Error::with_chain(cause, ErrorKind::Fatal("refused".into()));
+        assert!(!is_retryable(&err));
+        assert_eq!(Kind::of(&err), Kind::Connection);
+
+        let err: Error = mysql::Error::MySqlError(mysql::MySqlError {
+            state: "28000".into(),
+            message: "Access denied for user 'root'@'localhost' (using password: YES)".into(),
+            code: 1045,
+        }).into();
+        assert!(!is_retryable(&err));
+        assert_eq!(Kind::of(&err), Kind::Denied);
+    }
+}
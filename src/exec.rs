@@ -0,0 +1,242 @@
+use errors::{Result, ResultExt};
+
+use hlua::AnyLuaValue;
+use json;
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 1 MiB per stream
+const DEFAULT_MAX_OUTPUT: usize = 1024 * 1024;
+const POLL_INTERVAL: u64 = 10;
+
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExecOptions {
+    max_output: Option<usize>,
+    env: Option<HashMap<String, String>>,
+    clear_env: Option<bool>,
+    stdin: Option<Stdin>,
+    cwd: Option<String>,
+    timeout: Option<u64>,
+}
+
+/// Data for stdin, either a string or a list of bytes
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Stdin {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Stdin {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Stdin::Text(x) => x.into_bytes(),
+            Stdin::Bytes(x) => x,
+        }
+    }
+}
+
+impl ExecOptions {
+    #[inline]
+    pub fn try_from(x: AnyLuaValue) -> Result<ExecOptions> {
+        json::options(x)
+    }
+
+    #[inline]
+    pub fn max_output(&self) -> usize {
+        self.max_output.unwrap_or(DEFAULT_MAX_OUTPUT)
+    }
+
+    #[inline]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    /// Spawn the program, stdout and stderr are piped if `capture` is set
+    fn spawn(&mut self, prog: &str, args: &[String], capture: bool) -> Result<Child> {
+        let mut cmd = Command::new(prog);
+        cmd.args(args);
+
+        if self.clear_env.unwrap_or(false) {
+            cmd.env_clear();
+        }
+
+        if let Some(ref env) = self.env {
+            cmd.envs(env);
+        }
+
+        if let Some(ref cwd) = self.cwd {
+            cmd.current_dir(cwd);
+        }
+
+        if self.stdin.is_some() {
+            cmd.stdin(Stdio::piped());
+        } else if capture {
+            cmd.stdin(Stdio::null());
+        }
+
+        if capture {
+            cmd.stdout(Stdio::piped())
+               .stderr(Stdio::piped());
+        }
+
+        let mut child = cmd.spawn()
+            .chain_err(|| "failed to spawn program")?;
+
+        if let Some(stdin) = self.stdin.take() {
+            let bytes = stdin.into_bytes();
+            let mut pipe = child.stdin.take().unwrap();
+            // write in the background, the pipe is closed when we're done
+            thread::spawn(move || {
+                if let Err(err) = pipe.write_all(&bytes) {
+                    debug!("failed to write to stdin: {:?}", err);
+                }
+            });
+        }
+
+        Ok(child)
+    }
+
+    /// Wait for the program to exit, it's killed if the timeout expires
+    fn wait(&self, child: &mut Child) -> Result<i32> {
+        let status = match self.timeout() {
+            Some(timeout) => {
+                let start = Instant::now();
+                loop {
+                    if let Some(status) = child.try_wait()? {
+                        break status;
+                    }
+
+                    if start.elapsed() >= timeout {
+                        child.kill()
+                            .chain_err(|| "failed to kill program")?;
+                        child.wait()?;
+                        bail!("program timed out");
+                    }
+
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL));
+                }
+            },
+            None => child.wait()
+                        .chain_err(|| "failed to wait for program")?,
+        };
+
+        let code = status.code()
+            .chain_err(|| "process didn't return exit code")?;
+        Ok(code)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExecOutput {
+    pub status: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Read up to `max` bytes and discard the rest, so the child doesn't block
+/// on a full pipe
+fn read_bounded<R: Read>(mut r: R, max: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.by_ref().take(max as u64).read_to_end(&mut buf)?;
+    io::copy(&mut r, &mut io::sink())?;
+    Ok(buf)
+}
+
+/// Run the program and return the exit code
+pub fn status(prog: &str, args: &[String], mut options: ExecOptions) -> Result<i32> {
+    let mut child = options.spawn(prog, args, false)?;
+    options.wait(&mut child)
+}
+
+/// Run the program and capture its output
+pub fn output(prog: &str, args: &[String], mut options: ExecOptions) -> Result<ExecOutput> {
+    let mut child = options.spawn(prog, args, true)?;
+
+    let max = options.max_output();
+
+    // read both pipes in the background to avoid a deadlock if the child
+    // fills both of them while we wait for it
+    let stdout = child.stdout.take().unwrap();
+    let stdout = thread::spawn(move || read_bounded(stdout, max));
+    let stderr = child.stderr.take().unwrap();
+    let stderr = thread::spawn(move || read_bounded(stderr, max));
+
+    let status = options.wait(&mut child)?;
+
+    let stdout = stdout.join()
+        .map_err(|_| "stdout thread panicked")?
+        .chain_err(|| "failed to read stdout")?;
+    let stderr = stderr.join()
+        .map_err(|_| "stderr thread panicked")?
+        .chain_err(|| "failed to read stderr")?;
+
+    Ok(ExecOutput {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_output() {
+        let output = output("sh", &["-c".into(), "echo foo; echo bar >&2; exit 3".into()], ExecOptions::default()).unwrap();
+        assert_eq!(output, ExecOutput {
+            status: 3,
+            stdout: b"foo\n".to_vec(),
+            stderr: b"bar\n".to_vec(),
+        });
+    }
+
+    #[test]
+    fn verify_output_bounded() {
+        let options = ExecOptions {
+            max_output: Some(4),
+            ..Default::default()
+        };
+        let output = output("sh", &["-c".into(), "printf '\\377\\376abcdef'".into()], options).unwrap();
+        assert_eq!(output.status, 0);
+        assert_eq!(output.stdout, b"\xff\xfeab");
+    }
+
+    fn options(x: Vec<(&str, AnyLuaValue)>) -> ExecOptions {
+        ExecOptions::try_from(AnyLuaValue::LuaArray(x.into_iter()
+            .map(|(k, v)| (AnyLuaValue::LuaString(k.into()), v))
+            .collect())).unwrap()
+    }
+
+    #[test]
+    fn verify_env_stdin_cwd() {
+        let options = options(vec![
+            ("env", AnyLuaValue::LuaArray(vec![
+                (AnyLuaValue::LuaString("FOO".into()), AnyLuaValue::LuaString("bar".into())),
+            ])),
+            ("clear_env", AnyLuaValue::LuaBoolean(true)),
+            ("stdin", AnyLuaValue::LuaString("hello".into())),
+            ("cwd", AnyLuaValue::LuaString("/".into())),
+        ]);
+        let output = output("/bin/sh", &["-c".into(), "echo $FOO $HOME; pwd; cat".into()], options).unwrap();
+        assert_eq!(output.status, 0);
+        assert_eq!(output.stdout, b"bar\n/\nhello");
+    }
+
+    #[test]
+    fn verify_timeout() {
+        let options = options(vec![
+            ("timeout", AnyLuaValue::LuaNumber(1.0)),
+        ]);
+        let start = Instant::now();
+        let err = status("sleep", &["10".into()], options).unwrap_err();
+        assert_eq!(err.to_string(), "program timed out");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,18 +1,334 @@
-use errors::Result;
+use errors::{Result, ResultExt, Error};
 use args::Fsck;
+use utils;
 
+use base64;
+use regex::Regex;
+use serde_json;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::io;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::prelude::*;
-use std::str;
+use std::str::{self, FromStr};
 
+// the delimiters that are considered by --delimiter auto, in this order
+const DELIMITERS: &[char] = &[':', '\t', ';', '|', ','];
+// how many lines --delimiter auto looks at
+const SAMPLE_LINES: usize = 1000;
 
-fn validate_file(path: &str, args: &Fsck) -> Result<()> {
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delimiter {
+    Auto,
+    Char(char),
+}
+
+impl FromStr for Delimiter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Delimiter> {
+        let mut chars = s.chars();
+        match (s, chars.next(), chars.next()) {
+            ("auto", _, _) => Ok(Delimiter::Auto),
+            ("tab", _, _) | ("\\t", _, _) => Ok(Delimiter::Char('\t')),
+            (_, Some(c), None) if c != '\n' && c != '"' => Ok(Delimiter::Char(c)),
+            _ => bail!("invalid delimiter {:?}, expected a single character, tab or auto", s),
+        }
+    }
+}
+
+/// The delimiter that comes first in the most lines, passwords might contain
+/// the others. Returns how many lines it comes first in
+fn guess_delimiter(sample: &[String]) -> Option<(char, usize)> {
+    let first = sample.iter()
+        .filter_map(|line| line.chars().find(|c| DELIMITERS.contains(c)))
+        .collect::<Vec<_>>();
+    DELIMITERS.iter()
+        .map(|c| (*c, first.iter().filter(|x| *x == c).count()))
+        .filter(|x| x.1 > 0)
+        .fold(None, |best, x| match best {
+            Some(best) if best.1 >= x.1 => Some(best),
+            _ => Some(x),
+        })
+}
+
+fn detect_delimiter(path: &str, json: bool) -> Result<char> {
+    let sample = BufReader::new(File::open(path)?)
+        .split(b'\n')
+        .take(SAMPLE_LINES)
+        .map(|line| line.map(|x| String::from_utf8_lossy(&x).into_owned()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let (delimiter, lines) = match guess_delimiter(&sample) {
+        Some(guess) => guess,
+        None => bail!("{}: no delimiter found, set it with --delimiter", path),
+    };
+    if json {
+        write_event(&Event::Delimiter {
+            path,
+            delimiter,
+            lines,
+            sampled: sample.len(),
+        })?;
+    } else {
+        eprintln!("Delimiter({}): {:?} in {} of {} sampled lines", path, delimiter, lines, sample.len());
+    }
+    Ok(delimiter)
+}
+
+/// Why a line is invalid or should be double checked, everything except the
+/// encoding is only checked with -c
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Problem {
+    InvalidUtf8,
+    // the file ends in the middle of the line
+    Truncated,
+    NoDelimiter,
+    EmptyUser,
+    EmptyPassword,
+    // the password contains the delimiter, the line is kept but the user
+    // might have been split at the wrong place
+    MultipleDelimiters,
+}
+
+impl Problem {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Problem::InvalidUtf8 => "invalid utf-8",
+            Problem::Truncated => "truncated",
+            Problem::NoDelimiter => "no delimiter",
+            Problem::EmptyUser => "empty user",
+            Problem::EmptyPassword => "empty password",
+            Problem::MultipleDelimiters => "multiple delimiters",
+        }
+    }
+}
+
+/// Why a line was dropped
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Keep,
+    // kept, but reported
+    Warn(Problem),
+    Invalid(Problem),
+    // doesn't match --filter-user or --filter-password
+    Filtered,
+    Duplicate,
+    RepeatedUser,
+}
+
+/// How many lines were read, kept and dropped, printed after the files
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct Stats {
+    read: usize,
+    kept: usize,
+    // kept lines that were reported
+    warnings: usize,
+    invalid: usize,
+    // valid lines that did or didn't pass --filter-user and --filter-password
+    matched: usize,
+    filtered: usize,
+    duplicates: usize,
+    repeated_users: usize,
+    problems: BTreeMap<Problem, usize>,
+}
+
+/// An invalid line in --json output, the line number starts at 1
+#[derive(Debug, Serialize)]
+struct JsonProblem<'a> {
+    path: &'a str,
+    line: usize,
+    reason: Problem,
+    // base64 if the line isn't valid utf-8
+    raw: String,
+    base64: bool,
+}
+
+impl<'a> JsonProblem<'a> {
+    fn new(path: &'a str, line: usize, reason: Problem, buf: &[u8]) -> JsonProblem<'a> {
+        let (raw, base64) = match str::from_utf8(buf) {
+            Ok(line) => (line.to_string(), false),
+            Err(_) => (base64::encode(buf), true),
+        };
+        JsonProblem {
+            path,
+            line,
+            reason,
+            raw,
+            base64,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    // the guess of --delimiter auto
+    Delimiter {
+        path: &'a str,
+        delimiter: char,
+        lines: usize,
+        sampled: usize,
+    },
+    Problem(JsonProblem<'a>),
+    Warning(JsonProblem<'a>),
+    Summary(&'a Stats),
+}
+
+fn write_event(event: &Event) -> Result<()> {
+    eprintln!("{}", serde_json::to_string(event)?);
+    Ok(())
+}
+
+#[inline]
+fn strip_newline(line: &str) -> &str {
+    line.strip_suffix('\n').unwrap_or(line)
+}
+
+/// Splits a line into user and password, the line ending isn't part of the
+/// password. Lines without delimiter are only a user
+fn split_line(line: &str, delimiter: char) -> (Cow<'_, str>, Option<&str>) {
+    let line = strip_newline(line);
+    match utils::split_combo(line, delimiter) {
+        Some((user, password)) => (user, Some(password)),
+        None => (Cow::Borrowed(line), None),
+    }
+}
+
+/// Write the line with another delimiter, see utils::format_combo
+fn convert_line(line: &str, from: char, to: char) -> String {
+    match split_line(line, from) {
+        (user, Some(password)) => {
+            let ending = &line[strip_newline(line).len()..];
+            utils::format_combo(&user, password, to) + ending
+        },
+        (_, None) => line.to_string(),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Filter {
+    require_colon: bool,
+    delimiter: char,
+    dedup: bool,
+    unique_users: bool,
+    user_pattern: Option<Regex>,
+    password_pattern: Option<Regex>,
+    // keep the lines that don't match
+    invert: bool,
+    lines: HashSet<(String, Option<String>)>,
+    users: HashSet<String>,
+    stats: Stats,
+}
+
+impl Filter {
+    fn new(args: &Fsck) -> Result<Filter> {
+        fn compile(pattern: &Option<String>) -> Result<Option<Regex>> {
+            match *pattern {
+                Some(ref pattern) => Regex::new(pattern)
+                    .map(Some)
+                    .chain_err(|| format!("invalid pattern {:?}", pattern)),
+                None => Ok(None),
+            }
+        }
+
+        Ok(Filter {
+            require_colon: args.require_colon,
+            delimiter: ':',
+            dedup: args.dedup,
+            unique_users: args.unique_users,
+            user_pattern: compile(&args.filter_user)?,
+            password_pattern: compile(&args.filter_password)?,
+            invert: args.invert,
+            ..Filter::default()
+        })
+    }
+
+    /// Both patterns have to match if both are set
+    fn matches(&self, user: &str, password: &str) -> bool {
+        fn is_match(pattern: &Option<Regex>, value: &str) -> bool {
+            match *pattern {
+                Some(ref pattern) => pattern.is_match(value),
+                None => true,
+            }
+        }
+        is_match(&self.user_pattern, user) && is_match(&self.password_pattern, password)
+    }
+
+    fn check(&mut self, line: &[u8]) -> Verdict {
+        self.stats.read += 1;
+        let verdict = self.verdict(line);
+        match verdict {
+            Verdict::Keep => self.stats.kept += 1,
+            Verdict::Warn(problem) => {
+                self.stats.kept += 1;
+                self.stats.warnings += 1;
+                *self.stats.problems.entry(problem).or_insert(0) += 1;
+            },
+            Verdict::Invalid(problem) => {
+                self.stats.invalid += 1;
+                *self.stats.problems.entry(problem).or_insert(0) += 1;
+            },
+            Verdict::Filtered => self.stats.filtered += 1,
+            Verdict::Duplicate => self.stats.duplicates += 1,
+            Verdict::RepeatedUser => self.stats.repeated_users += 1,
+        }
+        verdict
+    }
+
+    fn verdict(&mut self, line: &[u8]) -> Verdict {
+        let line = match str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => return Verdict::Invalid(Problem::InvalidUtf8),
+        };
+        let (user, password) = split_line(line, self.delimiter);
+        if self.require_colon {
+            // read_until only returns a line without newline at the end of the file
+            if !line.ends_with('\n') {
+                return Verdict::Invalid(Problem::Truncated);
+            }
+            match password {
+                None => return Verdict::Invalid(Problem::NoDelimiter),
+                _ if user.is_empty() => return Verdict::Invalid(Problem::EmptyUser),
+                Some("") => return Verdict::Invalid(Problem::EmptyPassword),
+                _ => (),
+            }
+        }
+
+        if self.matches(&user, password.unwrap_or("")) == self.invert {
+            return Verdict::Filtered;
+        }
+        self.stats.matched += 1;
+
+        if self.dedup && !self.lines.insert((user.to_string(), password.map(|x| x.to_string()))) {
+            return Verdict::Duplicate;
+        }
+        // a quoted user was split at the right place
+        let quoted = match user {
+            Cow::Owned(_) => true,
+            Cow::Borrowed(_) => false,
+        };
+        if self.unique_users && !self.users.insert(user.into_owned()) {
+            return Verdict::RepeatedUser;
+        }
+
+        match password {
+            Some(password) if self.require_colon && !quoted && password.contains(self.delimiter) => Verdict::Warn(Problem::MultipleDelimiters),
+            _ => Verdict::Keep,
+        }
+    }
+}
+
+/// A kept line with the user and password it's sorted by
+type SortedLine = (String, Option<String>, Vec<u8>);
+
+fn validate_file(path: &str, args: &Fsck, filter: &mut Filter, to_delimiter: Option<char>, out: &mut dyn Write, sorted: &mut Vec<SortedLine>) -> Result<()> {
     let f = File::open(path)?;
     let mut file = BufReader::new(&f);
-    let mut out = BufWriter::new(io::stdout());
 
     let mut i = 0;
     let mut buf = Vec::new();
@@ -27,41 +343,244 @@ fn validate_file(path: &str, args: &Fsck) -> Result<()> {
         */
         // TODO: remove empty lines?
 
-        match str::from_utf8(&buf) {
-            Ok(line) => {
-                if !args.require_colon || buf.iter().any(|x| *x == b':') {
-                    if !args.silent {
-                        out.write_all(line.as_bytes())?;
+        let verdict = filter.check(&buf);
+        match verdict {
+            Verdict::Keep | Verdict::Warn(_) => {
+                if let Verdict::Warn(problem) = verdict {
+                    if args.json {
+                        write_event(&Event::Warning(JsonProblem::new(path, i + 1, problem, &buf)))?;
+                    } else if !args.quiet {
+                        eprintln!("Warning(line {}, {}): {:?}", i, problem.as_str(), String::from_utf8_lossy(&buf));
                     }
-                } else if !args.quiet {
-                    eprintln!("Invalid(line {}): {:?}",
-                        i,
-                        line);
                 }
-            },
-            Err(_) => {
-                if !args.quiet {
-                    eprintln!("Invalid(line {}): {:?} {:?}",
-                        i,
-                        String::from_utf8_lossy(&buf),
-                        buf);
+                if let Some(to) = to_delimiter {
+                    // kept lines are always valid utf-8
+                    let line = convert_line(str::from_utf8(&buf).unwrap(), filter.delimiter, to);
+                    buf = line.into_bytes();
+                }
+                if args.sort {
+                    // the last line might not end with a newline
+                    if buf.last() != Some(&DELIM) {
+                        buf.push(DELIM);
+                    }
+                    let (user, password) = {
+                        let line = str::from_utf8(&buf).unwrap();
+                        let (user, password) = split_line(line, to_delimiter.unwrap_or(filter.delimiter));
+                        (user.into_owned(), password.map(|x| x.to_string()))
+                    };
+                    sorted.push((user, password, buf.clone()));
+                } else if !args.silent {
+                    // write the original bytes
+                    out.write_all(&buf)?;
                 }
             },
+            Verdict::Invalid(problem) if args.json => write_event(&Event::Problem(JsonProblem::new(path, i + 1, problem, &buf)))?,
+            Verdict::Invalid(problem) if !args.quiet => match str::from_utf8(&buf) {
+                Ok(line) => eprintln!("Invalid(line {}, {}): {:?}",
+                    i,
+                    problem.as_str(),
+                    line),
+                Err(_) => eprintln!("Invalid(line {}, {}): {:?} {:?}",
+                    i,
+                    problem.as_str(),
+                    String::from_utf8_lossy(&buf),
+                    buf),
+            },
+            _ => (),
         };
 
         buf.clear();
         i += 1;
     }
 
-    // Close the BufWriter to flush it
-    let _ = out.into_inner()?;
-
     Ok(())
 }
 
+/// Sort by user, then password. The sort is stable, so equal pairs keep the
+/// order they were read in
+fn sort_lines(lines: &mut [SortedLine]) {
+    lines.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+}
+
 pub fn run_fsck(args: &Fsck) -> Result<()> {
+    let out: Box<dyn Write> = match args.output {
+        Some(ref path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+
+    let to_delimiter = match args.to_delimiter {
+        Some(Delimiter::Char(c)) => Some(c),
+        Some(Delimiter::Auto) => bail!("--to-delimiter can't be auto"),
+        None => None,
+    };
+
+    let mut filter = Filter::new(args)?;
+    let mut sorted = Vec::new();
     for path in &args.paths {
-        validate_file(path, &args)?;
+        filter.delimiter = match args.delimiter {
+            Delimiter::Char(c) => c,
+            Delimiter::Auto => detect_delimiter(path, args.json)?,
+        };
+        validate_file(path, args, &mut filter, to_delimiter, &mut out, &mut sorted)?;
+    }
+
+    if !args.silent {
+        sort_lines(&mut sorted);
+        for line in &sorted {
+            out.write_all(&line.2)?;
+        }
+    }
+    // Close the BufWriter to flush it
+    let _ = out.into_inner()
+        .map_err(|err| err.into_error())?;
+
+    let stats = &filter.stats;
+    if args.json {
+        return write_event(&Event::Summary(stats));
+    }
+    eprintln!("{} lines read, {} kept, {} warnings, {} invalid, {} duplicates, {} repeated users",
+        stats.read, stats.kept, stats.warnings, stats.invalid, stats.duplicates, stats.repeated_users);
+    if args.filter_user.is_some() || args.filter_password.is_some() {
+        eprintln!("{} lines matched, {} filtered", stats.matched, stats.filtered);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(dedup: bool, unique_users: bool) -> Filter {
+        Filter {
+            require_colon: true,
+            delimiter: ':',
+            dedup,
+            unique_users,
+            ..Filter::default()
+        }
+    }
+
+    #[test]
+    fn verify_dedup() {
+        let mut filter = filter(true, false);
+        assert_eq!(filter.check(b"root:toor\n"), Verdict::Keep);
+        assert_eq!(filter.check(b"root:admin\n"), Verdict::Keep);
+        assert_eq!(filter.check(b"root:toor\n"), Verdict::Duplicate);
+        assert_eq!(filter.check(b"root\n"), Verdict::Invalid(Problem::NoDelimiter));
+        assert_eq!(filter.check(b"admin:\xff\n"), Verdict::Invalid(Problem::InvalidUtf8));
+        assert_eq!(filter.stats.read, 5);
+        assert_eq!(filter.stats.kept, 2);
+        assert_eq!(filter.stats.invalid, 2);
+        assert_eq!(filter.stats.duplicates, 1);
+    }
+
+    #[test]
+    fn verify_unique_users() {
+        let mut filter = filter(true, true);
+        assert_eq!(filter.check(b"root:toor\n"), Verdict::Keep);
+        assert_eq!(filter.check(b"root:toor\n"), Verdict::Duplicate);
+        assert_eq!(filter.check(b"root:admin\n"), Verdict::RepeatedUser);
+        assert_eq!(filter.check(b"admin:root:x\n"), Verdict::Warn(Problem::MultipleDelimiters));
+        assert_eq!(filter.check(b"admin:admin\n"), Verdict::RepeatedUser);
+        assert_eq!(filter.stats.kept, 2);
+    }
+
+    #[test]
+    fn verify_problems() {
+        let mut filter = filter(false, false);
+        assert_eq!(filter.check(b":toor\n"), Verdict::Invalid(Problem::EmptyUser));
+        assert_eq!(filter.check(b"root:\n"), Verdict::Invalid(Problem::EmptyPassword));
+        assert_eq!(filter.check(b"root:to"), Verdict::Invalid(Problem::Truncated));
+        assert_eq!(filter.check(b"root:\n"), Verdict::Invalid(Problem::EmptyPassword));
+        assert_eq!(filter.stats.problems.get(&Problem::EmptyPassword), Some(&2));
+
+        assert_eq!(filter.check(b"root:pass:phrase\n"), Verdict::Warn(Problem::MultipleDelimiters));
+        assert_eq!(filter.check(b"root:a:b:c\n"), Verdict::Warn(Problem::MultipleDelimiters));
+        assert_eq!(filter.check(b"\"acme:root\":secret\n"), Verdict::Keep);
+        assert_eq!((filter.stats.kept, filter.stats.warnings), (3, 2));
+
+        // only the encoding is checked without -c
+        filter.require_colon = false;
+        assert_eq!(filter.check(b"root"), Verdict::Keep);
+        assert_eq!(filter.check(b":\n"), Verdict::Keep);
+    }
+
+    #[test]
+    fn verify_json() {
+        let problem = JsonProblem::new("combos.txt", 3, Problem::InvalidUtf8, b"admin:\xff\n");
+        assert_eq!(serde_json::to_string(&Event::Problem(problem)).unwrap(),
+            r#"{"event":"problem","path":"combos.txt","line":3,"reason":"invalid_utf8","raw":"YWRtaW46/wo=","base64":true}"#);
+
+        let mut filter = filter(false, false);
+        filter.check(b"root:toor\n");
+        filter.check(b"root\n");
+        assert_eq!(serde_json::to_string(&Event::Summary(&filter.stats)).unwrap(),
+            r#"{"event":"summary","read":2,"kept":1,"warnings":0,"invalid":1,"matched":1,"filtered":0,"duplicates":0,"repeated_users":0,"problems":{"no_delimiter":1}}"#);
+    }
+
+    #[test]
+    fn verify_patterns() {
+        let mut filter = filter(true, false);
+        filter.user_pattern = Some(Regex::new("(?i)@ourclient\\.com$").unwrap());
+        assert_eq!(filter.check(b"jane@OurClient.com:secret\n"), Verdict::Keep);
+        assert_eq!(filter.check(b"joe@other.com:secret\n"), Verdict::Filtered);
+        assert_eq!(filter.check(b"jane@ourclient.com:secret\n"), Verdict::Keep);
+        assert_eq!(filter.check(b"jane@ourclient.com:secret\n"), Verdict::Duplicate);
+        assert_eq!(filter.check(b"jane@ourclient.com\n"), Verdict::Invalid(Problem::NoDelimiter));
+        assert_eq!((filter.stats.matched, filter.stats.filtered), (3, 1));
+
+        filter.password_pattern = Some(Regex::new("^.{8,}$").unwrap());
+        assert_eq!(filter.check(b"john@ourclient.com:secret\n"), Verdict::Filtered);
+        assert_eq!(filter.check(b"john@ourclient.com:password1\n"), Verdict::Keep);
+
+        filter.invert = true;
+        assert_eq!(filter.check(b"bob@other.com:password1\n"), Verdict::Keep);
+        assert_eq!(filter.check(b"bob@ourclient.com:password2\n"), Verdict::Filtered);
+    }
+
+    #[test]
+    fn verify_sort() {
+        let mut lines = ["root:toor\n", "admin:b\r\n", "admin:a\n", "admin\n", "admin:a\n"].iter()
+            .map(|line| {
+                let (user, password) = split_line(line, ':');
+                (user.into_owned(), password.map(|x| x.to_string()), line.as_bytes().to_vec())
+            })
+            .collect::<Vec<_>>();
+        sort_lines(&mut lines);
+        let lines = lines.iter()
+            .map(|x| str::from_utf8(&x.2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["admin\n", "admin:a\n", "admin:a\n", "admin:b\r\n", "root:toor\n"]);
+    }
+
+    #[test]
+    fn verify_delimiter() {
+        assert_eq!("auto".parse::<Delimiter>().unwrap(), Delimiter::Auto);
+        assert_eq!("tab".parse::<Delimiter>().unwrap(), Delimiter::Char('\t'));
+        assert_eq!("\\t".parse::<Delimiter>().unwrap(), Delimiter::Char('\t'));
+        assert_eq!("|".parse::<Delimiter>().unwrap(), Delimiter::Char('|'));
+        assert!("::".parse::<Delimiter>().is_err());
+        assert!("".parse::<Delimiter>().is_err());
+
+        let sample = ["jane@acme.com;pass:word", "joe@acme.com;secret", "acme:jim;x", "invalid"].iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(guess_delimiter(&sample), Some((';', 2)));
+        assert_eq!(guess_delimiter(&sample[3..]), None);
+    }
+
+    #[test]
+    fn verify_convert() {
+        assert_eq!(convert_line("jane@acme.com;pass:word\n", ';', ':'), "jane@acme.com:pass:word\n");
+        assert_eq!(convert_line("acme:jane\tsecret\r\n", '\t', ':'), "\"acme:jane\":secret\r\n");
+        assert_eq!(convert_line("\"acme:jane\":secret", ':', '|'), "acme:jane|secret");
+        assert_eq!(convert_line("invalid\n", ';', ':'), "invalid\n");
+
+        let mut filter = filter(true, false);
+        filter.delimiter = ';';
+        assert_eq!(filter.check(b"jane;secret\n"), Verdict::Keep);
+        assert_eq!(filter.check(b"jane:secret\n"), Verdict::Invalid(Problem::NoDelimiter));
+        assert_eq!(filter.check(b"\"jane\";secret\n"), Verdict::Duplicate);
+    }
+}
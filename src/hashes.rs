@@ -0,0 +1,228 @@
+//! Load the accounts of a secretsdump or pwdump file, see --input-format
+//!
+//! Both formats have a `user:rid:lmhash:nthash:::` line per account, the user
+//! may be prefixed with the domain as in `DOMAIN\user`. The NT hash is
+//! attempted as the password, scripts get the rest with cred_info.
+
+use errors::{Error, Result, ResultExt};
+use hlua::AnyLuaValue;
+use utils::{self, Cred, ListOptions};
+
+use std::io::BufRead;
+use std::str::{self, FromStr};
+use std::sync::Arc;
+
+// pwdump writes this instead of a hash if the account has no password
+const NO_PASSWORD: &str = "NO PASSWORD*********************";
+// the hashes of an empty password, the LM hash is also used if LM is disabled
+const EMPTY_LM: &str = "aad3b435b51404eeaad3b435b51404ee";
+const EMPTY_NT: &str = "31d6cfe0d16ae931b73c59d7e0c089c0";
+
+
+/// The format of the creds list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Plain,
+    Secretsdump,
+    Pwdump,
+}
+
+impl FromStr for InputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<InputFormat> {
+        match s {
+            "plain" => Ok(InputFormat::Plain),
+            "secretsdump" => Ok(InputFormat::Secretsdump),
+            "pwdump" => Ok(InputFormat::Pwdump),
+            _ => bail!("unknown input format {:?}, expected plain, secretsdump or pwdump", s),
+        }
+    }
+}
+
+impl InputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            InputFormat::Plain => "plain",
+            InputFormat::Secretsdump => "secretsdump",
+            InputFormat::Pwdump => "pwdump",
+        }
+    }
+}
+
+/// An account of a dump, the hashes are lowercase hex
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NtHash {
+    pub user: String,
+    pub domain: Option<String>,
+    pub rid: u32,
+    // none if LM hashes are disabled
+    pub lm: Option<String>,
+    pub nt: String,
+}
+
+impl NtHash {
+    /// The value of cred_info
+    pub fn to_lua(&self) -> AnyLuaValue {
+        let mut fields = vec![
+            (AnyLuaValue::LuaString("user".to_string()), AnyLuaValue::LuaString(self.user.clone())),
+            (AnyLuaValue::LuaString("rid".to_string()), AnyLuaValue::LuaNumber(f64::from(self.rid))),
+            (AnyLuaValue::LuaString("nt".to_string()), AnyLuaValue::LuaString(self.nt.clone())),
+        ];
+        if let Some(ref domain) = self.domain {
+            fields.push((AnyLuaValue::LuaString("domain".to_string()), AnyLuaValue::LuaString(domain.clone())));
+        }
+        if let Some(ref lm) = self.lm {
+            fields.push((AnyLuaValue::LuaString("lm".to_string()), AnyLuaValue::LuaString(lm.clone())));
+        }
+        AnyLuaValue::LuaArray(fields)
+    }
+}
+
+fn parse_hash(hash: &str, what: &str) -> ::std::result::Result<String, String> {
+    if hash.len() != 32 || !hash.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return Err(format!("invalid {} hash", what));
+    }
+    Ok(hash.to_lowercase())
+}
+
+/// Parse a line of the dump, the fields after the NT hash are ignored
+pub fn parse_line(line: &str) -> ::std::result::Result<NtHash, String> {
+    let fields = line.splitn(5, ':').collect::<Vec<_>>();
+    if fields.len() < 4 {
+        return Err("expected user:rid:lmhash:nthash:::".to_string());
+    }
+
+    let (domain, user) = match fields[0].find('\\') {
+        Some(idx) => (Some(&fields[0][..idx]), &fields[0][idx+1..]),
+        None => (None, fields[0]),
+    };
+    if user.is_empty() {
+        return Err("empty user".to_string());
+    }
+    let rid = fields[1].parse()
+        .map_err(|_| format!("invalid rid {:?}", fields[1]))?;
+    let lm = match fields[2] {
+        NO_PASSWORD => None,
+        lm => match parse_hash(lm, "LM")? {
+            ref lm if lm == EMPTY_LM => None,
+            lm => Some(lm),
+        },
+    };
+    let nt = match fields[3] {
+        NO_PASSWORD => EMPTY_NT.to_string(),
+        nt => parse_hash(nt, "NT")?,
+    };
+
+    Ok(NtHash {
+        user: user.to_string(),
+        domain: domain.filter(|x| !x.is_empty()).map(|x| x.to_string()),
+        rid,
+        lm,
+        nt,
+    })
+}
+
+/// Parse a dump, returns the accounts and the lines that were skipped with
+/// their line number. secretsdump also prints status lines like
+/// `[*] Dumping domain credentials` and the kerberos keys of every account,
+/// those are skipped silently
+pub fn parse_hashes<R: BufRead>(file: R, format: InputFormat, options: &ListOptions) -> Result<(Vec<Cred>, Vec<String>)> {
+    let mut creds = Vec::new();
+    let mut skipped = Vec::new();
+
+    for (i, line) in file.split(b'\n').enumerate() {
+        let lineno = i + 1;
+        let line = line?;
+        let line = str::from_utf8(&line)
+            .chain_err(|| format!("line {}: failed to decode utf8", lineno))?;
+        let line = options.normalize(line, lineno == 1);
+
+        if line.trim().is_empty() {
+            continue;
+        }
+        if format == InputFormat::Secretsdump && (line.starts_with('[') || line.split(':').count() == 3) {
+            continue;
+        }
+
+        match parse_line(line) {
+            Ok(hash) => creds.push(Cred::Hash(Arc::new(hash))),
+            Err(err) => skipped.push(format!("line {}: {}", lineno, err)),
+        }
+    }
+
+    Ok((creds, skipped))
+}
+
+/// Load a dump, returns the accounts, the number of duplicates that were
+/// removed and the lines that were skipped
+pub fn load_hashes(path: &str, format: InputFormat, options: &ListOptions) -> Result<(Vec<Cred>, usize, Vec<String>)> {
+    let (creds, skipped) = parse_hashes(utils::open(path)?, format, options)?;
+    let (creds, duplicates) = options.dedup(creds);
+    Ok((creds, duplicates, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(cred: &Cred) -> &NtHash {
+        match *cred {
+            Cred::Hash(ref hash) => hash,
+            _ => panic!("expected a hash"),
+        }
+    }
+
+    #[test]
+    fn verify_parse_line() {
+        assert_eq!(parse_line("CORP\\alice:1104:aad3b435b51404eeaad3b435b51404ee:32ED87BDB5FDC5E9CBA88547376818D4:::"), Ok(NtHash {
+            user: "alice".to_string(),
+            domain: Some("CORP".to_string()),
+            rid: 1104,
+            lm: None,
+            nt: "32ed87bdb5fdc5e9cba88547376818d4".to_string(),
+        }));
+        assert_eq!(parse_line("Guest:501:NO PASSWORD*********************:NO PASSWORD*********************:::"), Ok(NtHash {
+            user: "Guest".to_string(),
+            domain: None,
+            rid: 501,
+            lm: None,
+            nt: EMPTY_NT.to_string(),
+        }));
+        // with -pwd-last-set, the fields after the hash are ignored
+        let hash = parse_line("bob:1105:e52cac67419a9a224a3b108f3fa6cb6d:8846f7eaee8fb117ad06bdd830b7586c::: (pwdLastSet=2018-06-01 12:00)").unwrap();
+        assert_eq!(hash.lm, Some("e52cac67419a9a224a3b108f3fa6cb6d".to_string()));
+
+        assert_eq!(parse_line("alice:password"), Err("expected user:rid:lmhash:nthash:::".to_string()));
+        assert_eq!(parse_line("CORP\\:1104:aad3b435b51404eeaad3b435b51404ee:32ed87bdb5fdc5e9cba88547376818d4:::"), Err("empty user".to_string()));
+        assert_eq!(parse_line("alice:x:aad3b435b51404eeaad3b435b51404ee:32ed87bdb5fdc5e9cba88547376818d4:::"), Err("invalid rid \"x\"".to_string()));
+        assert_eq!(parse_line("alice:1104:aad3b435b51404eeaad3b435b51404ee:32ed87bdb5fdc5e9:::"), Err("invalid NT hash".to_string()));
+    }
+
+    #[test]
+    fn verify_parse_secretsdump() {
+        let dump = b"[*] Dumping Domain Credentials (domain\\uid:rid:lmhash:nthash)\n\
+            CORP\\alice:1104:aad3b435b51404eeaad3b435b51404ee:32ed87bdb5fdc5e9cba88547376818d4:::\n\
+            CORP\\bob:1105:aad3b435b51404eeaad3b435b51404ee:zzz:::\n\
+            \n\
+            [*] Kerberos keys grabbed\n\
+            CORP\\alice:aes256-cts-hmac-sha1-96:0123456789abcdef\n\
+            krbtgt:502:aad3b435b51404eeaad3b435b51404ee:8846f7eaee8fb117ad06bdd830b7586c:::\r\n";
+        let (creds, skipped) = parse_hashes(&dump[..], InputFormat::Secretsdump, &ListOptions::default()).unwrap();
+        assert_eq!(creds.len(), 2);
+        assert_eq!(hash(&creds[0]).user, "alice");
+        assert_eq!(hash(&creds[1]).user, "krbtgt");
+        assert_eq!(hash(&creds[1]).domain, None);
+        assert_eq!(skipped, vec!["line 3: invalid NT hash"]);
+
+        // pwdump has no status lines, they are reported
+        let (creds, skipped) = parse_hashes(&dump[..], InputFormat::Pwdump, &ListOptions::default()).unwrap();
+        assert_eq!(creds.len(), 2);
+        assert_eq!(skipped, vec![
+            "line 1: invalid rid \"rid\"",
+            "line 3: invalid NT hash",
+            "line 5: expected user:rid:lmhash:nthash:::",
+            "line 6: expected user:rid:lmhash:nthash:::",
+        ]);
+    }
+}
@@ -4,23 +4,44 @@ use structs::LuaMap;
 use reqwest;
 use reqwest::header::Headers;
 use reqwest::header::Cookie;
+use reqwest::header::Location;
 use reqwest::header::UserAgent;
+use reqwest::Url;
 use hlua::AnyLuaValue;
+use serde::{Deserialize, Deserializer};
 use serde_json;
-use json::LuaJsonValue;
+use json::{self, LuaJsonValue};
 use std::collections::HashMap;
-use std::ops::Deref;
-use std::sync::Arc;
+use std::io::Read;
+use std::net::IpAddr;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use rand::{Rng, thread_rng};
 use rand::distributions::Alphanumeric;
-use config::Config;
+use config::{Config, Rotation};
 use ctx::State;
 
+// pick one of the configured user agents instead
+const RANDOM_USER_AGENT: &str = "random";
+// how long a 429 without Retry-After pauses the script
+const DEFAULT_THROTTLE: Duration = Duration::from_secs(30);
+// a Retry-After can't pause the attack for longer than this
+const MAX_THROTTLE: Duration = Duration::from_secs(3600);
 
-#[derive(Debug)]
+// the proxy and if the hostname is verified
+type ClientKey = (Option<String>, bool);
+
+#[derive(Debug, Clone)]
 pub struct HttpSession {
     id: String,
     pub cookies: CookieJar,
+    // built by the first request through each proxy and shared with the
+    // copies of the session. A session of worker_setup keeps its connections
+    // open for the following attempts, other sessions only for one attempt
+    clients: Arc<Mutex<HashMap<ClientKey, reqwest::Client>>>,
+    // picked by the first request if user_agent_rotation is session
+    user_agent: Arc<Mutex<Option<Option<String>>>>,
 }
 
 impl HttpSession {
@@ -29,19 +50,172 @@ impl HttpSession {
         (id.clone(), HttpSession {
             id,
             cookies: CookieJar::default(),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            user_agent: Arc::new(Mutex::new(None)),
         })
     }
+
+    /// The user agent of the session, it's picked with `pick` if this is
+    /// the first request
+    pub fn user_agent<F: FnOnce() -> Option<String>>(&self, pick: F) -> Option<String> {
+        self.user_agent.lock().unwrap()
+            .get_or_insert_with(pick)
+            .clone()
+    }
+
+    /// The client of the session for the proxy of the attempt, it's built
+    /// with `build` if this is the first request through that proxy
+    pub fn client<F: FnOnce() -> Result<reqwest::Client>>(&self, proxy: Option<&str>, verify_hostname: bool, build: F) -> Result<reqwest::Client> {
+        let key = (proxy.map(|x| x.to_string()), verify_hostname);
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let new = build()?;
+        clients.insert(key, new.clone());
+        Ok(new)
+    }
+}
+
+/// The settings are the same for all clients, except for the timeout and the
+/// hostname verification that http_basic_auth can override. The proxy is
+/// the one of the attempt, see State::http_proxy
+fn build_client(state: &State, timeout: Duration, verify_hostname: bool) -> Result<reqwest::Client> {
+    let runtime = &state.config().runtime;
+    let mut builder = reqwest::Client::builder();
+    builder.redirect(reqwest::RedirectPolicy::none()); // TODO: this should be configurable
+    builder.timeout(timeout);
+    if let Some(proxy) = state.http_proxy() {
+        builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    if let Some(ref ca) = runtime.tls_ca {
+        builder.add_root_certificate(reqwest::Certificate::from_pem(ca)?);
+    }
+    if !verify_hostname {
+        builder.danger_disable_hostname_verification();
+    }
+    let client = builder.build()?;
+    Ok(client)
 }
 
 #[derive(Debug, Default, Deserialize)]
 pub struct RequestOptions {
-    query: Option<HashMap<String, String>>,
+    query: Option<Query>,
     headers: Option<HashMap<String, String>>,
+    cookies: Option<HashMap<String, String>>,
     basic_auth: Option<(String, String)>,
     user_agent: Option<String>,
     json: Option<serde_json::Value>,
     form: Option<serde_json::Value>,
-    body: Option<String>,
+    body: Option<RawBody>,
+    #[serde(default)]
+    array_style: ArrayStyle,
+    max_response_bytes: Option<u64>,
+}
+
+/// The body option is a string, a byte string or a table of bytes
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawBody {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<RawBody> for Vec<u8> {
+    fn from(body: RawBody) -> Vec<u8> {
+        match body {
+            RawBody::Text(text) => text.into_bytes(),
+            RawBody::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// The query option, a table of values or lists of values, a list of pairs
+/// that keeps its order, or a string that is already encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Query {
+    Encoded(String),
+    Pairs(Vec<(String, serde_json::Value)>),
+    Map(HashMap<String, serde_json::Value>),
+}
+
+impl Query {
+    /// The encoded query has no pairs
+    fn pairs(&self, style: ArrayStyle) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        match *self {
+            Query::Encoded(_) => (),
+            Query::Pairs(ref values) => for (key, value) in values {
+                push_pair(&mut pairs, key, value, style)?;
+            },
+            Query::Map(ref values) => for (key, value) in values {
+                push_pair(&mut pairs, key, value, style)?;
+            },
+        }
+        Ok(pairs)
+    }
+}
+
+/// How a list of values is encoded in query and form
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArrayStyle {
+    // id=1&id=2
+    #[default]
+    Repeat,
+    // id[]=1&id[]=2
+    Brackets,
+}
+
+fn push_pair(pairs: &mut Vec<(String, String)>, key: &str, value: &serde_json::Value, style: ArrayStyle) -> Result<()> {
+    use serde_json::Value;
+
+    fn scalar(key: &str, value: &Value) -> Result<String> {
+        match *value {
+            Value::String(ref x) => Ok(x.clone()),
+            Value::Number(ref x) => Ok(x.to_string()),
+            Value::Bool(x) => Ok(x.to_string()),
+            _ => bail!("invalid value for {:?}, expected a string, number or boolean", key),
+        }
+    }
+
+    match *value {
+        Value::Array(ref values) => {
+            let key = match style {
+                ArrayStyle::Repeat => key.to_string(),
+                ArrayStyle::Brackets => format!("{}[]", key),
+            };
+            for value in values {
+                pairs.push((key.clone(), scalar(&key, value)?));
+            }
+        },
+        // an empty lua table isn't a list
+        Value::Object(ref x) if x.is_empty() => (),
+        Value::Null => (),
+        ref value => pairs.push((key.to_string(), scalar(key, value)?)),
+    }
+    Ok(())
+}
+
+/// The form option, a table or a list of pairs that keeps its order
+fn form_pairs(form: &serde_json::Value, style: ArrayStyle) -> Result<Vec<(String, String)>> {
+    use serde_json::Value;
+
+    let mut pairs = Vec::new();
+    match *form {
+        Value::Object(ref values) => for (key, value) in values {
+            push_pair(&mut pairs, key, value, style)?;
+        },
+        Value::Array(ref values) => for pair in values {
+            match pair.as_array().map(|x| x.as_slice()) {
+                Some(&[Value::String(ref key), ref value]) => push_pair(&mut pairs, key, value, style)?,
+                _ => bail!("invalid form pair, expected {{key, value}}"),
+            }
+        },
+        _ => bail!("form must be a table"),
+    }
+    Ok(pairs)
 }
 
 impl RequestOptions {
@@ -52,6 +226,92 @@ impl RequestOptions {
     }
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct BasicAuthOptions {
+    // in seconds, overrides http_timeout
+    timeout: Option<u64>,
+    user_agent: Option<String>,
+    // overrides tls_verify_hostname
+    tls_verify: Option<bool>,
+    // the status codes of a valid login
+    success: Option<Vec<u16>>,
+}
+
+impl BasicAuthOptions {
+    #[inline]
+    pub fn try_from(x: AnyLuaValue) -> Result<BasicAuthOptions> {
+        json::options(x)
+    }
+}
+
+/// How long the target asked us to slow down, for a 429 and a 503 with
+/// Retry-After. Retry-After is either a number of seconds or an http date
+fn throttle_delay(status: u16, retry_after: Option<&str>, now: i64) -> Option<Duration> {
+    let retry_after = retry_after.map(|x| x.trim()).and_then(|value| match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => parse_http_date(value).map(|date| Duration::from_secs(date.saturating_sub(now).max(0) as u64)),
+    });
+    let delay = match (status, retry_after) {
+        (429, delay) => delay.unwrap_or(DEFAULT_THROTTLE),
+        (503, Some(delay)) => delay,
+        _ => return None,
+    };
+    Some(delay.min(MAX_THROTTLE))
+}
+
+/// Pause the script if the response asked us to slow down, see throttle
+fn check_throttle(state: &State, res: &reqwest::Response) {
+    let retry_after = res.headers().get_raw("retry-after")
+        .and_then(|raw| raw.one())
+        .and_then(|value| str::from_utf8(value).ok());
+    if let Some(delay) = throttle_delay(res.status().as_u16(), retry_after, ::time::get_time().sec) {
+        debug!("throttled by {} for {:?}", res.url(), delay);
+        state.throttle(delay);
+    }
+}
+
+/// Without an explicit list any 2xx is a valid login, and a 3xx unless the
+/// server asked for credentials again
+fn is_authenticated(status: u16, challenge: bool, success: Option<&[u16]>) -> bool {
+    match success {
+        Some(success) => success.contains(&status),
+        None => match status {
+            200..=299 => true,
+            300..=399 => !challenge,
+            _ => false,
+        },
+    }
+}
+
+/// Send a `GET` request with basic auth, redirects aren't followed
+pub fn basic_auth(state: &State, url: &str, user: String, password: String, options: BasicAuthOptions) -> Result<bool> {
+    let config = state.config();
+    let mut timeout = options.timeout.map(Duration::from_secs)
+        .unwrap_or_else(|| config.runtime.http_timeout());
+    if let Some(remaining) = state.remaining()? {
+        timeout = timeout.min(remaining);
+    }
+    let verify_hostname = options.tls_verify.unwrap_or_else(|| config.runtime.verify_hostname());
+    let client = build_client(state, timeout, verify_hostname)?;
+
+    let mut req = client.get(url);
+    req.basic_auth(user, Some(password));
+    let user_agent = match options.user_agent {
+        Some(ref agent) if agent != RANDOM_USER_AGENT => Some(agent.clone()),
+        _ => config.runtime.pick_user_agent(),
+    };
+    if let Some(agent) = user_agent {
+        req.header(UserAgent::new(agent));
+    }
+
+    let res = req.send()
+        .chain_err(|| "http request failed")?;
+    debug!("http_basic_auth: {} {}", url, res.status());
+    check_throttle(state, &res);
+    let challenge = res.headers().get_raw("www-authenticate").is_some();
+    Ok(is_authenticated(res.status().as_u16(), challenge, options.success.as_ref().map(|x| &x[..])))
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct HttpRequest {
     // reference to the HttpSession
@@ -59,18 +319,32 @@ pub struct HttpRequest {
     cookies: CookieJar,
     method: String,
     url: String,
-    query: Option<HashMap<String, String>>,
+    query: Option<Query>,
     headers: Option<HashMap<String, String>>,
+    // sent with this request only, they win over the cookies of the session
+    #[serde(default)]
+    extra_cookies: Option<HashMap<String, String>>,
     basic_auth: Option<(String, String)>,
     user_agent: Option<String>,
     body: Option<Body>,
+    #[serde(default)]
+    array_style: ArrayStyle,
+    // overrides http_max_response
+    max_response_bytes: Option<u64>,
 }
 
 impl HttpRequest {
     pub fn new(config: &Arc<Config>, session: &HttpSession, method: String, url: String, options: RequestOptions) -> HttpRequest {
         let cookies = session.cookies.clone();
 
-        let user_agent = options.user_agent.or_else(|| config.runtime.user_agent.clone());
+        // without a user agent of the script, a configured one is picked
+        let user_agent = match options.user_agent {
+            Some(ref agent) if agent != RANDOM_USER_AGENT => Some(agent.clone()),
+            _ => match config.runtime.user_agent_rotation {
+                Rotation::Request => config.runtime.pick_user_agent(),
+                Rotation::Session => session.user_agent(|| config.runtime.pick_user_agent()),
+            },
+        };
 
         let mut request = HttpRequest {
             session: session.id.clone(),
@@ -79,9 +353,12 @@ impl HttpRequest {
             url,
             query: options.query,
             headers: options.headers,
+            extra_cookies: options.cookies,
             basic_auth: options.basic_auth,
             user_agent,
             body: None,
+            array_style: options.array_style,
+            max_response_bytes: options.max_response_bytes,
         };
 
         if let Some(json) = options.json {
@@ -92,8 +369,8 @@ impl HttpRequest {
             request.body = Some(Body::Form(form));
         }
 
-        if let Some(text) = options.body {
-            request.body = Some(Body::Raw(text));
+        if let Some(body) = options.body {
+            request.body = Some(Body::Raw(body.into()));
         }
 
         request
@@ -102,18 +379,20 @@ impl HttpRequest {
     pub fn send(&self, state: &State) -> Result<LuaMap> {
         debug!("http send: {:?}", self);
 
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::RedirectPolicy::none()) // TODO: this should be configurable
-            .build().unwrap();
+        let config = state.config();
+        let timeout = config.runtime.http_timeout();
+        // the timeout can only be set for the whole client. Close to the
+        // deadline of the attempt a client with a shorter timeout is used
+        // so we don't wait past it
+        let client = match state.remaining()? {
+            Some(remaining) if remaining < timeout => build_client(state, remaining, config.runtime.verify_hostname())?,
+            _ => state.http_client(&self.session, config.runtime.verify_hostname(), || build_client(state, timeout, config.runtime.verify_hostname()))?,
+        };
         let method = self.method.parse()
                         .chain_err(|| "Invalid http method")?;
-        let mut req = client.request(method, &self.url);
+        let mut req = client.request(method, &self.url()?);
 
-        let mut cookie = Cookie::new();
-        for (key, value) in self.cookies.iter() {
-            cookie.append(key.clone(), value.clone());
-        }
-        req.header(cookie);
+        req.header(self.cookie_header(::time::get_time().sec));
 
         if let Some(ref agent) = self.user_agent {
             req.header(UserAgent::new(agent.clone()));
@@ -124,72 +403,244 @@ impl HttpRequest {
             req.basic_auth(user.clone(), Some(password.clone()));
         }
 
-        if let Some(ref headers) = self.headers {
-            let mut hdrs = Headers::new();
-            for (k, v) in headers {
-                hdrs.set_raw(k.clone(), v.clone());
-            }
-            req.headers(hdrs);
-        }
-
         if let Some(ref query) = self.query {
-            req.query(query);
+            let pairs = query.pairs(self.array_style)?;
+            if !pairs.is_empty() {
+                req.query(&pairs);
+            }
         }
 
         match self.body {
             Some(Body::Raw(ref x))  => { req.body(x.clone()); },
-            Some(Body::Form(ref x)) => { req.form(x); },
+            Some(Body::Form(ref x)) => { req.form(&form_pairs(x, self.array_style)?); },
             Some(Body::Json(ref x)) => { req.json(x); },
             None => (),
         };
 
-        info!("http req: {:?}", req);
+        // set after the body so the content-type of json and form can be
+        // overwritten, raw bodies don't get one
+        if let Some(ref headers) = self.headers {
+            let mut hdrs = Headers::new();
+            for (k, v) in headers {
+                hdrs.set_raw(k.clone(), v.clone());
+            }
+            req.headers(hdrs);
+        }
+
+        debug!("http req: {} {} ({:?})", self.method, self.url, self.user_agent);
+        let start = Instant::now();
         let mut res = req.send()?;
-        info!("http res: {:?}", res);
+        let elapsed = start.elapsed();
+        debug!("http res: {} after {}ms", res.status(), duration_ms(elapsed));
+        check_throttle(state, &res);
 
         let mut resp = LuaMap::new();
         let status = res.status();
         resp.insert_num("status", f64::from(status.as_u16()));
+        resp.insert_num("time_ms", duration_ms(elapsed));
+        // redirects aren't followed, this is the url with the query
+        resp.insert_str("url", res.url().as_str());
+        if let Some(ref agent) = self.user_agent {
+            resp.insert_str("user_agent", agent.clone());
+        }
+        if let Some(location) = res.headers().get::<Location>() {
+            resp.insert_str("location", percent_decode(location));
+        }
 
         if let Some(cookies) = res.headers().get_raw("set-cookie") {
-            HttpRequest::register_cookies_on_state(&self.session, state, cookies);
+            HttpRequest::register_cookies_on_state(&self.session, state, res.url(), cookies);
         }
 
-        let mut headers = LuaMap::new();
-        for header in res.headers().iter() {
-            headers.insert_str(header.name().to_lowercase(), header.value_string());
-        }
+        let (headers, headers_all) = response_headers(res.headers());
         resp.insert("headers", headers);
+        resp.insert("headers_all", headers_all);
 
-        if let Ok(text) = res.text() {
-            resp.insert_str("text", text);
-        }
+        let max = self.max_response_bytes.unwrap_or_else(|| config.runtime.http_max_response());
+        let (body, truncated) = read_body(&mut res, max)?;
+        resp.insert_str("text", String::from_utf8_lossy(&body));
+        resp.insert("truncated", AnyLuaValue::LuaBoolean(truncated));
+        resp.insert_num("time_total_ms", duration_ms(start.elapsed()));
 
         Ok(resp)
     }
 
-    fn register_cookies_on_state(session: &str, state: &State, cookies: &reqwest::header::Raw) {
+    /// The url with an encoded query appended, the other queries are added
+    /// by reqwest
+    fn url(&self) -> Result<String> {
+        match self.query {
+            Some(Query::Encoded(ref query)) => {
+                let mut url = Url::parse(&self.url)
+                    .chain_err(|| format!("invalid url {:?}", self.url))?;
+                let query = query.trim_start_matches('?');
+                let query = match url.query() {
+                    Some(old) if !old.is_empty() => format!("{}&{}", old, query),
+                    _ => query.to_string(),
+                };
+                url.set_query(Some(&query));
+                Ok(url.into_string())
+            },
+            _ => Ok(self.url.clone()),
+        }
+    }
+
+    /// The cookies of the session that match the url, and the cookies of
+    /// this request
+    fn cookie_header(&self, now: i64) -> Cookie {
+        let mut cookie = Cookie::new();
+        if let Ok(url) = Url::parse(&self.url) {
+            for c in self.cookies.matching(&url, now) {
+                cookie.append(c.name.clone(), c.value.clone());
+            }
+        }
+        if let Some(ref cookies) = self.extra_cookies {
+            for (name, value) in cookies {
+                cookie.set(name.clone(), value.clone());
+            }
+        }
+        cookie
+    }
+
+    fn register_cookies_on_state(session: &str, state: &State, url: &Url, cookies: &reqwest::header::Raw) {
+        let now = ::time::get_time().sec;
         let mut jar = Vec::new();
 
-        for cookie in cookies {
-            let mut key = String::new();
-            let mut value = String::new();
-            let mut in_key = true;
-
-            for c in cookie.iter() {
-                match *c as char {
-                    '=' if in_key => in_key = false,
-                    ';' => break,
-                    c if in_key => key.push(c),
-                    c => value.push(c),
+        for header in cookies {
+            let header = String::from_utf8_lossy(header);
+            for cookie in split_folded(&header) {
+                match SetCookie::parse(cookie, now) {
+                    Some(cookie) => jar.push(cookie),
+                    None => debug!("invalid set-cookie: {:?}", cookie),
                 }
             }
+        }
+
+        state.register_in_jar(session, url, jar, now);
+    }
+}
+
+/// The headers by their lowercase name. In the first map the values of a
+/// header that was sent more than once are joined with commas, the second
+/// map has a list with every value
+fn response_headers(headers: &Headers) -> (LuaMap, LuaMap) {
+    let mut joined = LuaMap::new();
+    let mut all = LuaMap::new();
+    for header in headers.iter() {
+        let name = header.name().to_lowercase();
+        let values = header.raw().iter()
+            .enumerate()
+            .map(|(i, x)| (AnyLuaValue::LuaNumber((i + 1) as f64), AnyLuaValue::LuaString(String::from_utf8_lossy(x).into_owned())))
+            .collect();
+        all.insert(name.clone(), AnyLuaValue::LuaArray(values));
+        joined.insert_str(name, header.value_string());
+    }
+    (joined, all)
+}
+
+/// A cookie of a Set-Cookie header, the value is kept as it was sent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    // without a leading dot
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    // unix time of Max-Age or Expires, none for a session cookie
+    pub expires: Option<i64>,
+}
+
+impl SetCookie {
+    /// Domain, Path, Max-Age and Expires are used, the other attributes are
+    /// ignored. None if there is no name
+    pub fn parse(header: &str, now: i64) -> Option<SetCookie> {
+        let mut attrs = header.split(';');
+        let pair = attrs.next()?;
+        let idx = pair.find('=')?;
+        let name = pair[..idx].trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut max_age = None;
+        let mut expires = None;
+        let mut domain = None;
+        let mut path = None;
+        for attr in attrs {
+            let (key, value) = match attr.find('=') {
+                Some(idx) => (attr[..idx].trim(), attr[idx+1..].trim()),
+                None => (attr.trim(), ""),
+            };
+            if key.eq_ignore_ascii_case("max-age") {
+                max_age = value.parse::<i64>().ok().or(max_age);
+            } else if key.eq_ignore_ascii_case("expires") {
+                expires = parse_http_date(value).or(expires);
+            } else if key.eq_ignore_ascii_case("domain") {
+                let value = value.trim_start_matches('.').to_lowercase();
+                domain = Some(value).filter(|x| !x.is_empty());
+            } else if key.eq_ignore_ascii_case("path") {
+                path = Some(value.to_string()).filter(|x| x.starts_with('/'));
+            }
+        }
+
+        Some(SetCookie {
+            name: name.to_string(),
+            value: pair[idx+1..].trim().to_string(),
+            domain,
+            path,
+            // Max-Age wins if both are set
+            expires: max_age.map(|max_age| now.saturating_add(max_age)).or(expires),
+        })
+    }
 
-            jar.push((key, value));
+    /// Max-Age=0 or an Expires in the past, the cookie is deleted
+    #[inline]
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+// RFC 1123, the netscape format, RFC 850 and asctime
+const HTTP_DATE_FORMATS: &[&str] = &[
+    "%a, %d %b %Y %H:%M:%S GMT",
+    "%a, %d-%b-%Y %H:%M:%S GMT",
+    "%A, %d-%b-%y %H:%M:%S GMT",
+    "%a %b %e %H:%M:%S %Y",
+];
+
+fn parse_http_date(date: &str) -> Option<i64> {
+    for format in HTTP_DATE_FORMATS {
+        if let Ok(mut tm) = ::time::strptime(date, format) {
+            // RFC 850 only has two digits
+            if format.contains("%y") && tm.tm_year < 70 {
+                tm.tm_year += 100;
+            }
+            return Some(tm.to_timespec().sec);
         }
+    }
+    None
+}
 
-        state.register_in_jar(session, jar);
+/// Split a header that has several cookies folded into one line. Expires has
+/// a comma as well, a comma only starts a new cookie if it's followed by a
+/// name and a value
+fn split_folded(header: &str) -> Vec<&str> {
+    let mut cookies = Vec::new();
+    let mut start = 0;
+    for (idx, _) in header.match_indices(',') {
+        let pair = header[idx+1..].split(';').next().unwrap_or("");
+        let is_cookie = match pair.find('=') {
+            Some(eq) => {
+                let name = pair[..eq].trim();
+                !name.is_empty() && !name.contains(char::is_whitespace)
+            },
+            None => false,
+        };
+        if is_cookie {
+            cookies.push(&header[start..idx]);
+            start = idx + 1;
+        }
     }
+    cookies.push(&header[start..]);
+    cookies
 }
 
 impl HttpRequest {
@@ -207,30 +658,626 @@ impl Into<AnyLuaValue> for HttpRequest {
     }
 }
 
+/// Decode %XX escapes, eg. in the location header so it can be compared with
+/// a path. Invalid escapes are kept as they are
+fn percent_decode(x: &str) -> String {
+    fn hex(x: u8) -> Option<u8> {
+        (x as char).to_digit(16).map(|x| x as u8)
+    }
+
+    let bytes = x.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                decoded.push(hi << 4 | lo);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Read up to `max` bytes of a body, returns if there was more. Everything
+/// that reads a response body goes through this, so a huge response or a
+/// tarpit can't fill the memory
+fn read_body<R: Read>(r: R, max: u64) -> Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+    r.take(max.saturating_add(1)).read_to_end(&mut body)
+        .chain_err(|| "failed to read response body")?;
+    let truncated = body.len() as u64 > max;
+    if truncated {
+        body.truncate(max as usize);
+    }
+    Ok((body, truncated))
+}
+
+#[inline]
+fn duration_ms(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
+/// A cookie in the jar of a session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionCookie {
+    pub name: String,
+    pub value: String,
+    // the host that set the cookie unless it had a Domain attribute, the
+    // cookies of older versions have none and are sent everywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    // without a Domain attribute, subdomains don't get the cookie
+    #[serde(default)]
+    pub host_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    // unix time, none for a session cookie
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+impl SessionCookie {
+    #[inline]
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, host: &str, path: &str, now: i64) -> bool {
+        if self.is_expired(now) {
+            return false;
+        }
+        let domain = match self.domain {
+            Some(ref domain) if self.host_only => host == domain,
+            Some(ref domain) => domain_match(host, domain),
+            None => true,
+        };
+        let path = match self.path {
+            Some(ref cookie_path) => path_match(path, cookie_path),
+            None => true,
+        };
+        domain && path
+    }
+}
+
+/// The host is the domain or a subdomain of it, ip addresses only match
+/// themselves
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain || (host.ends_with(domain)
+        && host[..host.len() - domain.len()].ends_with('.')
+        && host.parse::<IpAddr>().is_err())
+}
+
+/// The path is the cookie path or below it
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path || (path.starts_with(cookie_path)
+        && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// The directory of the url, used if a cookie has no Path attribute
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(idx) => &path[..idx],
+    }
+}
+
 // see https://github.com/seanmonstar/reqwest/issues/14 for proper cookie jars
-// maybe change this to reqwest::header::Cookie
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
-pub struct CookieJar(HashMap<String, String>);
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CookieJar(Vec<SessionCookie>);
 
 impl CookieJar {
-    pub fn register_in_jar(&mut self, cookies: Vec<(String, String)>) {
-        for (key, value) in cookies {
-            self.0.insert(key, value);
+    /// The cookies of a response from url. Expired cookies are removed from
+    /// the jar, cookies for a domain the url doesn't belong to are ignored
+    pub fn register_in_jar(&mut self, url: &Url, cookies: Vec<SetCookie>, now: i64) {
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        for cookie in cookies {
+            let expired = cookie.is_expired(now);
+            let SetCookie { name, value, domain, path, expires } = cookie;
+            let (domain, host_only) = match domain {
+                Some(domain) if domain_match(&host, &domain) => (domain, false),
+                Some(domain) => {
+                    debug!("ignoring cookie {:?} for {:?} from {:?}", name, domain, host);
+                    continue;
+                },
+                None => (host.clone(), true),
+            };
+            let path = path.unwrap_or_else(|| default_path(url.path()).to_string());
+
+            // the same cookie, or one of an older version that has no scope
+            self.0.retain(|x| x.name != name || match x.domain {
+                Some(ref x_domain) => *x_domain != domain || x.path.as_ref() != Some(&path),
+                None => false,
+            });
+            if !expired {
+                self.0.push(SessionCookie {
+                    name,
+                    value,
+                    domain: Some(domain),
+                    host_only,
+                    path: Some(path),
+                    expires,
+                });
+            }
         }
+        self.0.retain(|x| !x.is_expired(now));
+    }
+
+    /// The cookies that are sent to url, longer paths first
+    pub fn matching(&self, url: &Url, now: i64) -> Vec<&SessionCookie> {
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let mut cookies = self.0.iter()
+            .filter(|x| x.matches(&host, url.path(), now))
+            .collect::<Vec<_>>();
+        cookies.sort_by_key(|x| ::std::cmp::Reverse(x.path.as_ref().map_or(0, |x| x.len())));
+        cookies
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.0.clear();
     }
 }
 
-impl Deref for CookieJar {
-    type Target = HashMap<String, String>;
+impl<'de> Deserialize<'de> for CookieJar {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<CookieJar, D::Error> {
+        // older versions had a table of names and values
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Jar {
+            Cookies(Vec<SessionCookie>),
+            Names(HashMap<String, String>),
+        }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        let cookies = match Jar::deserialize(deserializer)? {
+            Jar::Cookies(cookies) => cookies,
+            Jar::Names(names) => names.into_iter()
+                .map(|(name, value)| SessionCookie {
+                    name,
+                    value,
+                    domain: None,
+                    host_only: false,
+                    path: None,
+                    expires: None,
+                })
+                .collect(),
+        };
+        Ok(CookieJar(cookies))
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Body {
-    Raw(String), // TODO: maybe Vec<u8>
+    #[serde(with = "raw_body")]
+    Raw(Vec<u8>),
     Form(serde_json::Value),
     Json(serde_json::Value),
 }
+
+/// Raw bodies are a string in lua unless they aren't valid utf-8, those are
+/// a table of bytes
+mod raw_body {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::str;
+    use super::RawBody;
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match str::from_utf8(body) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.collect_seq(body),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        RawBody::deserialize(deserializer).map(Vec::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ctx::Script;
+    use hlua::{AnyHashableLuaValue, AnyLuaString};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn verify_is_authenticated() {
+        assert!(is_authenticated(200, false, None));
+        assert!(is_authenticated(302, false, None));
+        assert!(!is_authenticated(302, true, None));
+        assert!(!is_authenticated(401, false, None));
+        assert!(!is_authenticated(403, false, None));
+        // a captive portal that redirects every request
+        assert!(!is_authenticated(302, false, Some(&[200, 204])));
+        assert!(is_authenticated(204, false, Some(&[200, 204])));
+    }
+
+    #[test]
+    fn verify_client_per_proxy() {
+        let session = HttpSession::new().1;
+        let built = AtomicUsize::new(0);
+        let build = || {
+            built.fetch_add(1, Ordering::SeqCst);
+            Ok(reqwest::Client::new())
+        };
+        session.client(Some("http://10.0.0.1:8080"), true, build).unwrap();
+        session.client(Some("http://10.0.0.1:8080"), true, build).unwrap();
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+        // an attempt that picked another proxy doesn't use the first one
+        session.client(Some("http://10.0.0.2:8080"), true, build).unwrap();
+        session.client(None, true, build).unwrap();
+        session.client(None, false, build).unwrap();
+        assert_eq!(built.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn verify_connection_reuse() {
+        // a tls handshake is done for every connection, counting the
+        // connections shows how many handshakes a https target would see
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        {
+            let connections = connections.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn(move || {
+                        let mut reader = BufReader::new(stream.try_clone().unwrap());
+                        let mut writer = stream;
+                        let mut line = String::new();
+                        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                            if line == "\r\n" {
+                                if writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").is_err() {
+                                    break;
+                                }
+                            }
+                            line.clear();
+                        }
+                    });
+                }
+            });
+        }
+
+        let script = Script::load_from(format!(r#"
+        descr = "keep-alive"
+
+        function verify(user, password)
+            session = http_mksession()
+            for i=1,5 do
+                req = http_request(session, "GET", "http://{}/", {{}})
+                resp = http_send(req)
+                if last_err() then return end
+                if resp["text"] ~= "ok" then return false end
+            end
+            return true
+        end
+        "#, addr).as_bytes(), Arc::new(Config::default())).unwrap();
+        assert!(script.run_creds("foo", "bar", None).unwrap());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn verify_throttle_delay() {
+        let now = 1445412480;
+        assert_eq!(throttle_delay(429, Some("120"), now), Some(Duration::from_secs(120)));
+        assert_eq!(throttle_delay(429, None, now), Some(DEFAULT_THROTTLE));
+        assert_eq!(throttle_delay(429, Some("soon"), now), Some(DEFAULT_THROTTLE));
+        assert_eq!(throttle_delay(503, Some("Wed, 21 Oct 2015 07:30:00 GMT"), now), Some(Duration::from_secs(120)));
+        // a date in the past
+        assert_eq!(throttle_delay(503, Some("Wed, 21 Oct 2015 07:00:00 GMT"), now), Some(Duration::from_secs(0)));
+        assert_eq!(throttle_delay(429, Some("86400"), now), Some(MAX_THROTTLE));
+        assert_eq!(throttle_delay(503, None, now), None);
+        assert_eq!(throttle_delay(200, Some("120"), now), None);
+    }
+
+    fn raw_body(options: AnyLuaValue) -> (Vec<u8>, serde_json::Value) {
+        let options = RequestOptions::try_from(options).unwrap();
+        let session = HttpSession::new().1;
+        let request = HttpRequest::new(&Arc::new(Config::default()), &session, "POST".to_string(), "https://example.com/".to_string(), options);
+        let json = serde_json::to_value(&request).unwrap();
+        // the round trip through lua
+        let request = HttpRequest::try_from(LuaJsonValue::from(json.clone()).into()).unwrap();
+        match request.body {
+            Some(Body::Raw(body)) => (body, json["body"]["Raw"].clone()),
+            _ => panic!("expected a raw body"),
+        }
+    }
+
+    #[test]
+    fn verify_raw_body() {
+        let body = |x| AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaString("body".to_string()), x)]);
+
+        assert_eq!(raw_body(body(AnyLuaValue::LuaString("user=admin".to_string()))),
+            (b"user=admin".to_vec(), json!("user=admin")));
+        // a NTLM type 1 message
+        let ntlm = b"NTLMSSP\x00\x01\x00\x00\x00\x07\x82\x08\xa2".to_vec();
+        assert_eq!(raw_body(body(AnyLuaValue::LuaAnyString(AnyLuaString(ntlm.clone())))),
+            (ntlm.clone(), serde_json::to_value(&ntlm).unwrap()));
+        let bytes = vec![(AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNumber(8.0)), (AnyLuaValue::LuaNumber(2.0), AnyLuaValue::LuaNumber(255.0))];
+        assert_eq!(raw_body(body(AnyLuaValue::LuaArray(bytes))).0, vec![8, 255]);
+    }
+
+    fn options(x: Vec<(&str, AnyLuaValue)>) -> RequestOptions {
+        RequestOptions::try_from(AnyLuaValue::LuaArray(x.into_iter()
+            .map(|(k, v)| (AnyLuaValue::LuaString(k.into()), v))
+            .collect())).unwrap()
+    }
+
+    fn lua_list(x: Vec<AnyLuaValue>) -> AnyLuaValue {
+        AnyLuaValue::LuaArray(x.into_iter()
+            .enumerate()
+            .map(|(i, x)| (AnyLuaValue::LuaNumber((i + 1) as f64), x))
+            .collect())
+    }
+
+    fn lua_str(x: &str) -> AnyLuaValue {
+        AnyLuaValue::LuaString(x.to_string())
+    }
+
+    fn pairs(x: &[(&str, &str)]) -> Vec<(String, String)> {
+        x.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn verify_query() {
+        // a list of pairs keeps its order
+        let signed = options(vec![("query", lua_list(vec![
+            lua_list(vec![lua_str("timestamp"), AnyLuaValue::LuaNumber(1527854400.0)]),
+            lua_list(vec![lua_str("id"), lua_str("1")]),
+            lua_list(vec![lua_str("id"), lua_str("2")]),
+            lua_list(vec![lua_str("action"), lua_str("login")]),
+        ]))]);
+        assert_eq!(signed.query.unwrap().pairs(ArrayStyle::Repeat).unwrap(),
+            pairs(&[("timestamp", "1527854400"), ("id", "1"), ("id", "2"), ("action", "login")]));
+
+        let ids = options(vec![("query", AnyLuaValue::LuaArray(vec![
+            (lua_str("id"), lua_list(vec![lua_str("1"), lua_str("2")])),
+        ]))]);
+        let query = ids.query.unwrap();
+        assert_eq!(query.pairs(ArrayStyle::Repeat).unwrap(), pairs(&[("id", "1"), ("id", "2")]));
+        assert_eq!(query.pairs(ArrayStyle::Brackets).unwrap(), pairs(&[("id[]", "1"), ("id[]", "2")]));
+
+        let nested = options(vec![("query", AnyLuaValue::LuaArray(vec![
+            (lua_str("id"), lua_list(vec![lua_list(vec![lua_str("1")])])),
+        ]))]);
+        assert!(nested.query.unwrap().pairs(ArrayStyle::Repeat).is_err());
+
+        // an encoded query is appended to the url
+        let session = HttpSession::new().1;
+        let request = |url: &str, query: &str| HttpRequest::new(&Arc::new(Config::default()), &session, "GET".to_string(), url.to_string(),
+            options(vec![("query", lua_str(query))]));
+        assert_eq!(request("https://example.com/api", "?id=1&id=2&sig=a%2Bb").url().unwrap(), "https://example.com/api?id=1&id=2&sig=a%2Bb");
+        assert_eq!(request("https://example.com/api?v=2#top", "id=1").url().unwrap(), "https://example.com/api?v=2&id=1#top");
+        assert!(request("/api", "id=1").url().is_err());
+    }
+
+    #[test]
+    fn verify_form() {
+        let form = json!({"user": "admin", "roles": ["a", "b"], "remember": true, "none": {}});
+        assert_eq!(form_pairs(&form, ArrayStyle::Brackets).unwrap(),
+            pairs(&[("remember", "true"), ("roles[]", "a"), ("roles[]", "b"), ("user", "admin")]));
+        let form = json!([["user", "admin"], ["password", "secret"], ["user", "root"]]);
+        assert_eq!(form_pairs(&form, ArrayStyle::Repeat).unwrap(),
+            pairs(&[("user", "admin"), ("password", "secret"), ("user", "root")]));
+        assert!(form_pairs(&json!([["user"]]), ArrayStyle::Repeat).is_err());
+        assert!(form_pairs(&json!("user=admin"), ArrayStyle::Repeat).is_err());
+    }
+
+    #[test]
+    fn verify_user_agent() {
+        let user_agent = |config: &Arc<Config>, session: &HttpSession, agent: Option<&str>| {
+            let options = match agent {
+                Some(agent) => options(vec![("user_agent", lua_str(agent))]),
+                None => RequestOptions::default(),
+            };
+            HttpRequest::new(config, session, "GET".to_string(), "https://example.com/".to_string(), options).user_agent
+        };
+
+        let session = HttpSession::new().1;
+        let config = Arc::new(Config::default());
+        assert_eq!(user_agent(&config, &session, None), None);
+        assert_eq!(user_agent(&config, &session, Some("random")), None);
+
+        let config = Arc::new(Config::try_from_str("[runtime]\nuser_agent = \"w3m\"\n").unwrap());
+        assert_eq!(user_agent(&config, &session, None), Some("w3m".to_string()));
+
+        // the copies of a session keep the user agent of the first request
+        let copy = session.clone();
+        let config = Arc::new(Config::try_from_str("[runtime]\nuser_agent = [\"curl/7.60.0\", \"Wget/1.19.5\"]\nuser_agent_rotation = \"session\"\n").unwrap());
+        let first = user_agent(&config, &session, Some("random")).unwrap();
+        assert!(first == "curl/7.60.0" || first == "Wget/1.19.5");
+        for _ in 0..10 {
+            assert_eq!(user_agent(&config, &copy, None), Some(first.clone()));
+        }
+        assert_eq!(user_agent(&config, &session, Some("badtouch")), Some("badtouch".to_string()));
+    }
+
+    #[test]
+    fn verify_extra_cookies() {
+        let (_, mut session) = HttpSession::new();
+        let url = "https://www.example.com/".parse().unwrap();
+        session.cookies.register_in_jar(&url, vec![
+            SetCookie { name: "sid".to_string(), value: "abc".to_string(), ..SetCookie::default() },
+            SetCookie { name: "lang".to_string(), value: "de".to_string(), ..SetCookie::default() },
+        ], NOW);
+
+        let options = RequestOptions::try_from(AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString("cookies".to_string()), AnyLuaValue::LuaArray(vec![
+                (AnyLuaValue::LuaString("sid".to_string()), AnyLuaValue::LuaString("stolen".to_string())),
+            ])),
+        ])).unwrap();
+        let request = HttpRequest::new(&Arc::new(Config::default()), &session, "GET".to_string(), "https://www.example.com/admin".to_string(), options);
+        assert_eq!(request.cookie_header(NOW).to_string(), "lang=de; sid=stolen");
+        // the session keeps its own cookie
+        let request = HttpRequest::new(&Arc::new(Config::default()), &session, "GET".to_string(), "https://www.example.com/".to_string(), RequestOptions::default());
+        assert_eq!(request.cookie_header(NOW).to_string(), "sid=abc; lang=de");
+    }
+
+    #[test]
+    fn verify_response_headers() {
+        let mut headers = Headers::new();
+        headers.append_raw("Set-Cookie", "ASP.NET_SessionId=0pxsvtkh1iwbgzu4qy5dtaul; path=/; HttpOnly");
+        headers.append_raw("Set-Cookie", "lang=de; path=/");
+        headers.append_raw("Set-Cookie", ".ASPXAUTH=; expires=Mon, 11-Oct-1999 22:00:00 GMT; path=/; HttpOnly");
+        headers.append_raw("WWW-Authenticate", "Negotiate");
+        headers.append_raw("WWW-Authenticate", "NTLM");
+        headers.append_raw("Content-Type", "text/html");
+
+        let (joined, all) = response_headers(&headers);
+        let joined: HashMap<_, _> = joined.into();
+        let all: HashMap<_, _> = all.into();
+        let get = |map: &HashMap<AnyHashableLuaValue, AnyLuaValue>, key: &str| map[&AnyHashableLuaValue::LuaString(key.to_string())].clone();
+        let list = |values: &[&str]| AnyLuaValue::LuaArray(values.iter()
+            .enumerate()
+            .map(|(i, x)| (AnyLuaValue::LuaNumber((i + 1) as f64), AnyLuaValue::LuaString(x.to_string())))
+            .collect());
+
+        assert_eq!(get(&all, "set-cookie"), list(&[
+            "ASP.NET_SessionId=0pxsvtkh1iwbgzu4qy5dtaul; path=/; HttpOnly",
+            "lang=de; path=/",
+            ".ASPXAUTH=; expires=Mon, 11-Oct-1999 22:00:00 GMT; path=/; HttpOnly",
+        ]));
+        assert_eq!(get(&all, "www-authenticate"), list(&["Negotiate", "NTLM"]));
+        assert_eq!(get(&all, "content-type"), list(&["text/html"]));
+        assert_eq!(get(&joined, "www-authenticate"), AnyLuaValue::LuaString("Negotiate, NTLM".to_string()));
+        assert_eq!(get(&joined, "content-type"), AnyLuaValue::LuaString("text/html".to_string()));
+    }
+
+    // 2018-06-01 12:00:00 UTC
+    const NOW: i64 = 1_527_854_400;
+
+    fn parse(header: &str) -> Vec<SetCookie> {
+        split_folded(header).into_iter()
+            .map(|x| SetCookie::parse(x, NOW).unwrap())
+            .collect()
+    }
+
+    fn cookies(header: &str) -> Vec<(String, String, bool)> {
+        parse(header).into_iter()
+            .map(|x| {
+                let expired = x.is_expired(NOW);
+                (x.name, x.value, expired)
+            })
+            .collect()
+    }
+
+    fn cookie(name: &str, value: &str, expired: bool) -> (String, String, bool) {
+        (name.to_string(), value.to_string(), expired)
+    }
+
+    #[test]
+    fn verify_set_cookie_aspnet() {
+        assert_eq!(cookies("ASP.NET_SessionId=0pxsvtkh1iwbgzu4qy5dtaul; path=/; HttpOnly; SameSite=Lax"),
+            vec![cookie("ASP.NET_SessionId", "0pxsvtkh1iwbgzu4qy5dtaul", false)]);
+        assert_eq!(cookies(".ASPXAUTH=8E3B9B8C1F0A4B6D9E2F7A1C3D5E6F70; expires=Fri, 01-Jun-2018 12:30:00 GMT; path=/; secure; HttpOnly"),
+            vec![cookie(".ASPXAUTH", "8E3B9B8C1F0A4B6D9E2F7A1C3D5E6F70", false)]);
+        // FormsAuthentication.SignOut
+        assert_eq!(cookies(".ASPXAUTH=; expires=Mon, 11-Oct-1999 22:00:00 GMT; path=/; HttpOnly"),
+            vec![cookie(".ASPXAUTH", "", true)]);
+        assert_eq!(cookies(".AspNetCore.Antiforgery.w5W7x28NAIs=CfDJ8Nq2xZ-mvbhFgSI3Nk_Z6dE; path=/; samesite=strict; httponly"),
+            vec![cookie(".AspNetCore.Antiforgery.w5W7x28NAIs", "CfDJ8Nq2xZ-mvbhFgSI3Nk_Z6dE", false)]);
+    }
+
+    #[test]
+    fn verify_set_cookie_php() {
+        assert_eq!(cookies("PHPSESSID=9k3qc1d9q2ehkl6c1ikrb3f2g5; path=/"),
+            vec![cookie("PHPSESSID", "9k3qc1d9q2ehkl6c1ikrb3f2g5", false)]);
+        // setcookie("remember", "", time() - 3600)
+        assert_eq!(cookies("remember=deleted; expires=Thu, 01-Jan-1970 00:00:01 GMT; Max-Age=0; path=/"),
+            vec![cookie("remember", "deleted", true)]);
+        // values are urlencoded by setcookie and kept as they are
+        assert_eq!(cookies("prefs=lang%3Dde%26theme%3Ddark; expires=Sat, 30-Jun-2018 12:00:00 GMT; Max-Age=2505600; path=/"),
+            vec![cookie("prefs", "lang%3Dde%26theme%3Ddark", false)]);
+    }
+
+    #[test]
+    fn verify_set_cookie_spring() {
+        assert_eq!(cookies("JSESSIONID=3F1B4D2C8A9E6F7B0C1D2E3F4A5B6C7D; Path=/app; HttpOnly"),
+            vec![cookie("JSESSIONID", "3F1B4D2C8A9E6F7B0C1D2E3F4A5B6C7D", false)]);
+        assert_eq!(cookies("remember-me=YWRtaW46MTUzMDQ0NjQwMDAwMDpiNmE3OGMxZjQ4MzU=; Max-Age=1209600; Expires=Fri, 15-Jun-2018 12:00:00 GMT; Path=/; HttpOnly"),
+            vec![cookie("remember-me", "YWRtaW46MTUzMDQ0NjQwMDAwMDpiNmE3OGMxZjQ4MzU=", false)]);
+        // logout
+        assert_eq!(cookies("remember-me=; Max-Age=0; Expires=Thu, 01-Jan-1970 00:00:10 GMT; Path=/"),
+            vec![cookie("remember-me", "", true)]);
+    }
+
+    #[test]
+    fn verify_set_cookie() {
+        // folded into one line
+        assert_eq!(cookies("sid=abc; Expires=Wed, 31 Dec 2025 23:59:59 GMT; Path=/, lang=\"en\"; Path=/,old=1; expires=Sunday, 06-Nov-94 08:49:37 GMT"), vec![
+            cookie("sid", "abc", false),
+            cookie("lang", "\"en\"", false),
+            cookie("old", "1", true),
+        ]);
+        // Max-Age wins over Expires
+        assert_eq!(cookies("a=1; Max-Age=60; Expires=Thu, 01 Jan 1970 00:00:00 GMT"), vec![cookie("a", "1", false)]);
+        assert_eq!(cookies("a=1; Expires=Sun Nov  6 08:49:37 1994"), vec![cookie("a", "1", true)]);
+        assert_eq!(cookies("a=1; Expires=whenever"), vec![cookie("a", "1", false)]);
+        assert_eq!(SetCookie::parse("HttpOnly", NOW), None);
+        assert_eq!(SetCookie::parse("=1; Path=/", NOW), None);
+        assert_eq!(SetCookie::parse("a=1; Domain=.Example.com; Path=/admin; Max-Age=60", NOW), Some(SetCookie {
+            name: "a".to_string(),
+            value: "1".to_string(),
+            domain: Some("example.com".to_string()),
+            path: Some("/admin".to_string()),
+            expires: Some(NOW + 60),
+        }));
+    }
+
+    #[test]
+    fn verify_cookie_jar() {
+        fn sent(jar: &CookieJar, url: &str, now: i64) -> Vec<String> {
+            jar.matching(&url.parse().unwrap(), now).into_iter()
+                .map(|x| format!("{}={}", x.name, x.value))
+                .collect()
+        }
+
+        let mut jar = CookieJar::default();
+        let login = "https://www.example.com/app/login".parse().unwrap();
+        jar.register_in_jar(&login, parse("sid=abc, admin=1; Path=/app/admin, lang=de; Domain=example.com; Path=/, short=1; Max-Age=10"), NOW);
+        // a cookie for another site is ignored
+        jar.register_in_jar(&login, parse("track=1; Domain=tracker.com"), NOW);
+
+        assert_eq!(sent(&jar, "https://www.example.com/app/admin/users", NOW), vec!["admin=1", "sid=abc", "short=1", "lang=de"]);
+        assert_eq!(sent(&jar, "https://www.example.com/app", NOW), vec!["sid=abc", "short=1", "lang=de"]);
+        assert_eq!(sent(&jar, "https://www.example.com/application", NOW), vec!["lang=de"]);
+        assert_eq!(sent(&jar, "https://mail.example.com/app/", NOW), vec!["lang=de"]);
+        assert_eq!(sent(&jar, "https://example.org/app/", NOW), Vec::<String>::new());
+        assert_eq!(sent(&jar, "https://www.example.com/app/", NOW + 10), vec!["sid=abc", "lang=de"]);
+
+        // logout deletes the session cookie
+        let logout = "https://www.example.com/app/logout".parse().unwrap();
+        jar.register_in_jar(&logout, parse("sid=deleted; expires=Thu, 01-Jan-1970 00:00:01 GMT; Max-Age=0"), NOW);
+        assert_eq!(sent(&jar, "https://www.example.com/app/", NOW), vec!["short=1", "lang=de"]);
+
+        // round trip through lua, and the format of older versions
+        let json = serde_json::to_value(&jar).unwrap();
+        assert_eq!(json[1], json!({"name": "lang", "value": "de", "domain": "example.com", "host_only": false, "path": "/"}));
+        let jar = serde_json::from_value::<CookieJar>(json).unwrap();
+        assert_eq!(sent(&jar, "https://www.example.com/app/", NOW), vec!["short=1", "lang=de"]);
+        let jar = serde_json::from_value::<CookieJar>(json!({"sid": "abc"})).unwrap();
+        assert_eq!(sent(&jar, "https://example.org/", NOW), vec!["sid=abc"]);
+    }
+
+    #[test]
+    fn verify_percent_decode() {
+        assert_eq!(percent_decode("/owa/auth/logon.aspx?reason=2&url=https%3A%2F%2Fmail%2Eacme%2Ecorp%2Fowa"), "/owa/auth/logon.aspx?reason=2&url=https://mail.acme.corp/owa");
+        assert_eq!(percent_decode("/caf%C3%A9"), "/café");
+        assert_eq!(percent_decode("/100%/%zz/%4"), "/100%/%zz/%4");
+    }
+
+    #[test]
+    fn verify_read_body() {
+        assert_eq!(read_body(&b"hello"[..], 5).unwrap(), (b"hello".to_vec(), false));
+        assert_eq!(read_body(&b"hello world"[..], 5).unwrap(), (b"hello".to_vec(), true));
+        assert_eq!(read_body(&b""[..], 5).unwrap(), (Vec::new(), false));
+    }
+}
@@ -1,5 +1,6 @@
 use errors::{Result, ResultExt};
 
+use serde::de::DeserializeOwned;
 use std::iter::FromIterator;
 use std::collections::HashMap;
 use hlua::AnyLuaValue;
@@ -20,6 +21,19 @@ pub fn encode(v: AnyLuaValue) -> Result<String> {
         .chain_err(|| "serialize failed")
 }
 
+/// Deserialize the options table of a function, it's optional and nil is
+/// the default
+pub fn options<T: DeserializeOwned + Default>(x: AnyLuaValue) -> Result<T> {
+    match x {
+        AnyLuaValue::LuaNil => Ok(T::default()),
+        x => {
+            let x = LuaJsonValue::from(x);
+            let x = serde_json::from_value(x.into())?;
+            Ok(x)
+        },
+    }
+}
+
 pub fn lua_array_is_list(array: &[(AnyLuaValue, AnyLuaValue)]) -> bool {
     if !array.is_empty() {
         let first = &array[0];
@@ -1,20 +1,36 @@
 // use getch;
 use getch::Getch;
 
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(windows))]
+use std::os::unix::io::AsRawFd;
+
 #[cfg(not(windows))]
 use termios::{self, tcsetattr, ICANON, ECHO};
 
+// the terminal is only reset if we changed it, see --no-keyboard
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+enum Input {
+    Stdin(Getch),
+    // stdin is used for a list, read from the terminal directly
+    Tty(File),
+}
+
 pub struct Keyboard {
-    getch: Getch,
+    input: Input,
 }
 
 impl Default for Keyboard {
     #[inline]
     fn default() -> Keyboard {
+        ACTIVE.store(true, Ordering::SeqCst);
         let getch = Getch::new();
 
         Keyboard {
-            getch,
+            input: Input::Stdin(getch),
         }
     }
 }
@@ -25,16 +41,81 @@ impl Keyboard {
         Keyboard::default()
     }
 
-    pub fn get(&self) -> Key {
+    /// Read keys from /dev/tty instead of stdin
+    #[cfg(not(windows))]
+    pub fn tty() -> io::Result<Keyboard> {
+        let tty = File::open("/dev/tty")?;
+
+        let mut termios = termios::Termios::from_fd(tty.as_raw_fd())?;
+        termios.c_lflag &= !(ICANON|ECHO);
+        ACTIVE.store(true, Ordering::SeqCst);
+        tcsetattr(tty.as_raw_fd(), termios::TCSADRAIN, &termios)?;
+
+        Ok(Keyboard {
+            input: Input::Tty(tty),
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn tty() -> io::Result<Keyboard> {
+        Err(io::Error::new(io::ErrorKind::NotFound, "no terminal available"))
+    }
+
+    fn getch(&self) -> io::Result<u8> {
+        match self.input {
+            Input::Stdin(ref getch) => getch.getch(),
+            Input::Tty(ref tty) => {
+                let mut tty = tty;
+                let mut buf = [0];
+                tty.read_exact(&mut buf)?;
+                // skip escape sequences, like getch does
+                if buf[0] == 27 {
+                    tty.read_exact(&mut buf)?;
+                    if buf[0] == 91 {
+                        tty.read_exact(&mut buf)?;
+                    }
+                }
+                Ok(buf[0])
+            },
+        }
+    }
+
+    /// Wait for the next key, returns None if there is no more input.
+    /// Digits are collected until enter is pressed
+    pub fn get(&self) -> Option<Key> {
+        let mut digits = String::new();
         loop {
-            let key = self.getch.getch();
+            let key = self.getch();
             // println!("{:?}", key);
             match key {
-                Ok(112) => return Key::P,
-                Ok(114) => return Key::R,
-                Ok(43)  => return Key::Plus,
-                Ok(45)  => return Key::Minus,
-                Ok(104) => return Key::H,
+                Ok(b @ 48..=57) => {
+                    digits.push(b as char);
+                    continue;
+                },
+                Ok(10) | Ok(13) if !digits.is_empty() => {
+                    // this only fails if the number is too large
+                    if let Ok(n) = digits.parse() {
+                        return Some(Key::Threads(n));
+                    }
+                },
+                _ => (),
+            }
+            digits.clear();
+
+            match key {
+                Ok(112) => return Some(Key::P),
+                Ok(113) => return Some(Key::Q),
+                Ok(114) => return Some(Key::R),
+                Ok(115) => return Some(Key::S),
+                Ok(43)  => return Some(Key::Plus),
+                Ok(45)  => return Some(Key::Minus),
+                Ok(104) => return Some(Key::H),
+                Ok(107) => return Some(Key::K),
+                Ok(75)  => return Some(Key::ShiftK),
+                Ok(91)  => return Some(Key::LeftBracket),
+                Ok(93)  => return Some(Key::RightBracket),
+                // getch returns 0 at the end of the input
+                Ok(0) | Err(_) => return None,
                 _ => (),
             }
         }
@@ -42,11 +123,22 @@ impl Keyboard {
 
     // since the getch thread is orphaned, we have to cleanup manually
     pub fn reset() {
+        if !ACTIVE.load(Ordering::SeqCst) {
+            return;
+        }
+
         #[cfg(not(windows))]
         {
-            if let Ok(mut termios) = termios::Termios::from_fd(0) {
-                termios.c_lflag |= ICANON|ECHO;
-                tcsetattr(0,termios::TCSADRAIN, &termios).unwrap_or(());
+            fn reset_fd(fd: i32) {
+                if let Ok(mut termios) = termios::Termios::from_fd(fd) {
+                    termios.c_lflag |= ICANON|ECHO;
+                    tcsetattr(fd, termios::TCSADRAIN, &termios).unwrap_or(());
+                }
+            }
+
+            reset_fd(0);
+            if let Ok(tty) = File::open("/dev/tty") {
+                reset_fd(tty.as_raw_fd());
             }
         }
     }
@@ -55,8 +147,16 @@ impl Keyboard {
 #[derive(Debug)]
 pub enum Key {
     H,
+    K,
+    ShiftK,
     P,
+    Q,
     R,
+    S,
     Plus,
     Minus,
+    LeftBracket,
+    RightBracket,
+    // a number followed by enter
+    Threads(usize),
 }
@@ -0,0 +1,617 @@
+use errors::{Result, ResultExt};
+
+use futures::{future, Future};
+use ldap3::{self, LdapConn, LdapConnSettings, Scope, SearchEntry};
+use native_tls::{HandshakeError, TlsConnector, TlsStream};
+use hlua::{AnyLuaValue, AnyLuaString};
+use structs::LuaMap;
+use json;
+use sasl::{self, Mechanism, DigestMd5};
+use proxy::{self, Route};
+use rand::{Rng, thread_rng};
+use rand::distributions::Alphanumeric;
+use std::borrow::Cow;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// ldap result codes, see rfc4511 section 4.1.9
+const LDAP_SUCCESS: u8 = 0;
+const LDAP_SIZE_LIMIT_EXCEEDED: u32 = 4;
+const LDAP_AUTH_METHOD_NOT_SUPPORTED: u8 = 7;
+const LDAP_REFERRAL: u32 = 10;
+const LDAP_SASL_BIND_IN_PROGRESS: u8 = 14;
+const LDAP_STARTTLS_OID: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LdapOptions {
+    timeout: Option<u64>,
+    starttls: Option<bool>,
+    tls_verify: Option<bool>,
+    realm: Option<String>,
+    // send PLAIN credentials over a connection without tls
+    allow_plaintext: Option<bool>,
+    filter: Option<String>,
+    multiple: Option<MultipleEntries>,
+    // see --proxy and --resolve
+    #[serde(skip)]
+    route: Route,
+}
+
+/// What ldap_search_bind does if the search returned more than one entry
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MultipleEntries {
+    /// Try the first entry
+    #[default]
+    First,
+    /// Record an error
+    Error,
+    /// Try every entry until one succeeds
+    All,
+}
+
+impl LdapOptions {
+    #[inline]
+    pub fn try_from(x: AnyLuaValue) -> Result<LdapOptions> {
+        json::options(x)
+    }
+
+    /// Route the connections through --proxy and --resolve
+    pub fn with_route(mut self, route: &Route) -> LdapOptions {
+        self.route = route.clone();
+        self
+    }
+
+    #[inline]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    #[inline]
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout().map(|timeout| Instant::now() + timeout)
+    }
+
+    pub fn connect(&self, url: &str) -> Result<LdapConn> {
+        let mut settings = LdapConnSettings::new()
+            .set_starttls(self.starttls.unwrap_or(false))
+            .set_no_tls_verify(!self.tls_verify.unwrap_or(true));
+
+        if let Some(timeout) = self.timeout() {
+            settings = settings.set_conn_timeout(timeout);
+        }
+
+        let mut url = Cow::Borrowed(url);
+        // ldapi:// urls are unix sockets
+        if !self.route.is_direct() && !url.starts_with("ldapi://") {
+            let (scheme, host, port, path) = split_url(&url)?;
+            if host.parse::<IpAddr>().is_ok() {
+                // ldap3 only resolves hostnames, connect to the relay directly
+                if self.route.proxy.is_some() {
+                    let addr = self.route.relay(host, port, self.deadline())?;
+                    url = Cow::Owned(format!("{}://{}{}", scheme, addr, path));
+                }
+            } else {
+                // resolving to the relay keeps the hostname for tls
+                let route = self.route.clone();
+                let deadline = self.deadline();
+                settings = settings.set_resolver(Rc::new(move |addr: &str| {
+                    let addr = proxy::split_host_port(addr, None)
+                        .and_then(|(host, port)| match route.proxy {
+                            Some(_) => route.relay(host, port, deadline),
+                            None => route.resolve(host, port),
+                        })
+                        .map_err(|err| io::Error::other(err.to_string()));
+                    Box::new(future::result(addr)) as Box<dyn Future<Item=SocketAddr, Error=io::Error>>
+                }));
+            }
+        }
+
+        let sock = LdapConn::with_settings(settings, &url)
+            .chain_err(|| "ldap connection failed")?;
+
+        Ok(sock)
+    }
+
+    /// Build the search filter for ldap_search_bind, defaults to `uid={}`
+    pub fn search_filter(&self, user: &str) -> String {
+        let filter = self.filter.as_deref().unwrap_or("uid={}");
+        filter.replace("{}", &ldap3::dn_escape(user))
+    }
+
+    #[inline]
+    pub fn multiple(&self) -> MultipleEntries {
+        self.multiple.unwrap_or_default()
+    }
+
+    /// Apply the operation timeout to the next request on this connection
+    #[inline]
+    pub fn apply<'a>(&self, sock: &'a LdapConn) -> &'a LdapConn {
+        if let Some(timeout) = self.timeout() {
+            sock.with_timeout(timeout)
+        } else {
+            sock
+        }
+    }
+}
+
+fn lua_list(values: Vec<AnyLuaValue>) -> AnyLuaValue {
+    AnyLuaValue::LuaArray(values.into_iter()
+        .enumerate()
+        .map(|(i, x)| (AnyLuaValue::LuaNumber((i + 1) as f64), x))
+        .collect())
+}
+
+pub fn entry_to_lua(entry: SearchEntry) -> AnyLuaValue {
+    let mut attrs = LuaMap::new();
+
+    for (k, v) in entry.attrs {
+        attrs.insert(k, lua_list(v.into_iter()
+            .map(AnyLuaValue::LuaString)
+            .collect()));
+    }
+
+    // binary values like objectGUID are returned as byte strings
+    for (k, v) in entry.bin_attrs {
+        attrs.insert(k, lua_list(v.into_iter()
+            .map(|x| AnyLuaValue::LuaAnyString(AnyLuaString(x)))
+            .collect()));
+    }
+
+    let mut map = LuaMap::new();
+    map.insert_str("dn", entry.dn);
+    map.insert("attrs", attrs);
+    map.into()
+}
+
+/// Bind with the given user and return all entries matching the filter
+pub fn search(url: &str, bind_dn: &str, bind_pw: &str, base_dn: &str, filter: &str, attrs: Vec<String>, options: &LdapOptions) -> Result<Vec<SearchEntry>> {
+    let sock = options.connect(url)?;
+
+    // an empty bind_dn searches anonymously
+    if !bind_dn.is_empty() {
+        let result = options.apply(&sock).simple_bind(bind_dn, bind_pw)
+            .chain_err(|| "fatal error during simple_bind")?;
+        result.success()
+            .chain_err(|| "ldap bind failed")?;
+    }
+
+    let attrs = if attrs.is_empty() {
+        vec![String::from("*")]
+    } else {
+        attrs
+    };
+
+    let result = options.apply(&sock).search(base_dn, Scope::Subtree, filter, attrs)
+        .chain_err(|| "fatal error during ldap search")?;
+
+    // partial results are still returned to the script
+    match result.1.rc {
+        0 | LDAP_SIZE_LIMIT_EXCEEDED | LDAP_REFERRAL => (),
+        _ => bail!("ldap search failed: {:?}", result.1),
+    }
+    debug!("ldap_search: {:?}", result.1);
+
+    Ok(result.0.into_iter()
+        .map(SearchEntry::construct)
+        .collect())
+}
+
+fn ber_len(out: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|x| **x == 0).count();
+        out.push(0x80 | (4 - skip) as u8);
+        out.extend(&bytes[skip..]);
+    }
+}
+
+fn ber(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    ber_len(&mut out, content.len());
+    out.extend(content);
+    out
+}
+
+/// Split the first tlv from the input, returns tag, content and the remainder
+fn ber_split(input: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    if input.len() < 2 {
+        bail!("truncated ber element");
+    }
+
+    let tag = input[0];
+    let (len, offset) = if input[1] & 0x80 == 0 {
+        (input[1] as usize, 2)
+    } else {
+        let n = (input[1] & 0x7f) as usize;
+        if n == 0 || n > 4 || input.len() < 2 + n {
+            bail!("invalid ber length");
+        }
+        let len = input[2..2 + n].iter()
+            .fold(0, |acc, x| (acc << 8) | *x as usize);
+        (len, 2 + n)
+    };
+
+    if input.len() < offset + len {
+        bail!("truncated ber element");
+    }
+
+    Ok((tag, &input[offset..offset + len], &input[offset + len..]))
+}
+
+fn read_ber<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+
+    let mut msg = head.to_vec();
+    let len = if head[1] & 0x80 == 0 {
+        head[1] as usize
+    } else {
+        let n = (head[1] & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            bail!("invalid ber length");
+        }
+        let mut len = vec![0; n];
+        stream.read_exact(&mut len)?;
+        msg.extend(&len);
+        len.iter().fold(0, |acc, x| (acc << 8) | *x as usize)
+    };
+
+    let mut content = vec![0; len];
+    stream.read_exact(&mut content)?;
+    msg.extend(content);
+
+    Ok(msg)
+}
+
+enum SaslStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for SaslStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            SaslStream::Plain(ref mut stream) => stream.read(buf),
+            SaslStream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for SaslStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            SaslStream::Plain(ref mut stream) => stream.write(buf),
+            SaslStream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            SaslStream::Plain(ref mut stream) => stream.flush(),
+            SaslStream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+struct SaslConn {
+    stream: SaslStream,
+    msgid: u8,
+}
+
+impl SaslConn {
+    fn connect(host: &str, port: u16, options: &LdapOptions) -> Result<SaslConn> {
+        let stream = options.route.connect(host, port, options.deadline())
+            .chain_err(|| "ldap connection failed")?;
+        stream.set_read_timeout(options.timeout())?;
+        stream.set_write_timeout(options.timeout())?;
+        Ok(SaslConn {
+            stream: SaslStream::Plain(stream),
+            msgid: 0,
+        })
+    }
+
+    #[inline]
+    fn is_tls(&self) -> bool {
+        match self.stream {
+            SaslStream::Plain(_) => false,
+            SaslStream::Tls(_) => true,
+        }
+    }
+
+    /// Start tls on the connection, for ldaps:// or after StartTLS
+    fn tls(self, host: &str, options: &LdapOptions) -> Result<SaslConn> {
+        let stream = match self.stream {
+            SaslStream::Plain(stream) => stream,
+            SaslStream::Tls(_) => bail!("tls is already started"),
+        };
+
+        let connector = TlsConnector::builder()
+            .and_then(|builder| builder.build())
+            .chain_err(|| "failed to setup tls")?;
+        let stream = if options.tls_verify.unwrap_or(true) {
+            connector.connect(host, stream)
+        } else {
+            connector.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+        };
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(HandshakeError::Failure(err)) => return Err(err).chain_err(|| "ldap tls handshake failed"),
+            Err(HandshakeError::Interrupted(_)) => bail!("ldap tls handshake timed out"),
+        };
+
+        Ok(SaslConn {
+            stream: SaslStream::Tls(Box::new(stream)),
+            msgid: self.msgid,
+        })
+    }
+
+    /// Send a request and return the resultCode and the rest of the response
+    /// after the diagnosticMessage
+    fn request(&mut self, tag: u8, op: &[u8], resp_tag: u8) -> Result<(u8, Vec<u8>)> {
+        self.msgid += 1;
+
+        let mut msg = ber(0x02, &[self.msgid]);
+        msg.extend(ber(tag, op));
+
+        self.stream.write_all(&ber(0x30, &msg))?;
+        self.stream.flush()?;
+
+        let resp = read_ber(&mut self.stream)?;
+        let (_, msg, _) = ber_split(&resp)?;
+        let (_, _msgid, msg) = ber_split(msg)?;
+        let (tag, resp, _) = ber_split(msg)?;
+        if tag != resp_tag {
+            bail!("unexpected ldap response: 0x{:02x}", tag);
+        }
+
+        let (_, code, resp) = ber_split(resp)?;
+        let code = *code.last().chain_err(|| "invalid resultCode")?;
+
+        let (_, _matched_dn, resp) = ber_split(resp)?;
+        let (_, diagnostics, resp) = ber_split(resp)?;
+        debug!("ldap response 0x{:02x}: code={}, diagnostics={:?}", tag, code, String::from_utf8_lossy(diagnostics));
+
+        Ok((code, resp.to_vec()))
+    }
+
+    /// Send a StartTLS ExtendedRequest
+    fn starttls(&mut self) -> Result<()> {
+        let (code, _) = self.request(0x77, &ber(0x80, LDAP_STARTTLS_OID), 0x78)?;
+        if code != LDAP_SUCCESS {
+            bail!("server refused StartTLS: resultCode {}", code);
+        }
+        Ok(())
+    }
+
+    /// Send a sasl BindRequest and return the resultCode and serverSaslCreds
+    fn bind(&mut self, mech: &Mechanism, creds: Option<&[u8]>) -> Result<(u8, Option<Vec<u8>>)> {
+        let mut sasl = ber(0x04, mech.name().as_bytes());
+        if let Some(creds) = creds {
+            sasl.extend(ber(0x04, creds));
+        }
+
+        let mut bind = ber(0x02, &[3]);
+        bind.extend(ber(0x04, b""));
+        bind.extend(ber(0xa3, &sasl));
+
+        let (code, resp) = self.request(0x60, &bind, 0x61)?;
+
+        let mut resp = &resp[..];
+        let mut server_creds = None;
+        while !resp.is_empty() {
+            let (tag, value, rest) = ber_split(resp)?;
+            if tag == 0x87 {
+                server_creds = Some(value.to_vec());
+            }
+            resp = rest;
+        }
+
+        Ok((code, server_creds))
+    }
+}
+
+/// Connect to an ldap:// or ldaps:// url and authenticate with sasl
+pub fn sasl_bind(url: &str, mech: &str, user: &str, password: &str, options: &LdapOptions) -> Result<bool> {
+    let mech = Mechanism::parse(mech)?;
+
+    let (scheme, host, port, _) = split_url(url)?;
+    let mut conn = SaslConn::connect(host, port, options)?;
+    if scheme == "ldaps" {
+        conn = conn.tls(host, options)?;
+    } else if options.starttls.unwrap_or(false) {
+        conn.starttls()?;
+        conn = conn.tls(host, options)?;
+    }
+
+    let (code, _) = match mech {
+        Mechanism::Plain => {
+            if !conn.is_tls() && !options.allow_plaintext.unwrap_or(false) {
+                bail!("PLAIN sends the password in cleartext, use ldaps://, starttls=true or set allow_plaintext=true");
+            }
+            let creds = sasl::plain("", user, password);
+            conn.bind(&mech, Some(&creds))?
+        },
+        Mechanism::DigestMd5 => {
+            let (code, challenge) = conn.bind(&mech, None)?;
+            if code != LDAP_SASL_BIND_IN_PROGRESS {
+                (code, challenge)
+            } else {
+                let challenge = challenge
+                    .chain_err(|| "server didn't send a challenge")?;
+
+                let digest = DigestMd5 {
+                    user,
+                    password,
+                    realm: options.realm.as_deref(),
+                    digest_uri: format!("ldap/{}", host),
+                    cnonce: thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
+                };
+                let response = digest.response(&challenge)?;
+
+                let (code, rspauth) = conn.bind(&mech, Some(&response))?;
+                match code {
+                    LDAP_SASL_BIND_IN_PROGRESS => conn.bind(&mech, None)?,
+                    LDAP_SUCCESS => {
+                        if let Some(rspauth) = rspauth {
+                            let expected = format!("rspauth={}", digest.rspauth(&challenge)?);
+                            if rspauth != expected.as_bytes() {
+                                bail!("server sent an invalid rspauth");
+                            }
+                        }
+                        (code, None)
+                    },
+                    code => (code, None),
+                }
+            }
+        },
+    };
+
+    match code {
+        LDAP_SUCCESS => Ok(true),
+        LDAP_AUTH_METHOD_NOT_SUPPORTED => bail!("sasl mechanism not supported by server: {}", mech.name()),
+        _ => Ok(false),
+    }
+}
+
+/// Split an ldap:// or ldaps:// url into the scheme, host, port and the rest
+fn split_url(url: &str) -> Result<(&str, &str, u16, &str)> {
+    let (scheme, default_port) = if url.starts_with("ldap://") {
+        ("ldap", 389)
+    } else if url.starts_with("ldaps://") {
+        ("ldaps", 636)
+    } else {
+        bail!("unsupported ldap url: {:?}", url);
+    };
+
+    let rest = &url[scheme.len() + 3..];
+    let (addr, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, ""),
+    };
+    let (host, port) = proxy::split_host_port(addr, Some(default_port))?;
+    Ok((scheme, host, port, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_split_url() {
+        assert_eq!(split_url("ldap://dc1.corp").unwrap(), ("ldap", "dc1.corp", 389, ""));
+        assert_eq!(split_url("ldaps://10.0.0.1:3269/dc=corp").unwrap(), ("ldaps", "10.0.0.1", 3269, "/dc=corp"));
+        assert_eq!(split_url("ldap://[::1]/").unwrap(), ("ldap", "::1", 389, "/"));
+        assert!(split_url("ldapi://%2fvar%2frun%2fldapi").is_err());
+        assert!(split_url("ldap://dc1.corp:ldap").is_err());
+    }
+
+    #[test]
+    fn verify_options_nil() {
+        let options = LdapOptions::try_from(AnyLuaValue::LuaNil).unwrap();
+        assert_eq!(options.timeout(), None);
+        assert_eq!(options.starttls, None);
+    }
+
+    #[test]
+    fn verify_options_table() {
+        let options = LdapOptions::try_from(AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString("timeout".into()), AnyLuaValue::LuaNumber(3.0)),
+            (AnyLuaValue::LuaString("starttls".into()), AnyLuaValue::LuaBoolean(true)),
+            (AnyLuaValue::LuaString("tls_verify".into()), AnyLuaValue::LuaBoolean(false)),
+        ])).unwrap();
+        assert_eq!(options.timeout(), Some(Duration::from_secs(3)));
+        assert_eq!(options.starttls, Some(true));
+        assert_eq!(options.tls_verify, Some(false));
+    }
+
+    #[test]
+    fn verify_search_filter() {
+        let options = LdapOptions::default();
+        assert_eq!(options.search_filter("foo"), "uid=foo");
+        assert_eq!(options.multiple(), MultipleEntries::First);
+
+        let options = LdapOptions::try_from(AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString("filter".into()), AnyLuaValue::LuaString("(sAMAccountName={})".into())),
+            (AnyLuaValue::LuaString("multiple".into()), AnyLuaValue::LuaString("all".into())),
+        ])).unwrap();
+        assert_eq!(options.search_filter("foo,bar"), "(sAMAccountName=foo\\2cbar)");
+        assert_eq!(options.multiple(), MultipleEntries::All);
+    }
+
+    #[test]
+    fn verify_entry_to_lua() {
+        let mut entry = SearchEntry {
+            dn: "cn=foo,dc=example,dc=com".into(),
+            attrs: Default::default(),
+            bin_attrs: Default::default(),
+        };
+        entry.attrs.insert("memberOf".into(), vec!["cn=a".into(), "cn=b".into()]);
+        entry.bin_attrs.insert("objectGUID".into(), vec![vec![0xff, 0x00]]);
+
+        let entry: LuaMap = match entry_to_lua(entry) {
+            AnyLuaValue::LuaArray(x) => x.into(),
+            x => panic!("unexpected value: {:?}", x),
+        };
+        let json = ::json::encode(entry.into()).unwrap();
+        assert!(json.contains(r#""dn":"cn=foo,dc=example,dc=com""#));
+        assert!(json.contains(r#""memberOf":["cn=a","cn=b"]"#));
+        assert!(json.contains(r#""objectGUID":[[255,0]]"#));
+    }
+
+    #[test]
+    fn verify_ber_roundtrip() {
+        let content = vec![0x41; 300];
+        let msg = ber(0x04, &content);
+        assert_eq!(&msg[..4], &[0x04, 0x82, 0x01, 0x2c]);
+
+        let (tag, value, rest) = ber_split(&msg).unwrap();
+        assert_eq!(tag, 0x04);
+        assert_eq!(value, &content[..]);
+        assert!(rest.is_empty());
+
+        let read = read_ber(&mut &msg[..]).unwrap();
+        assert_eq!(read, msg);
+    }
+
+    #[test]
+    fn verify_sasl_cleartext() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            // the first connection is closed without a request
+            let (mut sock, _) = listener.accept().unwrap();
+            assert_eq!(sock.read(&mut [0; 16]).unwrap(), 0);
+
+            // refuse StartTLS with protocolError
+            let (mut sock, _) = listener.accept().unwrap();
+            let req = read_ber(&mut sock).unwrap();
+            assert!(req.windows(LDAP_STARTTLS_OID.len()).any(|x| x == LDAP_STARTTLS_OID));
+            let mut resp = ber(0x0a, &[2]);
+            resp.extend(ber(0x04, b""));
+            resp.extend(ber(0x04, b""));
+            let mut msg = ber(0x02, &[1]);
+            msg.extend(ber(0x78, &resp));
+            sock.write_all(&ber(0x30, &msg)).unwrap();
+        });
+
+        let err = sasl_bind(&url, "PLAIN", "foo", "bar", &LdapOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("cleartext"));
+
+        let options = LdapOptions::try_from(AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString("starttls".into()), AnyLuaValue::LuaBoolean(true)),
+        ])).unwrap();
+        let err = sasl_bind(&url, "PLAIN", "foo", "bar", &options).unwrap_err();
+        assert!(err.to_string().contains("refused StartTLS"));
+        server.join().unwrap();
+    }
+}
@@ -7,13 +7,18 @@ extern crate time;
 extern crate atty;
 extern crate rand;
 extern crate getch;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 extern crate kuchiki;
 extern crate toml;
 extern crate nix;
 extern crate libc;
 extern crate bufstream;
 extern crate regex;
+extern crate flate2;
+extern crate xz2;
+extern crate humantime;
+extern crate serde;
+extern crate env_logger;
 #[macro_use] extern crate log;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate error_chain;
@@ -30,26 +35,53 @@ extern crate bcrypt;
 
 #[cfg(not(windows))]
 extern crate termios;
+#[cfg(all(unix, not(target_os = "macos")))]
+extern crate openssl_probe;
 
 extern crate reqwest;
 extern crate mysql;
 extern crate ldap3;
+extern crate native_tls;
+extern crate futures;
 extern crate twox_hash;
 
 pub mod args;
+pub mod autoscale;
+pub mod benchmark;
+pub mod catalog;
+pub mod checkpoint;
+pub mod cluster;
 pub mod config;
+pub mod control;
 pub mod ctx;
 pub mod db;
+pub mod dns;
+pub mod errkind;
+pub mod exec;
 pub mod fsck;
+pub mod hashes;
 pub mod html;
 pub mod http;
 pub mod json;
 pub mod keyboard;
+pub mod ldap;
+pub mod logger;
+pub mod notify;
 pub mod pb;
+pub mod process;
+pub mod proxy;
+pub mod ratelimit;
+pub mod recheck;
+pub mod report;
 pub mod runtime;
+pub mod sasl;
 pub mod scheduler;
+pub mod shuffle;
 pub mod sockets;
+pub mod status;
 pub mod structs;
+pub mod syslog;
+pub mod traceback;
 pub mod ulimit;
 pub mod utils;
 
@@ -80,5 +112,27 @@ pub mod errors {
             Mysql(mysql::Error);
             Regex(regex::Error);
         }
+
+        errors {
+            // the script decided if the error is retried, see set_retry
+            Transient(msg: String) {
+                description("transient error")
+                display("{}", msg)
+            }
+            Fatal(msg: String) {
+                description("fatal error")
+                display("{}", msg)
+            }
+            // the target asked to slow down, see throttle
+            Throttled(delay: ::std::time::Duration) {
+                description("throttled")
+                display("throttled for {:?}", delay)
+            }
+            // the proxy of the attempt couldn't be reached, see [proxies]
+            ProxyDown(proxy: String) {
+                description("proxy is down")
+                display("proxy {} is down", proxy)
+            }
+        }
     }
 }
@@ -0,0 +1,162 @@
+//! Log records are written above the progress bar, records that are emitted
+//! during an attempt are tagged with it so concurrent output can be told apart
+
+use env_logger::filter::{self, Filter};
+use log::{self, Log, Metadata, Record, LevelFilter};
+use scheduler::Msg;
+
+use std::cell::RefCell;
+use std::env;
+use std::fmt::Write;
+use std::sync::{mpsc, Mutex};
+
+// bytes per line of a hexdump
+const HEXDUMP_WIDTH: usize = 16;
+
+
+thread_local! {
+    // the attempt that is currently running on this thread, if any
+    static ATTEMPT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// the records are sent to the main thread once the progress bar is shown
+static OUTPUT: Mutex<Option<mpsc::Sender<Msg>>> = Mutex::new(None);
+
+struct Logger {
+    filter: Filter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let attempt = ATTEMPT.with(|x| x.borrow().clone());
+        let line = match attempt {
+            Some(attempt) => format!("[{}] {} ({}): {}", record.level(), record.target(), attempt, record.args()),
+            None => format!("[{}] {}: {}", record.level(), record.target(), record.args()),
+        };
+
+        let output = OUTPUT.lock().unwrap();
+        // fall back to stderr if the receiver is already gone
+        let line = match *output {
+            Some(ref tx) => match tx.send(Msg::Log(line)) {
+                Ok(_) => return,
+                Err(err) => match err.0 {
+                    Msg::Log(line) => line,
+                    _ => unreachable!(),
+                },
+            },
+            None => line,
+        };
+        eprintln!("{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Setup the logger, `RUST_LOG` takes precedence over `-v`
+pub fn init(verbose: u8) {
+    let mut builder = filter::Builder::new();
+    match env::var("RUST_LOG") {
+        Ok(filters) => { builder.parse(&filters); },
+        Err(_) => {
+            match verbose {
+                0 => builder.filter_level(LevelFilter::Error),
+                1 => builder.filter_level(LevelFilter::Error).filter_module("badtouch", LevelFilter::Info),
+                2 => builder.filter_level(LevelFilter::Error).filter_module("badtouch", LevelFilter::Debug),
+                _ => builder.filter_level(LevelFilter::Debug),
+            };
+        },
+    }
+
+    let filter = builder.build();
+    log::set_max_level(filter.filter());
+    // this only fails if a logger is already set
+    let _ = log::set_boxed_logger(Box::new(Logger {
+        filter,
+    }));
+}
+
+/// Write log records above the progress bar instead of stderr
+pub fn set_output(tx: Option<mpsc::Sender<Msg>>) {
+    let mut output = OUTPUT.lock().unwrap();
+    *output = tx;
+}
+
+/// Tags log records on this thread with the attempt until it's dropped
+pub struct Scope(Option<String>);
+
+impl Scope {
+    pub fn enter(attempt: String) -> Scope {
+        Scope(ATTEMPT.with(|x| x.replace(Some(attempt))))
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        ATTEMPT.with(|x| *x.borrow_mut() = previous);
+    }
+}
+
+/// Format bytes like `hexdump -C`
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in data.chunks(HEXDUMP_WIDTH).enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+
+        let _ = write!(out, "{:08x} ", i * HEXDUMP_WIDTH);
+        for idx in 0..HEXDUMP_WIDTH {
+            if idx % 8 == 0 {
+                out.push(' ');
+            }
+            match chunk.get(idx) {
+                Some(b) => { let _ = write!(out, "{:02x} ", b); },
+                None => out += "   ",
+            }
+        }
+
+        out += " |";
+        for b in chunk {
+            out.push(if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' });
+        }
+        out.push('|');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_hexdump() {
+        assert_eq!(hexdump(b""), "");
+        assert_eq!(hexdump(b"hello\n"),
+            "00000000  68 65 6c 6c 6f 0a                                 |hello.|");
+        let dump = hexdump(b"0123456789abcdefXY");
+        assert_eq!(dump.lines().count(), 2);
+        assert!(dump.ends_with("00000010  58 59                                             |XY|"));
+    }
+
+    #[test]
+    fn verify_scope() {
+        {
+            let _outer = Scope::enter("outer".into());
+            {
+                let _inner = Scope::enter("inner".into());
+                assert_eq!(ATTEMPT.with(|x| x.borrow().clone()), Some("inner".into()));
+            }
+            assert_eq!(ATTEMPT.with(|x| x.borrow().clone()), Some("outer".into()));
+        }
+        assert_eq!(ATTEMPT.with(|x| x.borrow().clone()), None);
+    }
+}
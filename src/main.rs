@@ -1,155 +1,930 @@
 #![warn(unused_extern_crates)]
 extern crate badtouch;
-extern crate env_logger;
 extern crate colored;
 extern crate humantime;
 extern crate atty;
 extern crate error_chain;
+extern crate nix;
+extern crate rand;
 #[macro_use] extern crate log;
 
 use badtouch::args;
-use badtouch::ctx::Script;
+use badtouch::autoscale::{self, AutoScale};
+use badtouch::benchmark;
+use badtouch::catalog;
+use badtouch::checkpoint::{Bitset, Checkpoint, Input, Found};
+use badtouch::cluster;
+use badtouch::ctx::{self, Script};
+use badtouch::errkind;
 use badtouch::fsck;
-use badtouch::utils;
+use badtouch::hashes::{self, InputFormat};
+use badtouch::logger;
+use badtouch::notify::{Notifier, Template};
+use badtouch::shuffle::Shuffle;
+use badtouch::status::{Status, StatusStream};
+use badtouch::syslog::Syslog;
+use badtouch::utils::{self, Cred, ListOptions, Passwords};
 use badtouch::config::Config;
+use badtouch::control::{self, ControlSocket};
 use badtouch::pb::ProgressBar;
-use badtouch::scheduler::{Scheduler, Attempt, Creds, Msg};
+use badtouch::scheduler::{Scheduler, Attempt, Backoff, Creds, Level, Msg, Order};
+use badtouch::ratelimit::RateLimit;
+use badtouch::recheck;
+use badtouch::report::{self, AttemptLog, Confirmations, LogEntry, Report, Finding};
 use badtouch::keyboard::{Keyboard, Key};
 use badtouch::ulimit::{Resource, getrlimit, setrlimit};
 
 use error_chain::ChainedError;
 use colored::*;
+use nix::sys::signal::{SigSet, Signal};
+use rand::Rng;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader};
 use std::thread;
-use std::fs::File;
 use std::sync::Arc;
-use std::time::Instant;
-use std::io::prelude::*;
-use badtouch::errors::{Result, ResultExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use badtouch::errors::{Result, ResultExt, Error, ErrorKind};
 
 
-enum Report {
-    Some(File),
-    None
+macro_rules! tinfof {
+    ($arg1:tt, $fmt:expr, $($arg:tt)*) => (
+        $arg1.bold().to_string() + " " + &(format!($fmt, $($arg)*).dimmed().to_string())
+    );
+}
+
+// set with --quiet, warnings are still shown
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+macro_rules! tinfo {
+    ($arg1:tt, $fmt:expr, $($arg:tt)*) => (
+        if $arg1 == "[!]" || !QUIET.load(Ordering::Relaxed) {
+            println!("{}", tinfof!($arg1, $fmt, $($arg)*));
+        }
+    );
+}
+
+// how many attempts are shown with --dry-run
+const DRY_RUN_SAMPLES: usize = 10;
+// the rate in the status is measured over this window
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// how long running attempts may finish after ctrl+c or SIGTERM
+const INTERRUPT_GRACE: Duration = Duration::from_secs(5);
+
+// exit codes, these are documented in --help
+const EXIT_OK: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+// nothing was found, or something was found with --fail-on-found
+const EXIT_FAILED: i32 = 2;
+const EXIT_INTERRUPTED: i32 = 130;
+const EXIT_TERMINATED: i32 = 143;
+
+fn report_unsafe(scripts: &[Arc<Script>], config: &Config) {
+    for (i, script) in scripts.iter().enumerate() {
+        // only report each script once if it's loaded for multiple targets
+        if scripts[..i].iter().any(|x| x.descr() == script.descr()) {
+            continue;
+        }
+        let unsafe_functions = script.unsafe_functions();
+        if unsafe_functions.is_empty() {
+            continue;
+        }
+
+        if config.runtime.sandbox {
+            tinfo!("[!]", "{} uses {} which is disabled by sandbox", script.descr(), unsafe_functions.join(", "));
+        } else if config.runtime.proxy_strict {
+            tinfo!("[!]", "{} uses {} which is disabled by proxy-strict", script.descr(), unsafe_functions.join(", "));
+        } else {
+            tinfo!("[*]", "{} uses unsafe functions: {}", script.descr(), unsafe_functions.join(", "));
+        }
+    }
+}
+
+fn script_inputs(scripts: &[Arc<Script>], config: &Config) -> Vec<Input> {
+    let mut inputs = scripts.iter()
+        .map(|script| Input::new(script.label(), script.code().as_bytes()))
+        .collect::<Vec<_>>();
+    // only added if set, so checkpoints of older versions still match
+    if !config.options.is_empty() {
+        let mut options = config.options.iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect::<Vec<_>>();
+        options.sort();
+        inputs.push(Input::new("options", options.concat().as_bytes()));
+    }
+    inputs
+}
+
+fn open_checkpoint<F>(path: Option<String>, total: usize, inputs: F) -> Result<Option<Checkpoint>>
+    where F: FnOnce() -> Result<Vec<Input>>
+{
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let checkpoint = Checkpoint::open(&path, inputs()?, total)
+        .chain_err(|| format!("failed to open checkpoint {:?}", path))?;
+    let completed = checkpoint.num_completed();
+    if completed > 0 {
+        tinfo!("[+]", "resuming from checkpoint, {} of {} attempts completed", completed, total);
+    }
+    Ok(Some(checkpoint))
+}
+
+/// The attempts that were completed before we resumed
+fn completed(checkpoint: &Option<Checkpoint>) -> Option<Bitset> {
+    checkpoint.as_ref()
+        .map(|checkpoint| checkpoint.completed().clone())
+}
+
+#[inline]
+fn is_completed(completed: &Option<Bitset>, idx: usize) -> bool {
+    match *completed {
+        Some(ref completed) => completed.contains(idx),
+        None => false,
+    }
+}
+
+fn remaining(checkpoint: &Option<Checkpoint>, total: usize) -> usize {
+    match *checkpoint {
+        Some(ref checkpoint) => total - checkpoint.num_completed(),
+        None => total,
+    }
+}
+
+/// Skip the findings of --skip-report that belong to the loaded scripts
+fn skip_known(pool: &mut Scheduler, scripts: &[Arc<Script>], skip_report: &Option<SkipReport>) {
+    let skip_report = match *skip_report {
+        Some(ref skip_report) => skip_report,
+        None => return,
+    };
+
+    let labels = scripts.iter().map(|x| x.label()).collect::<Vec<_>>();
+    let mut creds = HashSet::new();
+    let mut users = HashSet::new();
+    for found in skip_report.prior.findings(&labels) {
+        let password = found.password.unwrap_or_default();
+        pool.skip_creds(&found.script, &found.user, &password);
+        if skip_report.users {
+            pool.skip_user(&found.script, &found.user);
+            users.insert((found.script.clone(), found.user.clone()));
+        }
+        creds.insert((found.script, found.user, password));
+    }
+
+    if skip_report.users {
+        tinfo!("[+]", "loaded {} findings from {}, skipping {} attempts and the other attempts of {} users",
+            skip_report.prior.len(), skip_report.path, creds.len(), users.len());
+    } else {
+        tinfo!("[+]", "loaded {} findings from {}, skipping {} attempts", skip_report.prior.len(), skip_report.path, creds.len());
+    }
+}
+
+/// Generate attempts while the list is read from stdin. The list might never
+/// end, so the attempts are created as needed instead of all at once
+fn stream_attempts<F>(pool: &mut Scheduler, setup: Setup, mut attempts: F) -> Result<(Option<usize>, Option<Checkpoint>)>
+    where F: FnMut(Arc<String>) -> Vec<Attempt> + 'static
+{
+    if setup.checkpoint.is_some() {
+        return Err("checkpoints can't be used with a list from stdin".into());
+    }
+
+    let tx = pool.tx();
+    let lists = setup.lists;
+    // only the attempts for each line can be shuffled
+    let mut rng = setup.seed.map(Shuffle::rng);
+    let source = BufReader::new(io::stdin()).lines()
+        .enumerate()
+        .map_while(move |(i, line)| match line {
+            Ok(line) => Some(Arc::new(lists.normalize(&line, i == 0).to_string())),
+            Err(err) => {
+                let _ = tx.send(Msg::Print(Level::Warn, format!("failed to read stdin: {}", err)));
+                None
+            },
+        })
+        .flat_map(move |line| {
+            let mut attempts = attempts(line);
+            if let Some(ref mut rng) = rng {
+                rng.shuffle(&mut attempts);
+            }
+            attempts
+        })
+        .enumerate()
+        .map(|(idx, mut attempt)| {
+            attempt.idx = idx;
+            attempt
+        });
+
+    tinfo!("[*]", "reading from stdin, submitting jobs to threadpool with {} workers", pool.max_count());
+    pool.feed(Box::new(source));
+
+    Ok((None, None))
+}
+
+/// Options that affect which attempts are generated and in which order
+struct Setup {
+    checkpoint: Option<String>,
+    lists: ListOptions,
+    // shuffle the attempts with this seed
+    seed: Option<u64>,
+    // run every script against each line of this file, see --targets
+    targets: Option<String>,
+    skip_report: Option<SkipReport>,
+    // the attempts are handed to workers, see serve
+    remote: Option<cluster::Dispatcher>,
+}
+
+/// The findings of --skip-report, they are matched with the scripts once
+/// they are loaded
+struct SkipReport {
+    path: String,
+    prior: report::PriorReport,
+    // also skip the other attempts of the found users, see --skip-found-users
+    users: bool,
+}
+
+/// The indexes of all attempts, in random order if a seed is set
+fn indexes(total: usize, seed: Option<u64>) -> Box<dyn Iterator<Item=usize>> {
+    match seed {
+        Some(seed) => {
+            let shuffle = Shuffle::new(total, seed);
+            Box::new((0..total).map(move |idx| shuffle.get(idx)))
+        },
+        None => Box::new(0..total),
+    }
+}
+
+fn report_loaded(name: &str, len: usize, duplicates: usize) {
+    if duplicates > 0 {
+        tinfo!("[+]", "loaded {} {} ({} duplicates removed)", len, name, duplicates);
+    } else {
+        tinfo!("[+]", "loaded {} {}", len, name);
+    }
+
+    if len == 0 {
+        tinfo!("[!]", "no {} loaded, the list is empty", name);
+    }
 }
 
-impl Report {
-    pub fn open(path: Option<String>) -> Result<Report> {
-        match path {
-            Some(path) => Ok(Report::Some(File::create(path)?)),
-            None => Ok(Report::None),
+/// Show what would be done without starting any attempts, see --dry-run
+fn print_plan(pool: &mut Scheduler) {
+    let mut scripts: Vec<(Arc<Script>, usize)> = Vec::new();
+    let mut samples = Vec::new();
+    let mut total = 0;
+
+    for attempt in pool.take_source().into_iter().flatten() {
+        match scripts.iter_mut().find(|x| Arc::ptr_eq(&x.0, &attempt.script)) {
+            Some(x) => x.1 += 1,
+            None => scripts.push((attempt.script.clone(), 1)),
+        }
+
+        if samples.len() < DRY_RUN_SAMPLES {
+            samples.push(match attempt.creds {
+                Creds::Enum(_) => format!("{} {:?}", attempt.script.label(), attempt.user()),
+                _ => format!("{} {:?}:{:?}", attempt.script.label(), attempt.user(), attempt.password()),
+            });
         }
+        total += 1;
     }
 
-    pub fn write_creds(&mut self, user: &str, password: &str, script: &str) -> Result<()> {
-        if let Report::Some(ref mut f) = *self {
-            writeln!(f, "{}:{}:{}", script, user, password)?;
+    tinfo!("[*]", "dry run, {} attempts would be started", total);
+    for (i, &(ref script, count)) in scripts.iter().enumerate() {
+        tinfo!("[*]", "  {}: {} attempts", script.label(), count);
+
+        if scripts[..i].iter().any(|x| x.0.label() == script.label()) {
+            tinfo!("[!]", "{} is loaded more than once", script.label());
         }
-        Ok(())
     }
 
-    pub fn write_enum(&mut self, user: &str, script: &str) -> Result<()> {
-        if let Report::Some(ref mut f) = *self {
-            writeln!(f, "{}:{}", script, user)?;
+    if !samples.is_empty() {
+        tinfo!("[*]", "{}", "first attempts:");
+        for sample in samples {
+            tinfo!("[*]", "  {}", sample);
         }
-        Ok(())
+    }
+
+    if total == 0 {
+        tinfo!("[!]", "{}", "there is nothing to do, the lists are empty or all attempts are completed");
     }
 }
 
-macro_rules! tinfof {
-    ($arg1:tt, $fmt:expr, $($arg:tt)*) => (
-        $arg1.bold().to_string() + " " + &(format!($fmt, $($arg)*).dimmed().to_string())
-    );
+fn load_list(path: &str, name: &str, lists: &ListOptions) -> Result<Vec<Arc<String>>> {
+    let (list, duplicates) = utils::load_list(path, lists)
+        .chain_err(|| format!("failed to load {}", name))?;
+    report_loaded(name, list.len(), duplicates);
+    Ok(list)
 }
 
-macro_rules! tinfo {
-    ($arg1:tt, $fmt:expr, $($arg:tt)*) => (
-        println!("{}", tinfof!($arg1, $fmt, $($arg)*));
-    );
+/// Tell the workers which lists to load, they create the attempts from their
+/// index like we do
+fn describe_lists(remote: &Option<cluster::Dispatcher>, mode: cluster::Mode, paths: &[&str], entries: usize, scripts: &[Arc<Script>], lists: &ListOptions) -> Result<()> {
+    let remote = match *remote {
+        Some(ref remote) => remote,
+        None => return Ok(()),
+    };
+    let files = paths.iter()
+        .map(|path| Input::file(path).chain_err(|| format!("failed to read {:?}", path)))
+        .collect::<Result<Vec<_>>>()?;
+    remote.describe(cluster::Lists {
+        mode,
+        files,
+        dedup: lists.dedup,
+        trim: lists.trim,
+        scripts: scripts.iter().map(|x| x.label().to_string()).collect(),
+        entries,
+    });
+    Ok(())
+}
+
+/// Normalizing the lists changes the attempts, this has to be part of the checkpoint
+fn list_input(lists: &ListOptions) -> Input {
+    Input::new("lists", format!("dedup={} trim={}", lists.dedup, lists.trim).as_bytes())
+}
+
+fn setup_dictionary_attack(pool: &mut Scheduler, args: args::Dict, config: &Arc<Config>, setup: Setup) -> Result<(Option<usize>, Option<Checkpoint>)> {
+    let Setup { checkpoint, ref lists, seed, ref targets, ref skip_report, ref remote } = setup;
+    if args.users == "-" && args.passwords == "-" {
+        return Err("only one list can be read from stdin".into());
+    }
+
+    if args.stream && (args.users == "-" || args.passwords == "-") {
+        return Err("--stream can't be used with a list from stdin".into());
+    }
+    if args.stream && seed.is_some() {
+        return Err("--shuffle can't be used with --stream, the passwords are read in order".into());
+    }
+
+    if args.users == "-" || args.passwords == "-" {
+        // the list from stdin is the outer loop
+        match (args.order, args.users == "-") {
+            (Some(Order::PasswordFirst), true) => return Err("--order password-first can't be used if the users are read from stdin".into()),
+            (Some(Order::UserFirst), false) => return Err("--order user-first can't be used if the passwords are read from stdin".into()),
+            _ => (),
+        }
+
+        let (list, stdin_is_users) = if args.users == "-" {
+            let passwords = load_list(&args.passwords, "passwords", lists)?;
+            (passwords, true)
+        } else {
+            let users = load_list(&args.users, "users", lists)?;
+            (users, false)
+        };
+        let scripts = load_scripts(args.scripts, targets.as_deref(), lists, config)?;
+        tinfo!("[+]", "loaded {} scripts", scripts.len());
+        report_unsafe(&scripts, config);
+        skip_known(pool, &scripts, skip_report);
+
+        return stream_attempts(pool, Setup { checkpoint, lists: *lists, seed, targets: targets.clone(), skip_report: None, remote: None }, move |line| {
+            let mut attempts = Vec::new();
+            for item in &list {
+                let (user, password) = if stdin_is_users { (&line, item) } else { (item, &line) };
+                for script in &scripts {
+                    attempts.push(Attempt::new(user, password, script, 0));
+                }
+            }
+            attempts
+        });
+    }
+
+    let users = load_list(&args.users, "users", lists)?;
+    let mut passwords = if args.stream {
+        let list = utils::StreamedList::open(&args.passwords, lists)
+            .chain_err(|| "failed to load passwords")?;
+        tinfo!("[+]", "streaming {} passwords from disk", list.len());
+        Passwords::Streamed(list)
+    } else {
+        Passwords::Loaded(load_list(&args.passwords, "passwords", lists)?)
+    };
+    let scripts = load_scripts(args.scripts, targets.as_deref(), lists, config)?;
+    tinfo!("[+]", "loaded {} scripts", scripts.len());
+    report_unsafe(&scripts, config);
+    skip_known(pool, &scripts, skip_report);
+
+    let total = users.len() * passwords.len() * scripts.len();
+    let order = match args.order {
+        Some(order) => order,
+        // older versions defaulted to user-first, keep resuming their checkpoints
+        None if checkpoint.as_ref().is_some_and(|path| {
+            Checkpoint::inputs(path).ok().flatten()
+                .is_some_and(|inputs| inputs.contains(&Input::new("order", Order::UserFirst.as_str().as_bytes())))
+        }) => Order::UserFirst,
+        None => Order::default(),
+    };
+    let (users_path, passwords_path) = (&args.users, &args.passwords);
+    describe_lists(remote, cluster::Mode::Dict {
+        order: order.as_str().to_string(),
+        stream: args.stream,
+    }, &[users_path, passwords_path], users.len() * passwords.len(), &scripts, lists)?;
+    let checkpoint = open_checkpoint(checkpoint, total, || {
+        let mut inputs = vec![
+            Input::new("mode", b"dict"),
+            Input::new("order", order.as_str().as_bytes()),
+            list_input(lists),
+            Input::file(users_path)?,
+            Input::file(passwords_path)?,
+        ];
+        inputs.extend(script_inputs(&scripts, config));
+        Ok(inputs)
+    })?;
+
+    let attempts = remaining(&checkpoint, total);
+    tinfo!("[*]", "submitting {} jobs to threadpool with {} workers", attempts, pool.max_count());
+
+    // the index is split into user, password and script, attempts are only
+    // created when the scheduler needs them
+    let completed = completed(&checkpoint);
+    let tx = pool.tx();
+    let source = indexes(total, seed)
+        .filter(move |idx| !is_completed(&completed, *idx))
+        .map_while(move |idx| {
+            let script = &scripts[idx % scripts.len()];
+            let (user, password) = order.split(idx / scripts.len(), users.len(), passwords.len());
+            match passwords.get(password) {
+                Ok(password) => Some(Attempt::new(&users[user], &password, script, idx)),
+                Err(err) => {
+                    let _ = tx.send(Msg::Print(Level::Warn, format!("failed to read passwords: {}", err)));
+                    None
+                },
+            }
+        });
+    pool.feed(Box::new(source));
+
+    Ok((Some(attempts), checkpoint))
 }
 
-fn setup_dictionary_attack(pool: &mut Scheduler, args: args::Dict, config: &Arc<Config>) -> Result<usize> {
-    let users = utils::load_list(&args.users).chain_err(|| "failed to load users")?;
-    tinfo!("[+]", "loaded {} users", users.len());
-    let passwords = utils::load_list(&args.passwords).chain_err(|| "failed to load passwords")?;
-    tinfo!("[+]", "loaded {} passwords", passwords.len());
-    let scripts = utils::load_scripts(args.scripts, &config).chain_err(|| "failed to load scripts")?;
+fn setup_credential_confirmation(pool: &mut Scheduler, args: args::Creds, config: &Arc<Config>, setup: Setup) -> Result<(Option<usize>, Option<Checkpoint>)> {
+    let Setup { checkpoint, ref lists, seed, ref targets, ref skip_report, ref remote } = setup;
+    let (creds, duplicates) = match args.input_format {
+        InputFormat::Plain => {
+            let (creds, duplicates, warnings) = utils::load_creds(&args.creds, lists)?;
+            for warning in warnings {
+                tinfo!("[!]", "{}, {}", args.creds, warning);
+            }
+            (creds, duplicates)
+        },
+        format => {
+            let (creds, duplicates, skipped) = hashes::load_hashes(&args.creds, format, lists)
+                .chain_err(|| format!("failed to load {} dump", format.as_str()))?;
+            for line in skipped {
+                tinfo!("[!]", "skipping {}, {}", args.creds, line);
+            }
+            (creds, duplicates)
+        },
+    };
+    report_loaded("credentials", creds.len(), duplicates);
+    let scripts = load_scripts(args.scripts, targets.as_deref(), lists, config)?;
     tinfo!("[+]", "loaded {} scripts", scripts.len());
+    report_unsafe(&scripts, config);
+    skip_known(pool, &scripts, skip_report);
+
+    let total = creds.len() * scripts.len();
+    let creds_path = &args.creds;
+    let input_format = args.input_format;
+    describe_lists(remote, cluster::Mode::Creds {
+        input_format: input_format.as_str().to_string(),
+    }, &[creds_path], creds.len(), &scripts, lists)?;
+    let checkpoint = open_checkpoint(checkpoint, total, || {
+        let mut inputs = vec![
+            Input::new("mode", b"creds"),
+            list_input(lists),
+            Input::file(creds_path)?,
+        ];
+        if input_format != InputFormat::Plain {
+            inputs.push(Input::new("input-format", input_format.as_str().as_bytes()));
+        }
+        inputs.extend(script_inputs(&scripts, config));
+        Ok(inputs)
+    })?;
 
-    let attempts = users.len() * passwords.len() * scripts.len();
+    let attempts = remaining(&checkpoint, total);
     tinfo!("[*]", "submitting {} jobs to threadpool with {} workers", attempts, pool.max_count());
 
-    for user in &users {
-        for password in &passwords {
-            for script in &scripts {
-                let attempt = Attempt::new(user, password, script);
-                pool.run(attempt);
+    let completed = completed(&checkpoint);
+    let source = indexes(total, seed)
+        .filter(move |idx| !is_completed(&completed, *idx))
+        .map(move |idx| {
+            let script = &scripts[idx % scripts.len()];
+            match creds[idx / scripts.len()] {
+                Cred::Line(ref line) => Attempt::bytes(line, script, idx),
+                Cred::Pair(ref user, ref password) => Attempt::new(user, password, script, idx),
+                Cred::Hash(ref hash) => Attempt::hash(hash, script, idx),
             }
+        });
+    pool.feed(Box::new(source));
+
+    Ok((Some(attempts), checkpoint))
+}
+
+fn setup_combo_attack(pool: &mut Scheduler, args: args::Combo, config: &Arc<Config>, setup: Setup) -> Result<(Option<usize>, Option<Checkpoint>)> {
+    let Setup { checkpoint, ref lists, seed, ref targets, ref skip_report, ref remote } = setup;
+    let mut combos = Vec::new();
+    for combo in utils::load_combos(&args.combos, lists).chain_err(|| "failed to load combos")? {
+        match combo {
+            Ok(combo) => combos.push(combo),
+            Err(err) => {
+                tinfo!("[!]", "skipping {}, {}", args.combos, err);
+            },
         }
     }
+    let (combos, duplicates) = lists.dedup(combos);
+    report_loaded("combos", combos.len(), duplicates);
+    let scripts = load_scripts(args.scripts, targets.as_deref(), lists, config)?;
+    tinfo!("[+]", "loaded {} scripts", scripts.len());
+    report_unsafe(&scripts, config);
+    skip_known(pool, &scripts, skip_report);
+
+    let total = combos.len() * scripts.len();
+    let combos_path = &args.combos;
+    describe_lists(remote, cluster::Mode::Combo, &[combos_path], combos.len(), &scripts, lists)?;
+    let checkpoint = open_checkpoint(checkpoint, total, || {
+        let mut inputs = vec![
+            Input::new("mode", b"combo"),
+            list_input(lists),
+            Input::file(combos_path)?,
+        ];
+        inputs.extend(script_inputs(&scripts, config));
+        Ok(inputs)
+    })?;
 
-    Ok(attempts)
+    let attempts = remaining(&checkpoint, total);
+    tinfo!("[*]", "submitting {} jobs to threadpool with {} workers", attempts, pool.max_count());
+
+    let completed = completed(&checkpoint);
+    let source = indexes(total, seed)
+        .filter(move |idx| !is_completed(&completed, *idx))
+        .map(move |idx| {
+            let script = &scripts[idx % scripts.len()];
+            let (ref user, ref password) = combos[idx / scripts.len()];
+            Attempt::new(user, password, script, idx)
+        });
+    pool.feed(Box::new(source));
+
+    Ok((Some(attempts), checkpoint))
 }
 
-fn setup_credential_confirmation(pool: &mut Scheduler, args: args::Creds, config: &Arc<Config>) -> Result<usize> {
-    let creds = utils::load_creds(&args.creds)?;
-    tinfo!("[+]", "loaded {} credentials", creds.len());
-    let scripts = utils::load_scripts(args.scripts, &config).chain_err(|| "failed to load scripts")?;
+fn setup_enum_attack(pool: &mut Scheduler, args: args::Enum, config: &Arc<Config>, setup: Setup) -> Result<(Option<usize>, Option<Checkpoint>)> {
+    let Setup { checkpoint, ref lists, seed, ref targets, ref skip_report, ref remote } = setup;
+    if args.users == "-" {
+        let scripts = load_scripts(args.scripts, targets.as_deref(), lists, config)?;
+        tinfo!("[+]", "loaded {} scripts", scripts.len());
+        report_unsafe(&scripts, config);
+        skip_known(pool, &scripts, skip_report);
+
+        return stream_attempts(pool, Setup { checkpoint, lists: *lists, seed, targets: targets.clone(), skip_report: None, remote: None }, move |user| {
+            scripts.iter()
+                .map(|script| Attempt::enumerate(&user, script, 0))
+                .collect()
+        });
+    }
+
+    let users = load_list(&args.users, "users", lists)?;
+    let scripts = load_scripts(args.scripts, targets.as_deref(), lists, config)?;
     tinfo!("[+]", "loaded {} scripts", scripts.len());
+    report_unsafe(&scripts, config);
+    skip_known(pool, &scripts, skip_report);
 
-    let attempts = creds.len() * scripts.len();
+    let total = users.len() * scripts.len();
+    let users_path = &args.users;
+    describe_lists(remote, cluster::Mode::Enum, &[users_path], users.len(), &scripts, lists)?;
+    let checkpoint = open_checkpoint(checkpoint, total, || {
+        let mut inputs = vec![
+            Input::new("mode", b"enum"),
+            list_input(lists),
+            Input::file(users_path)?,
+        ];
+        inputs.extend(script_inputs(&scripts, config));
+        Ok(inputs)
+    })?;
+
+    let attempts = remaining(&checkpoint, total);
     tinfo!("[*]", "submitting {} jobs to threadpool with {} workers", attempts, pool.max_count());
 
-    for cred in creds {
-        // TODO: optimization if we only have once script
-        for script in &scripts {
-            let attempt = Attempt::bytes(&cred, script);
-            pool.run(attempt);
+    let completed = completed(&checkpoint);
+    let source = indexes(total, seed)
+        .filter(move |idx| !is_completed(&completed, *idx))
+        .map(move |idx| {
+            let script = &scripts[idx % scripts.len()];
+            let user = &users[idx / scripts.len()];
+            Attempt::enumerate(user, script, idx)
+        });
+    pool.feed(Box::new(source));
+
+    Ok((Some(attempts), checkpoint))
+}
+
+/// Load the scripts and run their init(), a script whose init() fails is
+/// skipped instead of failing every attempt
+fn load_scripts(paths: Vec<String>, targets: Option<&str>, lists: &ListOptions, config: &Arc<Config>) -> Result<Vec<Arc<Script>>> {
+    let targets = match targets {
+        Some("-") => return Err("--targets can't be read from stdin".into()),
+        Some(path) => Some(load_list(path, "targets", lists)?),
+        None => None,
+    };
+    let scripts = utils::load_scripts(paths, targets.as_deref(), config).chain_err(|| "failed to load scripts")?;
+    for script in &scripts {
+        script.check_options()?;
+    }
+    let loaded = scripts.len();
+
+    let mut ready = Vec::new();
+    for mut script in scripts {
+        match script.init() {
+            Ok(_) => ready.push(Arc::new(script)),
+            Err(err) => tinfo!("[!]", "skipping {}, {}", script.label(), format_error(&err)),
         }
     }
+    if ready.is_empty() && loaded > 0 {
+        return Err("init() failed for all scripts".into());
+    }
+    Ok(ready)
+}
+
+fn run_oneshot(oneshot: args::Oneshot, config: Arc<Config>) -> Result<bool> {
+    let mut script = Script::load(&oneshot.script, config)?;
+    script.check_options()?;
+    script.init()?;
+    let user = oneshot.user;
+
+    let valid = script.run_single(&user, oneshot.password.as_deref())?;
 
-    Ok(attempts)
+    match (valid, oneshot.password) {
+        (true, Some(ref password)) => println!("{}", format_valid_creds(script.descr(), &user, &password)),
+        (true, None) => println!("{}", format_valid_enum(script.descr(), &user)),
+        (false, Some(ref password)) => println!("{} {}({}) => {:?}:{:?}", "[-]".bold(), "invalid".red(), script.descr().yellow(), user, password),
+        (false, None) => println!("{} {}({}) => {:?}", "[-]".bold(), "invalid".red(), script.descr().yellow(), user),
+    }
+
+    Ok(valid)
 }
 
-fn setup_enum_attack(pool: &mut Scheduler, args: args::Enum, config: &Arc<Config>) -> Result<usize> {
-    let users = utils::load_list(&args.users).chain_err(|| "failed to load users")?;
-    tinfo!("[+]", "loaded {} users", users.len());
-    let scripts = utils::load_scripts(args.scripts, &config).chain_err(|| "failed to load scripts")?;
-    tinfo!("[+]", "loaded {} scripts", scripts.len());
+/// Show the error and its causes on one line, tracebacks are too long for
+/// the progress output
+fn format_error(err: &Error) -> String {
+    let mut out = String::new();
+    for err in err.iter() {
+        let err = err.to_string();
+        let line = err.lines().next().unwrap_or("");
+        // errors with a location already contain their cause
+        if out.ends_with(line) {
+            continue;
+        }
+        if !out.is_empty() {
+            out += ": ";
+        }
+        out += line;
+    }
+    out
+}
 
-    let attempts = users.len() * scripts.len();
-    tinfo!("[*]", "submitting {} jobs to threadpool with {} workers", attempts, pool.max_count());
+/// Why the attack was stopped before all attempts were done
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    // ctrl+c
+    Interrupt,
+    // SIGTERM
+    Terminate,
+    // the q key
+    Quit,
+}
+
+impl Stop {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stop::Interrupt => "interrupted",
+            Stop::Terminate => "terminated",
+            Stop::Quit => "aborted",
+        }
+    }
 
-    for user in &users {
-        for script in &scripts {
-            let attempt = Attempt::enumerate(user, script);
-            pool.run(attempt);
+    fn exit_code(self) -> i32 {
+        match self {
+            Stop::Interrupt | Stop::Quit => EXIT_INTERRUPTED,
+            Stop::Terminate => EXIT_TERMINATED,
         }
     }
+}
 
-    Ok(attempts)
+#[derive(Debug, Default)]
+struct ScriptStats {
+    runs: usize,
+    valid: usize,
+    errors: usize,
 }
 
-fn run_oneshot(oneshot: args::Oneshot, config: Arc<Config>) -> Result<()> {
-    let script = Script::load(&oneshot.script, config)?;
-    let user = oneshot.user;
+/// Counters for the summary and the status, see SIGUSR1 and the s key
+#[derive(Debug, Default)]
+struct Stats {
+    attempts: usize,
+    valid: usize,
+    retries: usize,
+    // moved to another proxy of [proxies] or started again after a
+    // throttle, they don't count as retries
+    failovers: usize,
+    throttled: usize,
+    expired: usize,
+    failed: usize,
+    skipped: usize,
+    // in the report of --skip-report
+    known: usize,
+    // skipped with the k key
+    dismissed: usize,
+    // users that were locked out and their attempts that were skipped
+    locked: usize,
+    locked_skipped: usize,
+    // what the scripts returned with a table, rate limits count every retry
+    statuses: BTreeMap<ctx::Status, usize>,
+    hidden: usize,
+    // in the order the scripts reported their first result
+    scripts: Vec<(String, ScriptStats)>,
+    // the number of done attempts about once per second, for the rate
+    samples: VecDeque<(Instant, usize)>,
+}
 
-    let valid = match oneshot.password {
-        Some(ref password) => script.run_creds(&user, &password)?,
-        None => script.run_enum(&user)?,
-    };
+impl Stats {
+    fn script(&mut self, descr: &str) -> &mut ScriptStats {
+        let idx = match self.scripts.iter().position(|(x, _)| x == descr) {
+            Some(idx) => idx,
+            None => {
+                self.scripts.push((descr.to_string(), ScriptStats::default()));
+                self.scripts.len() - 1
+            },
+        };
+        &mut self.scripts[idx].1
+    }
+
+    /// Attempts that don't have to be started again
+    #[inline]
+    fn done(&self) -> usize {
+        self.attempts + self.skipped + self.known + self.dismissed + self.locked_skipped
+    }
+
+    /// Remember how many attempts were done, old samples are dropped
+    fn sample(&mut self, now: Instant) {
+        if self.samples.back().is_some_and(|(time, _)| now.duration_since(*time) < Duration::from_secs(1)) {
+            return;
+        }
+        while self.samples.front().is_some_and(|(time, _)| now.duration_since(*time) > RATE_WINDOW) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, self.done()));
+    }
 
-    if valid {
-        match oneshot.password {
-            Some(ref password) => println!("{}", format_valid_creds(script.descr(), &user, &password)),
-            None => println!("{}", format_valid_enum(script.descr(), &user)),
+    /// Attempts per second over the last minute
+    fn rate(&self, now: Instant) -> f64 {
+        match self.samples.front() {
+            Some(&(time, done)) => {
+                let elapsed = now.duration_since(time).as_secs_f64().max(1.0);
+                (self.done() - done) as f64 / elapsed
+            },
+            None => 0.0,
         }
-    } else if oneshot.exitcode {
-        std::process::exit(2);
     }
 
+    /// eg. "2 mfa, 40 nouser"
+    fn format_statuses(&self) -> String {
+        self.statuses.iter()
+            .map(|(status, num)| format!("{} {}", num, status.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// How much of the attack is done in percent, if we know the total
+    fn progress(&self, total: Option<usize>) -> Option<f64> {
+        total.map(|total| self.done() as f64 * 100.0 / cmp::max(total, 1) as f64)
+    }
+
+    /// The status for --status-json, the rate is the one of the progress bar
+    fn json_status(&self, total: Option<usize>, pb: &ProgressBar, pool: &Scheduler) -> Status {
+        Status {
+            attempts_done: self.done(),
+            attempts_total: total,
+            valid: self.valid,
+            errors: self.failed + self.expired,
+            retries: self.retries,
+            expired: self.expired,
+            rate: pb.rate(),
+            eta_seconds: pb.eta().map(|x| x.as_secs()),
+            threads: pool.max_count(),
+            paused: pool.is_paused(),
+        }
+    }
+
+    fn status(&self, elapsed: Duration, total: Option<usize>, pool: &Scheduler) -> String {
+        let rate = self.rate(Instant::now());
+        let progress = match (total, self.progress(total)) {
+            (Some(total), Some(progress)) => {
+                let remaining = total.saturating_sub(self.done());
+                let eta = if rate > 0.0 {
+                    humantime::format_duration(Duration::from_secs((remaining as f64 / rate) as u64)).to_string()
+                } else {
+                    "unknown".to_string()
+                };
+                format!("{} of {} ({:.1}%), eta {}", self.done(), total, progress, eta)
+            },
+            _ => self.done().to_string(),
+        };
+
+        let mut out = format!("status after {}\n", humantime::format_duration(Duration::from_secs(elapsed.as_secs_f64().round() as u64)));
+        out += &format!("    attempts: {}\n", progress);
+        out += &format!("    rate: {:.2}/s over the last minute\n", rate);
+        out += &format!("    valid: {}, retries: {}, expired: {}, failed: {}, skipped: {}, skipped by operator: {}, locked out: {}\n",
+            self.valid, self.retries, self.expired, self.failed, self.skipped, self.dismissed, self.locked);
+        if !self.statuses.is_empty() {
+            out += &format!("    reported: {}\n", self.format_statuses());
+        }
+        out += &format!("    threads: {}", pool.max_count());
+        for (descr, script) in &self.scripts {
+            out += &format!("\n    {}: {} running, {} runs, {} valid, {} errors", descr, pool.running(descr), script.runs, script.valid, script.errors);
+        }
+        out
+    }
+}
+
+fn pause(pool: &mut Scheduler, pb: &mut ProgressBar) {
+    pb.writeln(format!("{} {}", "[*]".bold(), "pausing threads".dimmed()));
+    pool.pause();
+    pb.pause();
+}
+
+fn resume(pool: &mut Scheduler, pb: &mut ProgressBar) {
+    pb.writeln(format!("{} {}", "[*]".bold(), "resuming threads".dimmed()));
+    pool.resume();
+    pb.resume();
+}
+
+/// Stop starting new attempts and save what we have in case we are killed.
+/// Running attempts get some time to finish
+fn stop(pool: &mut Scheduler, checkpoint: &mut Option<Checkpoint>, attempt_log: &mut Option<AttemptLog>) -> Result<()> {
+    if let Some(ref mut checkpoint) = *checkpoint {
+        checkpoint.save()?;
+    }
+    if let Some(ref mut log) = *attempt_log {
+        log.flush()?;
+    }
+    pool.drain();
+    // attempts that are queued behind the pause are cancelled
+    pool.resume();
     Ok(())
 }
 
+/// Record a run of an attempt in the attempt log
+fn log_attempt(log: &mut AttemptLog, attempt: &Attempt, result: &Result<bool>) -> Result<()> {
+    let (outcome, error) = match *result {
+        Ok(true) => ("valid", None),
+        Ok(false) => ("invalid", None),
+        Err(ref err) => ("error", Some(format_error(err))),
+    };
+    let status = attempt.status.map(|x| x.as_str());
+    let password = match attempt.creds {
+        Creds::Enum(_) => None,
+        _ => Some(attempt.password()),
+    };
+
+    log.write(&LogEntry {
+        timestamp: &report::timestamp(),
+        script: attempt.script.label(),
+        user: attempt.user(),
+        password,
+        outcome,
+        error: error.as_deref(),
+        status,
+        note: attempt.note.as_deref(),
+        duration: attempt.duration.as_secs_f64(),
+        retries: attempt.retries(),
+    })
+}
+
+/// Skip the remaining attempts of a user that is locked out, returns false if
+/// the user was already skipped
+fn lock_user(pool: &mut Scheduler, pb: &mut ProgressBar, attempt_log: &mut Option<AttemptLog>, syslog: &Option<Syslog>, script: &str, user: &str, reason: &str) -> Result<bool> {
+    if !pool.lock_user(script, user) {
+        return Ok(false);
+    }
+
+    pb.writeln(Level::Warn.format(&format!("{:?} {} with {}, skipping the remaining attempts", user, reason, script)));
+    if let Some(ref syslog) = *syslog {
+        syslog.locked(script, user, reason);
+    }
+    if let Some(ref mut log) = *attempt_log {
+        log.write(&LogEntry {
+            timestamp: &report::timestamp(),
+            script,
+            user,
+            password: None,
+            outcome: "locked",
+            error: Some(reason),
+            status: None,
+            note: None,
+            duration: 0.0,
+            retries: 0,
+        })?;
+    }
+    Ok(true)
+}
+
 fn format_valid_creds(script: &str, user: &str, password: &str) -> String {
     format!("{} {}({}) => {:?}:{:?}", "[+]".bold(), "valid".green(),
         script.yellow(), user, password)
@@ -160,6 +935,16 @@ fn format_valid_enum(script: &str, user: &str) -> String {
         script.yellow(), user)
 }
 
+/// Appended to valid credentials if the script returned a status
+fn format_status(status: Option<&str>, note: Option<&str>) -> String {
+    match (status, note) {
+        (Some(status), Some(note)) => format!(" ({}: {})", status.yellow(), note),
+        (Some(status), None) => format!(" ({})", status.yellow()),
+        (None, Some(note)) => format!(" ({})", note),
+        (None, None) => String::new(),
+    }
+}
+
 fn set_nofile(config: &Config) -> Result<()> {
     let (soft_limit, hard_limit) = getrlimit(Resource::RLIMIT_NOFILE)?;
     debug!("soft_limit={:?}, hard_limit={:?}", soft_limit, hard_limit);
@@ -174,142 +959,958 @@ fn set_nofile(config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn run() -> Result<()> {
-    let args = args::parse();
+fn run() -> Result<i32> {
+    let mut args = args::parse();
+    QUIET.store(args.quiet > 0, Ordering::Relaxed);
 
-    let env = env_logger::Env::default();
-    let env = match args.verbose {
-        0 => env,
-        1 => env.filter_or("RUST_LOG", "info"),
-        _ => env.filter_or("RUST_LOG", "debug"),
-    };
-    env_logger::init_from_env(env);
+    logger::init(args.verbose);
+
+    if let args::SubCommand::Ctl(ref ctl) = args.subcommand {
+        return control::run_ctl(ctl).map(|_| EXIT_OK);
+    }
+
+    // serve runs the attack it wraps, the attempts are handed to the workers
+    let (subcommand, serve) = args.subcommand.into_serve();
+    args.subcommand = subcommand;
+    if serve.is_some() && args.subcommand.reads_stdin() {
+        return Err("the workers load the same lists as the coordinator, they can't be read from stdin".into());
+    }
 
     if atty::isnt(atty::Stream::Stdout) {
         colored::control::SHOULD_COLORIZE.set_override(false);
     }
 
-    let config = Arc::new(Config::load()?);
+    // the flags override the environment, which overrides the config file
+    let mut config = Config::load(args.config.as_deref())?;
+    if args.sandbox {
+        config.runtime.sandbox = true;
+    }
+    if args.timeout.is_some() {
+        config.runtime.timeout = args.timeout;
+    }
+    if config.runtime.timeout == Some(Duration::from_secs(0)) {
+        return Err("timeout must be greater than zero".into());
+    }
+    if args.retries.is_some() {
+        config.runtime.retries = args.retries;
+    }
+    if args.rate.is_some() {
+        config.runtime.rate = args.rate;
+    }
+    if args.http_timeout.is_some() {
+        config.runtime.http_timeout = args.http_timeout;
+    }
+    if config.runtime.http_timeout() == Duration::from_secs(0) {
+        return Err("http-timeout must be greater than zero".into());
+    }
+    if args.http_max_response.is_some() {
+        config.runtime.http_max_response = args.http_max_response;
+    }
+    if config.runtime.http_max_response() == 0 {
+        return Err("http-max-response must be greater than zero".into());
+    }
+    if args.proxy.is_some() {
+        config.runtime.proxy = args.proxy;
+    }
+    if args.proxy_strict {
+        config.runtime.proxy_strict = true;
+    }
+    if let Some(ref path) = args.proxy_list {
+        config.proxies.load_list(path)?;
+    }
+    if let Some(rotation) = args.proxy_rotation {
+        config.proxies.rotation = rotation;
+    }
+    config.start_route(args.resolve)?;
+    if args.no_verify_hostname {
+        config.runtime.tls_verify_hostname = Some(false);
+    }
+    if args.tls_ca_file.is_some() {
+        config.runtime.tls_ca_file = args.tls_ca_file;
+    }
+    if args.notify_webhook.is_some() {
+        config.runtime.notify_webhook = args.notify_webhook;
+    }
+    if args.notify_template.is_some() {
+        config.runtime.notify_template = args.notify_template;
+    }
+    if args.notify_password {
+        config.runtime.notify_password = true;
+    }
+    config.runtime.load_ca()?;
+    config.runtime.load_user_agents()?;
+    if let args::SubCommand::Benchmark(ref benchmark) = args.subcommand {
+        config.runtime.offline = benchmark.offline;
+    }
+    config.options = args.subcommand.script_options().iter()
+        .map(|x| (x.key.clone(), x.value.clone()))
+        .collect();
+    // a worker gets the options and the scripts from the coordinator
+    let connection = match args.subcommand {
+        args::SubCommand::Worker(ref worker) => {
+            let connection = cluster::Connection::open(worker, &config)?;
+            config.options = connection.options.clone();
+            // the scripts are sent by the coordinator, they only execute
+            // programs on this host if the operator allows it
+            if !worker.unsandboxed {
+                config.runtime.sandbox = true;
+            }
+            tinfo!("[+]", "connected to {}, running {} attempts at a time", worker.connect, args.workers);
+            Some(connection)
+        },
+        _ => None,
+    };
+    let config = Arc::new(config);
     #[cfg(target_os="linux")]
     set_nofile(&config)
         .chain_err(|| "failed to set RLIMIT_NOFILE")?;
 
-    let mut pool = Scheduler::new(args.workers);
-    let mut report = Report::open(args.output)?;
+    if let Some(rate) = config.runtime.rate {
+        if rate.is_nan() || rate <= 0.0 {
+            return Err("rate must be greater than zero".into());
+        }
+    }
+    let interval = RateLimit::interval(config.runtime.rate, args.delay);
+    if let Some(connection) = connection {
+        return cluster::run_worker(connection, &config, args.workers, interval).map(|_| EXIT_OK);
+    }
+    if args.per_host == Some(0) {
+        return Err("per-host limit must be greater than zero".into());
+    }
+    if args.stop_after == Some(0) {
+        return Err("stop-after must be greater than zero".into());
+    }
+    if args.lockout_threshold == Some(0) {
+        return Err("lockout-threshold must be greater than zero".into());
+    }
+    if args.verify_runs == 0 {
+        return Err("verify-runs must be greater than zero".into());
+    }
+    let auto_scale_max = args.auto_scale_max.unwrap_or(args.workers * 4);
+    if args.auto_scale {
+        if args.auto_scale_min == 0 {
+            return Err("auto-scale-min must be greater than zero".into());
+        }
+        if args.workers < args.auto_scale_min || args.workers > auto_scale_max {
+            return Err(format!("workers must be between {} and {} with --auto-scale", args.auto_scale_min, auto_scale_max).into());
+        }
+    }
 
-    let attempts = match args.subcommand {
-        args::SubCommand::Dict(dict) => setup_dictionary_attack(&mut pool, dict, &config)?,
-        args::SubCommand::Creds(creds) => setup_credential_confirmation(&mut pool, creds, &config)?,
-        args::SubCommand::Enum(enumerate) => setup_enum_attack(&mut pool, enumerate, &config)?,
-        args::SubCommand::Oneshot(oneshot) => return run_oneshot(oneshot, config),
-        args::SubCommand::Fsck(fsck) => return fsck::run_fsck(&fsck),
+    // handle ctrl+c, SIGTERM, SIGUSR1 and SIGUSR2 on a separate thread so we can stop
+    // gracefully. This has to happen before any threads are started so they
+    // inherit the mask
+    let signals = if args.subcommand.is_attack() && !args.dry_run {
+        let mut set = SigSet::empty();
+        set.add(Signal::SIGINT);
+        set.add(Signal::SIGTERM);
+        set.add(Signal::SIGUSR1);
+        set.add(Signal::SIGUSR2);
+        set.thread_block()?;
+        Some(set)
+    } else {
+        None
     };
 
-    let tx = pool.tx();
-    thread::spawn(move || {
-        let kb = Keyboard::new();
-        loop {
-            let key = kb.get();
-            tx.send(Msg::Key(key)).expect("failed to send key");
+    let seed = match (args.shuffle, args.seed) {
+        (_, Some(seed)) => Some(seed),
+        (true, None) => Some(utils::rand_u64()),
+        (false, None) => None,
+    };
+    if let Some(seed) = seed {
+        tinfo!("[*]", "shuffling attempts, use --seed {} to repeat this order", seed);
+    }
+
+    let skip_report = match args.skip_report {
+        Some(path) => {
+            let prior = report::load_findings(&path)
+                .chain_err(|| format!("failed to load previous report {:?}", path))?;
+            Some(SkipReport { path, prior, users: args.skip_found_users })
+        },
+        None => None,
+    };
+
+    let mut setup = Setup {
+        checkpoint: args.checkpoint,
+        lists: ListOptions {
+            dedup: !args.no_dedup,
+            trim: args.trim,
+        },
+        seed,
+        targets: args.targets,
+        skip_report,
+        remote: None,
+    };
+    let mut pool = Scheduler::new(args.workers, interval, args.per_host, seed.is_some());
+    let backoff = Backoff {
+        base: match args.backoff {
+            Some(base) => base,
+            None => config.runtime.backoff()?,
+        },
+        cap: match args.backoff_cap {
+            Some(cap) => cap,
+            None => config.runtime.backoff_cap()?,
+        },
+    };
+    if backoff.base > Duration::from_secs(0) {
+        pool.set_backoff(backoff);
+    }
+    if let Some(ref serve) = serve {
+        if !args.dry_run {
+            setup.remote = Some(cluster::serve(serve, &config, &mut pool)?);
+            tinfo!("[*]", "waiting for workers on {}", serve.listen);
         }
-    });
+    }
+
+    // removed when we return
+    let control_socket = match args.control_socket {
+        Some(ref path) => Some(ControlSocket::bind(path, pool.tx())?),
+        None => None,
+    };
 
-    let mut pb = ProgressBar::new(attempts as u64);
-    pb.print_help();
+    // the signals are handled by the main loop, there's nothing to clean up
+    // before the attack started. If we are stopped a second time only the
+    // control socket is removed
+    let running = Arc::new(AtomicBool::new(false));
+    if let Some(set) = signals {
+        let tx = pool.tx();
+        let running = running.clone();
+        let control_path = control_socket.as_ref().map(|x| x.path().to_path_buf());
+        thread::spawn(move || {
+            let mut stopping = false;
+            while let Ok(signal) = set.wait() {
+                let (msg, stop) = match signal {
+                    Signal::SIGUSR1 if running.load(Ordering::SeqCst) => (Msg::Status, None),
+                    Signal::SIGUSR2 if running.load(Ordering::SeqCst) => (Msg::TogglePause, None),
+                    Signal::SIGUSR1 | Signal::SIGUSR2 => continue,
+                    Signal::SIGTERM => (Msg::Terminate, Some(Stop::Terminate)),
+                    _ => (Msg::Interrupt, Some(Stop::Interrupt)),
+                };
+
+                if let Some(stop) = stop {
+                    if stopping || !running.load(Ordering::SeqCst) {
+                        Keyboard::reset();
+                        eprintln!("\n{} {}", "[!]".bold(), stop.as_str());
+                        if let Some(ref path) = control_path {
+                            let _ = fs::remove_file(path);
+                        }
+                        std::process::exit(stop.exit_code());
+                    }
+                    stopping = true;
+                }
+                // this only fails if the main loop is already done
+                let _ = tx.send(msg);
+            }
+        });
+    }
+    // nothing is written during a dry run
+    let dry_run = args.dry_run;
+    let mut report = if dry_run {
+        Report::None
+    } else {
+        Report::open(args.output, args.format, args.append, args.force, args.plain_metadata)?
+    };
+    let mut confirmations = Confirmations::new(args.dedup_report);
+    let mut attempt_log = match args.log {
+        Some(ref path) if !dry_run => Some(AttemptLog::open(path).chain_err(|| "failed to open attempt log")?),
+        _ => None,
+    };
+    let mut status_stream = if (args.status_json || args.status_file.is_some()) && !dry_run {
+        Some(StatusStream::open(args.status_file.as_deref()).chain_err(|| "failed to open status stream")?)
+    } else {
+        None
+    };
+    let syslog = match args.syslog {
+        Some(ref target) if !dry_run => Some(Syslog::open(target.clone(), args.syslog_facility, pool.tx())?),
+        _ => None,
+    };
+    let notifier = match config.runtime.notify_webhook()? {
+        Some(url) if !dry_run => {
+            let template = match config.runtime.notify_template {
+                Some(ref path) => Template::load(path)
+                    .chain_err(|| format!("failed to load notify template {:?}", path))?,
+                None => Template::default(),
+            };
+            Some(Notifier::start(url, template, config.runtime.notify_password, pool.tx())?)
+        },
+        _ => None,
+    };
+    if dry_run {
+        pool.hold();
+    }
+
+    let reads_stdin = args.subcommand.reads_stdin();
+    let no_keyboard = args.no_keyboard;
+    let skip_found_users = args.skip_found_users;
+    let lockout_threshold = args.lockout_threshold;
+    let stop_after = args.stop_after;
+    let verify_found = args.verify_found;
+    let verify_runs = args.verify_runs;
+    let verify_delay = args.verify_delay;
+    let quiet = args.quiet;
+    let (total, mut checkpoint) = match args.subcommand {
+        args::SubCommand::Dict(dict) => setup_dictionary_attack(&mut pool, dict, &config, setup)?,
+        args::SubCommand::Creds(creds) => setup_credential_confirmation(&mut pool, creds, &config, setup)?,
+        args::SubCommand::Combo(combo) => setup_combo_attack(&mut pool, combo, &config, setup)?,
+        args::SubCommand::Enum(enumerate) => setup_enum_attack(&mut pool, enumerate, &config, setup)?,
+        args::SubCommand::Oneshot(oneshot) => {
+            if setup.targets.is_some() {
+                return Err("--targets can't be used with oneshot".into());
+            }
+            let valid = run_oneshot(oneshot, config)?;
+            return Ok(if valid != args.fail_on_found { EXIT_OK } else { EXIT_FAILED });
+        },
+        args::SubCommand::Fsck(fsck) => return fsck::run_fsck(&fsck).map(|_| EXIT_OK),
+        args::SubCommand::Scripts(scripts) => return catalog::run_scripts(&scripts, &config).map(|_| EXIT_OK),
+        args::SubCommand::Benchmark(benchmark) => return benchmark::run_benchmark(&benchmark, &config).map(|_| EXIT_OK),
+        // replaced with the attack or handled above
+        args::SubCommand::Serve(_) | args::SubCommand::Worker(_) | args::SubCommand::Ctl(_) => unreachable!(),
+    };
+
+    if dry_run {
+        print_plan(&mut pool);
+        return Ok(EXIT_OK);
+    }
+
+    // the report is recreated, add what we found before we were interrupted.
+    // If we append to the report they are already in there
+    if let Some(ref checkpoint) = checkpoint {
+        for found in checkpoint.found() {
+            if skip_found_users {
+                pool.skip_user(&found.script, &found.user);
+            }
+
+            let status = format_status(found.status.as_deref(), found.note.as_deref());
+            match found.password {
+                Some(ref password) => println!("{}{}", format_valid_creds(&found.script, &found.user, password), status),
+                None => println!("{}{}", format_valid_enum(&found.script, &found.user), status),
+            }
+
+            if !args.append {
+                let timestamp = found.timestamp.clone().unwrap_or_else(report::timestamp);
+                confirmations.write(&mut report, &Finding {
+                    script: &found.script,
+                    user: &found.user,
+                    password: found.password.as_deref(),
+                    timestamp: &timestamp,
+                    target: found.target.as_deref(),
+                    status: found.status.as_deref(),
+                    note: found.note.as_deref(),
+                    duration: None,
+                    retries: 0,
+                })?;
+            }
+        }
+    }
+
+    // if stdin is used for a list we have to read keys from the terminal
+    let kb = if no_keyboard {
+        None
+    } else if reads_stdin {
+        match Keyboard::tty() {
+            Ok(kb) => Some(kb),
+            Err(_) => {
+                tinfo!("[!]", "{}", "keyboard input is disabled, stdin is used for a list and there's no terminal");
+                None
+            },
+        }
+    } else if atty::isnt(atty::Stream::Stdin) {
+        tinfo!("[!]", "{}", "keyboard input is disabled, stdin isn't a terminal");
+        None
+    } else {
+        Some(Keyboard::new())
+    };
+
+    let has_keyboard = kb.is_some();
+    if let Some(kb) = kb {
+        let tx = pool.tx();
+        thread::spawn(move || {
+            while let Some(key) = kb.get() {
+                tx.send(Msg::Key(key)).expect("failed to send key");
+            }
+        });
+    }
+
+    if status_stream.is_some() {
+        let tx = pool.tx();
+        let interval = args.status_interval;
+        thread::spawn(move || {
+            // this only fails if the main loop is already done
+            while tx.send(Msg::StatusJson).is_ok() {
+                thread::sleep(interval);
+            }
+        });
+    }
+    if syslog.is_some() {
+        let tx = pool.tx();
+        let interval = args.syslog_interval;
+        thread::spawn(move || {
+            thread::sleep(interval);
+            while tx.send(Msg::SyslogStatus).is_ok() {
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    let mut pb = match total {
+        Some(total) => ProgressBar::new(total as u64),
+        None => ProgressBar::unbounded(),
+    };
+    if quiet >= 2 {
+        pb.hide();
+    }
+    logger::set_output(Some(pool.tx()));
+    pb.set_rate(pool.rate());
+    if has_keyboard && quiet == 0 {
+        pb.print_help();
+    }
     pb.tick();
 
     pool.resume();
+    running.store(true, Ordering::SeqCst);
     let start = Instant::now();
 
-    let mut valid = 0;
-    let mut retries = 0;
-    let mut expired = 0;
+    let mut stats = Stats::default();
+    stats.sample(start);
+    let mut auto_scale = if args.auto_scale {
+        Some(AutoScale::new(args.auto_scale_min, auto_scale_max, args.auto_scale_errors, start))
+    } else {
+        None
+    };
+    // running attempts are given some time to finish after we were stopped
+    let mut stopped: Option<(Stop, Instant)> = None;
+    // the script and user of the last error, for the k key
+    let mut last_error: Option<(String, String)> = None;
+    // failed attempts in a row per script and user, see --lockout-threshold
+    let mut failures: HashMap<(String, String), usize> = HashMap::new();
+    // valid attempts and when they were found, see --verify-found
+    let mut held: Vec<(Box<Attempt>, String)> = Vec::new();
     while pool.has_work() {
-        match pool.recv() {
+        let msg = match pool.recv_until(stopped.map(|(_, x)| x + INTERRUPT_GRACE)) {
+            Some(msg) => msg,
+            None => {
+                pb.writeln(format!("{} {}", "[*]".bold(), format!("giving up on {} unfinished attempts", pool.inflight()).dimmed()));
+                break;
+            },
+        };
+
+        match msg {
             Msg::Key(key) => {
                 match key {
                     Key::H => pb.print_help(),
-                    Key::P => {
-                        pb.writeln(format!("{} {}", "[*]".bold(), "pausing threads".dimmed()));
-                        pool.pause();
+                    Key::P => pause(&mut pool, &mut pb),
+                    Key::R => resume(&mut pool, &mut pb),
+                    Key::S => {
+                        let status = stats.status(start.elapsed(), total, &pool);
+                        pb.writeln(format!("{} {}", "[*]".bold(), status));
+                    },
+                    Key::Q => if stopped.is_none() {
+                        stop(&mut pool, &mut checkpoint, &mut attempt_log)?;
+                        stopped = Some((Stop::Quit, Instant::now()));
+                        pb.writeln(format!("{} {}", "[*]".bold(), "quitting, waiting for running attempts, press ctrl+c to exit".dimmed()));
                     },
-                    Key::R => {
-                        pb.writeln(format!("{} {}", "[*]".bold(), "resuming threads".dimmed()));
-                        pool.resume();
+                    Key::K | Key::ShiftK => match (key, &last_error) {
+                        (_, None) => pb.writeln(format!("{} {}", "[*]".bold(), "nothing to skip, there were no errors so far".dimmed())),
+                        (Key::K, Some((script, user))) => {
+                            pool.dismiss_user(script, user);
+                            pb.writeln(format!("{} {}", "[*]".bold(), format!("skipping the remaining attempts for {:?} with {}", user, script).dimmed()));
+                        },
+                        (_, Some((script, _))) => {
+                            pool.dismiss_script(script);
+                            pb.writeln(format!("{} {}", "[*]".bold(), format!("skipping the remaining attempts with {}", script).dimmed()));
+                        },
                     },
-                    Key::Plus => {
-                        let num = pool.incr();
-                        pb.writeln(format!("{} {}", "[*]".bold(), format!("increased to {} threads", num).dimmed()));
+                    Key::Plus | Key::Minus | Key::Threads(_) => {
+                        let msg = match key {
+                            Key::Plus => format!("increased to {} threads", pool.incr()),
+                            Key::Minus => format!("decreased to {} threads", pool.decr()),
+                            Key::Threads(num) => format!("set to {} threads", pool.set_threads(num)),
+                            _ => unreachable!(),
+                        };
+                        pb.reset_eta();
+                        // the operator knows better than we do
+                        let msg = match auto_scale {
+                            Some(ref mut auto_scale) => {
+                                auto_scale.suspend(Instant::now());
+                                format!("{}, auto-scale resumes in {}", msg, humantime::format_duration(autoscale::OVERRIDE))
+                            },
+                            None => msg,
+                        };
+                        pb.writeln(format!("{} {}", "[*]".bold(), msg.dimmed()));
                     },
-                    Key::Minus => {
-                        let num = pool.decr();
-                        pb.writeln(format!("{} {}", "[*]".bold(), format!("decreased to {} threads", num).dimmed()));
+                    Key::LeftBracket | Key::RightBracket => {
+                        let rate = match key {
+                            Key::LeftBracket => pool.slower(),
+                            _ => pool.faster(),
+                        };
+                        match rate {
+                            Some(rate) => pb.writeln(format!("{} {}", "[*]".bold(), format!("limited to {:.2} attempts per second", rate).dimmed())),
+                            None => pb.writeln(format!("{} {}", "[*]".bold(), "no rate limit set, use --rate or --delay".dimmed())),
+                        }
+                        pb.set_rate(rate);
                     },
                 }
                 pb.tick();
             },
+            // warnings are shown even if we are quiet
+            Msg::Print(Level::Info, _) if quiet > 0 => (),
+            Msg::Print(level, msg) => pb.writeln(level.format(&msg)),
+            Msg::Log(line) => pb.writeln(line),
+            // we are already stopping, don't wait for the running attempts
+            Msg::Interrupt if stopped.is_some() => break,
+            Msg::Interrupt => {
+                stop(&mut pool, &mut checkpoint, &mut attempt_log)?;
+                stopped = Some((Stop::Interrupt, Instant::now()));
+                pb.writeln(format!("{} {}", "[*]".bold(), "interrupted, waiting for running attempts, press ctrl+c again to exit".dimmed()));
+            },
+            Msg::Terminate => {
+                stop(&mut pool, &mut checkpoint, &mut attempt_log)?;
+                stopped = Some((Stop::Terminate, Instant::now()));
+                pb.writeln(format!("{} {}", "[*]".bold(), "terminated, waiting for running attempts".dimmed()));
+            },
+            Msg::Status => {
+                let status = stats.status(start.elapsed(), total, &pool);
+                pb.writeln(format!("{} {}", "[*]".bold(), status));
+            },
+            // we are stopping, the paused attempts are cancelled already
+            Msg::TogglePause if stopped.is_some() => (),
+            Msg::TogglePause if pool.is_paused() => resume(&mut pool, &mut pb),
+            Msg::TogglePause => pause(&mut pool, &mut pb),
+            Msg::SyslogStatus => if let Some(ref syslog) = syslog {
+                syslog.status("progress", &stats.json_status(total, &pb, &pool));
+            },
+            Msg::Query(reply) => {
+                let _ = reply.send(stats.json_status(total, &pb, &pool));
+            },
+            Msg::StatusJson => if let Some(ref mut status_stream) = status_stream {
+                let status = stats.json_status(total, &pb, &pool);
+                status_stream.status(&status, &mut pb)?;
+            },
+            Msg::Skip(attempt) => {
+                if let Some(ref mut checkpoint) = checkpoint {
+                    checkpoint.complete(attempt.idx);
+                }
+                stats.skipped += 1;
+                pb.inc();
+            },
+            Msg::Known(attempt) => {
+                if let Some(ref mut checkpoint) = checkpoint {
+                    checkpoint.complete(attempt.idx);
+                }
+                stats.known += 1;
+                pb.inc();
+            },
+            // the attempt stays incomplete in the checkpoint so it's tried
+            // again if the attack is resumed
+            Msg::Dismiss(_) => {
+                stats.dismissed += 1;
+                pb.inc();
+            },
+            // the lockout might be over when the attack is resumed
+            Msg::Locked(_) => {
+                stats.locked_skipped += 1;
+                pb.inc();
+            },
+            // we are stopping, the attempt stays incomplete in the checkpoint
+            Msg::Cancel(_) => pb.tick(),
             Msg::Attempt(mut attempt, result) => {
+                if let Some(ref mut log) = attempt_log {
+                    log_attempt(log, &attempt, &result)?;
+                }
+                {
+                    let script = stats.script(attempt.script.label());
+                    script.runs += 1;
+                    match result {
+                        Ok(true) => script.valid += 1,
+                        Ok(false) => (),
+                        Err(_) => script.errors += 1,
+                    }
+                }
+                if let Some(status) = attempt.status {
+                    *stats.statuses.entry(status).or_insert(0) += 1;
+                }
+                if let Some(delay) = attempt.throttle {
+                    if pool.throttle(&attempt, Instant::now() + delay) {
+                        pb.writeln(format!("{} {}", "[*]".bold(), format!("{} was throttled for {}", attempt.script.label(), humantime::format_duration(delay)).dimmed()));
+                    }
+                }
+                if let Some(ref mut auto_scale) = auto_scale {
+                    let error = result.as_ref().err().is_some_and(errkind::is_retryable);
+                    auto_scale.record(attempt.duration, error);
+                }
+
+                for user in attempt.locked.drain(..) {
+                    if lock_user(&mut pool, &mut pb, &mut attempt_log, &syslog, attempt.script.label(), &user, "is locked out")? {
+                        stats.locked += 1;
+                    }
+                }
+                // errors don't count as failed logins
+                if let (Some(threshold), Ok(valid)) = (lockout_threshold, &result) {
+                    let key = (attempt.script.label().to_string(), attempt.user().to_string());
+                    let failed = if *valid {
+                        failures.remove(&key);
+                        0
+                    } else {
+                        let failed = failures.entry(key.clone()).or_insert(0);
+                        *failed += 1;
+                        *failed
+                    };
+                    if failed >= threshold {
+                        failures.remove(&key);
+                        let reason = format!("failed {} times in a row", failed);
+                        if lock_user(&mut pool, &mut pb, &mut attempt_log, &syslog, &key.0, &key.1, &reason)? {
+                            stats.locked += 1;
+                        }
+                    }
+                }
+
                 match result {
                     Ok(is_valid) => {
+                        // the report is written after the finding was verified
+                        let mut hold = None;
                         if is_valid {
-                            match attempt.creds {
-                                Creds::Enum(_) => {
-                                    let user = attempt.user();
-                                    let script = attempt.script.descr();
-
-                                    pb.writeln(format_valid_enum(script, user));
-                                    report.write_enum(user, script)?;
-                                },
-                                _ => {
-                                    let user = attempt.user();
-                                    let password = attempt.password();
-                                    let script = attempt.script.descr();
-
-                                    pb.writeln(format_valid_creds(script, user, password));
-                                    report.write_creds(user, password, script)?;
-                                },
+                            let user = attempt.user();
+                            let password = match attempt.creds {
+                                Creds::Enum(_) => None,
+                                _ => Some(attempt.password()),
+                            };
+                            let script = attempt.script.label();
+                            let timestamp = report::timestamp();
+                            let status = attempt.status.map(|x| x.as_str());
+
+                            let line = match password {
+                                Some(password) => format_valid_creds(script, user, password),
+                                None => format_valid_enum(script, user),
+                            };
+                            pb.writeln(line + &format_status(status, attempt.note.as_deref()));
+                            let finding = Finding {
+                                script,
+                                user,
+                                password,
+                                timestamp: &timestamp,
+                                target: attempt.target.as_deref(),
+                                status,
+                                note: attempt.note.as_deref(),
+                                duration: Some(attempt.duration),
+                                retries: attempt.retries(),
                             };
-                            valid += 1;
+                            if verify_found {
+                                hold = Some(timestamp.clone());
+                            } else {
+                                confirmations.write(&mut report, &finding)?;
+                            }
+                            if let Some(ref mut status_stream) = status_stream {
+                                status_stream.found(&finding, &mut pb)?;
+                            }
+                            if let Some(ref syslog) = syslog {
+                                syslog.found(&finding);
+                            }
+                            if let Some(ref notifier) = notifier {
+                                notifier.found(&finding);
+                            }
+
+                            if let Some(ref mut checkpoint) = checkpoint {
+                                checkpoint.add_found(Found {
+                                    script: script.to_string(),
+                                    user: user.to_string(),
+                                    password: password.map(|x| x.to_string()),
+                                    timestamp: Some(timestamp),
+                                    target: attempt.target.clone(),
+                                    status: status.map(|x| x.to_string()),
+                                    note: attempt.note.clone(),
+                                });
+                            }
+                            stats.valid += 1;
+
+                            if skip_found_users {
+                                pool.skip_user(attempt.script.label(), attempt.user());
+                            }
+
+                            if Some(stats.valid) == stop_after {
+                                pb.writeln(format!("{} {}", "[*]".bold(), format!("found {} valid credentials, waiting for running attempts", stats.valid).dimmed()));
+                                pool.drain();
+                            }
+                        }
+                        if let Some(ref mut checkpoint) = checkpoint {
+                            checkpoint.complete(attempt.idx);
                         }
+                        stats.attempts += 1;
                         pb.inc();
+                        if let Some(timestamp) = hold {
+                            held.push((attempt, timestamp));
+                        }
                     },
                     Err(err) => {
-                        pb.writeln(format!("{} {}({}, {}): {:?}", "[!]".bold(), "error".red(), attempt.script.descr().yellow(), format!("{:?}:{:?}", attempt.user(), attempt.password()).dimmed(), err));
+                        last_error = Some((attempt.script.label().to_string(), attempt.user().to_string()));
+                        if quiet > 0 {
+                            stats.hidden += 1;
+                        } else {
+                            // rate limits are expected, they are retried after the backoff
+                            let label = if attempt.status == Some(ctx::Status::RateLimited) || attempt.throttle.is_some() {
+                                "error".yellow()
+                            } else {
+                                "error".red()
+                            };
+                            pb.writeln(format!("{} {}({}, {}): {}", "[!]".bold(), label, attempt.script.label().yellow(), format!("{:?}:{:?}", attempt.user(), attempt.password()).dimmed(), format_error(&err)));
+                        }
 
-                        if attempt.ttl > 0 {
+                        // another proxy is tried, unless all of them are down
+                        let failover = match *err.kind() {
+                            ErrorKind::ProxyDown(_) => config.proxy_pool.as_ref()
+                                .is_some_and(|proxies| proxies.is_up(Instant::now())),
+                            _ => false,
+                        };
+                        let throttled = match *err.kind() {
+                            ErrorKind::Throttled(_) => true,
+                            _ => false,
+                        };
+                        if failover || throttled {
+                            if throttled {
+                                stats.throttled += 1;
+                            } else {
+                                stats.failovers += 1;
+                            }
+                            pool.requeue(*attempt);
+                            pb.tick();
+                        } else if !errkind::is_retryable(&err) {
+                            // retrying would only repeat the same error
+                            if let Some(ref syslog) = syslog {
+                                syslog.error(attempt.script.label(), attempt.user(), "failed", &format_error(&err));
+                            }
+                            stats.failed += 1;
+                            stats.attempts += 1;
+                            pb.inc();
+                        } else if attempt.ttl > 0 {
                             // we have retries left
-                            retries += 1;
+                            stats.retries += 1;
                             attempt.ttl -= 1;
-                            pool.run(*attempt);
+                            pool.retry(*attempt);
                             pb.tick();
                         } else {
                             // giving up
-                            expired += 1;
+                            if let Some(ref syslog) = syslog {
+                                syslog.error(attempt.script.label(), attempt.user(), "expired", &format_error(&err));
+                            }
+                            stats.expired += 1;
+                            stats.attempts += 1;
                             pb.inc();
                         }
                     }
                 };
             },
         }
+
+        stats.sample(Instant::now());
+        // don't start anything new if we are stopping
+        let change = match auto_scale {
+            Some(ref mut auto_scale) if stopped.is_none() => auto_scale.tick(Instant::now(), pool.max_count()),
+            _ => None,
+        };
+        if let Some(change) = change {
+            let num = pool.set_threads(change.threads);
+            pb.reset_eta();
+            pb.writeln(format!("{} {}", "[*]".bold(), format!("auto-scale: {}, set to {} threads", change.reason, num).dimmed()));
+        }
+        pb.set_counters(stats.valid, stats.failed + stats.expired, stats.retries);
+        if let Some(ref mut checkpoint) = checkpoint {
+            checkpoint.tick()?;
+        }
+        if let Some(ref mut log) = attempt_log {
+            log.tick()?;
+        }
+    }
+
+    if let Some(ref mut checkpoint) = checkpoint {
+        checkpoint.save()?;
+    }
+    if let Some(ref mut log) = attempt_log {
+        log.flush()?;
+    }
+    for err in pool.teardown_workers() {
+        pb.writeln(Level::Warn.format(&err));
+    }
+    let mut verified = None;
+    if !held.is_empty() {
+        let verdicts = if stopped.is_some() {
+            pb.writeln(format!("{} {}", "[*]".bold(), "the attack was stopped, the findings aren't verified".dimmed()));
+            None
+        } else {
+            pb.writeln(format!("{} {}", "[*]".bold(), format!("verifying {} findings, running each {} times", held.len(), verify_runs).dimmed()));
+            let attempts = held.iter().map(|(attempt, _)| &**attempt).collect::<Vec<_>>();
+            let (verdicts, errors) = recheck::recheck(&attempts, verify_runs, verify_delay, |timeout| {
+                let deadline = Instant::now() + timeout;
+                while let Some(msg) = pool.recv_until(Some(deadline)) {
+                    match msg {
+                        Msg::Interrupt | Msg::Terminate | Msg::Key(Key::Q) => {
+                            pb.writeln(format!("{} {}", "[*]".bold(), "stopping, the remaining findings aren't verified".dimmed()));
+                            return true;
+                        },
+                        Msg::Print(Level::Info, _) if quiet > 0 => (),
+                        Msg::Print(level, msg) => pb.writeln(level.format(&msg)),
+                        Msg::Log(line) => pb.writeln(line),
+                        _ => (),
+                    }
+                }
+                false
+            });
+            for err in errors {
+                pb.writeln(Level::Warn.format(&err));
+            }
+            Some(verdicts)
+        };
+
+        let (mut confirmed, mut flaky) = (0, 0);
+        for (i, (attempt, timestamp)) in held.iter().enumerate() {
+            let verdict = match verdicts {
+                Some(ref verdicts) => {
+                    let verdict = verdicts[i];
+                    if verdict.is_flaky() {
+                        flaky += 1;
+                        pb.writeln(Level::Warn.format(&format!("{} {:?}:{:?} passed only {} of {} runs", attempt.script.label(), attempt.user(), attempt.password(), verdict.passed, verdict.done)));
+                    } else if verdict.is_confirmed() {
+                        confirmed += 1;
+                    }
+                    verdict.to_string()
+                },
+                None => "not verified".to_string(),
+            };
+            let note = match attempt.note {
+                Some(ref note) => format!("{}, {}", note, verdict),
+                None => verdict,
+            };
+            confirmations.write(&mut report, &Finding {
+                script: attempt.script.label(),
+                user: attempt.user(),
+                password: match attempt.creds {
+                    Creds::Enum(_) => None,
+                    _ => Some(attempt.password()),
+                },
+                timestamp,
+                target: attempt.target.as_deref(),
+                status: attempt.status.map(|x| x.as_str()),
+                note: Some(&note),
+                duration: Some(attempt.duration),
+                retries: attempt.retries(),
+            })?;
+        }
+        if verdicts.is_some() {
+            verified = Some((confirmed, flaky));
+        }
+    }
+    // the last status has the final numbers
+    if let Some(ref mut status_stream) = status_stream {
+        let status = stats.json_status(total, &pb, &pool);
+        status_stream.status(&status, &mut pb)?;
+    }
+    if let Some(notifier) = notifier {
+        let lost = notifier.close();
+        if lost > 0 {
+            pb.writeln(Level::Warn.format(&format!("{} webhook notifications couldn't be sent", lost)));
+        }
     }
+    if let Some(syslog) = syslog {
+        syslog.status("done", &stats.json_status(total, &pb, &pool));
+        let dropped = syslog.close();
+        if dropped > 0 {
+            pb.writeln(Level::Warn.format(&format!("{} syslog messages were dropped", dropped)));
+        }
+    }
+    logger::set_output(None);
 
     let elapsed = start.elapsed();
-    let average = elapsed / attempts as u32;
-    pb.finish_replace(tinfof!("[+]", "found {} valid credentials with {} attempts and {} retries after {} and on average {} per attempt. {} attempts expired.\n",
-            valid, attempts, retries,
+    // everything might have been completed already if we resumed
+    let average = elapsed / cmp::max(stats.attempts, 1) as u32;
+    let mut summary = format!("found {} valid credentials with {} attempts and {} retries after {} and on average {} per attempt. {} attempts expired.",
+            stats.valid, stats.attempts, stats.retries,
             humantime::format_duration(elapsed),
             humantime::format_duration(average),
-            expired,
-    ));
+            stats.expired,
+    );
+    // paused time isn't counted for the rate
+    let active = pb.active_time().as_secs_f64();
+    if active > 0.0 {
+        let rate = stats.attempts as f64 / active;
+        summary += &format!(" The average rate was {:.2} attempts per second", rate);
+        match pb.peak_rate() {
+            // the peak is sampled, it can miss a steady rate by a bit
+            Some(peak) => summary += &format!(" with a peak of {:.2}.", peak.max(rate)),
+            None => summary += ".",
+        }
+    }
+    if !stats.statuses.is_empty() {
+        summary += &format!(" The scripts reported {}.", stats.format_statuses());
+    }
+    if let Some((confirmed, flaky)) = verified {
+        summary += &format!(" {} findings were confirmed by --verify-found, {} were flaky.", confirmed, flaky);
+    }
+    if stats.failed > 0 {
+        summary += &format!(" {} attempts failed with errors that aren't retried.", stats.failed);
+    }
+    if stats.hidden > 0 {
+        summary += &format!(" {} errors were hidden by --quiet.", stats.hidden);
+    }
+    if stats.skipped > 0 {
+        summary += &format!(" {} attempts skipped because the user was already found.", stats.skipped);
+    }
+    if stats.known > 0 {
+        summary += &format!(" {} attempts skipped because they were in the previous report.", stats.known);
+    }
+    if stats.locked > 0 {
+        summary += &format!(" {} users were locked out, {} attempts for them were skipped.", stats.locked, stats.locked_skipped);
+    }
+    if stats.dismissed > 0 {
+        summary += &format!(" {} attempts skipped by the operator.", stats.dismissed);
+    }
+    if pool.abandoned() > 0 {
+        summary += &format!(" {} attempts were abandoned after the timeout.", pool.abandoned());
+    }
+    if let Some(ref proxies) = config.proxy_pool {
+        let counts = proxies.stats().into_iter()
+            .map(|(proxy, attempts, failures)| format!("{} {}/{}", proxy, attempts, failures))
+            .collect::<Vec<_>>();
+        summary += &format!(" Attempts/failures per proxy: {}.", counts.join(", "));
+        if stats.failovers > 0 {
+            summary += &format!(" {} attempts were moved to another proxy.", stats.failovers);
+        }
+    }
+    if stats.throttled > 0 {
+        summary += &format!(" {} attempts were throttled and started again.", stats.throttled);
+    }
+    if pool.backoff_time() > Duration::from_secs(0) {
+        // the jitter is random down to the nanosecond
+        let backoff = Duration::from_millis(pool.backoff_time().as_millis() as u64);
+        summary += &format!(" Retries waited {} in backoff.", humantime::format_duration(backoff));
+    }
+    if let Some((stop, _)) = stopped {
+        match stats.progress(total) {
+            Some(progress) => summary += &format!(" The attack was {} at {:.1}% complete.", stop.as_str(), progress),
+            None => summary += &format!(" The attack was {}.", stop.as_str()),
+        }
+        if checkpoint.is_some() {
+            summary += " Progress has been saved to the checkpoint.";
+        }
+    } else if pool.is_draining() {
+        summary += &format!(" Stopped after {} valid credentials.", stats.valid);
+    }
+    pb.finish_replace(tinfof!("[+]", "{}\n", summary));
 
     Keyboard::reset();
 
-    Ok(())
+    // with --fail-on-found, finding something is the failure
+    if let Some((stop, _)) = stopped {
+        Ok(stop.exit_code())
+    } else if (stats.valid > 0) != args.fail_on_found {
+        Ok(EXIT_OK)
+    } else {
+        Ok(EXIT_FAILED)
+    }
 }
 
 fn main() {
-    if let Err(ref e) = run() {
-        eprint!("{}", e.display_chain());
-        std::process::exit(1);
+    match run() {
+        Ok(code) => std::process::exit(code),
+        Err(ref e) => {
+            eprint!("{}", e.display_chain());
+            std::process::exit(EXIT_ERROR);
+        },
     }
 }
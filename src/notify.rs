@@ -0,0 +1,313 @@
+//! Post valid credentials to a webhook as they are found, see --notify-webhook
+//!
+//! The requests are sent from a separate thread, a webhook that is down or
+//! slow only delays the notifications and never the attack.
+
+use errors::{Result, ResultExt};
+use reqwest::{self, Url};
+use report::Finding;
+use scheduler::{Level, Msg};
+use serde_json::{self, Value};
+
+use std::fs::File;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// notifications that wait for the webhook, later ones are dropped
+const QUEUE_SIZE: usize = 64;
+const ATTEMPTS: usize = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// doubled after every failed attempt
+const BACKOFF: Duration = Duration::from_secs(1);
+// a notification is given up after this long, including the retries
+const DEADLINE: Duration = Duration::from_secs(30);
+// how long we wait for the notifications that are still queued at the end
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// The json body, string values may contain {{script}}, {{user}},
+/// {{password}}, {{timestamp}}, {{target}}, {{status}} and {{note}}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template(Value);
+
+impl Default for Template {
+    fn default() -> Template {
+        Template(json!({
+            "script": "{{script}}",
+            "user": "{{user}}",
+            "password": "{{password}}",
+            "timestamp": "{{timestamp}}",
+            "target": "{{target}}",
+        }))
+    }
+}
+
+impl Template {
+    pub fn load(path: &str) -> Result<Template> {
+        let file = File::open(path)?;
+        let value = serde_json::from_reader(file)
+            .chain_err(|| "template isn't valid json")?;
+        Ok(Template(value))
+    }
+
+    /// A string that is only a placeholder is replaced with the value, null
+    /// if there is none. Placeholders in longer strings are replaced with the
+    /// text of the value
+    fn render(&self, fields: &[(&str, Option<&str>)]) -> Value {
+        fn render(value: &Value, fields: &[(&str, Option<&str>)]) -> Value {
+            match *value {
+                Value::String(ref s) => {
+                    for &(key, value) in fields {
+                        if *s == format!("{{{{{}}}}}", key) {
+                            return value.map(|x| Value::String(x.to_string()))
+                                .unwrap_or(Value::Null);
+                        }
+                    }
+                    let mut s = s.clone();
+                    for &(key, value) in fields {
+                        s = s.replace(&format!("{{{{{}}}}}", key), value.unwrap_or(""));
+                    }
+                    Value::String(s)
+                },
+                Value::Array(ref values) => Value::Array(values.iter()
+                    .map(|x| render(x, fields))
+                    .collect()),
+                Value::Object(ref map) => Value::Object(map.iter()
+                    .map(|(k, v)| (k.clone(), render(v, fields)))
+                    .collect()),
+                ref value => value.clone(),
+            }
+        }
+        render(&self.0, fields)
+    }
+}
+
+/// Keep the first character so the operator can tell passwords apart
+pub fn redact(password: &str) -> String {
+    match password.chars().next() {
+        Some(first) if password.chars().count() >= 4 => format!("{}***", first),
+        _ => "***".to_string(),
+    }
+}
+
+fn post(client: &reqwest::Client, url: &Url, body: &Value) -> Result<()> {
+    let response = client.post(url.clone())
+        .json(body)
+        .send()?;
+    if !response.status().is_success() {
+        bail!("webhook returned {}", response.status());
+    }
+    Ok(())
+}
+
+/// Retry with a backoff until the deadline, returns the last error
+fn deliver<F: FnMut(&Value) -> Result<()>>(post: &mut F, body: &Value) -> Result<()> {
+    let deadline = Instant::now() + DEADLINE;
+    let mut backoff = BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match post(body) {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt >= ATTEMPTS || Instant::now() + backoff >= deadline => return Err(err),
+            Err(err) => debug!("webhook failed, retrying in {:?}: {}", backoff, err),
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+pub struct Notifier {
+    template: Template,
+    full_password: bool,
+    tx: mpsc::SyncSender<Value>,
+    // notifications that were dropped or couldn't be sent
+    lost: Arc<AtomicUsize>,
+    // notifications that weren't handled yet
+    pending: Arc<AtomicUsize>,
+    done: mpsc::Receiver<()>,
+}
+
+impl Notifier {
+    /// Failed notifications are reported through out
+    pub fn start(url: Url, template: Template, full_password: bool, out: mpsc::Sender<Msg>) -> Result<Notifier> {
+        let mut builder = reqwest::Client::builder();
+        builder.timeout(REQUEST_TIMEOUT);
+        let client = builder.build()?;
+        let name = url.to_string();
+        Notifier::spawn(name, move |body| post(&client, &url, body), template, full_password, out)
+    }
+
+    fn spawn<F>(name: String, mut post: F, template: Template, full_password: bool, out: mpsc::Sender<Msg>) -> Result<Notifier>
+            where F: FnMut(&Value) -> Result<()> + Send + 'static {
+        let (tx, rx) = mpsc::sync_channel::<Value>(QUEUE_SIZE);
+        let (done_tx, done) = mpsc::channel();
+        let lost = Arc::new(AtomicUsize::new(0));
+        let pending = Arc::new(AtomicUsize::new(0));
+        {
+            let lost = lost.clone();
+            let pending = pending.clone();
+            thread::Builder::new()
+                .name("notify".to_string())
+                .spawn(move || {
+                    for body in rx {
+                        if let Err(err) = deliver(&mut post, &body) {
+                            lost.fetch_add(1, Ordering::SeqCst);
+                            let _ = out.send(Msg::Print(Level::Warn, format!("failed to notify {}: {}", name, err)));
+                        }
+                        pending.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    let _ = done_tx.send(());
+                })
+                .chain_err(|| "failed to start notify thread")?;
+        }
+
+        Ok(Notifier {
+            template,
+            full_password,
+            tx,
+            lost,
+            pending,
+            done,
+        })
+    }
+
+    fn body(&self, finding: &Finding) -> Value {
+        let password = finding.password.map(|password| if self.full_password {
+            password.to_string()
+        } else {
+            redact(password)
+        });
+        self.template.render(&[
+            ("script", Some(finding.script)),
+            ("user", Some(finding.user)),
+            ("password", password.as_deref()),
+            ("timestamp", Some(finding.timestamp)),
+            ("target", finding.target),
+            ("status", finding.status),
+            ("note", finding.note),
+        ])
+    }
+
+    /// Never blocks, the notification is dropped if too many are queued
+    pub fn found(&self, finding: &Finding) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if self.tx.try_send(self.body(finding)).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            self.lost.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Wait a while for the queued notifications, returns how many were lost
+    pub fn close(self) -> usize {
+        drop(self.tx);
+        let _ = self.done.recv_timeout(CLOSE_TIMEOUT);
+        self.lost.load(Ordering::SeqCst) + self.pending.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn finding<'a>(password: Option<&'a str>) -> Finding<'a> {
+        Finding {
+            script: "ssh",
+            user: "root",
+            password,
+            timestamp: "2018-06-01T12:00:00Z",
+            target: None,
+            status: Some("mfa"),
+            note: None,
+            duration: None,
+            retries: 0,
+        }
+    }
+
+    #[test]
+    fn verify_template() {
+        let body = Template::default().render(&[
+            ("script", Some("ssh")),
+            ("user", Some("root")),
+            ("password", None),
+            ("timestamp", Some("2018-06-01T12:00:00Z")),
+            ("target", None),
+        ]);
+        assert_eq!(body, json!({
+            "script": "ssh",
+            "user": "root",
+            "password": null,
+            "timestamp": "2018-06-01T12:00:00Z",
+            "target": null,
+        }));
+
+        let slack = Template(json!({
+            "text": "valid {{user}}:{{password}} with {{script}}{{target}}",
+            "blocks": [{"status": "{{status}}", "n": 1}],
+        }));
+        let body = slack.render(&[
+            ("script", Some("ssh")),
+            ("user", Some("root")),
+            ("password", Some("t***")),
+            ("target", None),
+            ("status", Some("mfa")),
+        ]);
+        assert_eq!(body, json!({
+            "text": "valid root:t*** with ssh",
+            "blocks": [{"status": "mfa", "n": 1}],
+        }));
+    }
+
+    #[test]
+    fn verify_redact() {
+        assert_eq!(redact("hunter2"), "h***");
+        assert_eq!(redact("abc"), "***");
+        assert_eq!(redact(""), "***");
+    }
+
+    #[test]
+    fn verify_notify() {
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let post = {
+            let bodies = bodies.clone();
+            move |body: &Value| -> Result<()> {
+                let mut bodies = bodies.lock().unwrap();
+                bodies.push(body.clone());
+                // the first request fails and is retried
+                if bodies.len() == 1 {
+                    bail!("webhook returned 500 Internal Server Error");
+                }
+                Ok(())
+            }
+        };
+
+        let (out, rx) = mpsc::channel();
+        let notifier = Notifier::spawn("test".to_string(), post, Template::default(), false, out).unwrap();
+        notifier.found(&finding(Some("hunter2")));
+        notifier.found(&finding(None));
+        assert_eq!(notifier.close(), 0);
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(bodies[1]["password"], "h***");
+        assert_eq!(bodies[2]["password"], Value::Null);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn verify_unreachable() {
+        let (out, rx) = mpsc::channel();
+        let post = |_: &Value| -> Result<()> { bail!("connection refused") };
+        let notifier = Notifier::spawn("test".to_string(), post, Template::default(), true, out).unwrap();
+        notifier.found(&finding(Some("hunter2")));
+        assert_eq!(notifier.close(), 1);
+        match rx.try_recv() {
+            Ok(Msg::Print(Level::Warn, msg)) => assert_eq!(msg, "failed to notify test: connection refused"),
+            _ => panic!("expected a warning"),
+        }
+    }
+}
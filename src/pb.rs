@@ -5,18 +5,32 @@
 //   This means we can't reliably redraw the graph after we wrote above it.
 //   We have to implement rate limiting in our wrapper to ensure we are
 //   able to bypass it when needed.
-// - using colored strings breaks pbr
+// - using colored strings breaks pbr, it counts the escape codes as part of
+//   the width. We pass a wider width to make up for them
 //
 // https://github.com/a8m/pb/pull/62
 
 use pbr;
 use atty;
+use humantime;
+use libc;
 use colored::Colorize;
+use std::collections::VecDeque;
 use std::fmt::Display;
 use std::io::prelude::*;
 use std::io::{self, Stdout};
+use std::mem;
+use std::time::{self as stdtime, Instant};
 use time::{self, SteadyTime, Duration};
 
+// the rate for the eta is averaged over this much time
+const ETA_WINDOW: stdtime::Duration = stdtime::Duration::from_secs(30);
+// don't guess before we know the rate for at least this long
+const ETA_MIN_WINDOW: stdtime::Duration = stdtime::Duration::from_secs(2);
+// the peak rate is only taken from windows at least this long, shorter bursts
+// are noise
+const PEAK_MIN_WINDOW: stdtime::Duration = stdtime::Duration::from_secs(10);
+
 
 macro_rules! printfl {
    ($w:expr, $($tt:tt)*) => {{
@@ -25,9 +39,145 @@ macro_rules! printfl {
     }}
 }
 
+/// A moving average of the attempts per second. Time spent paused isn't
+/// counted and the average starts over if the threads or the rate limit
+/// change, so the eta reflects how fast we are right now
+#[derive(Debug)]
+struct Estimate {
+    start: Instant,
+    // the progress about once per second
+    samples: VecDeque<(Instant, u64)>,
+    paused: Option<Instant>,
+    // all pauses until the last resume
+    paused_time: stdtime::Duration,
+    peak: Option<f64>,
+}
+
+impl Estimate {
+    fn new(now: Instant) -> Estimate {
+        let mut samples = VecDeque::new();
+        samples.push_back((now, 0));
+        Estimate {
+            start: now,
+            samples,
+            paused: None,
+            paused_time: stdtime::Duration::from_secs(0),
+            peak: None,
+        }
+    }
+
+    fn sample(&mut self, now: Instant, current: u64) {
+        if self.paused.is_some() {
+            return;
+        }
+        if self.samples.back().is_some_and(|(time, _)| now.saturating_duration_since(*time) < stdtime::Duration::from_secs(1)) {
+            return;
+        }
+        while self.samples.len() > 1 && self.samples.front().is_some_and(|(time, _)| now.saturating_duration_since(*time) > ETA_WINDOW) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, current));
+
+        let window = self.samples.front().map(|(time, _)| now.saturating_duration_since(*time));
+        if window.is_some_and(|window| window >= PEAK_MIN_WINDOW) {
+            if let Some(rate) = self.rate(now, current) {
+                self.peak = Some(self.peak.map_or(rate, |peak| peak.max(rate)));
+            }
+        }
+    }
+
+    fn pause(&mut self, now: Instant) {
+        if self.paused.is_none() {
+            self.paused = Some(now);
+        }
+    }
+
+    /// Move the samples forward so the pause isn't part of the window
+    fn resume(&mut self, now: Instant) {
+        if let Some(paused) = self.paused.take() {
+            let pause = now.saturating_duration_since(paused);
+            self.paused_time += pause;
+            for (time, _) in &mut self.samples {
+                *time += pause;
+            }
+        }
+    }
+
+    fn reset(&mut self, now: Instant, current: u64) {
+        self.samples.clear();
+        self.samples.push_back((now, current));
+    }
+
+    /// The time since the start without pauses
+    fn active(&self, now: Instant) -> stdtime::Duration {
+        let now = self.paused.unwrap_or(now);
+        now.saturating_duration_since(self.start).saturating_sub(self.paused_time)
+    }
+
+    /// Attempts per second, if we have enough samples
+    fn rate(&self, now: Instant, current: u64) -> Option<f64> {
+        let now = self.paused.unwrap_or(now);
+        let &(time, done) = self.samples.front()?;
+        let elapsed = now.saturating_duration_since(time);
+        if elapsed < ETA_MIN_WINDOW {
+            return None;
+        }
+        Some(current.saturating_sub(done) as f64 / elapsed.as_secs_f64())
+    }
+
+    /// The remaining time, rounded to seconds so it doesn't flicker
+    fn eta(&self, now: Instant, current: u64, total: u64) -> Option<stdtime::Duration> {
+        let rate = self.rate(now, current)?;
+        if rate <= 0.0 {
+            return None;
+        }
+        let remaining = total.saturating_sub(current) as f64 / rate;
+        Some(stdtime::Duration::from_secs(remaining.round() as u64))
+    }
+}
+
+/// The number of columns of the terminal
+fn term_width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size as *mut _) };
+    if res == 0 && size.ws_col > 0 {
+        Some(size.ws_col as usize)
+    } else {
+        None
+    }
+}
+
+/// The number of bytes that aren't shown because they are escape codes
+fn invisible_len(s: &str) -> usize {
+    let mut len = 0;
+    let mut escape = false;
+    for c in s.chars() {
+        if c == '\x1B' {
+            escape = true;
+        }
+        if escape {
+            len += c.len_utf8();
+            escape = !c.is_ascii_alphabetic();
+        }
+    }
+    len
+}
+
+/// Counters shown in the progress bar, see set_counters
+#[derive(Debug, Default)]
+struct Counters {
+    valid: usize,
+    errors: usize,
+    retries: usize,
+}
+
 pub struct ProgressBar {
     pb: pbr::ProgressBar<Stdout>,
     current: u64,
+    total: Option<u64>,
+    limit: Option<f64>,
+    estimate: Estimate,
+    counters: Counters,
     last_refresh_time: SteadyTime,
     max_refresh_rate: Option<time::Duration>,
     atty: bool,
@@ -38,6 +188,10 @@ impl ProgressBar {
     pub fn new(total: u64) -> ProgressBar {
         let mut pb = pbr::ProgressBar::new(total);
         pb.format("(=> )");
+        // the speed and time left of pbr are averaged over the whole run,
+        // including pauses, we show our own estimate instead
+        pb.show_speed = false;
+        pb.show_time_left = false;
 
         let now = SteadyTime::now();
         let refresh_rate = Duration::milliseconds(250);
@@ -46,30 +200,158 @@ impl ProgressBar {
         ProgressBar {
             pb,
             current: 0,
+            total: Some(total),
+            limit: None,
+            estimate: Estimate::new(Instant::now()),
+            counters: Counters::default(),
             last_refresh_time: now - refresh_rate,
             max_refresh_rate: Some(refresh_rate),
             atty,
         }
     }
 
+    /// A progress bar for an unknown number of attempts
+    #[inline]
+    pub fn unbounded() -> ProgressBar {
+        let mut pb = ProgressBar::new(u64::MAX);
+        pb.pb.show_bar = false;
+        pb.pb.show_percent = false;
+        pb.pb.show_counter = false;
+        pb.total = None;
+        pb
+    }
+
+    /// Don't draw the progress bar, lines are still written
+    #[inline]
+    pub fn hide(&mut self) {
+        self.atty = false;
+    }
+
     #[inline]
     pub fn draw(&mut self) {
+        let now = Instant::now();
+        // the rate is also needed for the summary if the bar is hidden
+        self.estimate.sample(now, self.current);
         if !self.atty {
             return;
         }
 
-        self.pb.tick()
+        let message = self.message(now);
+        let invisible = invisible_len(&message);
+        self.pb.set_width(match invisible {
+            0 => None,
+            _ => term_width().map(|width| width + invisible),
+        });
+        self.pb.message(&message);
+        self.pb.set(self.current);
+    }
+
+    /// The rate limit, elapsed time and eta in front of the progress bar
+    fn message(&self, now: Instant) -> String {
+        let mut out = String::new();
+        if let Some(limit) = self.limit {
+            out += &format!("limit {:.2}/s, ", limit);
+        }
+        let elapsed = now.saturating_duration_since(self.estimate.start);
+        out += &format!("elapsed {}", humantime::format_duration(stdtime::Duration::from_secs(elapsed.as_secs())));
+        if let Some(total) = self.total {
+            let eta = match self.estimate.eta(now, self.current, total) {
+                _ if self.estimate.paused.is_some() => "paused".to_string(),
+                Some(eta) => humantime::format_duration(eta).to_string(),
+                None => "-".to_string(),
+            };
+            out += &format!(", eta {}", eta);
+        }
+        if let (None, Some(rate)) = (self.estimate.paused, self.estimate.rate(now, self.current)) {
+            out += &format!(", {:.2}/s", rate);
+        }
+        let counters = &self.counters;
+        out += &format!(" {}:{} {}:{} {}:{} ",
+            "ok".green(), counters.valid,
+            "err".red(), counters.errors,
+            "ret".yellow(), counters.retries);
+        out
+    }
+
+    /// Update the counters in the progress bar, they are shown with the next
+    /// tick
+    #[inline]
+    pub fn set_counters(&mut self, valid: usize, errors: usize, retries: usize) {
+        self.counters = Counters {
+            valid,
+            errors,
+            retries,
+        };
+    }
+
+    /// Don't count the time until resume() for the eta
+    #[inline]
+    pub fn pause(&mut self) {
+        self.estimate.pause(Instant::now());
+        self.draw()
+    }
+
+    #[inline]
+    pub fn resume(&mut self) {
+        self.estimate.resume(Instant::now());
+        self.draw()
+    }
+
+    /// Attempts per second over the last 30 seconds, if we know it yet
+    #[inline]
+    pub fn rate(&self) -> Option<f64> {
+        self.estimate.rate(Instant::now(), self.current)
+    }
+
+    /// The remaining time, if we know the total and the rate
+    #[inline]
+    pub fn eta(&self) -> Option<stdtime::Duration> {
+        let total = self.total?;
+        self.estimate.eta(Instant::now(), self.current, total)
+    }
+
+    /// How long we ran without the time spent paused
+    #[inline]
+    pub fn active_time(&self) -> stdtime::Duration {
+        self.estimate.active(Instant::now())
+    }
+
+    /// The highest rate over the last 30 seconds we have seen, if we ran long
+    /// enough to know
+    #[inline]
+    pub fn peak_rate(&self) -> Option<f64> {
+        self.estimate.peak
+    }
+
+    /// Start over with the eta because the threads changed
+    #[inline]
+    pub fn reset_eta(&mut self) {
+        self.estimate.reset(Instant::now(), self.current);
+        self.draw()
     }
 
     #[inline]
     pub fn print_help(&mut self) {
         self.writeln(format!("{} {}", "[+]".bold(),
-            "[h] help, [p] pause, [r] resume, [+] increase threads, [-] decrease threads".dimmed()));
+            "[h] help, [p] pause, [r] resume, [s] status, [k] skip user of last error, [K] skip script of last error, [q] quit, [+] increase threads, [-] decrease threads, [0-9 enter] set threads, [[] slower, []] faster".dimmed()));
+    }
+
+    /// Show the configured rate limit in front of the progress bar, the eta
+    /// starts over if it was changed
+    #[inline]
+    pub fn set_rate(&mut self, rate: Option<f64>) {
+        if self.limit != rate {
+            self.estimate.reset(Instant::now(), self.current);
+        }
+        self.limit = rate;
+        self.draw()
     }
 
     #[inline]
     pub fn writeln<T: Display>(&mut self, s: T) {
-        printfl!(io::stderr(), "\r\x1B[2K{}\n", s);
+        // only clear the line if there is a progress bar
+        let clear = if self.atty { "\r\x1B[2K" } else { "" };
+        printfl!(io::stderr(), "{}{}\n", clear, s);
         self.draw()
     }
 
@@ -89,21 +371,8 @@ impl ProgressBar {
 
     #[inline]
     pub fn inc(&mut self) {
-        if !self.atty {
-            return;
-        }
-
-        let now = SteadyTime::now();
-        if let Some(mrr) = self.max_refresh_rate {
-            if now - self.last_refresh_time < mrr {
-                self.current += 1;
-                return;
-            }
-        }
-
-        self.pb.set(self.current);
-
-        self.last_refresh_time = SteadyTime::now();
+        self.current += 1;
+        self.tick();
     }
 
     #[inline]
@@ -115,3 +384,76 @@ impl ProgressBar {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> stdtime::Duration {
+        stdtime::Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn verify_invisible_len() {
+        assert_eq!(invisible_len("ok:1 err:2"), 0);
+        assert_eq!(invisible_len("\x1B[32mok\x1B[0m:1"), 9);
+    }
+
+    #[test]
+    fn verify_eta() {
+        let start = Instant::now();
+        let mut estimate = Estimate::new(start);
+        assert_eq!(estimate.eta(start + secs(1), 10, 100), None);
+
+        estimate.sample(start + secs(5), 50);
+        assert_eq!(estimate.rate(start + secs(5), 50), Some(10.0));
+        assert_eq!(estimate.eta(start + secs(5), 50, 100), Some(secs(5)));
+    }
+
+    #[test]
+    fn verify_eta_pause() {
+        let start = Instant::now();
+        let mut estimate = Estimate::new(start);
+        estimate.sample(start + secs(5), 50);
+
+        estimate.pause(start + secs(5));
+        assert_eq!(estimate.rate(start + secs(60), 50), Some(10.0));
+        estimate.resume(start + secs(65));
+        assert_eq!(estimate.rate(start + secs(70), 100), Some(10.0));
+    }
+
+    #[test]
+    fn verify_peak_rate() {
+        let start = Instant::now();
+        let mut estimate = Estimate::new(start);
+        estimate.sample(start + secs(5), 50);
+        assert_eq!(estimate.peak, None);
+
+        estimate.sample(start + secs(10), 200);
+        assert_eq!(estimate.peak, Some(20.0));
+        estimate.sample(start + secs(40), 300);
+        assert_eq!(estimate.peak, Some(20.0));
+    }
+
+    #[test]
+    fn verify_active() {
+        let start = Instant::now();
+        let mut estimate = Estimate::new(start);
+        estimate.pause(start + secs(5));
+        assert_eq!(estimate.active(start + secs(8)), secs(5));
+        estimate.resume(start + secs(10));
+        assert_eq!(estimate.active(start + secs(12)), secs(7));
+    }
+
+    #[test]
+    fn verify_eta_reset() {
+        let start = Instant::now();
+        let mut estimate = Estimate::new(start);
+        estimate.sample(start + secs(10), 100);
+
+        estimate.reset(start + secs(10), 100);
+        assert_eq!(estimate.rate(start + secs(11), 120), None);
+        assert_eq!(estimate.rate(start + secs(12), 140), Some(20.0));
+        assert_eq!(estimate.eta(start + secs(12), 140, 200), Some(secs(3)));
+    }
+}
@@ -0,0 +1,141 @@
+use errors::{Result, ResultExt};
+
+use regex::bytes::Regex;
+use std::io::prelude::*;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// An interactive child process, stdout and stderr are merged into one stream
+#[derive(Debug)]
+pub struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+fn forward<R: Read>(mut r: R, tx: mpsc::Sender<Vec<u8>>) {
+    let mut buf = [0; 4096];
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => if tx.send(buf[..n].to_vec()).is_err() {
+                break;
+            },
+        }
+    }
+}
+
+impl Process {
+    pub fn spawn(prog: &str, args: &[String]) -> Result<Process> {
+        let mut child = Command::new(prog)
+                            .args(args)
+                            .stdin(Stdio::piped())
+                            .stdout(Stdio::piped())
+                            .stderr(Stdio::piped())
+                            .spawn()
+                            .chain_err(|| "failed to spawn program")?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        // the channel is closed after both pipes are closed
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+        thread::spawn(move || forward(stdout, tx));
+        thread::spawn(move || forward(stderr, tx2));
+
+        Ok(Process {
+            child,
+            stdin,
+            rx,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        debug!("proc send: {:?}", String::from_utf8_lossy(data));
+        self.stdin.write_all(data)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    /// Read until the pattern matches, returns everything up to the end of the match
+    pub fn recv_until(&mut self, pattern: &str, timeout: Duration) -> Result<Vec<u8>> {
+        let regex = Regex::new(pattern)?;
+        let start = Instant::now();
+
+        loop {
+            if let Some(m) = regex.find(&self.buf) {
+                let end = m.end();
+                let rest = self.buf.split_off(end);
+                let buf = ::std::mem::replace(&mut self.buf, rest);
+                debug!("proc recv_until: {:?}", String::from_utf8_lossy(&buf));
+                return Ok(buf);
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                bail!("timeout while waiting for {:?}", pattern);
+            }
+
+            match self.rx.recv_timeout(timeout - elapsed) {
+                Ok(data) => self.buf.extend(data),
+                Err(mpsc::RecvTimeoutError::Timeout) => bail!("timeout while waiting for {:?}", pattern),
+                Err(mpsc::RecvTimeoutError::Disconnected) => bail!("process exited before {:?} was received", pattern),
+            }
+        }
+    }
+
+    pub fn kill(&mut self) -> Result<()> {
+        // the process might have exited already
+        if self.child.try_wait()?.is_none() {
+            self.child.kill()
+                .chain_err(|| "failed to kill program")?;
+        }
+        self.child.wait()?;
+        Ok(())
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        if let Err(err) = self.kill() {
+            debug!("failed to kill process: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_recv_until() {
+        let mut p = Process::spawn("sh", &["-c".into(), "echo 'login:'; read x; echo \"hello $x\" >&2".into()]).unwrap();
+        let x = p.recv_until("login:", Duration::from_secs(5)).unwrap();
+        assert_eq!(x, b"login:");
+        p.send(b"foo\n").unwrap();
+        let x = p.recv_until("hello \\w+", Duration::from_secs(5)).unwrap();
+        assert_eq!(x, b"\nhello foo");
+    }
+
+    #[test]
+    fn verify_recv_until_timeout() {
+        let mut p = Process::spawn("sleep", &["10".into()]).unwrap();
+        let start = Instant::now();
+        assert!(p.recv_until("foo", Duration::from_millis(100)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        p.kill().unwrap();
+    }
+
+    #[test]
+    fn verify_recv_until_exited() {
+        let mut p = Process::spawn("echo", &["foo".into()]).unwrap();
+        assert!(p.recv_until("bar", Duration::from_secs(5)).is_err());
+    }
+}
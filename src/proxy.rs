@@ -0,0 +1,782 @@
+//! Route connections through --proxy, [proxies] and --resolve, socks5 and http
+//! proxies are supported
+
+use errors::{Result, ResultExt, Error};
+
+use dns::Resolver;
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use utils;
+
+// how long a relay waits for the client it was started for
+const RELAY_ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    Socks5,
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    pub scheme: Scheme,
+    pub host: String,
+    pub port: u16,
+    // username and password, socks5 only
+    auth: Option<(String, String)>,
+}
+
+impl FromStr for Proxy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Proxy> {
+        let (scheme, rest) = match s.find("://") {
+            Some(idx) => (&s[..idx], &s[idx + 3..]),
+            None => bail!("expected scheme://host:port"),
+        };
+        let scheme = match scheme {
+            "socks5" | "socks5h" => Scheme::Socks5,
+            "http" => Scheme::Http,
+            _ => bail!("unsupported proxy scheme {:?}, expected socks5 or http", scheme),
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let (auth, addr) = match rest.rfind('@') {
+            Some(idx) => {
+                let auth = &rest[..idx];
+                let auth = match auth.find(':') {
+                    Some(idx) => (auth[..idx].to_string(), auth[idx + 1..].to_string()),
+                    None => (auth.to_string(), String::new()),
+                };
+                (Some(auth), &rest[idx + 1..])
+            },
+            None => (None, rest),
+        };
+        if auth.is_some() && scheme != Scheme::Socks5 {
+            bail!("credentials are only supported for socks5 proxies");
+        }
+
+        let (host, port) = split_host_port(addr, None)?;
+        Ok(Proxy {
+            scheme,
+            host: host.to_string(),
+            port,
+            auth,
+        })
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.scheme {
+            Scheme::Socks5 => "socks5",
+            Scheme::Http => "http",
+        };
+        write!(f, "{}://{}", scheme, format_addr(&self.host, self.port))
+    }
+}
+
+impl Proxy {
+    /// Open a connection to host:port through the proxy, the hostname is
+    /// resolved by the proxy
+    pub fn connect(&self, host: &str, port: u16, deadline: Option<Instant>) -> Result<TcpStream> {
+        debug!("connecting to {} through {}", format_addr(host, port), self);
+        let stream = self.open(deadline)?;
+        self.handshake(stream, host, port, deadline)
+    }
+
+    fn open(&self, deadline: Option<Instant>) -> Result<TcpStream> {
+        connect_direct(&self.host, self.port, deadline)
+            .chain_err(|| format!("couldn't connect to proxy {}", self))
+    }
+
+    fn handshake(&self, stream: TcpStream, host: &str, port: u16, deadline: Option<Instant>) -> Result<TcpStream> {
+        // don't let the handshake block past the deadline
+        let timeout = utils::remaining(deadline)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+
+        match self.scheme {
+            Scheme::Socks5 => self.socks5_connect(&stream, host, port)?,
+            Scheme::Http => self.http_connect(&stream, host, port)?,
+        }
+
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// See rfc1928 and rfc1929
+    fn socks5_connect(&self, mut stream: &TcpStream, host: &str, port: u16) -> Result<()> {
+        let methods: &[u8] = if self.auth.is_some() { &[0, 2] } else { &[0] };
+        let mut hello = vec![5, methods.len() as u8];
+        hello.extend(methods);
+        stream.write_all(&hello)?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 5 {
+            bail!("proxy doesn't speak socks5");
+        }
+        match (reply[1], &self.auth) {
+            (0, _) => (),
+            (2, &Some((ref user, ref password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    bail!("socks5 credentials are limited to 255 bytes");
+                }
+                let mut auth = vec![1, user.len() as u8];
+                auth.extend(user.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend(password.as_bytes());
+                stream.write_all(&auth)?;
+
+                stream.read_exact(&mut reply)?;
+                if reply[1] != 0 {
+                    bail!("proxy rejected the credentials");
+                }
+            },
+            _ => bail!("proxy requires authentication"),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend(&ip.octets());
+            },
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend(&ip.octets());
+            },
+            Err(_) => {
+                if host.len() > 255 {
+                    bail!("hostname is too long");
+                }
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend(host.as_bytes());
+            },
+        }
+        request.push((port >> 8) as u8);
+        request.push(port as u8);
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            bail!("proxy couldn't connect to {}: {}", format_addr(host, port), socks5_error(reply[1]));
+        }
+
+        // skip the address the proxy bound to
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            },
+            x => bail!("invalid socks5 address type: {}", x),
+        };
+        let mut addr = vec![0; len + 2];
+        stream.read_exact(&mut addr)?;
+
+        Ok(())
+    }
+
+    fn http_connect(&self, mut stream: &TcpStream, host: &str, port: u16) -> Result<()> {
+        let addr = format_addr(host, port);
+        let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", addr, addr);
+        stream.write_all(request.as_bytes())?;
+
+        // read byte by byte so nothing after the response is consumed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            if stream.read(&mut byte)? == 0 {
+                bail!("proxy closed the connection");
+            }
+            response.push(byte[0]);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or("");
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => bail!("proxy couldn't connect to {}: {}", addr, status),
+        }
+    }
+
+}
+
+/// How often a proxy of [proxies] was used and how often it couldn't be
+/// reached. A proxy that couldn't be reached is skipped until the cooldown is
+/// over
+#[derive(Debug, Default)]
+pub struct Health {
+    attempts: AtomicUsize,
+    failures: AtomicUsize,
+    cooldown: Duration,
+    down_until: Mutex<Option<Instant>>,
+}
+
+// every proxy of the pool has its own
+impl PartialEq for Health {
+    fn eq(&self, other: &Health) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Health {
+    pub fn new(cooldown: Duration) -> Health {
+        Health {
+            cooldown,
+            ..Health::default()
+        }
+    }
+
+    #[inline]
+    pub fn attempted(&self) {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn attempts(&self) -> usize {
+        self.attempts.load(Ordering::SeqCst)
+    }
+
+    pub fn failed(&self, now: Instant) {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        *self.down_until.lock().unwrap() = Some(now + self.cooldown);
+    }
+
+    #[inline]
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    pub fn is_down(&self, now: Instant) -> bool {
+        self.down_until.lock().unwrap()
+            .is_some_and(|until| now < until)
+    }
+}
+
+/// Where connections go, the dns overrides apply before the proxy so they
+/// also work for names only the client knows
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Route {
+    pub proxy: Option<Proxy>,
+    pub resolver: Resolver,
+    // set for the proxies of [proxies], failures mark the proxy as down
+    pub health: Option<Arc<Health>>,
+}
+
+impl Route {
+    /// Nothing to route, libraries can connect on their own
+    #[inline]
+    pub fn is_direct(&self) -> bool {
+        self.proxy.is_none() && self.resolver.is_empty()
+    }
+
+    pub fn connect(&self, host: &str, port: u16, deadline: Option<Instant>) -> Result<TcpStream> {
+        let ip = self.resolver.lookup(host, port).map(|ip| ip.to_string());
+        let host = ip.as_deref().unwrap_or(host);
+        let proxy = match self.proxy {
+            Some(ref proxy) => proxy,
+            None => return connect_direct(host, port, deadline),
+        };
+
+        debug!("connecting to {} through {}", format_addr(host, port), proxy);
+        let stream = match proxy.open(deadline) {
+            Ok(stream) => stream,
+            Err(err) => {
+                if let Some(ref health) = self.health {
+                    health.failed(Instant::now());
+                }
+                return Err(err);
+            },
+        };
+        proxy.handshake(stream, host, port, deadline)
+    }
+
+    /// Resolve host with the overrides or the system resolver, this doesn't
+    /// go through the proxy
+    pub fn resolve(&self, host: &str, port: u16) -> Result<SocketAddr> {
+        if let Some(ip) = self.resolver.lookup(host, port) {
+            return Ok(SocketAddr::new(ip, port));
+        }
+        match (host, port).to_socket_addrs()?.next() {
+            Some(addr) => Ok(addr),
+            None => bail!("no dns records found"),
+        }
+    }
+
+    /// Connect to host:port and forward the first local connection to it,
+    /// for libraries that open their own connections
+    pub fn relay(&self, host: &str, port: u16, deadline: Option<Instant>) -> Result<SocketAddr> {
+        let upstream = self.connect(host, port, deadline)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        debug!("relaying {} to {}", addr, format_addr(host, port));
+
+        thread::spawn(move || {
+            match accept(&listener, RELAY_ACCEPT_TIMEOUT) {
+                Ok(client) => pipe(client, upstream),
+                Err(err) => debug!("relay failed: {}", err),
+            }
+        });
+
+        Ok(addr)
+    }
+
+    /// Run a local http proxy that forwards everything along this route,
+    /// reqwest only supports http proxies and has no dns overrides
+    pub fn serve(&self) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        debug!("forwarding http proxy {} to {:?}", addr, self);
+
+        let route = self.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let route = route.clone();
+                match client {
+                    Ok(client) => {
+                        thread::spawn(move || {
+                            if let Err(err) = route.forward(client) {
+                                debug!("http proxy failed: {}", err);
+                            }
+                        });
+                    },
+                    Err(err) => debug!("http proxy failed: {}", err),
+                }
+            }
+        });
+
+        Ok(addr)
+    }
+
+    fn forward(&self, mut client: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(client.try_clone()?);
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                bail!("client closed the connection");
+            }
+            let end = line == "\r\n" || line == "\n";
+            head.push(line);
+            if end {
+                break;
+            }
+        }
+
+        let request = head[0].trim_end().to_string();
+        let mut parts = request.splitn(3, ' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) => (method, target, version),
+            _ => bail!("invalid request: {:?}", request),
+        };
+
+        let upstream = if method == "CONNECT" {
+            let (host, port) = split_host_port(target, None)?;
+            let upstream = self.connect(host, port, None);
+            if upstream.is_err() {
+                client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
+            } else {
+                client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+            }
+            upstream?
+        } else {
+            // plain http requests use the absolute url
+            let url = match target.strip_prefix("http://") {
+                Some(url) => url,
+                None => bail!("unsupported request target: {:?}", target),
+            };
+            let (addr, path) = match url.find('/') {
+                Some(idx) => (&url[..idx], &url[idx..]),
+                None => (url, "/"),
+            };
+            let (host, port) = split_host_port(addr, Some(80))?;
+            let mut upstream = match self.connect(host, port, None) {
+                Ok(upstream) => upstream,
+                Err(err) => {
+                    client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
+                    return Err(err);
+                },
+            };
+            write!(upstream, "{} {} {}\r\n", method, path, version)?;
+            for line in &head[1..] {
+                upstream.write_all(line.as_bytes())?;
+            }
+            upstream
+        };
+
+        // the client may have sent more than the head already
+        (&upstream).write_all(reader.buffer())?;
+        pipe(client, upstream);
+        Ok(())
+    }
+}
+
+/// How the proxy of an attempt is picked from [proxies], see --proxy-rotation
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rotation {
+    #[default]
+    RoundRobin,
+    Random,
+    Sticky,
+}
+
+impl FromStr for Rotation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rotation> {
+        match s {
+            "round-robin" => Ok(Rotation::RoundRobin),
+            "random" => Ok(Rotation::Random),
+            "sticky" => Ok(Rotation::Sticky),
+            _ => bail!("unknown rotation {:?}, expected round-robin, random or sticky", s),
+        }
+    }
+}
+
+/// A proxy of [proxies] and the local http proxy in front of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub route: Route,
+    pub http_proxy: String,
+}
+
+impl Member {
+    #[inline]
+    pub fn health(&self) -> &Health {
+        self.route.health.as_ref().expect("member without health")
+    }
+}
+
+/// The proxies of [proxies], every attempt picks one of them
+#[derive(Debug)]
+pub struct ProxyPool {
+    members: Vec<Member>,
+    rotation: Rotation,
+    next: AtomicUsize,
+}
+
+impl PartialEq for ProxyPool {
+    fn eq(&self, other: &ProxyPool) -> bool {
+        self.members == other.members && self.rotation == other.rotation
+    }
+}
+
+impl ProxyPool {
+    /// Every proxy gets a local http proxy, even http proxies, so failed http
+    /// requests mark the proxy as down too
+    pub fn start(proxies: Vec<Proxy>, resolver: &Resolver, rotation: Rotation, cooldown: Duration) -> Result<ProxyPool> {
+        let members = proxies.into_iter()
+            .map(|proxy| {
+                let route = Route {
+                    proxy: Some(proxy),
+                    resolver: resolver.clone(),
+                    health: Some(Arc::new(Health::new(cooldown))),
+                };
+                let addr = route.serve()?;
+                Ok(Member {
+                    route,
+                    http_proxy: format!("http://{}", addr),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ProxyPool::new(members, rotation))
+    }
+
+    fn new(members: Vec<Member>, rotation: Rotation) -> ProxyPool {
+        assert!(!members.is_empty());
+        ProxyPool {
+            members,
+            rotation,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Proxies that are down are skipped, the next one that is up is used
+    /// instead. If all of them are down the first pick is used anyway. Sticky
+    /// rotation keeps the user on the same proxy, without a user it works
+    /// like round-robin
+    pub fn pick(&self, user: Option<&str>, now: Instant) -> &Member {
+        let len = self.members.len();
+        let start = match (self.rotation, user) {
+            (Rotation::Random, _) => utils::rand_below(len),
+            (Rotation::Sticky, Some(user)) => {
+                let mut hasher = DefaultHasher::new();
+                user.hash(&mut hasher);
+                (hasher.finish() % len as u64) as usize
+            },
+            _ => self.next.fetch_add(1, Ordering::SeqCst) % len,
+        };
+        (0..len)
+            .map(|i| &self.members[(start + i) % len])
+            .find(|member| !member.health().is_down(now))
+            .unwrap_or(&self.members[start])
+    }
+
+    /// At least one proxy can be used
+    pub fn is_up(&self, now: Instant) -> bool {
+        self.members.iter().any(|member| !member.health().is_down(now))
+    }
+
+    /// The proxies with their attempts and failures, for the summary
+    pub fn stats(&self) -> Vec<(String, usize, usize)> {
+        self.members.iter()
+            .map(|member| {
+                let proxy = member.route.proxy.as_ref().map(|x| x.to_string()).unwrap_or_default();
+                (proxy, member.health().attempts(), member.health().failures())
+            })
+            .collect()
+    }
+}
+
+fn connect_direct(host: &str, port: u16, deadline: Option<Instant>) -> Result<TcpStream> {
+    let mut errors = Vec::new();
+    for addr in (host, port).to_socket_addrs()? {
+        debug!("connecting to {:?}", addr);
+        let stream = match utils::remaining(deadline)? {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match stream {
+            Ok(stream) => {
+                debug!("successfully connected to {:?}", addr);
+                return Ok(stream);
+            },
+            Err(err) => errors.push((addr, err)),
+        }
+    }
+
+    if errors.is_empty() {
+        bail!("no dns records found");
+    } else {
+        bail!("couldn't connect: {:?}", errors);
+    }
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "ttl expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Split host:port and [ipv6]:port, the port is required without a default
+pub fn split_host_port(addr: &str, default: Option<u16>) -> Result<(&str, u16)> {
+    let (host, port) = match addr.rfind(':') {
+        Some(idx) if !addr.ends_with(']') => {
+            let port = addr[idx + 1..].parse()
+                .chain_err(|| format!("invalid port in {:?}", addr))?;
+            (&addr[..idx], port)
+        },
+        _ => match default {
+            Some(port) => (addr, port),
+            None => bail!("missing port in {:?}", addr),
+        },
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        bail!("missing host in {:?}", addr);
+    }
+    Ok((host, port))
+}
+
+fn format_addr(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn accept(listener: &TcpListener, timeout: Duration) -> io::Result<TcpStream> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((client, _)) => {
+                client.set_nonblocking(false)?;
+                return Ok(client);
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10));
+            },
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Copy in both directions until both sides are done
+fn pipe(a: TcpStream, b: TcpStream) {
+    let (mut a2, mut b2) = match (a.try_clone(), b.try_clone()) {
+        (Ok(a2), Ok(b2)) => (a2, b2),
+        _ => return,
+    };
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut a2, &mut b2);
+        let _ = b2.shutdown(Shutdown::Write);
+    });
+
+    let (mut a, mut b) = (a, b);
+    let _ = io::copy(&mut b, &mut a);
+    let _ = a.shutdown(Shutdown::Write);
+    let _ = upload.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_parse() {
+        let proxy = "socks5://127.0.0.1:9050".parse::<Proxy>().unwrap();
+        assert_eq!(proxy.scheme, Scheme::Socks5);
+        assert_eq!(proxy.to_string(), "socks5://127.0.0.1:9050");
+
+        let proxy = "socks5h://user:hunter2@[::1]:1080/".parse::<Proxy>().unwrap();
+        assert_eq!(proxy.host, "::1");
+        assert_eq!(proxy.auth, Some(("user".to_string(), "hunter2".to_string())));
+        assert_eq!(proxy.to_string(), "socks5://[::1]:1080");
+
+        assert_eq!("http://proxy.corp:3128".parse::<Proxy>().unwrap().scheme, Scheme::Http);
+        assert!("http://user:pw@proxy.corp:3128".parse::<Proxy>().is_err());
+        assert!("https://proxy.corp:3128".parse::<Proxy>().is_err());
+        assert!("socks5://127.0.0.1".parse::<Proxy>().is_err());
+        assert!("127.0.0.1:9050".parse::<Proxy>().is_err());
+    }
+
+    #[test]
+    fn verify_socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 3];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+
+            let mut buf = [0; 5 + 11 + 2];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..5], &[5, 1, 0, 3, 11]);
+            assert_eq!(&buf[5..16], b"example.com");
+            assert_eq!(&buf[16..], &[0, 80]);
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            stream.write_all(b"hello").unwrap();
+        });
+
+        let proxy = format!("socks5://127.0.0.1:{}", port).parse::<Proxy>().unwrap();
+        let mut stream = proxy.connect("example.com", 80, None).unwrap();
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "hello");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn verify_http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 64];
+            let n = stream.read(&mut buf).unwrap();
+            assert!(buf[..n].starts_with(b"CONNECT [::1]:389 HTTP/1.1\r\n"));
+            stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").unwrap();
+        });
+
+        let proxy = format!("http://127.0.0.1:{}", port).parse::<Proxy>().unwrap();
+        let err = proxy.connect("::1", 389, None).unwrap_err();
+        assert_eq!(err.to_string(), "proxy couldn't connect to [::1]:389: HTTP/1.1 403 Forbidden");
+        server.join().unwrap();
+    }
+
+    fn pool(rotation: Rotation) -> ProxyPool {
+        let members = (1..4)
+            .map(|i| Member {
+                route: Route {
+                    proxy: Some(format!("socks5://10.0.0.{}:1080", i).parse().unwrap()),
+                    resolver: Resolver::default(),
+                    health: Some(Arc::new(Health::new(Duration::from_secs(60)))),
+                },
+                http_proxy: format!("http://127.0.0.1:{}", 8000 + i),
+            })
+            .collect();
+        ProxyPool::new(members, rotation)
+    }
+
+    #[test]
+    fn verify_pool() {
+        let now = Instant::now();
+        let pool = pool(Rotation::RoundRobin);
+        let picks = (0..4).map(|_| pool.pick(Some("root"), now).http_proxy.as_str()).collect::<Vec<_>>();
+        assert_eq!(picks, vec!["http://127.0.0.1:8001", "http://127.0.0.1:8002", "http://127.0.0.1:8003", "http://127.0.0.1:8001"]);
+
+        // the second proxy is skipped until the cooldown is over
+        pool.members[1].health().failed(now);
+        assert_eq!(pool.pick(None, now).http_proxy, "http://127.0.0.1:8003");
+        assert_eq!(pool.pick(None, now).http_proxy, "http://127.0.0.1:8003");
+        assert_eq!(pool.pick(None, now).http_proxy, "http://127.0.0.1:8001");
+        assert_eq!(pool.pick(None, now + Duration::from_secs(61)).http_proxy, "http://127.0.0.1:8002");
+
+        let pool = self::pool(Rotation::Sticky);
+        let first = pool.pick(Some("root"), now).http_proxy.clone();
+        assert!((0..5).all(|_| pool.pick(Some("root"), now).http_proxy == first));
+        let idx = pool.members.iter().position(|x| x.http_proxy == first).unwrap();
+        pool.members[idx].health().failed(now);
+        assert!(pool.pick(Some("root"), now).http_proxy != first);
+        assert!(pool.is_up(now));
+
+        // everything is down, the first pick is used anyway
+        for member in &pool.members {
+            member.health().failed(now);
+        }
+        assert!(!pool.is_up(now));
+        assert_eq!(pool.pick(Some("root"), now).http_proxy, first);
+    }
+
+    #[test]
+    fn verify_proxy_down() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let health = Arc::new(Health::new(Duration::from_secs(60)));
+        let route = Route {
+            proxy: Some(format!("socks5://127.0.0.1:{}", port).parse().unwrap()),
+            resolver: Resolver::default(),
+            health: Some(health.clone()),
+        };
+        let err = route.connect("example.com", 80, None).unwrap_err();
+        assert!(err.to_string().starts_with("couldn't connect to proxy socks5://127.0.0.1:"));
+        assert_eq!(health.failures(), 1);
+        assert!(health.is_down(Instant::now()));
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Limits how often attempts are started, this is shared between all workers
+/// so the limit holds regardless of the number of threads
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    interval: Option<Duration>,
+    next: Instant,
+}
+
+impl RateLimit {
+    pub fn new(interval: Option<Duration>) -> RateLimit {
+        RateLimit {
+            bucket: Arc::new(Mutex::new(Bucket {
+                interval,
+                next: Instant::now(),
+            })),
+        }
+    }
+
+    /// Combine `--rate` and `--delay`, the slower one wins
+    pub fn interval(rate: Option<f64>, delay: Option<Duration>) -> Option<Duration> {
+        let rate = rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+        match (rate, delay) {
+            (Some(rate), Some(delay)) => Some(rate.max(delay)),
+            (rate, delay) => rate.or(delay),
+        }
+    }
+
+    /// The configured number of attempts per second
+    pub fn rate(&self) -> Option<f64> {
+        let bucket = self.bucket.lock().unwrap();
+        bucket.interval.map(|x| 1.0 / x.as_secs_f64())
+    }
+
+    pub fn set_rate(&self, rate: f64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.interval = Some(Duration::from_secs_f64(1.0 / rate));
+    }
+
+    /// Block until we are allowed to start the next attempt
+    pub fn wait(&self) {
+        let slot = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let interval = match bucket.interval {
+                Some(interval) => interval,
+                None => return,
+            };
+
+            // reserve the next slot, don't save up slots while idle
+            let now = Instant::now();
+            let slot = if bucket.next > now { bucket.next } else { now };
+            bucket.next = slot + interval;
+            slot
+        };
+
+        let now = Instant::now();
+        if slot > now {
+            thread::sleep(slot - now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_interval() {
+        assert_eq!(RateLimit::interval(None, None), None);
+        assert_eq!(RateLimit::interval(Some(4.0), None), Some(Duration::from_millis(250)));
+        assert_eq!(RateLimit::interval(Some(4.0), Some(Duration::from_secs(1))), Some(Duration::from_secs(1)));
+        assert_eq!(RateLimit::interval(Some(0.5), Some(Duration::from_secs(1))), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn verify_wait() {
+        let limit = RateLimit::new(Some(Duration::from_millis(50)));
+        let start = Instant::now();
+
+        let threads = (0..4)
+            .map(|_| {
+                let limit = limit.clone();
+                thread::spawn(move || limit.wait())
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // the first attempt starts immediately
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(limit.rate(), Some(20.0));
+    }
+}
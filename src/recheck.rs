@@ -0,0 +1,149 @@
+//! Run the valid attempts again once the attack is done, see --verify-found
+//!
+//! An error page that looks like a successful login shows up as a finding
+//! that only passes some of the time. Every finding is run again a few
+//! times, with a delay between the rounds so a short outage doesn't fail all
+//! runs at once.
+
+use ctx::{Script, Worker};
+use scheduler::Attempt;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+
+/// How often a finding passed when it was run again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Verdict {
+    pub passed: usize,
+    pub done: usize,
+    pub runs: usize,
+}
+
+impl Verdict {
+    #[inline]
+    pub fn is_confirmed(&self) -> bool {
+        self.passed == self.runs
+    }
+
+    /// A single failed run is enough, even if the rest wasn't run
+    #[inline]
+    pub fn is_flaky(&self) -> bool {
+        self.passed < self.done
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_flaky() {
+            write!(w, "flaky {}/{}", self.passed, self.done)
+        } else if self.is_confirmed() {
+            write!(w, "confirmed {}/{}", self.passed, self.runs)
+        } else {
+            write!(w, "not verified")
+        }
+    }
+}
+
+/// Run every attempt `runs` times, in rounds that are `delay` apart. `wait` is
+/// called with the time until the next run and returns true if we should
+/// stop, the findings that weren't run often enough aren't verified. Returns
+/// the verdicts and the errors of the worker hooks
+pub fn recheck<F>(attempts: &[&Attempt], runs: usize, delay: Duration, mut wait: F) -> (Vec<Verdict>, Vec<String>)
+        where F: FnMut(Duration) -> bool {
+    let mut verdicts = attempts.iter()
+        .map(|_| Verdict {
+            passed: 0,
+            done: 0,
+            runs,
+        })
+        .collect::<Vec<_>>();
+    let mut errors = Vec::new();
+
+    // the workers are shared by all findings of a script
+    let mut workers: HashMap<&str, (&Script, Option<Worker>)> = HashMap::new();
+    'rounds: for round in 0..runs {
+        if round > 0 && wait(delay) {
+            break;
+        }
+        for (attempt, verdict) in attempts.iter().zip(verdicts.iter_mut()) {
+            if wait(Duration::from_secs(0)) {
+                break 'rounds;
+            }
+            let script = &attempt.script;
+            let (_, ref worker) = *workers.entry(script.label())
+                .or_insert_with(|| match script.worker_setup(None) {
+                    Ok(worker) => (script, Some(worker)),
+                    Err(err) => {
+                        errors.push(format!("{}: worker_setup failed: {}", script.label(), err));
+                        (script, None)
+                    },
+                });
+            let valid = match *worker {
+                Some(ref worker) => attempt.rerun(Some(worker)).unwrap_or(false),
+                None => false,
+            };
+            if valid {
+                verdict.passed += 1;
+            }
+            verdict.done += 1;
+        }
+    }
+
+    for (_, (script, worker)) in workers {
+        if let Some(worker) = worker {
+            if let Err(err) = script.worker_teardown(worker) {
+                errors.push(format!("{}: worker_teardown failed: {}", script.label(), err));
+            }
+        }
+    }
+
+    (verdicts, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use std::sync::Arc;
+
+    #[test]
+    fn verify_verdict() {
+        let verdict = |passed, done| Verdict { passed, done, runs: 3 };
+        assert_eq!(verdict(3, 3).to_string(), "confirmed 3/3");
+        assert_eq!(verdict(1, 3).to_string(), "flaky 1/3");
+        assert_eq!(verdict(0, 1).to_string(), "flaky 0/1");
+        assert_eq!(verdict(2, 2).to_string(), "not verified");
+    }
+
+    #[test]
+    fn verify_recheck() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "recheck"
+
+        function verify(user, password)
+            if user == "broken" then
+                return 'connection reset'
+            end
+            return password == "secret"
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+        let attempt = |user: &str, password: &str| Attempt::new(&Arc::new(user.to_string()), &Arc::new(password.to_string()), &script, 0);
+        let attempts = vec![attempt("root", "secret"), attempt("admin", "admin"), attempt("broken", "secret")];
+        let attempts = attempts.iter().collect::<Vec<_>>();
+
+        let mut waits = Vec::new();
+        let (verdicts, errors) = recheck(&attempts, 2, Duration::from_secs(5), |delay| {
+            waits.push(delay.as_secs());
+            false
+        });
+        assert_eq!(verdicts.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec!["confirmed 2/2", "flaky 0/2", "flaky 0/2"]);
+        assert!(errors.is_empty());
+        assert_eq!(waits, vec![0, 0, 0, 5, 0, 0, 0]);
+
+        // stopped before the second round
+        let (verdicts, _) = recheck(&attempts, 2, Duration::from_secs(5), |delay| delay.as_secs() > 0);
+        assert_eq!(verdicts.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec!["not verified", "flaky 0/1", "flaky 0/1"]);
+    }
+}
@@ -0,0 +1,850 @@
+use errors::{Result, ResultExt, Error};
+use checkpoint::Found;
+use proxy;
+
+use serde_json;
+use time;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// how often the attempt log is flushed, in seconds
+const LOG_FLUSH_INTERVAL: u64 = 1;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Plain,
+    Json,
+    Csv,
+    // [port][service] host: host   login: user   password: password
+    Hydra,
+    // user:password, the user takes the place of the hash
+    Potfile,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "hydra" => Ok(Format::Hydra),
+            "potfile" => Ok(Format::Potfile),
+            _ => bail!("unknown format {:?}, expected plain, json, csv, hydra or potfile", s),
+        }
+    }
+}
+
+impl Format {
+    /// Pick the format from the file extension if it wasn't set explicitly
+    pub fn detect(format: Option<Format>, path: &str) -> Format {
+        match format {
+            Some(format) => format,
+            None if path.ends_with(".json") => Format::Json,
+            None if path.ends_with(".csv") => Format::Csv,
+            None if path.ends_with(".pot") || path.ends_with(".potfile") => Format::Potfile,
+            None => Format::Plain,
+        }
+    }
+}
+
+/// What happens to credentials that were already reported by another script,
+/// see --dedup-report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dedup {
+    Drop,
+    // the note names the script that reported them first
+    Annotate,
+}
+
+impl FromStr for Dedup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Dedup> {
+        match s {
+            "drop" => Ok(Dedup::Drop),
+            "annotate" => Ok(Dedup::Annotate),
+            _ => bail!("unknown mode {:?}, expected drop or annotate", s),
+        }
+    }
+}
+
+/// The current time in rfc3339, used to timestamp findings
+pub fn timestamp() -> String {
+    time::now_utc().rfc3339().to_string()
+}
+
+/// A valid credential, or a valid user if we are enumerating users
+#[derive(Debug)]
+pub struct Finding<'a> {
+    pub script: &'a str,
+    pub user: &'a str,
+    pub password: Option<&'a str>,
+    pub timestamp: &'a str,
+    pub target: Option<&'a str>,
+    // returned by the script, eg. mfa
+    pub status: Option<&'a str>,
+    pub note: Option<&'a str>,
+    pub duration: Option<Duration>,
+    pub retries: u8,
+}
+
+impl<'a> Finding<'a> {
+    pub fn to_json(&self) -> JsonFinding<'a> {
+        JsonFinding {
+            script: self.script,
+            user: self.user,
+            password: self.password,
+            timestamp: self.timestamp,
+            target: self.target,
+            status: self.status,
+            note: self.note,
+            duration: self.duration.map(|x| x.as_secs_f64()),
+            retries: self.retries,
+        }
+    }
+}
+
+/// A finding in json reports and the --status-json stream
+#[derive(Debug, Serialize)]
+pub struct JsonFinding<'a> {
+    script: &'a str,
+    user: &'a str,
+    password: Option<&'a str>,
+    timestamp: &'a str,
+    target: Option<&'a str>,
+    status: Option<&'a str>,
+    note: Option<&'a str>,
+    // seconds
+    duration: Option<f64>,
+    retries: u8,
+}
+
+pub enum Report {
+    // the timestamp and target are only added if enabled, so existing
+    // parsers don't break
+    Plain(File, bool),
+    // one object per line, so a crash doesn't lose what we already found
+    Json(File),
+    Csv(File),
+    // enumerated users have no password and are left out of these
+    Hydra(File),
+    Potfile(File),
+    None,
+}
+
+/// Remembers which script reported a user and password first, so the report
+/// doesn't list the same credentials for every script they work with
+#[derive(Debug)]
+pub struct Confirmations {
+    mode: Option<Dedup>,
+    first: HashMap<(String, String), String>,
+}
+
+impl Confirmations {
+    pub fn new(mode: Option<Dedup>) -> Confirmations {
+        Confirmations {
+            mode,
+            first: HashMap::new(),
+        }
+    }
+
+    /// Write the finding unless another script already reported the
+    /// credentials, enumerated users are always written
+    pub fn write(&mut self, report: &mut Report, finding: &Finding) -> Result<()> {
+        let (mode, password) = match (self.mode, finding.password) {
+            (Some(mode), Some(password)) => (mode, password),
+            _ => return report.write(finding),
+        };
+
+        let key = (finding.user.to_string(), password.to_string());
+        let first = match self.first.get(&key) {
+            Some(first) if first != finding.script => first,
+            Some(_) => return report.write(finding),
+            None => {
+                self.first.insert(key, finding.script.to_string());
+                return report.write(finding);
+            },
+        };
+
+        match mode {
+            Dedup::Drop => Ok(()),
+            Dedup::Annotate => {
+                let note = match finding.note {
+                    Some(note) => format!("{}, confirms {}", note, first),
+                    None => format!("confirms {}", first),
+                };
+                report.write(&Finding {
+                    note: Some(&note),
+                    ..*finding
+                })
+            },
+        }
+    }
+}
+
+/// Quote a csv field if necessary, see rfc4180
+fn csv_field(x: &str) -> String {
+    if x.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", x.replace('"', "\"\""))
+    } else {
+        x.to_string()
+    }
+}
+
+/// The way hashcat writes plains it can't write as they are, eg. $HEX[726f6f74]
+fn hex(x: &str) -> String {
+    let hex = x.bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("$HEX[{}]", hex)
+}
+
+/// Hydra doesn't escape anything, values that would be cut off or mangled by
+/// a parser that splits on the separators are written as $HEX[...]
+fn hydra_field(x: &str, last: bool) -> String {
+    let mangled = x.chars().any(|c| c.is_control()) ||
+        x.starts_with(char::is_whitespace) ||
+        x.ends_with(char::is_whitespace) ||
+        x.starts_with("$HEX[") ||
+        (!last && x.contains("  "));
+    if mangled {
+        hex(x)
+    } else {
+        x.to_string()
+    }
+}
+
+/// Hashcat writes plains that aren't printable ascii as $HEX[...], the user
+/// also can't contain the separator
+fn potfile_field(x: &str, separator: bool) -> String {
+    let mangled = x.bytes().any(|b| !(0x20..0x7f).contains(&b)) ||
+        x.starts_with("$HEX[") ||
+        (separator && x.contains(':'));
+    if mangled {
+        hex(x)
+    } else {
+        x.to_string()
+    }
+}
+
+/// Split the target into the scheme, the host and the port, eg.
+/// ssh://10.0.0.1:22, https://vpn.acme.corp/login or 10.0.0.1:3306
+fn split_target(target: &str) -> (Option<&str>, &str, Option<u16>) {
+    let (scheme, rest) = match target.find("://") {
+        Some(idx) => (Some(&target[..idx]), &target[idx + 3..]),
+        None => (None, target),
+    };
+    let rest = rest.split('/').next().unwrap_or(rest);
+    let rest = rest.rsplit('@').next().unwrap_or(rest);
+    match proxy::split_host_port(rest, Some(0)) {
+        Ok((host, 0)) => (scheme, host, None),
+        Ok((host, port)) => (scheme, host, Some(port)),
+        Err(_) => (scheme, rest, None),
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    let port = match scheme {
+        "ftp" => 21,
+        "ssh" => 22,
+        "telnet" => 23,
+        "smtp" => 25,
+        "http" => 80,
+        "pop3" => 110,
+        "imap" => 143,
+        "ldap" => 389,
+        "https" => 443,
+        "smb" => 445,
+        "ldaps" => 636,
+        "imaps" => 993,
+        "pop3s" => 995,
+        "mysql" => 3306,
+        "rdp" => 3389,
+        "postgres" => 5432,
+        "vnc" => 5900,
+        _ => return None,
+    };
+    Some(port)
+}
+
+impl Report {
+    /// Open the report, refuses to overwrite an existing report unless `force`
+    /// or `append` is set
+    pub fn open(path: Option<String>, format: Option<Format>, append: bool, force: bool, metadata: bool) -> Result<Report> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Report::None),
+        };
+
+        let len = match fs::metadata(&path) {
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        };
+
+        if len > 0 && !append && !force {
+            bail!("report {:?} already exists, use --append or --force", path);
+        }
+
+        let file = if append {
+            OpenOptions::new().append(true).create(true).open(&path)?
+        } else {
+            File::create(&path)?
+        };
+
+        let mut report = match Format::detect(format, &path) {
+            Format::Plain => Report::Plain(file, metadata),
+            Format::Json => Report::Json(file),
+            Format::Csv => Report::Csv(file),
+            Format::Hydra => Report::Hydra(file),
+            Format::Potfile => Report::Potfile(file),
+        };
+
+        // don't repeat the header if we append to an existing file
+        if let Report::Csv(ref mut f) = report {
+            if !append || len == 0 {
+                f.write_all(b"timestamp,script,user,password,attempts_for_this_cred,target,status,note\r\n")?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn format(&self, finding: &Finding) -> Result<String> {
+        let line = match *self {
+            Report::Plain(_, metadata) => {
+                let mut line = match finding.password {
+                    Some(password) => format!("{}:{}:{}", finding.script, finding.user, password),
+                    None => format!("{}:{}", finding.script, finding.user),
+                };
+                if metadata {
+                    line += &format!("\t{}\t{}\t{}\t{}", finding.timestamp, finding.target.unwrap_or(""),
+                        finding.status.unwrap_or(""), finding.note.unwrap_or(""));
+                }
+                line + "\n"
+            },
+            Report::Json(_) => serde_json::to_string(&finding.to_json())? + "\n",
+            Report::Csv(_) => format!("{},{},{},{},{},{},{},{}\r\n",
+                    finding.timestamp,
+                    csv_field(finding.script),
+                    csv_field(finding.user),
+                    csv_field(finding.password.unwrap_or("")),
+                    finding.retries as u32 + 1,
+                    csv_field(finding.target.unwrap_or("")),
+                    finding.status.unwrap_or(""),
+                    csv_field(finding.note.unwrap_or(""))),
+            Report::Hydra(_) => match finding.password {
+                Some(password) => {
+                    // the script is named descr@target if it was started with --targets
+                    let descr = finding.script.split('@').next().unwrap_or(finding.script);
+                    let (scheme, host, port) = match finding.target {
+                        Some(target) => split_target(target),
+                        None => (None, "-", None),
+                    };
+                    let port = port.or_else(|| scheme.and_then(default_port)).unwrap_or(0);
+                    let service = scheme.unwrap_or(descr).replace(char::is_whitespace, "-");
+                    format!("[{}][{}] host: {}   login: {}   password: {}\n", port, service, host,
+                        hydra_field(finding.user, false), hydra_field(password, true))
+                },
+                None => String::new(),
+            },
+            Report::Potfile(_) => match finding.password {
+                Some(password) => format!("{}:{}\n", potfile_field(finding.user, true), potfile_field(password, false)),
+                None => String::new(),
+            },
+            Report::None => String::new(),
+        };
+        Ok(line)
+    }
+
+    /// Write a finding, the file is flushed immediately so nothing is lost
+    /// if we get killed
+    pub fn write(&mut self, finding: &Finding) -> Result<()> {
+        let line = self.format(finding)?;
+        match *self {
+            Report::Plain(ref mut f, _) |
+            Report::Json(ref mut f) |
+            Report::Csv(ref mut f) |
+            Report::Hydra(ref mut f) |
+            Report::Potfile(ref mut f) => {
+                f.write_all(line.as_bytes())?;
+                f.flush()?;
+            },
+            Report::None => (),
+        }
+        Ok(())
+    }
+}
+
+/// The findings of a previous plain or json report, see --skip-report
+#[derive(Debug, Default)]
+pub struct PriorReport {
+    found: Vec<Found>,
+    // lines of a plain report without --plain-metadata, they are split once
+    // the scripts are known since the descr can contain colons
+    plain: Vec<String>,
+}
+
+impl PriorReport {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.found.len() + self.plain.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The findings of the scripts with these labels, the others are ignored
+    pub fn findings(&self, labels: &[&str]) -> Vec<Found> {
+        let mut findings = self.found.iter()
+            .filter(|found| labels.contains(&found.script.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        findings.extend(self.plain.iter()
+            .filter_map(|line| parse_plain(line, labels)));
+        findings
+    }
+}
+
+/// Parse a line of a plain report, `script:user:password` or `script:user`
+/// for enumerated users. The script is the longest label the line starts
+/// with, eg. `ssh@10.0.0.1:22:root:toor` if the script has a target
+fn parse_plain(line: &str, labels: &[&str]) -> Option<Found> {
+    let script = labels.iter()
+        .filter(|label| line.strip_prefix(**label).is_some_and(|rest| rest.starts_with(':')))
+        .max_by_key(|label| label.len())?;
+    let rest = &line[script.len() + 1..];
+
+    let (user, password) = match rest.split_once(':') {
+        Some((user, password)) => (user, Some(password.to_string())),
+        None => (rest, None),
+    };
+    Some(Found {
+        script: script.to_string(),
+        user: user.to_string(),
+        password,
+        timestamp: None,
+        target: None,
+        status: None,
+        note: None,
+    })
+}
+
+/// Read the findings of a previous plain or json report, see --skip-report
+pub fn load_findings(path: &str) -> Result<PriorReport> {
+    let report = fs::read_to_string(path)?;
+    if report.starts_with("timestamp,script,user,") {
+        bail!("{:?} is a csv report, only plain and json reports can be skipped", path);
+    }
+    if report.starts_with('[') && report.lines().next().is_some_and(|x| x.contains("] host: ")) {
+        bail!("{:?} is a hydra report, only plain and json reports can be skipped", path);
+    }
+
+    let mut prior = PriorReport::default();
+    for (i, line) in report.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('{') {
+            let found = serde_json::from_str(line)
+                .chain_err(|| format!("invalid finding in line {}", i + 1))?;
+            prior.found.push(found);
+            continue;
+        }
+
+        // remove --plain-metadata, there are always four fields
+        let fields = line.rsplitn(5, '\t').collect::<Vec<_>>();
+        let line = if fields.len() == 5 { fields[4] } else { line };
+        if !line.contains(':') {
+            bail!("invalid finding in line {}: {:?}", i + 1, line);
+        }
+        prior.plain.push(line.to_string());
+    }
+    Ok(prior)
+}
+
+/// A single run of an attempt, see --log
+#[derive(Debug, Serialize)]
+pub struct LogEntry<'a> {
+    pub timestamp: &'a str,
+    pub script: &'a str,
+    pub user: &'a str,
+    pub password: Option<&'a str>,
+    // valid, invalid or error
+    pub outcome: &'a str,
+    pub error: Option<&'a str>,
+    pub status: Option<&'a str>,
+    pub note: Option<&'a str>,
+    // seconds
+    pub duration: f64,
+    pub retries: u8,
+}
+
+/// Records every attempt as json, one object per line. The file is buffered
+/// and flushed regularly so a crash only loses the last few entries
+pub struct AttemptLog {
+    file: BufWriter<File>,
+    dirty: bool,
+    last_flush: Instant,
+}
+
+impl AttemptLog {
+    /// Entries are always appended, the log of a previous run is kept
+    pub fn open(path: &str) -> Result<AttemptLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(AttemptLog {
+            file: BufWriter::new(file),
+            dirty: false,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn write(&mut self, entry: &LogEntry) -> Result<()> {
+        serde_json::to_writer(&mut self.file, entry)?;
+        self.file.write_all(b"\n")?;
+        self.dirty = true;
+        self.tick()
+    }
+
+    /// Flush the log if it wasn't flushed recently
+    pub fn tick(&mut self) -> Result<()> {
+        if self.dirty && self.last_flush.elapsed() >= Duration::from_secs(LOG_FLUSH_INTERVAL) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.dirty = false;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_detect_format() {
+        assert_eq!(Format::detect(None, "out.json"), Format::Json);
+        assert_eq!(Format::detect(None, "out.txt"), Format::Plain);
+        assert_eq!(Format::detect(Some(Format::Plain), "out.json"), Format::Plain);
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn verify_json() {
+        let path = ::std::env::temp_dir().join(format!("badtouch-report-{}.json", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let mut report = Report::open(Some(path.clone()), None, false, false, false).unwrap();
+        report.write(&Finding {
+            script: "test",
+            user: "foo",
+            password: Some("a:b"),
+            timestamp: "2026-01-01T00:00:00Z",
+            target: Some("https://vpn.acme.corp"),
+            status: Some("mfa"),
+            note: None,
+            duration: Some(Duration::from_millis(1500)),
+            retries: 1,
+        }).unwrap();
+
+        let mut buf = String::new();
+        File::open(&path).unwrap().read_to_string(&mut buf).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(buf.trim_end()).unwrap();
+        assert_eq!(json["password"], "a:b");
+        assert_eq!(json["duration"], 1.5);
+        assert_eq!(json["retries"], 1);
+        assert_eq!(json["timestamp"], "2026-01-01T00:00:00Z");
+        assert_eq!(json["target"], "https://vpn.acme.corp");
+        assert_eq!(json["status"], "mfa");
+        assert_eq!(json["note"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn verify_append() {
+        let path = ::std::env::temp_dir().join(format!("badtouch-append-{}.csv", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let finding = Finding {
+            script: "test",
+            user: "foo",
+            password: Some("bar"),
+            timestamp: "2026-01-01T00:00:00Z",
+            target: None,
+            status: None,
+            note: None,
+            duration: None,
+            retries: 0,
+        };
+
+        let mut report = Report::open(Some(path.clone()), None, false, false, false).unwrap();
+        report.write(&finding).unwrap();
+
+        // refuse to overwrite the existing report
+        assert!(Report::open(Some(path.clone()), None, false, false, false).is_err());
+
+        let mut report = Report::open(Some(path.clone()), None, true, false, false).unwrap();
+        report.write(&finding).unwrap();
+
+        let mut buf = String::new();
+        File::open(&path).unwrap().read_to_string(&mut buf).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(buf.matches("timestamp").count(), 1);
+        assert_eq!(buf.lines().count(), 3);
+    }
+
+    #[test]
+    fn verify_plain_metadata() {
+        let finding = Finding {
+            script: "test",
+            user: "foo",
+            password: Some("bar"),
+            timestamp: "2026-01-01T00:00:00Z",
+            target: Some("vpn.acme.corp"),
+            status: Some("expired"),
+            note: Some("changed 2019"),
+            duration: None,
+            retries: 0,
+        };
+
+        let path = ::std::env::temp_dir().join(format!("badtouch-plain-{}.txt", ::std::process::id()));
+        let file = File::create(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        let report = Report::Plain(file.try_clone().unwrap(), false);
+        assert_eq!(report.format(&finding).unwrap(), "test:foo:bar\n");
+        let report = Report::Plain(file, true);
+        assert_eq!(report.format(&finding).unwrap(), "test:foo:bar\t2026-01-01T00:00:00Z\tvpn.acme.corp\texpired\tchanged 2019\n");
+    }
+
+    #[test]
+    fn verify_attempt_log() {
+        let path = ::std::env::temp_dir().join(format!("badtouch-log-{}.json", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        for outcome in &["invalid", "error"] {
+            let mut log = AttemptLog::open(&path).unwrap();
+            log.write(&LogEntry {
+                timestamp: "2026-01-01T00:00:00Z",
+                script: "test",
+                user: "foo",
+                password: Some("bar"),
+                outcome,
+                error: if *outcome == "error" { Some("connection refused") } else { None },
+                status: None,
+                note: None,
+                duration: 0.5,
+                retries: 0,
+            }).unwrap();
+            log.flush().unwrap();
+        }
+
+        let mut buf = String::new();
+        File::open(&path).unwrap().read_to_string(&mut buf).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        let lines = buf.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["outcome"], "invalid");
+        assert_eq!(lines[0]["error"], serde_json::Value::Null);
+        assert_eq!(lines[1]["error"], "connection refused");
+        assert_eq!(lines[1]["duration"], 0.5);
+    }
+
+    #[test]
+    fn verify_load_findings() {
+        let path = ::std::env::temp_dir().join(format!("badtouch-findings-{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "ssh:root:toor\r\nssh@10.0.0.1:22:admin:a:b\nsmtp:mail\n\n\
+                         ldap:jane:secret\t2019-01-01T00:00:00Z\tdc1\tmfa\t\n\
+                         Acme VPN portal (https://vpn.acme.corp):alice:hunter2\n\
+                         ftp:anonymous:guest\n\
+                         {\"script\":\"http\",\"user\":\"bob\",\"password\":null,\"timestamp\":\"2019-01-01T00:00:00Z\",\"duration\":0.5,\"retries\":0}\n\
+                         {\"script\":\"pop3\",\"user\":\"carol\",\"password\":\"x\",\"timestamp\":\"2019-01-01T00:00:00Z\",\"duration\":0.5,\"retries\":0}\n").unwrap();
+        let prior = load_findings(path).unwrap();
+        assert_eq!(prior.len(), 8);
+
+        // findings of scripts that aren't loaded are ignored
+        let labels = ["ssh", "ssh@10.0.0.1:22", "smtp", "ldap", "Acme VPN portal (https://vpn.acme.corp)", "http"];
+        let findings = prior.findings(&labels).into_iter()
+            .map(|x| (x.script, x.user, x.password))
+            .collect::<Vec<_>>();
+        let creds = |script: &str, user: &str, password: Option<&str>| (script.to_string(), user.to_string(), password.map(|x| x.to_string()));
+        assert_eq!(findings, vec![
+            creds("http", "bob", None),
+            creds("ssh", "root", Some("toor")),
+            creds("ssh@10.0.0.1:22", "admin", Some("a:b")),
+            creds("smtp", "mail", None),
+            creds("ldap", "jane", Some("secret")),
+            creds("Acme VPN portal (https://vpn.acme.corp)", "alice", Some("hunter2")),
+        ]);
+
+        fs::write(path, "nocolon\n").unwrap();
+        assert!(load_findings(path).is_err());
+        fs::write(path, "timestamp,script,user,password\r\n").unwrap();
+        assert!(load_findings(path).is_err());
+        fs::write(path, "[22][ssh] host: 10.0.0.1   login: root   password: toor\n").unwrap();
+        assert!(load_findings(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn verify_confirmations() {
+        let path = ::std::env::temp_dir().join(format!("badtouch-confirm-{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let finding = |script| Finding {
+            script,
+            user: "jane",
+            password: Some("Summer2019"),
+            timestamp: "2026-01-01T00:00:00Z",
+            target: None,
+            status: None,
+            note: None,
+            duration: None,
+            retries: 0,
+        };
+
+        let mut lines = Vec::new();
+        for mode in &[None, Some(Dedup::Drop), Some(Dedup::Annotate)] {
+            let mut report = Report::open(Some(path.clone()), None, false, true, true).unwrap();
+            let mut confirmations = Confirmations::new(*mode);
+            for script in &["owa", "vpn", "owa"] {
+                confirmations.write(&mut report, &finding(script)).unwrap();
+            }
+            confirmations.write(&mut report, &Finding {
+                password: None,
+                ..finding("vpn")
+            }).unwrap();
+            lines.push(fs::read_to_string(&path).unwrap().lines().count());
+        }
+        assert_eq!(lines, vec![4, 3, 4]);
+        assert!(fs::read_to_string(&path).unwrap().contains("\tconfirms owa\n"));
+        ::std::fs::remove_file(&path).unwrap();
+        assert!("merge".parse::<Dedup>().is_err());
+    }
+
+    // the awkward credentials the formats have to survive
+    const CREDS: &[(&str, &str)] = &[
+        ("root", "toor"),
+        ("jane doe", "pass word"),
+        ("a:b", "c:d"),
+        ("j\u{fc}rgen", "p\u{e4}ssw\u{f6}rd \u{1f511}"),
+        ("two  spaces", "  padded  "),
+        ("tab\tuser", "line\nbreak\r"),
+        ("$HEX[41]", "$HEX[42]"),
+        ("empty", ""),
+    ];
+
+    fn unhex(x: &str) -> String {
+        match x.strip_prefix("$HEX[").and_then(|x| x.strip_suffix(']')) {
+            Some(hex) => {
+                let bytes = (0..hex.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                    .collect::<Vec<_>>();
+                String::from_utf8(bytes).unwrap()
+            },
+            None => x.to_string(),
+        }
+    }
+
+    fn format_creds(format: Format, target: Option<&str>) -> Vec<String> {
+        let path = ::std::env::temp_dir().join(format!("badtouch-{:?}-{}", format, ::std::process::id()));
+        let file = File::create(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+        let report = match format {
+            Format::Hydra => Report::Hydra(file),
+            _ => Report::Potfile(file),
+        };
+
+        let mut lines = CREDS.iter()
+            .map(|&(user, password)| report.format(&Finding {
+                script: "ssh@10.0.0.1",
+                user,
+                password: Some(password),
+                timestamp: "2026-01-01T00:00:00Z",
+                target,
+                status: None,
+                note: None,
+                duration: None,
+                retries: 0,
+            }).unwrap())
+            .collect::<Vec<_>>();
+        // enumerated users have no password
+        assert_eq!(report.format(&Finding {
+            script: "ssh",
+            user: "root",
+            password: None,
+            timestamp: "2026-01-01T00:00:00Z",
+            target,
+            status: None,
+            note: None,
+            duration: None,
+            retries: 0,
+        }).unwrap(), "");
+
+        for line in &mut lines {
+            assert_eq!(line.matches('\n').count(), 1, "{:?}", line);
+            line.pop();
+        }
+        lines
+    }
+
+    #[test]
+    fn verify_hydra() {
+        let lines = format_creds(Format::Hydra, Some("ssh://10.0.0.1/"));
+        assert_eq!(lines[0], "[22][ssh] host: 10.0.0.1   login: root   password: toor");
+        assert_eq!(lines[1], "[22][ssh] host: 10.0.0.1   login: jane doe   password: pass word");
+        for (line, &(user, password)) in lines.iter().zip(CREDS) {
+            let rest = line.strip_prefix("[22][ssh] host: 10.0.0.1   login: ").unwrap();
+            let idx = rest.find("   password: ").unwrap();
+            assert_eq!((unhex(&rest[..idx]), unhex(&rest[idx + 13..])), (user.to_string(), password.to_string()));
+        }
+
+        let line = |target| format_creds(Format::Hydra, target).remove(0);
+        assert_eq!(line(Some("https://admin@vpn.acme.corp/login")), "[443][https] host: vpn.acme.corp   login: root   password: toor");
+        assert_eq!(line(Some("[::1]:2222")), "[2222][ssh] host: ::1   login: root   password: toor");
+        assert_eq!(line(Some("vpn.acme.corp")), "[0][ssh] host: vpn.acme.corp   login: root   password: toor");
+        assert_eq!(line(None), "[0][ssh] host: -   login: root   password: toor");
+    }
+
+    #[test]
+    fn verify_potfile() {
+        let lines = format_creds(Format::Potfile, None);
+        assert_eq!(lines[0], "root:toor");
+        assert_eq!(lines[2], "$HEX[613a62]:c:d");
+        assert_eq!(lines[3], "$HEX[6ac3bc7267656e]:$HEX[70c3a4737377c3b6726420f09f9491]");
+        for (line, &(user, password)) in lines.iter().zip(CREDS) {
+            let (left, right) = line.split_once(':').unwrap();
+            assert_eq!((unhex(left), unhex(right)), (user.to_string(), password.to_string()));
+        }
+
+        assert_eq!(Format::detect(None, "found.pot"), Format::Potfile);
+        assert_eq!("hydra".parse::<Format>().unwrap(), Format::Hydra);
+    }
+
+    #[test]
+    fn verify_csv_field() {
+        assert_eq!(csv_field("foo"), "foo");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+    }
+}
@@ -5,6 +5,8 @@ use structs::LuaMap;
 use errors::{Result, ResultExt};
 use json;
 use db;
+use db::mysql::MysqlOptions;
+use exec::{self, ExecOptions};
 
 use md5;
 use sha1;
@@ -16,22 +18,49 @@ use hmac::{Hmac, Mac};
 use base64;
 use bcrypt;
 
-use reqwest;
 use ldap3;
 use mysql;
 use rand;
 use rand::RngCore;
 
+use std::io;
 use std::thread;
 use std::time::Duration;
-use std::process::Command;
 use std::collections::HashMap;
 use ctx::State;
-use http::HttpRequest;
-use http::RequestOptions;
+use scheduler::Level;
+use http::{self, BasicAuthOptions, HttpRequest, RequestOptions};
+use ldap::{self, LdapOptions, MultipleEntries};
 use html;
 
 
+/// Functions that are disabled with --sandbox and --proxy-strict
+pub const UNSAFE_FUNCTIONS: &[&str] = &[
+    "execve",
+    "execve_output",
+    "proc_spawn",
+];
+
+/// Functions that connect to the target, disabled by badtouch benchmark --offline
+pub const NETWORK_FUNCTIONS: &[&str] = &[
+    "http_basic_auth",
+    "http_send",
+    "ldap_bind",
+    "ldap_sasl_bind",
+    "ldap_search",
+    "ldap_search_bind",
+    "mysql_connect",
+    "sock_connect",
+];
+
+/// Replace a function with a stub that raises an error
+pub fn sandboxed(lua: &mut hlua::Lua, state: State, name: &'static str, reason: &'static str) {
+    lua.set(name, hlua::function0(move || -> Result<()> {
+        let err = format!("{} is disabled by {}", name, reason);
+        Err(state.set_error(err.into()))
+    }))
+}
+
 fn byte_array(bytes: AnyLuaValue) -> Result<Vec<u8>> {
     match bytes {
         AnyLuaValue::LuaAnyString(bytes) => Ok(bytes.0),
@@ -92,29 +121,68 @@ pub fn clear_err(lua: &mut hlua::Lua, state: State) {
     }))
 }
 
+pub fn cred_info(lua: &mut hlua::Lua, state: State) {
+    lua.set("cred_info", hlua::function0(move || -> AnyLuaValue {
+        state.cred_info()
+            .map(|hash| hash.to_lua())
+            .unwrap_or(AnyLuaValue::LuaNil)
+    }))
+}
+
+fn exec_args(args: Vec<AnyLuaValue>) -> Vec<String> {
+    args.into_iter()
+        .flat_map(|x| match x {
+            LuaString(x) => Some(x),
+            _ => None, // TODO: error
+        })
+        .collect()
+}
+
 pub fn execve(lua: &mut hlua::Lua, state: State) {
-    lua.set("execve", hlua::function2(move |prog: String, args: Vec<AnyLuaValue>| -> Result<i32> {
-        let args: Vec<_> = args.into_iter()
-                    .flat_map(|x| match x {
-                        LuaString(x) => Some(x),
-                        _ => None, // TODO: error
-                    })
-                    .collect();
-
-        let status = match Command::new(prog)
-                        .args(&args)
-                        .status()
-                        .chain_err(|| "failed to spawn program") {
-            Ok(status) => status,
+    lua.set("execve", hlua::function3(move |prog: String, args: Vec<AnyLuaValue>, options: AnyLuaValue| -> Result<i32> {
+        let args = exec_args(args);
+
+        let options = match ExecOptions::try_from(options)
+                            .chain_err(|| "invalid execve options") {
+            Ok(options) => options,
             Err(err) => return Err(state.set_error(err)),
         };
 
-        let code = match status.code() {
-            Some(code) => code,
-            None => return Err(state.set_error("process didn't return exit code".into())),
+        exec::status(&prog, &args, options)
+            .map_err(|err| state.set_error(err))
+    }))
+}
+
+pub fn execve_output(lua: &mut hlua::Lua, state: State) {
+    lua.set("execve_output", hlua::function3(move |prog: String, args: Vec<AnyLuaValue>, options: AnyLuaValue| -> Result<AnyLuaValue> {
+        let args = exec_args(args);
+
+        let options = match ExecOptions::try_from(options)
+                            .chain_err(|| "invalid execve options") {
+            Ok(options) => options,
+            Err(err) => return Err(state.set_error(err)),
+        };
+
+        let output = match exec::output(&prog, &args, options) {
+            Ok(output) => output,
+            Err(err) => return Err(state.set_error(err)),
         };
 
-        Ok(code)
+        let mut map = LuaMap::new();
+        map.insert_num("status", f64::from(output.status));
+        map.insert("stdout", lua_bytes(&output.stdout));
+        map.insert("stderr", lua_bytes(&output.stderr));
+
+        Ok(map.into())
+    }))
+}
+
+pub fn getopt(lua: &mut hlua::Lua, state: State) {
+    lua.set("getopt", hlua::function1(move |name: String| -> AnyLuaValue {
+        match state.config().options.get(&name) {
+            Some(value) => AnyLuaValue::LuaString(value.clone()),
+            None => AnyLuaValue::LuaNil,
+        }
     }))
 }
 
@@ -210,19 +278,18 @@ pub fn html_select_list(lua: &mut hlua::Lua, state: State) {
 }
 
 pub fn http_basic_auth(lua: &mut hlua::Lua, state: State) {
-    lua.set("http_basic_auth", hlua::function3(move |url: String, user: String, password: String| -> Result<bool> {
-        let client = reqwest::Client::new();
+    lua.set("http_basic_auth", hlua::function4(move |url: String, user: String, password: String, options: AnyLuaValue| -> Result<bool> {
+        let options = BasicAuthOptions::try_from(options)
+            .map_err(|err| state.set_error(err))?;
+        http::basic_auth(&state, &url, user, password, options)
+            .map_err(|err| state.set_error(err))
+    }))
+}
 
-        client.get(&url)
-            .basic_auth(user, Some(password))
-            .send()
-            .chain_err(|| "http request failed")
+pub fn http_clear_cookies(lua: &mut hlua::Lua, state: State) {
+    lua.set("http_clear_cookies", hlua::function1(move |session: String| -> Result<()> {
+        state.http_clear_cookies(&session)
             .map_err(|err| state.set_error(err))
-            .map(|response| {
-                info!("http_basic_auth: {:?}", response);
-                response.headers().get_raw("www-authenticate").is_none() &&
-                    response.status() != reqwest::StatusCode::Unauthorized
-            })
     }))
 }
 
@@ -274,21 +341,41 @@ pub fn json_encode(lua: &mut hlua::Lua, state: State) {
 pub fn last_err(lua: &mut hlua::Lua, state: State) {
     lua.set("last_err", hlua::function0(move || -> AnyLuaValue {
         match state.last_error() {
-            Some(err) => AnyLuaValue::LuaString(err),
+            Some((kind, err)) => {
+                let mut map = LuaMap::new();
+                map.insert_str("kind", kind.as_str());
+                map.insert_str("message", err);
+                map.into()
+            },
+            None => AnyLuaValue::LuaNil,
+        }
+    }))
+}
+
+pub fn last_err_str(lua: &mut hlua::Lua, state: State) {
+    lua.set("last_err_str", hlua::function0(move || -> AnyLuaValue {
+        match state.last_error() {
+            Some((_, err)) => AnyLuaValue::LuaString(err),
             None => AnyLuaValue::LuaNil,
         }
     }))
 }
 
 pub fn ldap_bind(lua: &mut hlua::Lua, state: State) {
-    lua.set("ldap_bind", hlua::function3(move |url: String, dn: String, password: String| -> Result<bool> {
-        let sock = match ldap3::LdapConn::new(&url)
-                        .chain_err(|| "ldap connection failed") {
+    lua.set("ldap_bind", hlua::function4(move |url: String, dn: String, password: String, options: AnyLuaValue| -> Result<bool> {
+        let options = match LdapOptions::try_from(options)
+                        .chain_err(|| "invalid ldap options")
+                        .map(|options| options.with_route(state.route())) {
+            Ok(options) => options,
+            Err(err) => return Err(state.set_error(err)),
+        };
+
+        let sock = match options.connect(&url) {
             Ok(sock) => sock,
             Err(err) => return Err(state.set_error(err)),
         };
 
-        sock.simple_bind(&dn, &password)
+        options.apply(&sock).simple_bind(&dn, &password)
             .chain_err(|| "fatal error during simple_bind")
             .map_err(|err| state.set_error(err))
             .map(|result| {
@@ -304,16 +391,63 @@ pub fn ldap_escape(lua: &mut hlua::Lua, _: State) {
     }))
 }
 
+pub fn ldap_sasl_bind(lua: &mut hlua::Lua, state: State) {
+    lua.set("ldap_sasl_bind", hlua::function5(move |url: String, mech: String, user: String, password: String, options: AnyLuaValue| -> Result<bool> {
+        let options = match LdapOptions::try_from(options)
+                        .chain_err(|| "invalid ldap options")
+                        .map(|options| options.with_route(state.route())) {
+            Ok(options) => options,
+            Err(err) => return Err(state.set_error(err)),
+        };
+
+        ldap::sasl_bind(&url, &mech, &user, &password, &options)
+            .map_err(|err| state.set_error(err))
+    }))
+}
+
+pub fn ldap_search(lua: &mut hlua::Lua, state: State) {
+    lua.set("ldap_search", hlua::function7(move |url: String, bind_dn: String, bind_pw: String, base_dn: String, filter: String, attrs: AnyLuaValue, options: AnyLuaValue| -> Result<Vec<AnyLuaValue>> {
+        let options = match LdapOptions::try_from(options)
+                        .chain_err(|| "invalid ldap options")
+                        .map(|options| options.with_route(state.route())) {
+            Ok(options) => options,
+            Err(err) => return Err(state.set_error(err)),
+        };
+
+        let attrs = match attrs {
+            AnyLuaValue::LuaNil => Vec::new(),
+            AnyLuaValue::LuaArray(attrs) => attrs.into_iter()
+                .flat_map(|(_, x)| match x {
+                    LuaString(x) => Some(x),
+                    _ => None,
+                })
+                .collect(),
+            _ => return Err(state.set_error("attrs must be a list of strings".into())),
+        };
+
+        ldap::search(&url, &bind_dn, &bind_pw, &base_dn, &filter, attrs, &options)
+            .map_err(|err| state.set_error(err))
+            .map(|entries| entries.into_iter()
+                .map(ldap::entry_to_lua)
+                .collect())
+    }))
+}
+
 pub fn ldap_search_bind(lua: &mut hlua::Lua, state: State) {
-    lua.set("ldap_search_bind", hlua::function6(move |url: String, search_user: String, search_pw: String, base_dn: String, user: String, password: String| -> Result<bool> {
+    lua.set("ldap_search_bind", hlua::function7(move |url: String, search_user: String, search_pw: String, base_dn: String, user: String, password: String, options: AnyLuaValue| -> Result<bool> {
+        let options = match LdapOptions::try_from(options)
+                        .chain_err(|| "invalid ldap options")
+                        .map(|options| options.with_route(state.route())) {
+            Ok(options) => options,
+            Err(err) => return Err(state.set_error(err)),
+        };
 
-        let sock = match ldap3::LdapConn::new(&url)
-                        .chain_err(|| "ldap connection failed") {
+        let sock = match options.connect(&url) {
             Ok(sock) => sock,
             Err(err) => return Err(state.set_error(err)),
         };
 
-        let result = match sock.simple_bind(&search_user, &search_pw)
+        let result = match options.apply(&sock).simple_bind(&search_user, &search_pw)
                             .chain_err(|| "fatal error during simple_bind with search user") {
             Ok(result) => result,
             Err(err) => return Err(state.set_error(err)),
@@ -323,8 +457,8 @@ pub fn ldap_search_bind(lua: &mut hlua::Lua, state: State) {
             return Err("login with search user failed".into());
         }
 
-        let search = format!("uid={}", ldap3::dn_escape(user));
-        let result = match sock.search(&base_dn, ldap3::Scope::Subtree, &search, vec!["*"])
+        let search = options.search_filter(&user);
+        let result = match options.apply(&sock).search(&base_dn, ldap3::Scope::Subtree, &search, vec!["*"])
                             .chain_err(|| "fatal error during ldap search") {
             Ok(result) => result,
             Err(err) => return Err(state.set_error(err)),
@@ -336,23 +470,39 @@ pub fn ldap_search_bind(lua: &mut hlua::Lua, state: State) {
             Err(err) => return Err(state.set_error(err)),
         };
 
-        // take the first result
-        if let Some(entry) = entries.into_iter().next() {
+        let entries = match options.multiple() {
+            MultipleEntries::First => entries.into_iter().take(1).collect(),
+            MultipleEntries::Error if entries.len() > 1 => {
+                let err = format!("ldap search returned {} entries", entries.len());
+                return Err(state.set_error(err.into()));
+            },
+            _ => entries,
+        };
+
+        for entry in entries {
             let entry = ldap3::SearchEntry::construct(entry);
 
             // we got the DN, try to login
-            let result = match sock.simple_bind(&entry.dn, &password)
+            let result = match options.apply(&sock).simple_bind(&entry.dn, &password)
                                 .chain_err(|| "fatal error during simple_bind") {
                 Ok(result) => result,
                 Err(err) => return Err(state.set_error(err)),
             };
 
-            // println!("{:?}", result);
+            debug!("ldap_search_bind: {:?}: {:?}", entry.dn, result);
 
-            Ok(result.success().is_ok())
-        } else {
-            return Ok(false);
+            if result.success().is_ok() {
+                return Ok(true);
+            }
         }
+
+        Ok(false)
+    }))
+}
+
+pub fn mark_locked(lua: &mut hlua::Lua, state: State) {
+    lua.set("mark_locked", hlua::function1(move |user: String| {
+        state.mark_locked(user)
     }))
 }
 
@@ -364,27 +514,48 @@ pub fn md5(lua: &mut hlua::Lua, state: State) {
     }))
 }
 
+pub fn mysql_close(lua: &mut hlua::Lua, state: State) {
+    lua.set("mysql_close", hlua::function1(move |session: String| {
+        state.mysql_close(&session);
+    }))
+}
+
 pub fn mysql_connect(lua: &mut hlua::Lua, state: State) {
-    lua.set("mysql_connect", hlua::function4(move |host: String, port: u16, user: String, password: String| -> Result<String> {
+    lua.set("mysql_connect", hlua::function5(move |host: String, port: u16, user: String, password: String, options: AnyLuaValue| -> Result<AnyLuaValue> {
+        let options = match MysqlOptions::try_from(options)
+                            .chain_err(|| "invalid mysql options") {
+            Ok(options) => options,
+            Err(err) => return Err(state.set_error(err)),
+        };
+
         let mut builder = mysql::OptsBuilder::new();
-        builder.ip_or_hostname(Some(host))
+        builder.ip_or_hostname(Some(host.clone()))
                .tcp_port(port)
                .prefer_socket(false)
                .user(Some(user))
                .pass(Some(password));
+        if let Err(err) = options.apply(state.config(), &mut builder) {
+            return Err(state.set_error(err));
+        }
+        if let Err(err) = options.apply_route(state.config(), state.route(), &mut builder, &host, port) {
+            return Err(state.set_error(err));
+        }
 
+        // rejected credentials are recorded too, see Kind::Denied
         mysql::Conn::new(builder)
-            // TODO: setting an error here means we can't bruteforce mysql anymore
-            .map_err(|err| state.set_error(err.into()))
-            .map(|sock| state.mysql_register(sock))
+            .map(|sock| LuaString(state.mysql_register(sock)))
+            .map_err(|err| state.set_error(options.connect_error(err)))
     }))
 }
 
 pub fn mysql_query(lua: &mut hlua::Lua, state: State) {
-    lua.set("mysql_query", hlua::function3(move |session: String, query: String, params: HashMap<AnyHashableLuaValue, AnyLuaValue>| -> Result<Vec<AnyLuaValue>> {
-        let params = LuaMap::from(params);
+    lua.set("mysql_query", hlua::function3(move |session: String, query: String, params: AnyLuaValue| -> Result<Vec<AnyLuaValue>> {
+        let params = match db::mysql::lua_to_params(params) {
+            Ok(params) => params,
+            Err(err) => return Err(state.set_error(err)),
+        };
 
-        let sock = state.mysql_session(&session);
+        let sock = state.mysql_session(&session)?;
         let mut sock = sock.lock().unwrap();
         let rows = sock.prep_exec(query, params)?; // TODO: handle error
 
@@ -449,6 +620,67 @@ pub fn print(lua: &mut hlua::Lua, _: State) {
     }))
 }
 
+fn format_msg(val: &AnyLuaValue) -> String {
+    match *val {
+        AnyLuaValue::LuaString(ref x) => x.to_string(),
+        ref x => {
+            let mut out = String::new();
+            format_lua(&mut out, x);
+            out
+        },
+    }
+}
+
+pub fn print_info(lua: &mut hlua::Lua, state: State) {
+    lua.set("print_info", hlua::function1(move |val: AnyLuaValue| {
+        state.print(Level::Info, format_msg(&val));
+    }))
+}
+
+pub fn print_warn(lua: &mut hlua::Lua, state: State) {
+    lua.set("print_warn", hlua::function1(move |val: AnyLuaValue| {
+        state.print(Level::Warn, format_msg(&val));
+    }))
+}
+
+pub fn proc_kill(lua: &mut hlua::Lua, state: State) {
+    lua.set("proc_kill", hlua::function1(move |process: String| -> Result<()> {
+        state.proc_kill(&process)
+            .map_err(|err| state.set_error(err))
+    }))
+}
+
+pub fn proc_recv_until(lua: &mut hlua::Lua, state: State) {
+    lua.set("proc_recv_until", hlua::function3(move |process: String, pattern: String, timeout: u32| -> Result<AnyLuaValue> {
+        let process = state.get_proc(&process)?;
+        let mut process = process.lock().unwrap();
+
+        process.recv_until(&pattern, Duration::from_millis(u64::from(timeout)))
+            .map_err(|err| state.set_error(err))
+            .map(|bytes| lua_bytes(&bytes))
+    }))
+}
+
+pub fn proc_send(lua: &mut hlua::Lua, state: State) {
+    lua.set("proc_send", hlua::function2(move |process: String, bytes: AnyLuaValue| -> Result<()> {
+        let process = state.get_proc(&process)?;
+        let mut process = process.lock().unwrap();
+
+        let bytes = byte_array(bytes)
+            .map_err(|err| state.set_error(err))?;
+        process.send(&bytes)
+            .map_err(|err| state.set_error(err))
+    }))
+}
+
+pub fn proc_spawn(lua: &mut hlua::Lua, state: State) {
+    lua.set("proc_spawn", hlua::function2(move |prog: String, args: Vec<AnyLuaValue>| -> Result<String> {
+        let args = exec_args(args);
+        state.proc_spawn(&prog, &args)
+            .map_err(|err| state.set_error(err))
+    }))
+}
+
 pub fn rand(lua: &mut hlua::Lua, _: State) {
     lua.set("rand", hlua::function2(move |min: u32, max: u32| -> u32 {
         let mut rng = rand::thread_rng();
@@ -465,6 +697,18 @@ pub fn randombytes(lua: &mut hlua::Lua, _: State) {
     }))
 }
 
+pub fn set_retry(lua: &mut hlua::Lua, state: State) {
+    lua.set("set_retry", hlua::function1(move |retry: bool| {
+        state.set_retry(retry)
+    }))
+}
+
+pub fn set_target(lua: &mut hlua::Lua, state: State) {
+    lua.set("set_target", hlua::function1(move |target: String| {
+        state.set_target(target)
+    }))
+}
+
 pub fn sha1(lua: &mut hlua::Lua, state: State) {
     lua.set("sha1", hlua::function1(move |bytes: AnyLuaValue| -> Result<AnyLuaValue> {
         byte_array(bytes)
@@ -505,10 +749,28 @@ pub fn sha3_512(lua: &mut hlua::Lua, state: State) {
     }))
 }
 
-pub fn sleep(lua: &mut hlua::Lua, _: State) {
-    lua.set("sleep", hlua::function1(move |n: i32| {
-        thread::sleep(Duration::from_secs(n as u64));
-        0
+pub fn sleep(lua: &mut hlua::Lua, state: State) {
+    lua.set("sleep", hlua::function1(move |n: i32| -> Result<i32> {
+        let duration = Duration::from_secs(n as u64);
+
+        // don't sleep past the deadline of the attempt
+        match state.remaining().map_err(|err| state.set_error(err))? {
+            Some(remaining) if remaining < duration => {
+                thread::sleep(remaining);
+                let err = io::Error::new(io::ErrorKind::TimedOut, "attempt timed out");
+                Err(state.set_error(err.into()))
+            },
+            _ => {
+                thread::sleep(duration);
+                Ok(0)
+            },
+        }
+    }))
+}
+
+pub fn sock_close(lua: &mut hlua::Lua, state: State) {
+    lua.set("sock_close", hlua::function1(move |sock: String| {
+        state.sock_close(&sock);
     }))
 }
 
@@ -521,7 +783,7 @@ pub fn sock_connect(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_send(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_send", hlua::function2(move |sock: String, bytes: AnyLuaValue| -> Result<()> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let bytes = byte_array(bytes)
@@ -536,7 +798,7 @@ pub fn sock_send(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_recv(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_recv", hlua::function1(move |sock: String| -> Result<AnyLuaValue> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let bytes = sock.recv()
@@ -548,7 +810,7 @@ pub fn sock_recv(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_sendline(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_sendline", hlua::function2(move |sock: String, line: String| -> Result<()> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         sock.sendline(&line)
@@ -560,7 +822,7 @@ pub fn sock_sendline(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_recvline(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_recvline", hlua::function1(move |sock: String| -> Result<String> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let line = sock.recvline()
@@ -572,7 +834,7 @@ pub fn sock_recvline(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_recvall(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_recvall", hlua::function1(move |sock: String| -> Result<AnyLuaValue> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let bytes = sock.recvall()
@@ -584,7 +846,7 @@ pub fn sock_recvall(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_recvline_contains(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_recvline_contains", hlua::function2(move |sock: String, needle: String| -> Result<String> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let line = sock.recvline_contains(&needle)
@@ -596,7 +858,7 @@ pub fn sock_recvline_contains(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_recvline_regex(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_recvline_regex", hlua::function2(move |sock: String, regex: String| -> Result<String> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let line = sock.recvline_regex(&regex)
@@ -608,7 +870,7 @@ pub fn sock_recvline_regex(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_recvn(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_recvn", hlua::function2(move |sock: String, n: u32| -> Result<AnyLuaValue> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let bytes = sock.recvn(n)
@@ -620,7 +882,7 @@ pub fn sock_recvn(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_recvuntil(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_recvuntil", hlua::function2(move |sock: String, delim: AnyLuaValue| -> Result<AnyLuaValue> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let delim = byte_array(delim)
@@ -635,7 +897,7 @@ pub fn sock_recvuntil(lua: &mut hlua::Lua, state: State) {
 
 pub fn sock_sendafter(lua: &mut hlua::Lua, state: State) {
     lua.set("sock_sendafter", hlua::function3(move |sock: String, delim: AnyLuaValue, bytes: AnyLuaValue| -> Result<()> {
-        let sock = state.get_sock(&sock);
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         let delim = byte_array(delim)
@@ -652,10 +914,17 @@ pub fn sock_sendafter(lua: &mut hlua::Lua, state: State) {
 }
 
 pub fn sock_newline(lua: &mut hlua::Lua, state: State) {
-    lua.set("sock_newline", hlua::function2(move |sock: String, newline: String| -> () {
-        let sock = state.get_sock(&sock);
+    lua.set("sock_newline", hlua::function2(move |sock: String, newline: String| -> Result<()> {
+        let sock = state.get_sock(&sock)?;
         let mut sock = sock.lock().unwrap();
 
         sock.newline(newline);
+        Ok(())
+    }))
+}
+
+pub fn throttle(lua: &mut hlua::Lua, state: State) {
+    lua.set("throttle", hlua::function1(move |seconds: i32| {
+        state.throttle(Duration::from_secs(seconds.max(0) as u64))
     }))
 }
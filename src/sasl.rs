@@ -0,0 +1,235 @@
+use errors::{Result, ResultExt};
+
+use md5::{Md5, Digest};
+use std::str;
+use std::collections::HashMap;
+
+
+#[derive(Debug, PartialEq)]
+pub enum Mechanism {
+    Plain,
+    DigestMd5,
+}
+
+impl Mechanism {
+    pub fn parse(mech: &str) -> Result<Mechanism> {
+        match mech.to_uppercase().as_str() {
+            "PLAIN" => Ok(Mechanism::Plain),
+            "DIGEST-MD5" => Ok(Mechanism::DigestMd5),
+            _ => bail!("unsupported sasl mechanism: {:?}", mech),
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::DigestMd5 => "DIGEST-MD5",
+        }
+    }
+}
+
+pub fn plain(authzid: &str, user: &str, password: &str) -> Vec<u8> {
+    format!("{}\x00{}\x00{}", authzid, user, password).into_bytes()
+}
+
+/// Parse a DIGEST-MD5 challenge as described in rfc2831 section 2.1.1
+pub fn parse_challenge(challenge: &[u8]) -> Result<HashMap<String, String>> {
+    let challenge = str::from_utf8(challenge)
+        .chain_err(|| "failed to decode utf8")?;
+
+    let mut map = HashMap::new();
+    let mut chars = challenge.chars().peekable();
+
+    loop {
+        let mut key = String::new();
+        for c in &mut chars {
+            match c {
+                '=' => break,
+                ',' if key.is_empty() => (),
+                c if c.is_whitespace() => (),
+                c => key.push(c),
+            }
+        }
+
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('\\') => if let Some(c) = chars.next() {
+                        value.push(c);
+                    },
+                    Some('"') => break,
+                    Some(c) => value.push(c),
+                    None => bail!("unterminated quoted string in challenge"),
+                }
+            }
+            // skip to the next delimiter
+            for c in &mut chars {
+                if c == ',' {
+                    break;
+                }
+            }
+        } else {
+            for c in &mut chars {
+                match c {
+                    ',' => break,
+                    c => value.push(c),
+                }
+            }
+            value = value.trim().to_string();
+        }
+
+        // if a directive is sent multiple times we keep the first one
+        map.entry(key.to_lowercase()).or_insert(value);
+    }
+
+    Ok(map)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for b in bytes {
+        out += &format!("{:02x}", b);
+    }
+    out
+}
+
+fn quote(x: &str) -> String {
+    format!("\"{}\"", x.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[derive(Debug)]
+pub struct DigestMd5<'a> {
+    pub user: &'a str,
+    pub password: &'a str,
+    pub realm: Option<&'a str>,
+    pub digest_uri: String,
+    pub cnonce: String,
+}
+
+impl<'a> DigestMd5<'a> {
+    fn a1(&self, realm: &str, nonce: &str) -> Vec<u8> {
+        let secret = Md5::digest(format!("{}:{}:{}", self.user, realm, self.password).as_bytes());
+
+        let mut a1 = secret.to_vec();
+        a1.extend(format!(":{}:{}", nonce, self.cnonce).as_bytes());
+        a1
+    }
+
+    fn kd(&self, realm: &str, nonce: &str, a2: &str) -> String {
+        let ha1 = hex(&Md5::digest(&self.a1(realm, nonce)));
+        let ha2 = hex(&Md5::digest(a2.as_bytes()));
+
+        let kd = format!("{}:{}:00000001:{}:auth:{}", ha1, nonce, self.cnonce, ha2);
+        hex(&Md5::digest(kd.as_bytes()))
+    }
+
+    /// Calculate the digest-response for a server challenge
+    pub fn response(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let challenge = parse_challenge(challenge)?;
+
+        let nonce = challenge.get("nonce")
+            .chain_err(|| "challenge is missing the nonce")?;
+
+        if let Some(qop) = challenge.get("qop") {
+            if !qop.split(',').any(|x| x.trim() == "auth") {
+                bail!("server doesn't offer qop=auth: {:?}", qop);
+            }
+        }
+
+        let realm = self.realm
+            .or_else(|| challenge.get("realm").map(|x| x.as_str()))
+            .unwrap_or("");
+
+        let a2 = format!("AUTHENTICATE:{}", self.digest_uri);
+        let response = self.kd(realm, nonce, &a2);
+
+        let mut out = Vec::new();
+        if challenge.get("charset").map(|x| x.as_str()) == Some("utf-8") {
+            out.push("charset=utf-8".to_string());
+        }
+        out.push(format!("username={}", quote(self.user)));
+        out.push(format!("realm={}", quote(realm)));
+        out.push(format!("nonce={}", quote(nonce)));
+        out.push("nc=00000001".to_string());
+        out.push(format!("cnonce={}", quote(&self.cnonce)));
+        out.push(format!("digest-uri={}", quote(&self.digest_uri)));
+        out.push(format!("response={}", response));
+        out.push("qop=auth".to_string());
+
+        Ok(out.join(",").into_bytes())
+    }
+
+    /// Calculate the rspauth value the server is expected to send back
+    pub fn rspauth(&self, challenge: &[u8]) -> Result<String> {
+        let challenge = parse_challenge(challenge)?;
+
+        let nonce = challenge.get("nonce")
+            .chain_err(|| "challenge is missing the nonce")?;
+        let realm = self.realm
+            .or_else(|| challenge.get("realm").map(|x| x.as_str()))
+            .unwrap_or("");
+
+        let a2 = format!(":{}", self.digest_uri);
+        Ok(self.kd(realm, nonce, &a2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vector from rfc2831 section 4
+    const CHALLENGE: &[u8] = b"realm=\"elwood.innosoft.com\",nonce=\"OA6MG9tEQGm2hh\",qop=\"auth\",algorithm=md5-sess,charset=utf-8";
+
+    fn rfc2831() -> DigestMd5<'static> {
+        DigestMd5 {
+            user: "chris",
+            password: "secret",
+            realm: None,
+            digest_uri: "imap/elwood.innosoft.com".into(),
+            cnonce: "OA6MHXh6VqTrRk".into(),
+        }
+    }
+
+    #[test]
+    fn verify_parse_challenge() {
+        let challenge = parse_challenge(CHALLENGE).unwrap();
+        assert_eq!(challenge.get("realm").unwrap(), "elwood.innosoft.com");
+        assert_eq!(challenge.get("nonce").unwrap(), "OA6MG9tEQGm2hh");
+        assert_eq!(challenge.get("qop").unwrap(), "auth");
+        assert_eq!(challenge.get("algorithm").unwrap(), "md5-sess");
+        assert_eq!(challenge.get("charset").unwrap(), "utf-8");
+    }
+
+    #[test]
+    fn verify_parse_challenge_escaped() {
+        let challenge = parse_challenge(br#"realm="a\"b,c", nonce="x""#).unwrap();
+        assert_eq!(challenge.get("realm").unwrap(), "a\"b,c");
+        assert_eq!(challenge.get("nonce").unwrap(), "x");
+    }
+
+    #[test]
+    fn verify_digest_md5_response() {
+        let response = rfc2831().response(CHALLENGE).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(response, "charset=utf-8,username=\"chris\",realm=\"elwood.innosoft.com\",nonce=\"OA6MG9tEQGm2hh\",nc=00000001,cnonce=\"OA6MHXh6VqTrRk\",digest-uri=\"imap/elwood.innosoft.com\",response=d388dad90d4bbd760a152321f2143af7,qop=auth");
+    }
+
+    #[test]
+    fn verify_digest_md5_rspauth() {
+        let rspauth = rfc2831().rspauth(CHALLENGE).unwrap();
+        assert_eq!(rspauth, "ea40f60335c427b5527b84dbabcdfffd");
+    }
+
+    #[test]
+    fn verify_plain() {
+        assert_eq!(plain("", "foo", "bar"), b"\x00foo\x00bar");
+    }
+}
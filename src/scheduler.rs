@@ -1,15 +1,38 @@
-use std::str;
-use ctx::Script;
+use std::str::{self, FromStr};
+use colored::Colorize;
+use cluster::Dispatcher;
+use ctx::{Outcome, Script, Status, Worker};
+use hashes::NtHash;
+use hlua::AnyLuaValue;
 use threadpool::ThreadPool;
 use keyboard;
-use errors::Result;
-use std::sync::{mpsc, Arc, Mutex, Condvar};
+use ratelimit::RateLimit;
+use utils;
+use status;
+use errors::{Result, Error, ErrorKind};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{mpsc, Arc, Mutex, RwLock, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::cell::RefCell;
+use std::io;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
+// the script gets a chance to notice the timeout itself before it's abandoned
+const WATCHDOG_GRACE: Duration = Duration::from_secs(1);
+// stop reading new attempts if this many wait for a per host or per script slot
+const MAX_WAITING: usize = 10_000;
+
+/// The credentials of an attempt, the strings are shared with the lists and
+/// all other attempts that use them
 #[derive(Debug)]
 pub enum Creds {
     Tuple((Arc<String>, Arc<String>)),
     Bytes(Arc<Vec<u8>>),
     Enum(Arc<String>),
+    // the NT hash is attempted as the password, see --input-format
+    Hash(Arc<NtHash>),
 }
 
 impl Creds {
@@ -26,6 +49,7 @@ impl Creds {
                 str::from_utf8(&bytes[..idx]).unwrap()
             },
             Creds::Enum(ref user) => user.as_str(),
+            Creds::Hash(ref hash) => hash.user.as_str(),
         }
     }
 
@@ -41,6 +65,7 @@ impl Creds {
             },
             // TODO: empty string is technically not correct
             Creds::Enum(_) => "",
+            Creds::Hash(ref hash) => hash.nt.as_str(),
         }
     }
 }
@@ -50,33 +75,89 @@ pub struct Attempt {
     pub creds: Creds,
     pub script: Arc<Script>,
     pub ttl: u8,
+    // position in the list of all attempts, used for checkpoints
+    pub idx: usize,
+    // how long the last run took
+    pub duration: Duration,
+    // the target reported by the script
+    pub target: Option<String>,
+    // a retry isn't started before this, see --backoff
+    pub not_before: Option<Instant>,
+    // users the script reported as locked out
+    pub locked: Vec<String>,
+    // details the script returned with the result
+    pub status: Option<Status>,
+    pub note: Option<String>,
+    // the target asked to slow down, see Scheduler::throttle
+    pub throttle: Option<Duration>,
 }
 
 impl Attempt {
     #[inline]
-    pub fn new(user: &Arc<String>, password: &Arc<String>, script: &Arc<Script>) -> Attempt {
+    pub fn new(user: &Arc<String>, password: &Arc<String>, script: &Arc<Script>, idx: usize) -> Attempt {
         Attempt {
             creds: Creds::Tuple((user.clone(), password.clone())),
             script: script.clone(),
-            ttl: 5,
+            ttl: script.retries(),
+            idx,
+            duration: Duration::from_secs(0),
+            target: None,
+            not_before: None,
+            locked: Vec::new(),
+            status: None,
+            note: None,
+            throttle: None,
         }
     }
 
     #[inline]
-    pub fn bytes(bytes: &Arc<Vec<u8>>, script: &Arc<Script>) -> Attempt {
+    pub fn bytes(bytes: &Arc<Vec<u8>>, script: &Arc<Script>, idx: usize) -> Attempt {
         Attempt {
             creds: Creds::Bytes(bytes.clone()),
             script: script.clone(),
-            ttl: 5,
+            ttl: script.retries(),
+            idx,
+            duration: Duration::from_secs(0),
+            target: None,
+            not_before: None,
+            locked: Vec::new(),
+            status: None,
+            note: None,
+            throttle: None,
         }
     }
 
     #[inline]
-    pub fn enumerate(user: &Arc<String>, script: &Arc<Script>) -> Attempt {
+    pub fn hash(hash: &Arc<NtHash>, script: &Arc<Script>, idx: usize) -> Attempt {
+        Attempt {
+            creds: Creds::Hash(hash.clone()),
+            script: script.clone(),
+            ttl: script.retries(),
+            idx,
+            duration: Duration::from_secs(0),
+            target: None,
+            not_before: None,
+            locked: Vec::new(),
+            status: None,
+            note: None,
+            throttle: None,
+        }
+    }
+
+    #[inline]
+    pub fn enumerate(user: &Arc<String>, script: &Arc<Script>, idx: usize) -> Attempt {
         Attempt {
             creds: Creds::Enum(user.clone()),
             script: script.clone(),
-            ttl: 5,
+            ttl: script.retries(),
+            idx,
+            duration: Duration::from_secs(0),
+            target: None,
+            not_before: None,
+            locked: Vec::new(),
+            status: None,
+            note: None,
+            throttle: None,
         }
     }
 
@@ -85,25 +166,365 @@ impl Attempt {
         self.creds.user()
     }
 
+    #[inline]
+    pub fn retries(&self) -> u8 {
+        self.script.retries() - self.ttl
+    }
+
     #[inline]
     pub fn password(&self) -> &str {
         self.creds.password()
     }
 
+    /// The arguments of the script
+    fn args(&self) -> (AnyLuaValue, AnyLuaValue, Option<Arc<NtHash>>) {
+        let user = AnyLuaValue::LuaString(self.user().to_string());
+        let password = match self.creds {
+            Creds::Enum(_) => AnyLuaValue::LuaNil,
+            _ => AnyLuaValue::LuaString(self.password().to_string()),
+        };
+        let hash = match self.creds {
+            Creds::Hash(ref hash) => Some(hash.clone()),
+            _ => None,
+        };
+        (user, password, hash)
+    }
+
+    /// Run the attempt again on this thread, see --verify-found
+    pub fn rerun(&self, worker: Option<&Worker>) -> Result<bool> {
+        let (user, password, hash) = self.args();
+        self.script.run_with(user, password, hash, None, worker)?
+            .check()
+    }
+
     #[inline]
-    pub fn run(self, tx: &mpsc::Sender<Msg>) {
-        let result = match self.creds {
-            Creds::Enum(_) => self.script.run_enum(self.user()),
-            _ => self.script.run_creds(self.user(), self.password()),
+    fn run(mut self, tx: &mpsc::Sender<Msg>, abandoned: &AtomicUsize, workers: &Workers) {
+        let (user, password, hash) = self.args();
+
+        let start = Instant::now();
+        let result = match workers.get(&self.script, tx) {
+            Ok(worker) => match self.script.timeout() {
+                Some(timeout) => self.watch(user, password, hash, tx, timeout, abandoned, worker),
+                None => self.script.run_with(user, password, hash, Some(tx.clone()), worker.as_ref()),
+            },
+            Err(err) => Err(err),
+        };
+        self.duration = start.elapsed();
+        self.throttle = match result {
+            Ok(ref outcome) => outcome.throttle,
+            Err(ref err) => match *err.kind() {
+                ErrorKind::Throttled(delay) => Some(delay),
+                _ => None,
+            },
         };
+
+        let result = result.and_then(|outcome| {
+            let valid = outcome.check();
+            self.target = outcome.target;
+            self.locked = outcome.locked;
+            self.status = outcome.status;
+            self.note = outcome.note;
+            valid
+        });
         tx.send(Msg::Attempt(Box::new(self), result)).expect("failed to send result");
     }
+
+    /// Run the script on a separate thread so we can give up on it if it's
+    /// stuck somewhere the deadline isn't checked, like a blocking library call.
+    /// An abandoned thread keeps running until the call returns
+    #[allow(clippy::too_many_arguments)]
+    fn watch(&self, user: AnyLuaValue, password: AnyLuaValue, hash: Option<Arc<NtHash>>, tx: &mpsc::Sender<Msg>, timeout: Duration, abandoned: &AtomicUsize, worker: Option<Worker>) -> Result<Outcome> {
+        let (done_tx, done_rx) = mpsc::channel();
+        let script = self.script.clone();
+        let tx2 = tx.clone();
+        thread::spawn(move || {
+            let result = script.run_with(user, password, hash, Some(tx2), worker.as_ref());
+            // the receiver is gone if the attempt was abandoned
+            let _ = done_tx.send(result);
+        });
+
+        match done_rx.recv_timeout(timeout + WATCHDOG_GRACE) {
+            Ok(result) => result,
+            Err(_) => {
+                abandoned.fetch_add(1, Ordering::SeqCst);
+                let msg = format!("{} didn't stop after the timeout, abandoning {:?}", self.script.label(), self.user());
+                let _ = tx.send(Msg::Print(Level::Warn, msg));
+                Err(io::Error::new(io::ErrorKind::TimedOut, "attempt timed out").into())
+            },
+        }
+    }
+}
+
+/// How long a failed attempt waits before it's retried, see --backoff
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub base: Duration,
+    pub cap: Duration,
+}
+
+impl Backoff {
+    /// The delay is doubled with every retry until it reaches the cap. The
+    /// jitter keeps attempts that failed at the same time from being retried
+    /// at the same time
+    pub fn delay(&self, retries: u8) -> Duration {
+        let exp = u32::from(retries.saturating_sub(1));
+        let delay = 2u32.checked_pow(exp)
+            .and_then(|factor| self.base.checked_mul(factor))
+            .map_or(self.cap, |delay| cmp::min(delay, self.cap));
+        delay.mul_f64(0.5 + utils::rand_f64() / 2.0)
+    }
+}
+
+/// Attempts the operator doesn't want to run anymore, see the k key, and
+/// users that are locked out
+#[derive(Debug, Default)]
+struct Dismissed {
+    scripts: HashSet<String>,
+    // users per script
+    users: HashMap<String, HashSet<String>>,
+    locked: HashMap<String, HashSet<String>>,
+}
+
+impl Dismissed {
+    fn contains(&self, script: &str, user: &str) -> bool {
+        self.scripts.contains(script) ||
+            self.users.get(script).is_some_and(|users| users.contains(user))
+    }
+
+    fn is_locked(&self, script: &str, user: &str) -> bool {
+        self.locked.get(script).is_some_and(|users| users.contains(user))
+    }
+}
+
+// the script is kept for worker_teardown()
+type ActiveWorkers = HashMap<(ThreadId, String), (Arc<Script>, Worker)>;
+
+/// The workers of scripts with worker_setup(), per thread and script
+#[derive(Debug, Clone, Default)]
+struct Workers {
+    active: Arc<Mutex<ActiveWorkers>>,
+}
+
+thread_local! {
+    // tears down the workers of a thread when it exits, eg. if the number of
+    // threads was decreased
+    static TEARDOWN: RefCell<Option<Teardown>> = const { RefCell::new(None) };
+}
+
+struct Teardown {
+    workers: Workers,
+    tx: mpsc::Sender<Msg>,
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        for err in self.workers.teardown(Some(thread::current().id())) {
+            let _ = self.tx.send(Msg::Print(Level::Warn, err));
+        }
+    }
+}
+
+impl Workers {
+    /// The worker of the script on the current thread, it's set up by the
+    /// first attempt. Scripts without worker hooks don't have one
+    fn get(&self, script: &Arc<Script>, tx: &mpsc::Sender<Msg>) -> Result<Option<Worker>> {
+        if !script.has_worker_hooks() {
+            return Ok(None);
+        }
+        let key = (thread::current().id(), script.label().to_string());
+        if let Some((_, worker)) = self.active.lock().unwrap().get(&key) {
+            return Ok(Some(worker.clone()));
+        }
+
+        let worker = script.worker_setup(Some(tx.clone()))?;
+        self.active.lock().unwrap().insert(key, (script.clone(), worker.clone()));
+        TEARDOWN.with(|teardown| {
+            teardown.borrow_mut().get_or_insert_with(|| Teardown {
+                workers: self.clone(),
+                tx: tx.clone(),
+            });
+        });
+        Ok(Some(worker))
+    }
+
+    /// Tear down the workers of a thread, or of all threads. Returns the
+    /// errors of worker_teardown()
+    fn teardown(&self, thread: Option<ThreadId>) -> Vec<String> {
+        let workers = {
+            let mut active = self.active.lock().unwrap();
+            let keys = active.keys()
+                .filter(|(id, _)| thread.is_none_or(|thread| *id == thread))
+                .cloned()
+                .collect::<Vec<_>>();
+            keys.iter()
+                .filter_map(|key| active.remove(key))
+                .collect::<Vec<_>>()
+        };
+
+        workers.into_iter()
+            .filter_map(|(script, worker)| {
+                script.worker_teardown(worker).err()
+                    .map(|err| format!("worker_teardown() of {} failed: {}", script.label(), err))
+            })
+            .collect()
+    }
+}
+
+/// Something that limits how many attempts run at the same time, see
+/// --per-host and max_concurrency. Throttles apply to the same slots
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Slot {
+    Host(String),
+    Script(String),
+}
+
+/// The slots a throttle applies to, the script and its target if it has one
+fn throttle_slots(attempt: &Attempt) -> Vec<Slot> {
+    let mut slots = vec![Slot::Script(attempt.script.label().to_string())];
+    if let Some(host) = attempt.script.target() {
+        slots.push(Slot::Host(host.to_string()));
+    }
+    slots
+}
+
+/// The order of a dictionary attack
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Order {
+    // try all passwords for a user before the next user
+    UserFirst,
+    // try a password for all users before the next password, like spraying.
+    // All users are covered evenly if we stop early
+    #[default]
+    PasswordFirst,
+}
+
+impl FromStr for Order {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Order> {
+        match s {
+            "user-first" => Ok(Order::UserFirst),
+            "password-first" => Ok(Order::PasswordFirst),
+            _ => bail!("unknown order {:?}, expected user-first or password-first", s),
+        }
+    }
+}
+
+impl Order {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Order::UserFirst => "user-first",
+            Order::PasswordFirst => "password-first",
+        }
+    }
+
+    /// Split the index of a combination into the index of the user and password
+    #[inline]
+    pub fn split(self, idx: usize, users: usize, passwords: usize) -> (usize, usize) {
+        match self {
+            Order::UserFirst => (idx / passwords, idx % passwords),
+            Order::PasswordFirst => (idx % users, idx / users),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Info,
+    Warn,
+}
+
+impl Level {
+    pub fn format(self, msg: &str) -> String {
+        match self {
+            Level::Info => format!("{} {}", "[*]".bold(), msg),
+            Level::Warn => format!("{} {}: {}", "[!]".bold(), "warning".yellow(), msg),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Msg {
     Attempt(Box<Attempt>, Result<bool>),
+    // the attempt wasn't started because the user was already found
+    Skip(Box<Attempt>),
+    // the attempt wasn't started because it's in a previous report
+    Known(Box<Attempt>),
+    // the attempt wasn't started because the operator skipped it
+    Dismiss(Box<Attempt>),
+    // the attempt wasn't started because the user is locked out
+    Locked(Box<Attempt>),
+    // the attempt wasn't started because we are stopping
+    Cancel(Box<Attempt>),
     Key(keyboard::Key),
+    Print(Level, String),
+    // a log record, see -v
+    Log(String),
+    // ctrl+c
+    Interrupt,
+    // SIGTERM
+    Terminate,
+    // SIGUSR1, print the status
+    Status,
+    // SIGUSR2, pause or resume
+    TogglePause,
+    // write the status to the --status-json stream
+    StatusJson,
+    // send the progress to --syslog
+    SyslogStatus,
+    // a client of --control-socket waits for the status, see control
+    Query(mpsc::Sender<status::Status>),
+}
+
+/// Passwords of a previous report per script and user, see --skip-report
+type Known = HashMap<String, HashMap<String, HashSet<String>>>;
+
+/// Returns the attempt if it should be started, otherwise it's sent back with
+/// the reason it was skipped
+fn admit(attempt: Attempt, tx: &mpsc::Sender<Msg>, draining: &AtomicBool, found: &RwLock<HashMap<String, HashSet<String>>>,
+         known: &RwLock<Known>, dismissed: &RwLock<Dismissed>) -> Option<Attempt> {
+    if draining.load(Ordering::SeqCst) {
+        tx.send(Msg::Cancel(Box::new(attempt))).expect("failed to send result");
+        return None;
+    }
+
+    // the attempt might have been queued before the user was found
+    let skip = {
+        let found = found.read().unwrap();
+        found.get(attempt.script.label())
+            .is_some_and(|users| users.contains(attempt.user()))
+    };
+    if skip {
+        tx.send(Msg::Skip(Box::new(attempt))).expect("failed to send result");
+        return None;
+    }
+
+    let known = {
+        let known = known.read().unwrap();
+        known.get(attempt.script.label())
+            .and_then(|users| users.get(attempt.user()))
+            .is_some_and(|passwords| passwords.contains(attempt.password()))
+    };
+    if known {
+        tx.send(Msg::Known(Box::new(attempt))).expect("failed to send result");
+        return None;
+    }
+
+    let (dismiss, locked) = {
+        let dismissed = dismissed.read().unwrap();
+        (dismissed.contains(attempt.script.label(), attempt.user()),
+         dismissed.is_locked(attempt.script.label(), attempt.user()))
+    };
+    if dismiss {
+        tx.send(Msg::Dismiss(Box::new(attempt))).expect("failed to send result");
+        return None;
+    }
+    if locked {
+        tx.send(Msg::Locked(Box::new(attempt))).expect("failed to send result");
+        return None;
+    }
+
+    Some(attempt)
 }
 
 pub struct Scheduler {
@@ -113,11 +534,47 @@ pub struct Scheduler {
     num_threads: usize,
     inflight: usize,
     pause_trigger: Arc<(Mutex<bool>, Condvar)>,
+    ratelimit: RateLimit,
+    per_host: Option<usize>,
+    // attempts that are currently running per host and per script
+    active: HashMap<Slot, usize>,
+    // attempts that are blocked by the limit of this slot
+    waiting: HashMap<Slot, VecDeque<Attempt>>,
+    num_waiting: usize,
+    // attempts are generated as needed so we don't have to keep all of them
+    // in memory, this might be endless if we read from stdin
+    source: Option<Box<dyn Iterator<Item=Attempt>>>,
+    // failed attempts, they are started before new attempts
+    retries: VecDeque<Attempt>,
+    // start retries at random positions instead of immediately
+    shuffle: bool,
+    backoff: Option<Backoff>,
+    // retries that wait for their backoff to pass
+    delayed: Vec<Attempt>,
+    // scripts and hosts that asked to slow down, and the attempts that wait
+    // for them, see throttle
+    throttled: HashMap<Slot, Instant>,
+    parked: Vec<Attempt>,
+    // the sum of all backoff delays
+    backoff_time: Duration,
+    // users that are already found, per script
+    found: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+    known: Arc<RwLock<Known>>,
+    dismissed: Arc<RwLock<Dismissed>>,
+    // don't start any new attempts
+    draining: Arc<AtomicBool>,
+    // attempts that didn't stop after the timeout
+    abandoned: Arc<AtomicUsize>,
+    workers: Workers,
+    // don't start any attempts, see --dry-run
+    held: bool,
+    // hand the attempts to workers on other hosts, see serve
+    remote: Option<Dispatcher>,
 }
 
 impl Scheduler {
     #[inline]
-    pub fn new(workers: usize) -> Scheduler {
+    pub fn new(workers: usize, interval: Option<Duration>, per_host: Option<usize>, shuffle: bool) -> Scheduler {
         let (tx, rx) = mpsc::channel();
         Scheduler {
             pool: ThreadPool::new(workers),
@@ -126,6 +583,27 @@ impl Scheduler {
             num_threads: workers,
             inflight: 0,
             pause_trigger: Arc::new((Mutex::new(true), Condvar::new())),
+            ratelimit: RateLimit::new(interval),
+            per_host,
+            active: HashMap::new(),
+            waiting: HashMap::new(),
+            num_waiting: 0,
+            source: None,
+            retries: VecDeque::new(),
+            shuffle,
+            backoff: None,
+            delayed: Vec::new(),
+            throttled: HashMap::new(),
+            parked: Vec::new(),
+            backoff_time: Duration::from_secs(0),
+            found: Arc::new(RwLock::new(HashMap::new())),
+            known: Arc::new(RwLock::new(HashMap::new())),
+            dismissed: Arc::new(RwLock::new(Dismissed::default())),
+            draining: Arc::new(AtomicBool::new(false)),
+            abandoned: Arc::new(AtomicUsize::new(0)),
+            workers: Workers::default(),
+            held: false,
+            remote: None,
         }
     }
 
@@ -136,6 +614,12 @@ impl Scheduler {
         *paused = true;
     }
 
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        let (lock, _) = &*self.pause_trigger;
+        *lock.lock().unwrap()
+    }
+
     #[inline]
     pub fn resume(&mut self) {
         let &(ref lock, ref cvar) = &*self.pause_trigger;
@@ -144,22 +628,46 @@ impl Scheduler {
         cvar.notify_all();
     }
 
-    #[inline]
-    pub fn incr(&mut self) -> usize {
-        self.num_threads += 1;
+    /// Change the number of workers, there is always at least one. Workers
+    /// that are busy finish their attempt before they are removed
+    pub fn set_threads(&mut self, num: usize) -> usize {
+        self.num_threads = cmp::max(num, 1);
         self.pool.set_num_threads(self.num_threads);
+        // more workers need more queued attempts
+        self.refill();
         self.num_threads
     }
 
+    #[inline]
+    pub fn incr(&mut self) -> usize {
+        let num = self.num_threads + 1;
+        self.set_threads(num)
+    }
+
     #[inline]
     pub fn decr(&mut self) -> usize {
-        if self.num_threads == 1 {
-            return self.num_threads;
-        }
+        let num = self.num_threads - 1;
+        self.set_threads(num)
+    }
 
-        self.num_threads -= 1;
-        self.pool.set_num_threads(self.num_threads);
-        self.num_threads
+    /// The configured attempts per second, if any
+    #[inline]
+    pub fn rate(&self) -> Option<f64> {
+        self.ratelimit.rate()
+    }
+
+    #[inline]
+    pub fn faster(&mut self) -> Option<f64> {
+        let rate = self.ratelimit.rate()?;
+        self.ratelimit.set_rate(rate * 2.0);
+        self.ratelimit.rate()
+    }
+
+    #[inline]
+    pub fn slower(&mut self) -> Option<f64> {
+        let rate = self.ratelimit.rate()?;
+        self.ratelimit.set_rate(rate / 2.0);
+        self.ratelimit.rate()
     }
 
     #[inline]
@@ -172,16 +680,334 @@ impl Scheduler {
         self.pool.max_count()
     }
 
+    /// Attempts that were started or are queued in a worker
+    #[inline]
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
     #[inline]
     pub fn has_work(&self) -> bool {
-        self.inflight > 0
+        self.inflight > 0 || self.source.is_some() || !self.retries.is_empty() || !self.delayed.is_empty() || !self.parked.is_empty()
+    }
+
+    /// Don't start any more attempts for this user with this script
+    pub fn skip_user(&mut self, script: &str, user: &str) {
+        let mut found = self.found.write().unwrap();
+        found.entry(script.to_string())
+            .or_default()
+            .insert(user.to_string());
+    }
+
+    /// Don't start the attempt with these credentials and this script, it's
+    /// returned with Msg::Known. The password of enumerated users is empty
+    pub fn skip_creds(&mut self, script: &str, user: &str, password: &str) {
+        let mut known = self.known.write().unwrap();
+        known.entry(script.to_string())
+            .or_default()
+            .entry(user.to_string())
+            .or_default()
+            .insert(password.to_string());
+    }
+
+    /// Don't start the remaining attempts for this user with this script,
+    /// they are returned with Msg::Dismiss
+    pub fn dismiss_user(&mut self, script: &str, user: &str) {
+        let mut dismissed = self.dismissed.write().unwrap();
+        dismissed.users.entry(script.to_string())
+            .or_default()
+            .insert(user.to_string());
+    }
+
+    /// Don't start the remaining attempts for this script
+    pub fn dismiss_script(&mut self, script: &str) {
+        let mut dismissed = self.dismissed.write().unwrap();
+        dismissed.scripts.insert(script.to_string());
+    }
+
+    /// Don't start the remaining attempts for this user with this script
+    /// because the account is locked, they are returned with Msg::Locked.
+    /// Returns false if the user was already locked
+    pub fn lock_user(&mut self, script: &str, user: &str) -> bool {
+        let mut dismissed = self.dismissed.write().unwrap();
+        dismissed.locked.entry(script.to_string())
+            .or_default()
+            .insert(user.to_string())
+    }
+
+    /// Stop starting new attempts, unlike pause this can't be undone.
+    /// Attempts that are already running finish normally, the others are
+    /// returned with Msg::Cancel
+    pub fn drain(&mut self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.source = None;
+        self.retries.clear();
+        self.delayed.clear();
+        self.parked.clear();
+
+        // attempts that wait for a slot were never started
+        self.waiting.clear();
+        self.inflight -= self.num_waiting;
+        self.num_waiting = 0;
+    }
+
+    #[inline]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// The number of attempts that were abandoned because they didn't stop
+    /// after the timeout
+    #[inline]
+    pub fn abandoned(&self) -> usize {
+        self.abandoned.load(Ordering::SeqCst)
+    }
+
+    /// Run worker_teardown() for the workers of all threads, this is done
+    /// when the attack is over. Returns the errors
+    pub fn teardown_workers(&self) -> Vec<String> {
+        self.workers.teardown(None)
+    }
+
+    /// Wait before a failed attempt is retried
+    #[inline]
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = Some(backoff);
+    }
+
+    /// How long the retries waited for their backoff in total
+    #[inline]
+    pub fn backoff_time(&self) -> Duration {
+        self.backoff_time
+    }
+
+    /// Run a failed attempt again, retries are preferred over new attempts.
+    /// With a backoff the attempt is kept until the delay passed, this
+    /// doesn't block a worker
+    pub fn retry(&mut self, mut attempt: Attempt) {
+        if self.is_draining() {
+            return;
+        }
+
+        if let Some(backoff) = self.backoff {
+            let delay = backoff.delay(attempt.retries());
+            self.backoff_time += delay;
+            attempt.not_before = Some(Instant::now() + delay);
+            self.delayed.push(attempt);
+        } else {
+            self.retries.push_back(attempt);
+        }
+        self.refill();
+    }
+
+    /// Run the attempt again without a backoff, it failed because of its
+    /// proxy or a throttle and not because of the target, see [proxies]
+    pub fn requeue(&mut self, attempt: Attempt) {
+        if self.is_draining() {
+            return;
+        }
+        self.retries.push_back(attempt);
+        self.refill();
+    }
+
+    /// Don't start attempts of the script, or for its target, before
+    /// `until`. Returns false if they were already throttled
+    pub fn throttle(&mut self, attempt: &Attempt, until: Instant) -> bool {
+        let now = Instant::now();
+        let mut new = false;
+        for slot in throttle_slots(attempt) {
+            let entry = self.throttled.entry(slot).or_insert(now);
+            new |= *entry <= now;
+            *entry = cmp::max(*entry, until);
+        }
+        new
+    }
+
+    fn throttled_until(&self, attempt: &Attempt) -> Option<Instant> {
+        let now = Instant::now();
+        throttle_slots(attempt).iter()
+            .filter_map(|slot| self.throttled.get(slot))
+            .filter(|until| **until > now)
+            .max()
+            .cloned()
+    }
+
+    /// Take attempts from the iterator as needed instead of queueing them
+    /// all at once, the iterator might never end
+    pub fn feed(&mut self, source: Box<dyn Iterator<Item=Attempt>>) {
+        self.source = Some(source);
+        self.refill();
+    }
+
+    /// Hand the attempts to the workers of the dispatcher instead of running
+    /// them on this host. The limits of the scheduler still apply, -n is the
+    /// number of attempts that are handed out at the same time
+    pub fn set_remote(&mut self, remote: Dispatcher) {
+        remote.attach(self.pause_trigger.clone(), self.draining.clone());
+        self.remote = Some(remote);
+    }
+
+    /// Keep the attempts that are fed in the source instead of starting them,
+    /// so they can be inspected with take_source
+    #[inline]
+    pub fn hold(&mut self) {
+        self.held = true;
+    }
+
+    #[inline]
+    pub fn take_source(&mut self) -> Option<Box<dyn Iterator<Item=Attempt>>> {
+        self.source.take()
+    }
+
+    fn refill(&mut self) {
+        if self.held {
+            return;
+        }
+
+        if !self.delayed.is_empty() {
+            let now = Instant::now();
+            let (ready, delayed): (Vec<_>, Vec<_>) = self.delayed.drain(..)
+                .partition(|attempt| attempt.not_before.is_none_or(|x| x <= now));
+            self.retries.extend(ready);
+            self.delayed = delayed;
+        }
+        if !self.parked.is_empty() {
+            let now = Instant::now();
+            let (ready, parked): (Vec<_>, Vec<_>) = self.parked.drain(..)
+                .partition(|attempt| attempt.not_before.is_none_or(|x| x <= now));
+            self.retries.extend(ready);
+            self.parked = parked;
+        }
+
+        // keep enough attempts queued so the workers don't run dry, attempts
+        // that wait for a slot don't count so other scripts keep running
+        while self.inflight - self.num_waiting < self.num_threads * 2 && self.num_waiting < MAX_WAITING && self.parked.len() < MAX_WAITING {
+            let retry = if self.shuffle && self.source.is_some() {
+                // pick one of the retries or a new attempt, this spreads the
+                // retries out so lockout counters have time to decay
+                let n = self.retries.len();
+                let pick = utils::rand_below(n + 1);
+                if pick < n { self.retries.remove(pick) } else { None }
+            } else {
+                self.retries.pop_front()
+            };
+
+            if let Some(attempt) = retry {
+                self.run(attempt);
+                continue;
+            }
+
+            let next = match self.source {
+                Some(ref mut source) => source.next(),
+                None => return,
+            };
+
+            match next {
+                Some(attempt) => self.run(attempt),
+                None => self.source = None,
+            }
+        }
     }
 
     #[inline]
-    pub fn run(&mut self, attempt: Attempt) {
+    pub fn run(&mut self, mut attempt: Attempt) {
+        // keep it until the throttle of its script or target is over
+        if let Some(until) = self.throttled_until(&attempt) {
+            attempt.not_before = Some(until);
+            self.parked.push(attempt);
+            return;
+        }
+        self.inflight += 1;
+
+        // don't block a worker, start it after a slot was released
+        match self.blocked(&attempt) {
+            Some(slot) => self.wait(slot, attempt),
+            None => self.start(attempt),
+        }
+    }
+
+    /// The slots of an attempt with their limits, running attempts are
+    /// always counted per script for the status
+    fn slots(&self, attempt: &Attempt) -> Vec<(Slot, Option<usize>)> {
+        let mut slots = vec![(Slot::Script(attempt.script.label().to_string()), attempt.script.max_concurrency())];
+        if let (Some(limit), Some(host)) = (self.per_host, attempt.script.target()) {
+            slots.push((Slot::Host(host.to_string()), Some(limit)));
+        }
+        slots
+    }
+
+    /// The first slot that has no room for this attempt
+    fn blocked(&self, attempt: &Attempt) -> Option<Slot> {
+        self.slots(attempt).into_iter()
+            .find(|(slot, limit)| limit.is_some_and(|limit| self.active.get(slot).copied().unwrap_or(0) >= limit))
+            .map(|(slot, _)| slot)
+    }
+
+    fn wait(&mut self, slot: Slot, attempt: Attempt) {
+        self.num_waiting += 1;
+        self.waiting.entry(slot)
+            .or_default()
+            .push_back(attempt);
+    }
+
+    fn start(&mut self, attempt: Attempt) {
+        for (slot, _) in self.slots(&attempt) {
+            *self.active.entry(slot).or_insert(0) += 1;
+        }
+        self.execute(attempt);
+    }
+
+    /// An attempt finished, start the attempts that waited for its slots
+    fn release(&mut self, attempt: &Attempt) {
+        for (slot, _) in self.slots(attempt) {
+            if let Some(active) = self.active.get_mut(&slot) {
+                *active -= 1;
+            }
+
+            while let Some(next) = self.waiting.get_mut(&slot).and_then(|queue| queue.pop_front()) {
+                self.num_waiting -= 1;
+                match self.blocked(&next) {
+                    // still blocked by the same slot, keep its place in the queue
+                    Some(ref blocked) if *blocked == slot => {
+                        self.num_waiting += 1;
+                        self.waiting.get_mut(&slot).unwrap().push_front(next);
+                        break;
+                    },
+                    // wait for another slot instead
+                    Some(blocked) => self.wait(blocked, next),
+                    None => {
+                        self.start(next);
+                        break;
+                    },
+                }
+            }
+        }
+    }
+
+    /// The number of attempts of this script that were started and didn't
+    /// finish yet, including attempts that are queued behind a pause
+    pub fn running(&self, script: &str) -> usize {
+        self.active.get(&Slot::Script(script.to_string())).copied().unwrap_or(0)
+    }
+
+    fn execute(&mut self, attempt: Attempt) {
+        if let Some(ref remote) = self.remote {
+            // a paused dispatcher doesn't hand out attempts
+            if let Some(attempt) = admit(attempt, &self.tx, &self.draining, &self.found, &self.known, &self.dismissed) {
+                remote.submit(attempt);
+            }
+            return;
+        }
+
         let tx = self.tx.clone();
         let pause_trigger = self.pause_trigger.clone();
-        self.inflight += 1;
+        let ratelimit = self.ratelimit.clone();
+        let found = self.found.clone();
+        let known = self.known.clone();
+        let dismissed = self.dismissed.clone();
+        let draining = self.draining.clone();
+        let abandoned = self.abandoned.clone();
+        let workers = self.workers.clone();
 
         self.pool.execute(move || {
             // verify the pause trigger isn't enabled
@@ -193,13 +1019,524 @@ impl Scheduler {
                     paused = cvar.wait(paused).unwrap();
                 }
             }
-            attempt.run(&tx);
+
+            if let Some(attempt) = admit(attempt, &tx, &draining, &found, &known, &dismissed) {
+                ratelimit.wait();
+                attempt.run(&tx, &abandoned, &workers);
+            }
         });
     }
 
+    /// The time when the next delayed retry or throttled attempt is ready
+    fn next_retry(&self) -> Option<Instant> {
+        self.delayed.iter()
+            .chain(self.parked.iter())
+            .filter_map(|attempt| attempt.not_before)
+            .min()
+    }
+
     #[inline]
     pub fn recv(&mut self) -> Msg {
-        self.inflight -= 1;
-        self.rx.recv().unwrap()
+        self.recv_until(None).expect("recv without a deadline returned nothing")
+    }
+
+    /// Wait for the next message, returns None if nothing arrived before the deadline
+    pub fn recv_until(&mut self, deadline: Option<Instant>) -> Option<Msg> {
+        let msg = loop {
+            // wake up when a delayed retry is ready so it can be started
+            let wakeup = match (self.next_retry(), deadline) {
+                (Some(next), Some(deadline)) => Some(cmp::min(next, deadline)),
+                (next, deadline) => next.or(deadline),
+            };
+
+            match wakeup {
+                Some(wakeup) => {
+                    let timeout = wakeup.saturating_duration_since(Instant::now());
+                    match self.rx.recv_timeout(timeout) {
+                        Ok(msg) => break msg,
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                                return None;
+                            }
+                            self.refill();
+                        },
+                        Err(mpsc::RecvTimeoutError::Disconnected) => unreachable!(),
+                    }
+                },
+                None => break self.rx.recv().unwrap(),
+            }
+        };
+        // only attempts are counted, other messages can arrive at any time
+        if let Msg::Attempt(ref attempt, _) | Msg::Skip(ref attempt) | Msg::Known(ref attempt) | Msg::Dismiss(ref attempt) | Msg::Locked(ref attempt) | Msg::Cancel(ref attempt) = msg {
+            self.inflight -= 1;
+            self.release(attempt);
+            self.refill();
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn verify_order() {
+        let combos = |order: Order| (0..6)
+            .map(|idx| order.split(idx, 2, 3))
+            .collect::<Vec<_>>();
+
+        assert_eq!(combos(Order::UserFirst), vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2)]);
+        assert_eq!(combos(Order::PasswordFirst), vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]);
+        assert_eq!("password-first".parse::<Order>().unwrap(), Order::PasswordFirst);
+        assert_eq!(combos(Order::default()), combos(Order::PasswordFirst));
+    }
+
+    #[test]
+    fn verify_shared_creds() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "shared"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let user = Arc::new("admin".to_string());
+        let passwords = (0..100).map(|i| Arc::new(i.to_string())).collect::<Vec<_>>();
+        let attempts = (0..1000)
+            .map(|idx| Attempt::new(&user, &passwords[idx % passwords.len()], &script, idx))
+            .collect::<Vec<_>>();
+
+        assert_eq!(Arc::strong_count(&user), 1001);
+        assert_eq!(Arc::strong_count(&passwords[0]), 11);
+        match attempts[999].creds {
+            Creds::Tuple((ref a, ref b)) => assert!(Arc::ptr_eq(a, &user) && Arc::ptr_eq(b, &passwords[99])),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn verify_creds_split() {
+        let creds = |line: &str| Creds::Bytes(Arc::new(line.as_bytes().to_vec()));
+
+        let one = creds("admin:pass:phrase");
+        assert_eq!((one.user(), one.password()), ("admin", "pass:phrase"));
+        let many = creds("root:a:b::c:");
+        assert_eq!((many.user(), many.password()), ("root", "a:b::c:"));
+        let empty = creds(":");
+        assert_eq!((empty.user(), empty.password()), ("", ""));
+    }
+
+    #[test]
+    fn verify_per_host() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "per_host"
+        target = "example.com"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(4, None, Some(1), false);
+        let user = Arc::new("foo".to_string());
+        for idx in 0..3 {
+            pool.run(Attempt::enumerate(&user, &script, idx));
+        }
+        let host = Slot::Host("example.com".to_string());
+        assert_eq!(pool.active[&host], 1);
+        assert_eq!(pool.waiting[&host].len(), 2);
+
+        pool.resume();
+        let mut done = 0;
+        while pool.has_work() {
+            if let Msg::Attempt(_, result) = pool.recv() {
+                assert!(result.unwrap());
+                done += 1;
+            }
+        }
+        assert_eq!(done, 3);
+        assert_eq!(pool.active[&host], 0);
+    }
+
+    #[test]
+    fn verify_max_concurrency() {
+        let fragile = Arc::new(Script::load_from(r#"
+        descr = "fragile"
+        max_concurrency = 1
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+        let sturdy = Arc::new(Script::load_from(r#"
+        descr = "sturdy"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(4, None, None, false);
+        let user = Arc::new("foo".to_string());
+        for idx in 0..3 {
+            pool.run(Attempt::enumerate(&user, &fragile, idx * 2));
+            pool.run(Attempt::enumerate(&user, &sturdy, idx * 2 + 1));
+        }
+        assert_eq!(pool.running("fragile"), 1);
+        assert_eq!(pool.running("sturdy"), 3);
+        assert_eq!(pool.waiting[&Slot::Script("fragile".to_string())].len(), 2);
+
+        pool.resume();
+        let mut done = 0;
+        while pool.has_work() {
+            if let Msg::Attempt(..) = pool.recv() {
+                assert!(pool.running("fragile") <= 1);
+                done += 1;
+            }
+        }
+        assert_eq!(done, 6);
+        assert_eq!(pool.running("fragile"), 0);
+    }
+
+    #[test]
+    fn verify_feed() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "feed"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(2, None, None, false);
+        let user = Arc::new("foo".to_string());
+        pool.feed(Box::new((0..100).map(move |idx| Attempt::enumerate(&user, &script, idx))));
+        // only a few attempts are taken from the iterator
+        assert_eq!(pool.inflight, 4);
+
+        pool.resume();
+        let mut done = 0;
+        while pool.has_work() {
+            if let Msg::Attempt(_, result) = pool.recv() {
+                assert!(result.unwrap());
+                assert!(pool.inflight <= 4);
+                done += 1;
+            }
+        }
+        assert_eq!(done, 100);
+    }
+
+    #[test]
+    fn verify_set_threads() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "threads"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(2, None, None, false);
+        let user = Arc::new("foo".to_string());
+        pool.feed(Box::new((0..100).map(move |idx| Attempt::enumerate(&user, &script, idx))));
+        assert_eq!(pool.inflight, 4);
+
+        // the new workers get attempts right away
+        assert_eq!(pool.set_threads(8), 8);
+        assert_eq!(pool.max_count(), 8);
+        assert_eq!(pool.inflight, 16);
+        assert_eq!(pool.set_threads(0), 1);
+        assert_eq!(pool.decr(), 1);
+
+        pool.resume();
+        let mut done = 0;
+        while pool.has_work() {
+            if let Msg::Attempt(..) = pool.recv() {
+                done += 1;
+            }
+        }
+        assert_eq!(done, 100);
+    }
+
+    #[test]
+    fn verify_hold() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "hold"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(2, None, None, false);
+        pool.hold();
+        let user = Arc::new("foo".to_string());
+        pool.feed(Box::new((0..100).map(move |idx| Attempt::enumerate(&user, &script, idx))));
+        assert_eq!(pool.inflight, 0);
+        assert_eq!(pool.take_source().unwrap().count(), 100);
+        assert!(!pool.has_work());
+    }
+
+    #[test]
+    fn verify_skip_user() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "skip"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(1, None, None, false);
+        let (foo, bar) = (Arc::new("foo".to_string()), Arc::new("bar".to_string()));
+        pool.run(Attempt::new(&foo, &foo, &script, 0));
+        pool.run(Attempt::new(&bar, &bar, &script, 1));
+        pool.skip_user("skip", "foo");
+
+        pool.resume();
+        let (mut done, mut skipped) = (Vec::new(), Vec::new());
+        while pool.has_work() {
+            match pool.recv() {
+                Msg::Attempt(attempt, _) => done.push(attempt.idx),
+                Msg::Skip(attempt) => skipped.push(attempt.idx),
+                _ => (),
+            }
+        }
+        assert_eq!(done, vec![1]);
+        assert_eq!(skipped, vec![0]);
+    }
+
+    #[test]
+    fn verify_skip_creds() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "known"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(1, None, None, false);
+        let (foo, bar) = (Arc::new("foo".to_string()), Arc::new("bar".to_string()));
+        pool.run(Attempt::new(&foo, &foo, &script, 0));
+        pool.run(Attempt::new(&foo, &bar, &script, 1));
+        pool.run(Attempt::enumerate(&bar, &script, 2));
+        pool.skip_creds("known", "foo", "foo");
+        pool.skip_creds("known", "bar", "");
+        pool.skip_creds("other", "foo", "bar");
+
+        pool.resume();
+        let (mut done, mut known) = (Vec::new(), Vec::new());
+        while pool.has_work() {
+            match pool.recv() {
+                Msg::Attempt(attempt, _) => done.push(attempt.idx),
+                Msg::Known(attempt) => known.push(attempt.idx),
+                _ => (),
+            }
+        }
+        assert_eq!(done, vec![1]);
+        assert_eq!(known, vec![0, 2]);
+    }
+
+    #[test]
+    fn verify_dismiss() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "dismiss"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(1, None, None, false);
+        let (foo, bar) = (Arc::new("foo".to_string()), Arc::new("bar".to_string()));
+        pool.run(Attempt::new(&foo, &foo, &script, 0));
+        pool.run(Attempt::new(&bar, &bar, &script, 1));
+        pool.run(Attempt::new(&foo, &bar, &script, 2));
+        pool.dismiss_user("dismiss", "foo");
+
+        pool.resume();
+        let (mut done, mut dismissed) = (Vec::new(), Vec::new());
+        while pool.has_work() {
+            match pool.recv() {
+                Msg::Attempt(attempt, _) => done.push(attempt.idx),
+                Msg::Dismiss(attempt) => dismissed.push(attempt.idx),
+                _ => (),
+            }
+        }
+        assert_eq!(done, vec![1]);
+        assert_eq!(dismissed, vec![0, 2]);
+
+        pool.lock_user("dismiss", "bar");
+        pool.run(Attempt::new(&bar, &foo, &script, 3));
+        match pool.recv() {
+            Msg::Locked(attempt) => assert_eq!(attempt.idx, 3),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+
+        pool.dismiss_script("dismiss");
+        pool.run(Attempt::new(&bar, &bar, &script, 4));
+        match pool.recv() {
+            Msg::Dismiss(attempt) => assert_eq!(attempt.idx, 4),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn verify_drain() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "drain"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(1, None, None, false);
+        let user = Arc::new("foo".to_string());
+        pool.feed(Box::new((0..100).map(move |idx| Attempt::enumerate(&user, &script, idx))));
+
+        pool.resume();
+        loop {
+            if let Msg::Attempt(..) = pool.recv() {
+                break;
+            }
+        }
+        pool.drain();
+
+        // only the attempts that were already queued are returned
+        let mut returned = 0;
+        while pool.has_work() {
+            match pool.recv() {
+                Msg::Attempt(..) | Msg::Cancel(_) => returned += 1,
+                _ => (),
+            }
+        }
+        assert_eq!(returned, 2);
+    }
+
+    #[test]
+    fn verify_retry_first() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "retry"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(1, None, None, false);
+        let user = Arc::new("foo".to_string());
+        let source_script = script.clone();
+        pool.feed(Box::new((0..10).map(move |idx| Attempt::enumerate(&user, &source_script, idx))));
+        assert_eq!(pool.inflight, 2);
+
+        let retry = Arc::new("retry".to_string());
+        pool.retry(Attempt::enumerate(&retry, &script, 99));
+        assert_eq!(pool.retries.len(), 1);
+
+        pool.resume();
+        let mut order = Vec::new();
+        while pool.has_work() {
+            if let Msg::Attempt(attempt, _) = pool.recv() {
+                order.push(attempt.idx);
+            }
+        }
+        // the retry is started as soon as a slot is free
+        assert_eq!(&order[..3], &[0, 1, 99]);
+        assert_eq!(order.len(), 11);
+    }
+
+    #[test]
+    fn verify_backoff_delay() {
+        let backoff = Backoff {
+            base: Duration::from_secs(1),
+            cap: Duration::from_secs(10),
+        };
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay >= Duration::from_millis(500) && delay < Duration::from_secs(1));
+            let delay = backoff.delay(3);
+            assert!(delay >= Duration::from_secs(2) && delay < Duration::from_secs(4));
+            let delay = backoff.delay(255);
+            assert!(delay >= Duration::from_secs(5) && delay < Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn verify_backoff() {
+        let script = Arc::new(Script::load_from(r#"
+        descr = "backoff"
+
+        function verify(user, password)
+            return true
+        end
+        "#.as_bytes(), Arc::new(Config::default())).unwrap());
+
+        let mut pool = Scheduler::new(1, None, None, false);
+        pool.set_backoff(Backoff {
+            base: Duration::from_millis(200),
+            cap: Duration::from_secs(1),
+        });
+        pool.resume();
+
+        let start = Instant::now();
+        let user = Arc::new("foo".to_string());
+        let mut attempt = Attempt::enumerate(&user, &script, 0);
+        attempt.ttl -= 1;
+        pool.retry(attempt);
+        // the worker is free, but the retry is held back
+        assert_eq!(pool.inflight, 0);
+        assert!(pool.has_work());
+        assert!(pool.backoff_time() >= Duration::from_millis(100));
+
+        match pool.recv() {
+            Msg::Attempt(attempt, result) => {
+                assert!(result.unwrap());
+                assert!(Instant::now() >= attempt.not_before.unwrap());
+                assert!(start.elapsed() >= Duration::from_millis(100));
+            },
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        assert!(!pool.has_work());
+    }
+
+    #[test]
+    fn verify_throttle() {
+        let script = |descr: &str| Arc::new(Script::load_from(format!(r#"
+        descr = "{}"
+        target = "example.com"
+
+        function verify(user, password)
+            return true
+        end
+        "#, descr).as_bytes(), Arc::new(Config::default())).unwrap());
+        let (throttled, other) = (script("throttled"), script("other"));
+
+        let mut pool = Scheduler::new(1, None, None, false);
+        pool.resume();
+        let start = Instant::now();
+        let user = Arc::new("foo".to_string());
+        assert!(pool.throttle(&Attempt::enumerate(&user, &throttled, 0), start + Duration::from_millis(200)));
+        assert!(!pool.throttle(&Attempt::enumerate(&user, &throttled, 0), start + Duration::from_millis(100)));
+
+        // the target is throttled too
+        pool.requeue(Attempt::enumerate(&user, &other, 0));
+        assert_eq!(pool.inflight, 0);
+        assert_eq!(pool.parked.len(), 1);
+        assert!(pool.has_work());
+
+        match pool.recv() {
+            Msg::Attempt(attempt, result) => {
+                assert!(result.unwrap());
+                assert_eq!(attempt.script.label(), "other");
+                assert!(start.elapsed() >= Duration::from_millis(200));
+            },
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        assert!(!pool.has_work());
     }
 }
@@ -0,0 +1,101 @@
+use rand::SeedableRng;
+use rand::prng::XorShiftRng;
+use std::cmp;
+
+const ROUNDS: usize = 4;
+
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A random permutation of `0..len`. The position of each index is computed
+/// when it's needed, so the order doesn't have to be kept in memory
+#[derive(Debug, Clone)]
+pub struct Shuffle {
+    len: u64,
+    // the permutation works on 2*half bits
+    half: u32,
+    keys: [u64; ROUNDS],
+}
+
+impl Shuffle {
+    pub fn new(len: usize, seed: u64) -> Shuffle {
+        let len = len as u64;
+        let bits = 64 - len.saturating_sub(1).leading_zeros();
+        let half = cmp::max(bits.div_ceil(2), 1);
+
+        let mut keys = [0; ROUNDS];
+        let mut state = seed;
+        for key in &mut keys {
+            state = splitmix64(state);
+            *key = state;
+        }
+
+        Shuffle {
+            len,
+            half,
+            keys,
+        }
+    }
+
+    /// A seeded rng for things that are shuffled in memory
+    pub fn rng(seed: u64) -> XorShiftRng {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&splitmix64(seed).to_le_bytes());
+        bytes[8..].copy_from_slice(&splitmix64(!seed).to_le_bytes());
+        XorShiftRng::from_seed(bytes)
+    }
+
+    /// A feistel network is a permutation of all numbers with 2*half bits
+    fn permute(&self, x: u64) -> u64 {
+        let mask = (1 << self.half) - 1;
+        let (mut left, mut right) = (x >> self.half, x & mask);
+        for key in &self.keys {
+            let next = left ^ (splitmix64(right ^ key) & mask);
+            left = right;
+            right = next;
+        }
+        (left << self.half) | right
+    }
+
+    /// The index at position `idx`
+    pub fn get(&self, idx: usize) -> usize {
+        // the domain of the permutation is larger than len, repeat until we
+        // are back in range. This is still a permutation of 0..len
+        let mut x = self.permute(idx as u64);
+        while x >= self.len {
+            x = self.permute(x);
+        }
+        x as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(len: usize, seed: u64) -> Vec<usize> {
+        let shuffle = Shuffle::new(len, seed);
+        (0..len).map(|x| shuffle.get(x)).collect()
+    }
+
+    #[test]
+    fn verify_permutation() {
+        for len in &[1, 2, 3, 7, 100, 1000, 1025] {
+            let mut x = order(*len, 42);
+            x.sort();
+            assert_eq!(x, (0..*len).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn verify_seed() {
+        assert_eq!(order(1000, 1), order(1000, 1));
+        assert_ne!(order(1000, 1), order(1000, 2));
+        assert_ne!(order(1000, 1), (0..1000).collect::<Vec<_>>());
+    }
+}
@@ -3,54 +3,55 @@ use errors::{Result, ResultExt};
 use bufstream::BufStream;
 use regex::Regex;
 
-use std::str;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufRead;
 use std::net::TcpStream;
-use std::net::ToSocketAddrs;
+use std::time::Instant;
+use logger;
+use proxy::Route;
+use utils;
 
 
 #[derive(Debug)]
 pub struct Socket {
     stream: BufStream<TcpStream>,
     newline: String,
+    // reads and writes fail after this, see --timeout
+    deadline: Option<Instant>,
 }
 
 impl Socket {
-    pub fn connect(host: &str, port: u16) -> Result<Socket> {
-        let addrs = (host, port).to_socket_addrs()?;
-
-        let mut errors = Vec::new();
-
-        for addr in addrs {
-            debug!("connecting to {:?}", addr);
-            match TcpStream::connect(&addr) {
-                Ok(socket) => {
-                    debug!("successfully connected to {:?}", addr);
-                    let stream = BufStream::new(socket);
-
-                    return Ok(Socket {
-                        stream,
-                        newline: String::from("\n"),
-                    });
-                },
-                Err(err) => errors.push((addr, err)),
-            }
-        }
+    /// Connect with --resolve and through --proxy
+    pub fn connect(host: &str, port: u16, deadline: Option<Instant>, route: &Route) -> Result<Socket> {
+        let stream = route.connect(host, port, deadline)?;
+        Ok(Socket {
+            stream: BufStream::new(stream),
+            newline: String::from("\n"),
+            deadline,
+        })
+    }
 
-        if errors.is_empty() {
-            bail!("no dns records found");
-        } else {
-            bail!("couldn't connect: {:?}", errors);
+    /// Connections from worker_setup are reused with the deadline of the
+    /// current attempt
+    #[inline]
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Make sure the next read or write doesn't block past the deadline
+    fn arm(&mut self) -> Result<()> {
+        if let Some(timeout) = utils::remaining(self.deadline)? {
+            let socket = self.stream.get_ref();
+            socket.set_read_timeout(Some(timeout))?;
+            socket.set_write_timeout(Some(timeout))?;
         }
+        Ok(())
     }
 
     pub fn send(&mut self, data: &[u8]) -> Result<()> {
-        match str::from_utf8(&data) {
-            Ok(data) => debug!("send: {:?}", data),
-            Err(_) => debug!("send: {:?}", data),
-        };
+        self.arm()?;
+        debug!("send: {} bytes\n{}", data.len(), logger::hexdump(data));
         self.stream.write_all(data)?;
         self.stream.flush()?;
         Ok(())
@@ -58,12 +59,10 @@ impl Socket {
 
     pub fn recv(&mut self) -> Result<Vec<u8>> {
         let mut buf = [0; 4096];
+        self.arm()?;
         let n = self.stream.read(&mut buf)?;
         let data = buf[..n].to_vec();
-        match str::from_utf8(&data) {
-            Ok(data) => debug!("recv: {:?}", data),
-            Err(_) => debug!("recv: {:?}", data),
-        };
+        debug!("recv: {} bytes\n{}", data.len(), logger::hexdump(&data));
         Ok(data)
     }
 
@@ -82,11 +81,9 @@ impl Socket {
 
     pub fn recvall(&mut self) -> Result<Vec<u8>> {
         let mut buf = Vec::new();
+        self.arm()?;
         self.stream.read_to_end(&mut buf)?;
-        match str::from_utf8(&buf) {
-            Ok(buf) => debug!("recvall: {:?}", buf),
-            Err(_) => debug!("recvall: {:?}", buf),
-        };
+        debug!("recvall: {} bytes\n{}", buf.len(), logger::hexdump(&buf));
         Ok(buf)
     }
 
@@ -111,11 +108,9 @@ impl Socket {
 
     pub fn recvn(&mut self, n: u32) -> Result<Vec<u8>> {
         let mut buf = vec![0; n as usize];
+        self.arm()?;
         self.stream.read_exact(buf.as_mut_slice())?;
-        match str::from_utf8(&buf) {
-            Ok(buf) => debug!("recvn: {:?}", buf),
-            Err(_) => debug!("recvn: {:?}", buf),
-        };
+        debug!("recvn: {} bytes\n{}", buf.len(), logger::hexdump(&buf));
         Ok(buf.to_vec())
     }
 
@@ -125,6 +120,7 @@ impl Socket {
         let delim_len = delim.len();
 
         loop {
+            self.arm()?;
             let (done, used) = {
                 let available = match self.stream.fill_buf() {
                     Ok(n) => n,
@@ -146,10 +142,7 @@ impl Socket {
             self.stream.consume(used);
 
             if done || used == 0 {
-                match str::from_utf8(&buf) {
-                    Ok(buf) => debug!("recvuntil: {:?}", buf),
-                    Err(_) => debug!("recvuntil: {:?}", buf),
-                };
+                debug!("recvuntil: {} bytes\n{}", buf.len(), logger::hexdump(&buf));
                 return Ok(buf);
             }
         }
@@ -0,0 +1,113 @@
+//! A stream of json objects for wrappers that can't read the progress bar,
+//! see --status-json
+
+use errors::Result;
+use pb::ProgressBar;
+use report::{Finding, JsonFinding};
+
+use serde_json;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+
+
+/// The progress of the attack
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub attempts_done: usize,
+    // unknown if we read from stdin
+    pub attempts_total: Option<usize>,
+    pub valid: usize,
+    // attempts that failed or expired
+    pub errors: usize,
+    pub retries: usize,
+    pub expired: usize,
+    // attempts per second, unknown right after the start
+    pub rate: Option<f64>,
+    pub eta_seconds: Option<u64>,
+    pub threads: usize,
+    pub paused: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Status(&'a Status),
+    Found(JsonFinding<'a>),
+}
+
+/// Writes one event per line to a file or fifo, or to stderr
+pub struct StatusStream {
+    file: Option<File>,
+}
+
+impl StatusStream {
+    /// Opening a fifo blocks until somebody reads from it
+    pub fn open(path: Option<&str>) -> Result<StatusStream> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().append(true).create(true).open(path)?),
+            None => None,
+        };
+        Ok(StatusStream {
+            file,
+        })
+    }
+
+    pub fn status(&mut self, status: &Status, pb: &mut ProgressBar) -> Result<()> {
+        self.write(&Event::Status(status), pb)
+    }
+
+    pub fn found(&mut self, finding: &Finding, pb: &mut ProgressBar) -> Result<()> {
+        self.write(&Event::Found(finding.to_json()), pb)
+    }
+
+    /// Lines on stderr are written above the progress bar
+    fn write(&mut self, event: &Event, pb: &mut ProgressBar) -> Result<()> {
+        let line = serde_json::to_string(event)?;
+        match self.file {
+            Some(ref mut file) => {
+                file.write_all(line.as_bytes())?;
+                file.write_all(b"\n")?;
+                file.flush()?;
+            },
+            None => pb.writeln(line),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_events() {
+        let status = Status {
+            attempts_done: 42,
+            attempts_total: Some(100),
+            valid: 1,
+            errors: 2,
+            retries: 3,
+            expired: 1,
+            rate: Some(2.5),
+            eta_seconds: Some(23),
+            threads: 16,
+            paused: false,
+        };
+        assert_eq!(serde_json::to_string(&Event::Status(&status)).unwrap(),
+            r#"{"event":"status","attempts_done":42,"attempts_total":100,"valid":1,"errors":2,"retries":3,"expired":1,"rate":2.5,"eta_seconds":23,"threads":16,"paused":false}"#);
+
+        let finding = Finding {
+            script: "ssh",
+            user: "root",
+            password: Some("toor"),
+            timestamp: "2026-01-01T00:00:00Z",
+            target: None,
+            status: None,
+            note: None,
+            duration: None,
+            retries: 0,
+        };
+        assert_eq!(serde_json::to_string(&Event::Found(finding.to_json())).unwrap(),
+            r#"{"event":"found","script":"ssh","user":"root","password":"toor","timestamp":"2026-01-01T00:00:00Z","target":null,"status":null,"note":null,"duration":null,"retries":0}"#);
+    }
+}
@@ -0,0 +1,437 @@
+//! Send findings, lockouts and the progress to syslog, see --syslog
+//!
+//! The messages are key=value pairs and are sent from a separate thread. If
+//! the destination can't keep up or isn't reachable messages are dropped
+//! instead of slowing down the attack.
+
+use errors::{Result, ResultExt, Error};
+use nix::unistd;
+use proxy;
+use report::Finding;
+use scheduler::{Level, Msg};
+use status::Status;
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use time;
+
+// messages that wait for the destination, later ones are dropped
+const QUEUE_SIZE: usize = 1024;
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(5);
+// don't try to reconnect for every message while the destination is down
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+const APP_NAME: &str = "badtouch";
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    // /dev/log or another unix socket of the local syslog daemon
+    Local(String),
+    Udp(String, u16),
+    Tcp(String, u16),
+    // the same lines, appended to a file
+    File(String),
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Target> {
+        if s == "local" {
+            return Ok(Target::Local("/dev/log".to_string()));
+        }
+        let (scheme, rest) = match s.find(':') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => bail!("expected local, unix:path, udp://host:port, tcp://host:port or file:path"),
+        };
+        let target = match scheme {
+            "unix" => Target::Local(rest.to_string()),
+            "file" => Target::File(rest.to_string()),
+            "udp" | "tcp" => {
+                let addr = match rest.strip_prefix("//") {
+                    Some(addr) => addr.trim_end_matches('/'),
+                    None => bail!("expected {}://host:port", scheme),
+                };
+                let (host, port) = proxy::split_host_port(addr, Some(514))?;
+                if scheme == "udp" {
+                    Target::Udp(host.to_string(), port)
+                } else {
+                    Target::Tcp(host.to_string(), port)
+                }
+            },
+            _ => bail!("unsupported syslog target {:?}", scheme),
+        };
+        match target {
+            Target::Local(ref path) | Target::File(ref path) if path.is_empty() => bail!("missing path in {:?}", s),
+            _ => Ok(target),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Local(ref path) => write!(f, "unix:{}", path),
+            Target::Udp(ref host, port) => write!(f, "udp://{}:{}", host, port),
+            Target::Tcp(ref host, port) => write!(f, "tcp://{}:{}", host, port),
+            Target::File(ref path) => write!(f, "file:{}", path),
+        }
+    }
+}
+
+/// See rfc5424, section 6.2.1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Facility(u8);
+
+impl FromStr for Facility {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Facility> {
+        let code = match s {
+            "kern" => 0,
+            "user" => 1,
+            "mail" => 2,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "lpr" => 6,
+            "news" => 7,
+            "uucp" => 8,
+            "cron" => 9,
+            "authpriv" => 10,
+            "ftp" => 11,
+            _ => match s.strip_prefix("local").and_then(|x| x.parse::<u8>().ok()) {
+                Some(num) if num <= 7 => 16 + num,
+                _ => bail!("unknown syslog facility {:?}", s),
+            },
+        };
+        Ok(Facility(code))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+/// key=value pairs, values are quoted if they contain spaces, quotes or an =
+pub fn format_fields(fields: &[(&str, &str)]) -> String {
+    fields.iter()
+        .map(|&(key, value)| {
+            let plain = !value.is_empty() && value.chars()
+                .all(|c| !c.is_whitespace() && !c.is_control() && c != '"' && c != '=');
+            if plain {
+                format!("{}={}", key, value)
+            } else {
+                let value = value.chars()
+                    .map(|c| match c {
+                        '"' => "\\\"".to_string(),
+                        '\\' => "\\\\".to_string(),
+                        '\n' => "\\n".to_string(),
+                        '\r' => "\\r".to_string(),
+                        c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+                        c => c.to_string(),
+                    })
+                    .collect::<String>();
+                format!("{}=\"{}\"", key, value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hostname() -> String {
+    let mut buf = [0; 256];
+    unistd::gethostname(&mut buf).ok()
+        .and_then(|name| name.to_str().ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("-")
+        .to_string()
+}
+
+/// Local syslog daemons expect rfc3164, remote ones get rfc5424
+fn format_line(target: &Target, facility: Facility, severity: Severity, hostname: &str, msg: &str) -> String {
+    let pri = facility.0 as usize * 8 + severity as usize;
+    match *target {
+        Target::Local(_) => {
+            let timestamp = time::now().strftime("%b %e %H:%M:%S")
+                .map(|x| x.to_string())
+                .unwrap_or_default();
+            format!("<{}>{} {}[{}]: {}", pri, timestamp, APP_NAME, process::id(), msg)
+        },
+        _ => format!("<{}>1 {} {} {} {} - - {}", pri, time::now_utc().rfc3339(), hostname, APP_NAME, process::id(), msg),
+    }
+}
+
+enum Connection {
+    Local(UnixDatagram, String),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    File(File),
+}
+
+impl Connection {
+    fn open(target: &Target) -> Result<Connection> {
+        let conn = match *target {
+            Target::Local(ref path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+                Connection::Local(socket, path.clone())
+            },
+            Target::Udp(ref host, port) => {
+                let addr = (host.as_str(), port).to_socket_addrs()?.next()
+                    .ok_or("no dns records found")?;
+                let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                Connection::Udp(socket)
+            },
+            Target::Tcp(ref host, port) => {
+                let addr = (host.as_str(), port).to_socket_addrs()?.next()
+                    .ok_or("no dns records found")?;
+                let stream = TcpStream::connect_timeout(&addr, NETWORK_TIMEOUT)?;
+                stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+                Connection::Tcp(stream)
+            },
+            Target::File(ref path) => Connection::File(OpenOptions::new().append(true).create(true).open(path)?),
+        };
+        Ok(conn)
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match *self {
+            Connection::Local(ref socket, ref path) => socket.send_to(line.as_bytes(), path).map(|_| ()),
+            Connection::Udp(ref socket) => socket.send(line.as_bytes()).map(|_| ()),
+            // octet counting, see rfc6587
+            Connection::Tcp(ref mut stream) => stream.write_all(format!("{} {}", line.len(), line).as_bytes()),
+            Connection::File(ref mut file) => writeln!(file, "{}", line),
+        }
+    }
+}
+
+/// Write the lines until the sender is dropped. While the destination is
+/// down the lines are dropped, the first failure is reported once
+fn deliver(target: &Target, rx: mpsc::Receiver<String>, dropped: &AtomicUsize, out: &mpsc::Sender<Msg>) {
+    let mut conn = None;
+    let mut failed: Option<Instant> = None;
+    for line in rx {
+        if conn.is_none() {
+            if failed.is_some_and(|x| x.elapsed() < RECONNECT_INTERVAL) {
+                dropped.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+            match Connection::open(target) {
+                Ok(c) => conn = Some(c),
+                Err(err) => {
+                    if failed.is_none() {
+                        let _ = out.send(Msg::Print(Level::Warn, format!("failed to connect to syslog {}: {}, messages are dropped until it's reachable", target, err)));
+                    }
+                    failed = Some(Instant::now());
+                    dropped.fetch_add(1, Ordering::SeqCst);
+                    continue;
+                },
+            }
+        }
+
+        let result = conn.as_mut()
+            .expect("connection was just opened")
+            .write(&line);
+        match result {
+            Ok(_) => {
+                if failed.take().is_some() {
+                    let _ = out.send(Msg::Print(Level::Info, format!("syslog {} is reachable again", target)));
+                }
+            },
+            Err(err) => {
+                if failed.is_none() {
+                    let _ = out.send(Msg::Print(Level::Warn, format!("failed to write to syslog {}: {}, messages are dropped until it's reachable", target, err)));
+                }
+                failed = Some(Instant::now());
+                dropped.fetch_add(1, Ordering::SeqCst);
+                conn = None;
+            },
+        }
+    }
+}
+
+pub struct Syslog {
+    target: Target,
+    facility: Facility,
+    hostname: String,
+    tx: mpsc::SyncSender<String>,
+    dropped: Arc<AtomicUsize>,
+    thread: JoinHandle<()>,
+}
+
+impl Syslog {
+    /// Warnings about the destination are printed through out
+    pub fn open(target: Target, facility: Facility, out: mpsc::Sender<Msg>) -> Result<Syslog> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let thread = {
+            let target = target.clone();
+            let dropped = dropped.clone();
+            thread::Builder::new()
+                .name("syslog".to_string())
+                .spawn(move || deliver(&target, rx, &dropped, &out))
+                .chain_err(|| "failed to start syslog thread")?
+        };
+
+        Ok(Syslog {
+            target,
+            facility,
+            hostname: hostname(),
+            tx,
+            dropped,
+            thread,
+        })
+    }
+
+    /// Never blocks, the message is dropped if the queue is full
+    pub fn send(&self, severity: Severity, fields: &[(&str, &str)]) {
+        let line = format_line(&self.target, self.facility, severity, &self.hostname, &format_fields(fields));
+        if self.tx.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// The password isn't sent, it's only written to the report
+    pub fn found(&self, finding: &Finding) {
+        let mut fields = vec![
+            ("event", "found"),
+            ("script", finding.script),
+            ("user", finding.user),
+        ];
+        fields.extend(finding.target.map(|x| ("target", x)));
+        fields.extend(finding.status.map(|x| ("status", x)));
+        fields.extend(finding.note.map(|x| ("note", x)));
+        self.send(Severity::Notice, &fields);
+    }
+
+    pub fn locked(&self, script: &str, user: &str, reason: &str) {
+        self.send(Severity::Warning, &[
+            ("event", "locked"),
+            ("script", script),
+            ("user", user),
+            ("reason", reason),
+        ]);
+    }
+
+    /// An attempt that failed and isn't retried
+    pub fn error(&self, script: &str, user: &str, outcome: &str, err: &str) {
+        self.send(Severity::Error, &[
+            ("event", "error"),
+            ("script", script),
+            ("user", user),
+            ("outcome", outcome),
+            ("error", err),
+        ]);
+    }
+
+    /// The event is progress while the attack is running and done at the end
+    pub fn status(&self, event: &str, status: &Status) {
+        let done = status.attempts_done.to_string();
+        let total = status.attempts_total.map(|x| x.to_string());
+        let valid = status.valid.to_string();
+        let errors = status.errors.to_string();
+        let retries = status.retries.to_string();
+        let rate = status.rate.map(|x| format!("{:.2}", x));
+        let eta = status.eta_seconds.map(|x| x.to_string());
+        let threads = status.threads.to_string();
+
+        let mut fields = vec![
+            ("event", event),
+            ("attempts_done", done.as_str()),
+        ];
+        fields.extend(total.as_ref().map(|x| ("attempts_total", x.as_str())));
+        fields.extend(&[
+            ("valid", valid.as_str()),
+            ("errors", errors.as_str()),
+            ("retries", retries.as_str()),
+        ]);
+        fields.extend(rate.as_ref().map(|x| ("rate", x.as_str())));
+        fields.extend(eta.as_ref().map(|x| ("eta_seconds", x.as_str())));
+        fields.extend(&[
+            ("threads", threads.as_str()),
+            ("paused", if status.paused { "true" } else { "false" }),
+        ]);
+        self.send(Severity::Info, &fields);
+    }
+
+    /// Wait until the queued messages were sent, returns how many messages
+    /// were dropped
+    pub fn close(self) -> usize {
+        drop(self.tx);
+        let _ = self.thread.join();
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_target() {
+        assert_eq!("local".parse::<Target>().unwrap(), Target::Local("/dev/log".to_string()));
+        assert_eq!("unix:/run/systemd/journal/syslog".parse::<Target>().unwrap(), Target::Local("/run/systemd/journal/syslog".to_string()));
+        assert_eq!("udp://siem.corp".parse::<Target>().unwrap(), Target::Udp("siem.corp".to_string(), 514));
+        assert_eq!("tcp://[::1]:6514/".parse::<Target>().unwrap(), Target::Tcp("::1".to_string(), 6514));
+        assert_eq!("file:badtouch.log".parse::<Target>().unwrap(), Target::File("badtouch.log".to_string()));
+        assert!("file:".parse::<Target>().is_err());
+        assert!("udp:siem.corp".parse::<Target>().is_err());
+        assert!("http://siem.corp".parse::<Target>().is_err());
+        assert!("/dev/log".parse::<Target>().is_err());
+
+        assert_eq!("authpriv".parse::<Facility>().unwrap(), Facility(10));
+        assert_eq!("local7".parse::<Facility>().unwrap(), Facility(23));
+        assert!("local8".parse::<Facility>().is_err());
+    }
+
+    #[test]
+    fn verify_format() {
+        assert_eq!(format_fields(&[
+            ("event", "found"),
+            ("user", "admin"),
+            ("note", "mfa \"totp\"=on"),
+            ("target", ""),
+            ("error", "line 1\nline 2"),
+        ]), r#"event=found user=admin note="mfa \"totp\"=on" target="" error="line 1\nline 2""#);
+
+        let line = format_line(&Target::Udp("siem.corp".to_string(), 514), Facility(4), Severity::Notice, "kali", "event=found");
+        assert!(line.starts_with("<37>1 "), "{}", line);
+        assert!(line.ends_with(&format!(" kali badtouch {} - - event=found", process::id())), "{}", line);
+
+        let line = format_line(&Target::Local("/dev/log".to_string()), Facility(1), Severity::Info, "kali", "event=progress");
+        assert!(line.starts_with("<14>"), "{}", line);
+        assert!(line.ends_with(&format!(" badtouch[{}]: event=progress", process::id())), "{}", line);
+    }
+
+    #[test]
+    fn verify_unreachable() {
+        let (out, rx) = mpsc::channel();
+        let syslog = Syslog::open(Target::Local("/nonexistent/badtouch.sock".to_string()), Facility(1), out).unwrap();
+        for _ in 0..3 {
+            syslog.locked("ssh", "root", "is locked out");
+        }
+        assert_eq!(syslog.close(), 3);
+
+        let warnings = rx.try_iter()
+            .filter(|msg| matches!(msg, Msg::Print(Level::Warn, _)))
+            .count();
+        assert_eq!(warnings, 1);
+    }
+}
@@ -0,0 +1,316 @@
+use hlua::{self, AnyLuaValue, AsMutLua, LuaError, LuaRead, Push};
+use hlua::ffi;
+use libc::{c_char, c_int, c_void, size_t};
+
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::time::Instant;
+
+// don't walk the stack forever if a script recurses
+const MAX_LEVELS: c_int = 20;
+// how often the deadline is checked, in lua instructions
+const HOOK_INTERVAL: c_int = 1000;
+
+
+thread_local! {
+    // the lua state that is currently executing on this thread, if any
+    static CURRENT: Cell<*mut ffi::lua_State> = const { Cell::new(ptr::null_mut()) };
+    // the deadline of the attempt that is running on this thread
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// lua_Debug from lua52-sys is missing the private field at the end, but
+/// lua_getstack writes to it
+#[repr(C)]
+struct Debug {
+    event: c_int,
+    name: *const c_char,
+    namewhat: *const c_char,
+    what: *const c_char,
+    source: *const c_char,
+    currentline: c_int,
+    linedefined: c_int,
+    lastlinedefined: c_int,
+    nups: u8,
+    nparams: u8,
+    isvararg: c_char,
+    istailcall: c_char,
+    short_src: [c_char; 60],
+    i_ci: *mut c_void,
+}
+
+impl Debug {
+    /// Get info about the function at the given stack level
+    fn at(l: *mut ffi::lua_State, level: c_int) -> Option<Debug> {
+        unsafe {
+            let mut ar: Debug = ::std::mem::zeroed();
+            let ptr = &mut ar as *mut Debug as *mut ffi::lua_Debug;
+            if ffi::lua_getstack(l, level, ptr) == 0 {
+                return None;
+            }
+            ffi::lua_getinfo(l, b"Slnt\0".as_ptr() as *const _, ptr);
+            Some(ar)
+        }
+    }
+
+    fn src(&self) -> String {
+        unsafe { CStr::from_ptr(self.short_src.as_ptr()) }.to_string_lossy().into_owned()
+    }
+
+    fn str_field(ptr: *const c_char) -> Option<String> {
+        if ptr.is_null() {
+            None
+        } else {
+            let s = unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned();
+            if s.is_empty() { None } else { Some(s) }
+        }
+    }
+
+    /// Format a line of the traceback, similar to debug.traceback
+    fn format(&self) -> String {
+        let mut line = self.src();
+        if self.currentline > 0 {
+            line += &format!(":{}", self.currentline);
+        }
+
+        match (Debug::str_field(self.name), Debug::str_field(self.what)) {
+            (Some(name), _) => line += &format!(": in function '{}'", name),
+            (None, Some(ref what)) if what == "main" => line += ": in main chunk",
+            (None, Some(ref what)) if what == "C" => line += ": in ?",
+            (None, _) => line += &format!(": in function <{}:{}>", self.src(), self.linedefined),
+        }
+
+        // the frame of the caller was replaced
+        if self.istailcall != 0 {
+            line += "\n\t(...tail calls...)";
+        }
+
+        line
+    }
+}
+
+/// Returns the location of the lua code that is currently executing, this
+/// can be used from inside a function that was called by the script
+pub fn location() -> Option<String> {
+    let l = CURRENT.with(|x| x.get());
+    if l.is_null() {
+        return None;
+    }
+
+    // skip our own functions, they don't have a line number
+    (0..MAX_LEVELS)
+        .map(|level| Debug::at(l, level))
+        .take_while(|ar| ar.is_some())
+        .flatten()
+        .find(|ar| ar.currentline > 0)
+        .map(|ar| format!("{}:{}", ar.src(), ar.currentline))
+}
+
+fn traceback(l: *mut ffi::lua_State, level: c_int) -> String {
+    let mut out = String::from("stack traceback:");
+    for level in level..level + MAX_LEVELS {
+        match Debug::at(l, level) {
+            Some(ar) => {
+                out += "\n\t";
+                out += &ar.format();
+            },
+            None => break,
+        }
+    }
+    out
+}
+
+unsafe fn error_msg(l: *mut ffi::lua_State, idx: c_int) -> String {
+    let msg = ffi::lua_tolstring(l, idx, ptr::null_mut());
+    if msg.is_null() {
+        String::from("(error object is not a string)")
+    } else {
+        CStr::from_ptr(msg).to_string_lossy().into_owned()
+    }
+}
+
+/// Message handler for lua_pcall that appends a traceback to the error
+extern "C" fn handler(l: *mut ffi::lua_State) -> c_int {
+    let msg = unsafe { error_msg(l, 1) };
+    let msg = format!("{}\n{}", msg, traceback(l, 1));
+    unsafe { ffi::lua_pushlstring(l, msg.as_ptr() as *const _, msg.len()) };
+    1
+}
+
+/// Raises an error inside the script once the deadline passed, this also
+/// stops scripts that are stuck in a loop
+extern "C" fn hook(l: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+    let expired = DEADLINE.with(|x| x.get())
+        .is_some_and(|deadline| Instant::now() >= deadline);
+    if !expired {
+        return;
+    }
+
+    let msg = match location() {
+        Some(location) => format!("{}: attempt timed out", location),
+        None => String::from("attempt timed out"),
+    };
+    unsafe { ffi::lua_pushlstring(l, msg.as_ptr() as *const _, msg.len()) };
+    // lua_error doesn't return, nothing may be left that needs to be dropped
+    drop(msg);
+    unsafe { ffi::lua_error(l) };
+}
+
+/// Abort the script if it's still running at the deadline. The deadline is
+/// only checked while lua code is executing, functions that block have to
+/// check it themselves
+pub fn set_deadline(lua: &mut hlua::Lua, deadline: Option<Instant>) {
+    DEADLINE.with(|x| x.set(deadline));
+    if deadline.is_some() {
+        let l = lua.as_mut_lua().state_ptr();
+        unsafe { ffi::lua_sethook(l, hook, ffi::LUA_MASKCOUNT, HOOK_INTERVAL) };
+    }
+}
+
+/// Marks the lua state as running until it's dropped
+struct Running(*mut ffi::lua_State);
+
+impl Running {
+    fn enter(l: *mut ffi::lua_State) -> Running {
+        Running(CURRENT.with(|x| x.replace(l)))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        CURRENT.with(|x| x.set(self.0));
+    }
+}
+
+extern "C" fn reader(_: *mut ffi::lua_State, data: *mut c_void, size: *mut size_t) -> *const c_char {
+    let code = unsafe { &mut *(data as *mut Option<&[u8]>) };
+    match code.take() {
+        Some(code) => {
+            unsafe { *size = code.len() };
+            code.as_ptr() as *const _
+        },
+        None => {
+            unsafe { *size = 0 };
+            ptr::null()
+        },
+    }
+}
+
+/// Execute the code, `name` is used for the location in error messages
+pub fn execute(lua: &mut hlua::Lua, code: &str, name: &str) -> Result<(), LuaError> {
+    let l = lua.as_mut_lua().state_ptr();
+    let chunkname = CString::new(format!("={}", name))
+        .map_err(|_| LuaError::SyntaxError("script name contains a null byte".into()))?;
+
+    unsafe {
+        let top = ffi::lua_gettop(l);
+        ffi::lua_pushcfunction(l, handler);
+
+        let mut code = Some(code.as_bytes());
+        let code = &mut code as *mut Option<&[u8]> as *mut c_void;
+        if ffi::lua_load(l, reader, code, chunkname.as_ptr(), ptr::null()) != ffi::LUA_OK {
+            let err = error_msg(l, -1);
+            ffi::lua_settop(l, top);
+            return Err(LuaError::SyntaxError(err));
+        }
+
+        let _running = Running::enter(l);
+        let ret = ffi::lua_pcall(l, 0, 0, top + 1);
+        let result = if ret == ffi::LUA_OK {
+            Ok(())
+        } else {
+            Err(LuaError::ExecutionError(error_msg(l, -1)))
+        };
+        ffi::lua_settop(l, top);
+        result
+    }
+}
+
+/// Call a global function and return the first return value
+pub fn call(lua: &mut hlua::Lua, function: &str, args: Vec<AnyLuaValue>) -> Result<AnyLuaValue, LuaError> {
+    let l = lua.as_mut_lua().state_ptr();
+    let function = CString::new(function)
+        .map_err(|_| LuaError::ExecutionError("function name contains a null byte".into()))?;
+
+    unsafe {
+        let top = ffi::lua_gettop(l);
+        ffi::lua_pushcfunction(l, handler);
+
+        ffi::lua_getglobal(l, function.as_ptr());
+        if !ffi::lua_isfunction(l, -1) {
+            ffi::lua_settop(l, top);
+            return Err(LuaError::ExecutionError(format!("{} undefined", function.to_string_lossy())));
+        }
+
+        let nargs = args.len() as i32;
+        for arg in args {
+            arg.push_no_err(&mut *lua).forget();
+        }
+
+        let _running = Running::enter(l);
+        let ret = ffi::lua_pcall(l, nargs, 1, top + 1);
+        let result = if ret == ffi::LUA_OK {
+            match AnyLuaValue::lua_read_at_position(&mut *lua, -1) {
+                Ok(x) => Ok(x),
+                Err(_) => Err(LuaError::WrongType),
+            }
+        } else {
+            Err(LuaError::ExecutionError(error_msg(l, -1)))
+        };
+        ffi::lua_settop(l, top);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_traceback() {
+        let mut lua = hlua::Lua::new();
+        execute(&mut lua, r#"
+        function inner()
+            return x.y
+        end
+
+        function verify(user, password)
+            inner()
+            return true
+        end
+        "#, "test.lua").unwrap();
+
+        let err = call(&mut lua, "verify", vec![AnyLuaValue::LuaNil, AnyLuaValue::LuaNil]).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("test.lua:3: attempt to index global 'x'"));
+        assert!(err.contains("stack traceback:\n\ttest.lua:3: in function 'inner'\n\ttest.lua:7: in function"));
+    }
+
+    #[test]
+    fn verify_syntax_error() {
+        let mut lua = hlua::Lua::new();
+        let err = execute(&mut lua, "function verify(", "test.lua").unwrap_err();
+        assert!(err.to_string().contains("test.lua:1:"));
+    }
+
+    #[test]
+    fn verify_deadline() {
+        let mut lua = hlua::Lua::new();
+        execute(&mut lua, "function verify(user, password)\n    while true do end\nend", "test.lua").unwrap();
+
+        set_deadline(&mut lua, Some(Instant::now() + ::std::time::Duration::from_millis(100)));
+        let err = call(&mut lua, "verify", vec![AnyLuaValue::LuaNil, AnyLuaValue::LuaNil]).unwrap_err();
+        set_deadline(&mut lua, None);
+        assert!(err.to_string().contains("test.lua:2: attempt timed out"));
+    }
+
+    #[test]
+    fn verify_call() {
+        let mut lua = hlua::Lua::new();
+        execute(&mut lua, "function verify(user, password) return user == password end", "test.lua").unwrap();
+        let x = call(&mut lua, "verify", vec![AnyLuaValue::LuaString("a".into()), AnyLuaValue::LuaString("a".into())]).unwrap();
+        assert_eq!(x, AnyLuaValue::LuaBoolean(true));
+        assert!(call(&mut lua, "foo", vec![AnyLuaValue::LuaNil, AnyLuaValue::LuaNil]).is_err());
+    }
+}
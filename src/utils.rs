@@ -1,72 +1,703 @@
 use errors::{Result, ResultExt};
 
+use std::borrow::Cow;
 use std::str;
 use std::fs::{self, File};
 use std::sync::Arc;
+use std::collections::HashSet;
+use std::hash::Hash;
 use std::io::{self, BufReader};
 use std::io::prelude::*;
+use std::time::{Duration, Instant};
 use config::Config;
+use hashes::NtHash;
+use flate2::bufread::MultiGzDecoder;
+use rand::{thread_rng, RngCore};
+use xz2::bufread::XzDecoder;
 
 use ctx;
 
 
-pub fn load_list(path: &str) -> Result<Vec<Arc<String>>> {
-    let f = File::open(path)?;
-    let file = BufReader::new(&f);
-    let lines: io::Result<_> = file.lines()
-            .map(|x| x.map(Arc::new))
-            .collect();
-    Ok(lines?)
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+
+/// Counts the bytes that were read from the file
+struct Counter<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// A decoder that reads the compressed file
+trait Decoder: Read {
+    fn source(&self) -> &BufReader<Counter<File>>;
+}
+
+impl Decoder for MultiGzDecoder<BufReader<Counter<File>>> {
+    #[inline]
+    fn source(&self) -> &BufReader<Counter<File>> {
+        self.get_ref()
+    }
+}
+
+impl Decoder for XzDecoder<BufReader<Counter<File>>> {
+    #[inline]
+    fn source(&self) -> &BufReader<Counter<File>> {
+        self.get_ref()
+    }
+}
+
+/// Decompresses a gzip or xz list, errors contain the path and the offset in
+/// the compressed file so a corrupt archive can be located
+struct CompressedList<D> {
+    path: String,
+    inner: D,
+}
+
+impl<D: Decoder> Read for CompressedList<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+            .map_err(|err| {
+                let reader = self.inner.source();
+                let offset = reader.get_ref().offset - reader.buffer().len() as u64;
+                io::Error::new(io::ErrorKind::InvalidData,
+                    format!("failed to decompress {:?} at offset {}: {}", self.path, offset, err))
+            })
+    }
+}
+
+/// The time that is left until the deadline, fails if it already passed
+pub fn remaining(deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "attempt timed out"))
+            } else {
+                Ok(Some(deadline - now))
+            }
+        },
+        None => Ok(None),
+    }
 }
 
-pub fn load_creds(path: &str) -> Result<Vec<Arc<Vec<u8>>>> {
+/// Open a list, `-` reads from stdin. Compressed lists are detected by their
+/// extension or magic bytes and decompressed while reading
+pub fn open(path: &str) -> Result<Box<dyn BufRead>> {
+    if path == "-" {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+
     let f = File::open(path)?;
-    let mut file = BufReader::new(&f);
+    let mut reader = BufReader::new(Counter {
+        inner: f,
+        offset: 0,
+    });
+
+    let (gzip, xz) = {
+        let magic = reader.fill_buf()?;
+        (magic.starts_with(GZIP_MAGIC) || path.ends_with(".gz"),
+         magic.starts_with(XZ_MAGIC) || path.ends_with(".xz"))
+    };
+
+    if gzip {
+        Ok(Box::new(BufReader::new(CompressedList {
+            path: path.to_string(),
+            inner: MultiGzDecoder::new(reader),
+        })))
+    } else if xz {
+        Ok(Box::new(BufReader::new(CompressedList {
+            path: path.to_string(),
+            inner: XzDecoder::new_multi_decoder(reader),
+        })))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// How lists are cleaned up while they are loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListOptions {
+    pub dedup: bool,
+    pub trim: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> ListOptions {
+        ListOptions {
+            dedup: true,
+            trim: false,
+        }
+    }
+}
+
+impl ListOptions {
+    /// Remove a windows line ending and the byte order mark, which is only
+    /// expected on the first line. Trailing whitespace is removed if enabled
+    pub fn normalize<'a>(&self, line: &'a str, first: bool) -> &'a str {
+        let line = if first {
+            line.trim_start_matches('\u{feff}')
+        } else {
+            line
+        };
+        let line = line.trim_end_matches('\r');
+
+        if self.trim {
+            line.trim_end()
+        } else {
+            line
+        }
+    }
+
+    /// Remove duplicates if enabled, the order is preserved. Returns the
+    /// number of duplicates that were removed
+    pub fn dedup<T: Hash + Eq + Clone>(&self, items: Vec<T>) -> (Vec<T>, usize) {
+        if !self.dedup {
+            return (items, 0);
+        }
+
+        let len = items.len();
+        let mut seen = HashSet::new();
+        let items = items.into_iter()
+            .filter(|x| seen.insert(x.clone()))
+            .collect::<Vec<_>>();
+        let duplicates = len - items.len();
+        (items, duplicates)
+    }
+}
+
+/// Load a list, returns the lines and the number of duplicates that were removed
+pub fn load_list(path: &str, options: &ListOptions) -> Result<(Vec<Arc<String>>, usize)> {
+    let file = open(path)?;
+
+    let mut list = Vec::new();
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        let line = options.normalize(&line, i == 0);
+        list.push(Arc::new(line.to_string()));
+    }
+
+    Ok(options.dedup(list))
+}
+
+/// Count the lines of a list without keeping them in memory
+pub fn count_lines(path: &str) -> Result<usize> {
+    let mut file = open(path)?;
+
+    let mut lines = 0;
+    let mut partial = false;
+    loop {
+        let len = {
+            let buf = file.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            lines += buf.iter().filter(|x| **x == b'\n').count();
+            partial = buf[buf.len() - 1] != b'\n';
+            buf.len()
+        };
+        file.consume(len);
+    }
+
+    // the last line doesn't need a newline
+    if partial {
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// A list that is read from disk as it's needed instead of being loaded into
+/// memory, see --stream. Reading a line before the current one reopens the
+/// file, so the lines should be requested in ascending order. Duplicates are
+/// not removed
+pub struct StreamedList {
+    path: String,
+    options: ListOptions,
+    len: usize,
+    reader: Option<Box<dyn BufRead>>,
+    // the index of the line that is read next
+    next: usize,
+    current: Option<Arc<String>>,
+}
+
+impl StreamedList {
+    /// Count the lines of the list, the file is opened when the first line
+    /// is read
+    pub fn open(path: &str, options: &ListOptions) -> Result<StreamedList> {
+        if path == "-" {
+            bail!("lists from stdin can't be streamed");
+        }
+        let len = count_lines(path)?;
+        Ok(StreamedList {
+            path: path.to_string(),
+            options: *options,
+            len,
+            reader: None,
+            next: 0,
+            current: None,
+        })
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&mut self, idx: usize) -> Result<Arc<String>> {
+        if idx >= self.len {
+            bail!("line {} is out of range", idx);
+        }
+        if idx + 1 == self.next {
+            if let Some(ref line) = self.current {
+                return Ok(line.clone());
+            }
+        }
+        if idx < self.next || self.reader.is_none() {
+            self.reader = Some(open(&self.path)?);
+            self.next = 0;
+        }
+
+        let reader = self.reader.as_mut().unwrap();
+        let mut buf = Vec::new();
+        while self.next <= idx {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                bail!("{:?} ended after {} lines, expected {}", self.path, self.next, self.len);
+            }
+            self.next += 1;
+        }
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+
+        let line = String::from_utf8(buf)
+            .chain_err(|| format!("failed to decode line {} of {:?}", idx + 1, self.path))?;
+        let line = Arc::new(self.options.normalize(&line, idx == 0).to_string());
+        self.current = Some(line.clone());
+        Ok(line)
+    }
+}
+
+/// The passwords of a dictionary attack, see --stream
+pub enum Passwords {
+    Loaded(Vec<Arc<String>>),
+    Streamed(StreamedList),
+}
+
+impl Passwords {
+    pub fn len(&self) -> usize {
+        match *self {
+            Passwords::Loaded(ref list) => list.len(),
+            Passwords::Streamed(ref list) => list.len(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
+    pub fn get(&mut self, idx: usize) -> Result<Arc<String>> {
+        match *self {
+            Passwords::Loaded(ref list) => Ok(list[idx].clone()),
+            Passwords::Streamed(ref mut list) => list.get(idx),
+        }
+    }
+}
+
+/// Split a combo at the first delimiter, the password may contain the
+/// delimiter. A user that starts with a double quote is read up to the closing
+/// quote, quotes inside are doubled. If there's no closing quote followed by
+/// the delimiter the quote is part of the user
+pub fn split_combo(line: &str, delimiter: char) -> Option<(Cow<'_, str>, &str)> {
+    if let Some(quoted) = line.strip_prefix('"') {
+        let mut user = String::new();
+        let mut chars = quoted.char_indices().peekable();
+        while let Some((_, c)) = chars.next() {
+            if c != '"' {
+                user.push(c);
+                continue;
+            }
+            match chars.peek() {
+                Some(&(_, '"')) => {
+                    chars.next();
+                    user.push('"');
+                },
+                Some(&(idx, c)) if c == delimiter => {
+                    return Some((Cow::Owned(user), &quoted[idx + c.len_utf8()..]));
+                },
+                _ => break,
+            }
+        }
+    }
+
+    let idx = line.find(delimiter)?;
+    Some((Cow::Borrowed(&line[..idx]), &line[idx + delimiter.len_utf8()..]))
+}
+
+/// Join a combo so split_combo reads it back, the user is quoted if it
+/// contains the delimiter or starts with a double quote
+pub fn format_combo(user: &str, password: &str, delimiter: char) -> String {
+    if user.contains(delimiter) || user.starts_with('"') {
+        format!("\"{}\"{}{}", user.replace('"', "\"\""), delimiter, password)
+    } else {
+        format!("{}{}{}", user, delimiter, password)
+    }
+}
+
+/// A line of a creds list, most lines are kept as they are to save memory.
+/// Lines with a quoted user are split while the list is loaded, accounts of
+/// a dump are attempted with their NT hash, see --input-format
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Cred {
+    Line(Arc<Vec<u8>>),
+    Pair(Arc<String>, Arc<String>),
+    Hash(Arc<NtHash>),
+}
+
+/// Load a list of user:password lines, returns the credentials, the number
+/// of duplicates that were removed and warnings about lines with an empty user
+/// or password. Those are still attempted
+pub fn load_creds(path: &str, options: &ListOptions) -> Result<(Vec<Cred>, usize, Vec<String>)> {
+    let (creds, warnings) = parse_creds(open(path)?, options)?;
+    let (creds, duplicates) = options.dedup(creds);
+    Ok((creds, duplicates, warnings))
+}
+
+/// Parse a list of user:password lines, split on the first colon
+pub fn parse_creds<R: BufRead>(mut file: R, options: &ListOptions) -> Result<(Vec<Cred>, Vec<String>)> {
     let mut creds = Vec::new();
+    let mut warnings = Vec::new();
 
     let mut buf = Vec::new();
     const DELIM: u8 = b'\n';
 
+    let mut lineno = 0;
     while 0 < file.read_until(DELIM, &mut buf)? {
+        lineno += 1;
         if buf[buf.len() - 1] == DELIM {
             buf.pop();
         }
 
         // ensure line is valid utf8
-        str::from_utf8(&buf)
+        let line = str::from_utf8(&buf)
             .chain_err(|| "failed to decode utf8")?;
+        let line = options.normalize(line, lineno == 1);
 
-        if buf.iter().any(|x| *x == b':') {
-            creds.push(Arc::new(buf.clone()));
-        } else {
-            return Err(format!("invalid list format: {:?}", buf).into())
+        match split_combo(line, ':') {
+            Some((ref user, _)) if user.is_empty() => warnings.push(format!("line {}: empty user", lineno)),
+            Some((_, "")) => warnings.push(format!("line {}: empty password", lineno)),
+            _ => (),
+        }
+        match split_combo(line, ':') {
+            Some((Cow::Owned(user), password)) => creds.push(Cred::Pair(Arc::new(user), Arc::new(password.to_string()))),
+            Some((Cow::Borrowed(_), _)) => creds.push(Cred::Line(Arc::new(line.as_bytes().to_vec()))),
+            None => return Err(format!("invalid list format: {:?}", line).into()),
         }
 
         buf.clear();
     }
 
-    Ok(creds)
+    Ok((creds, warnings))
 }
 
-pub fn load_scripts(paths: Vec<String>, config: &Arc<Config>) -> Result<Vec<Arc<ctx::Script>>> {
+/// A user:password pair, or the reason why a line was skipped
+pub type Combo = ::std::result::Result<(Arc<String>, Arc<String>), String>;
+
+/// Parse a list of user:password pairs, malformed lines are returned as
+/// errors with their line number so the rest of the list can still be used
+pub fn parse_combos<R: BufRead>(mut reader: R, options: &ListOptions) -> Result<Vec<Combo>> {
+    let mut combos = Vec::new();
+    let mut buf = Vec::new();
+
+    let mut lineno = 0;
+    while 0 < reader.read_until(b'\n', &mut buf)? {
+        lineno += 1;
+
+        let line = match str::from_utf8(&buf) {
+            Ok(line) => options.normalize(line.trim_end_matches('\n'), lineno == 1),
+            Err(_) => {
+                combos.push(Err(format!("line {}: invalid utf8", lineno)));
+                buf.clear();
+                continue;
+            },
+        };
+
+        if line.is_empty() || line.starts_with('#') {
+            buf.clear();
+            continue;
+        }
+
+        // passwords may contain colons, split on the first one only
+        match split_combo(line, ':') {
+            Some((user, password)) => {
+                combos.push(Ok((Arc::new(user.into_owned()), Arc::new(password.to_string()))));
+            },
+            None => combos.push(Err(format!("line {}: missing colon", lineno))),
+        }
+
+        buf.clear();
+    }
+
+    Ok(combos)
+}
+
+pub fn load_combos(path: &str, options: &ListOptions) -> Result<Vec<Combo>> {
+    parse_combos(open(path)?, options)
+}
+
+/// Expand directories to the scripts they contain
+pub fn script_paths(paths: Vec<String>) -> Result<Vec<String>> {
     let mut scripts = Vec::new();
 
     for path in paths {
         let meta = fs::metadata(&path)?;
 
         if meta.is_dir() {
-            for path in fs::read_dir(path)? {
-                let path = path?.path();
-                let path = path.to_str().unwrap();
-                let script = Arc::new(ctx::Script::load(path, config.clone())?);
-                scripts.push(script);
+            // sort the scripts so the order of attempts is stable
+            let mut paths = fs::read_dir(path)?
+                .map(|x| x.map(|x| x.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            paths.sort();
+
+            for path in paths {
+                scripts.push(path.to_str().unwrap().to_string());
             }
         } else {
-            let script = Arc::new(ctx::Script::load(&path, config.clone())?);
-            scripts.push(script);
+            scripts.push(path);
         }
     }
 
     Ok(scripts)
 }
+
+/// Load the scripts, with targets every script is loaded once per target
+pub fn load_scripts(paths: Vec<String>, targets: Option<&[Arc<String>]>, config: &Arc<Config>) -> Result<Vec<ctx::Script>> {
+    let paths = script_paths(paths)?;
+    match targets {
+        Some(targets) => paths.iter()
+            .flat_map(|path| targets.iter().map(move |target| ctx::Script::load_target(path, target, config.clone())))
+            .collect(),
+        None => paths.iter()
+            .map(|path| ctx::Script::load(path, config.clone()))
+            .collect(),
+    }
+}
+
+// thread_rng() reads u64s from its u32 buffer through an unaligned pointer,
+// which debug builds abort on. Anything that needs more than 32 random bits
+// is built from two u32 draws here instead of using gen() or gen_range()
+
+/// A random u64, eg. to seed a rng
+pub fn rand_u64() -> u64 {
+    let mut rng = thread_rng();
+    (u64::from(rng.next_u32()) << 32) | u64::from(rng.next_u32())
+}
+
+/// A random index below n, n has to be greater than zero
+pub fn rand_below(n: usize) -> usize {
+    (rand_u64() % n as u64) as usize
+}
+
+/// A random float in 0..1
+pub fn rand_f64() -> f64 {
+    (rand_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::env;
+    use xz2::write::XzEncoder;
+
+    fn gzip(name: &str, data: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("badtouch-{}-{}.gz", name, ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut gz = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        gz.write_all(data).unwrap();
+        gz.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn verify_normalize() {
+        let options = ListOptions::default();
+        assert_eq!(options.normalize("\u{feff}foo\r", true), "foo");
+        assert_eq!(options.normalize("\u{feff}foo", false), "\u{feff}foo");
+        assert_eq!(options.normalize("foo \r", false), "foo ");
+
+        let options = ListOptions {
+            trim: true,
+            ..ListOptions::default()
+        };
+        assert_eq!(options.normalize("foo \t\r", false), "foo");
+    }
+
+    #[test]
+    fn verify_dedup() {
+        let options = ListOptions::default();
+        assert_eq!(options.dedup(vec!["b", "a", "b", "c", "a"]), (vec!["b", "a", "c"], 2));
+
+        let options = ListOptions {
+            dedup: false,
+            ..ListOptions::default()
+        };
+        assert_eq!(options.dedup(vec!["a", "a"]), (vec!["a", "a"], 0));
+    }
+
+    #[test]
+    fn verify_gzip_list() {
+        let path = gzip("list", b"foo\nbar\n");
+        let (list, _) = load_list(&path, &ListOptions::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(list, vec![Arc::new("foo".to_string()), Arc::new("bar".to_string())]);
+    }
+
+    #[test]
+    fn verify_xz_list() {
+        let path = env::temp_dir().join(format!("badtouch-list-{}.xz", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        // concatenated streams are read like one list
+        let mut file = File::create(&path).unwrap();
+        for data in &[&b"foo\n"[..], &b"bar\n"[..]] {
+            let mut xz = XzEncoder::new(&mut file, 6);
+            xz.write_all(data).unwrap();
+            xz.finish().unwrap();
+        }
+
+        let (list, _) = load_list(&path, &ListOptions::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(list, vec![Arc::new("foo".to_string()), Arc::new("bar".to_string())]);
+    }
+
+    #[test]
+    fn verify_corrupt_gzip_list() {
+        let path = gzip("corrupt", &vec![b'a'; 4096]);
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let len = data.len();
+        data[len / 2] ^= 0xff;
+        data.truncate(len - 4);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let err = load_list(&path, &ListOptions::default()).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        let err = err.to_string();
+        assert!(err.contains(&path), "{}", err);
+        assert!(err.contains("at offset"), "{}", err);
+    }
+
+    #[test]
+    fn verify_parse_combos() {
+        let list = b"# leaked\nfoo:bar\r\n\nadmin:a:b:c\ninvalid\nempty:\n\xff:x\n";
+        let combos = parse_combos(&list[..], &ListOptions::default()).unwrap();
+
+        let combos = combos.iter()
+            .map(|x| match *x {
+                Ok((ref user, ref password)) => Ok((user.as_str(), password.as_str())),
+                Err(ref err) => Err(err.as_str()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(combos, vec![
+            Ok(("foo", "bar")),
+            Ok(("admin", "a:b:c")),
+            Err("line 5: missing colon"),
+            Ok(("empty", "")),
+            Err("line 7: invalid utf8"),
+        ]);
+    }
+
+    #[test]
+    fn verify_split_combo() {
+        assert_eq!(split_combo("admin:a:b", ':'), Some(("admin".into(), "a:b")));
+        assert_eq!(split_combo("\"acme:admin\":secret", ':'), Some(("acme:admin".into(), "secret")));
+        assert_eq!(split_combo("\"say \"\"hi\"\"\"\tx", '\t'), Some(("say \"hi\"".into(), "x")));
+        assert_eq!(split_combo("\"admin:secret", ':'), Some(("\"admin".into(), "secret")));
+        assert_eq!(split_combo("\"admin\"", ':'), None);
+        assert_eq!(split_combo("admin", ':'), None);
+
+        for &(user, password) in &[("acme:admin", "se:cret"), ("\"quoted", "x"), ("jane@acme.com", "")] {
+            let line = format_combo(user, password, ':');
+            assert_eq!(split_combo(&line, ':'), Some((user.into(), password)));
+        }
+        assert_eq!(format_combo("acme;admin", "a;b", ';'), "\"acme;admin\";a;b");
+    }
+
+    #[test]
+    fn verify_streamed_list() {
+        let path = env::temp_dir().join(format!("badtouch-stream-{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "\u{feff}foo\r\nbar\n\nbar\nbaz").unwrap();
+        assert_eq!(count_lines(path).unwrap(), 5);
+
+        let mut list = StreamedList::open(path, &ListOptions::default()).unwrap();
+        let lines = [0, 0, 1, 4, 2, 3, 3].iter()
+            .map(|idx| list.get(*idx).unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["foo", "foo", "bar", "baz", "", "bar", "bar"]);
+        assert!(list.get(5).is_err());
+
+        fs::write(path, "foo\n").unwrap();
+        assert_eq!(count_lines(path).unwrap(), 1);
+        fs::write(path, "").unwrap();
+        assert_eq!(count_lines(path).unwrap(), 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn verify_load_creds() {
+        let path = env::temp_dir().join(format!("badtouch-creds-{}.txt", ::std::process::id()));
+        fs::write(&path, "foo:bar\n\"acme:admin\":secret\nfoo:bar\n").unwrap();
+        let (creds, duplicates, warnings) = load_creds(path.to_str().unwrap(), &ListOptions::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(creds, vec![
+            Cred::Line(Arc::new(b"foo:bar".to_vec())),
+            Cred::Pair(Arc::new("acme:admin".to_string()), Arc::new("secret".to_string())),
+        ]);
+        assert_eq!(duplicates, 1);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn verify_parse_creds() {
+        let list = b"admin:pass:phrase\nroot:a:b::c\r\n:nouser\nnopassword:\ninvalid\n";
+        let err = parse_creds(&list[..], &ListOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid list format: \"invalid\"");
+
+        let (creds, warnings) = parse_creds(&list[..list.len() - 8], &ListOptions::default()).unwrap();
+        let creds = creds.iter()
+            .map(|x| match *x {
+                Cred::Line(ref line) => str::from_utf8(line).unwrap(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(creds, vec!["admin:pass:phrase", "root:a:b::c", ":nouser", "nopassword:"]);
+        assert_eq!(warnings, vec!["line 3: empty user", "line 4: empty password"]);
+    }
+
+    #[test]
+    fn verify_rand_below() {
+        let mut seen = HashSet::new();
+        for _ in 0..1000 {
+            let x = rand_below(3);
+            assert!(x < 3);
+            seen.insert(x);
+        }
+        assert_eq!(seen.len(), 3);
+    }
+}